@@ -0,0 +1,116 @@
+//! Throughput of the tunnel data path. Run with `cargo bench`.
+//!
+//! `datagram_buffers` compares a buffer allocated for each datagram, as the
+//! forwarding loops used to do, with buffers from a `BufferPool`.
+//! `static_tunnel` forwards datagrams end to end through a static tunnel on
+//! loopback.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use moblink_rust::buffer_pool::{BufferPool, DATAGRAM_BUFFER_SIZE};
+use moblink_rust::relay::{Relay, StaticTunnel};
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+/// Typical SRT datagram with 7 MPEG-TS packets.
+const DATAGRAM_SIZE: usize = 1316;
+/// Datagrams in flight, well below the socket receive buffer sizes.
+const BATCH_SIZE: usize = 32;
+
+fn datagram_buffers(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("datagram_buffers");
+    group.throughput(Throughput::Bytes(DATAGRAM_SIZE as u64));
+    let datagram = [0x47; DATAGRAM_SIZE];
+    group.bench_function("allocate", |bencher| {
+        bencher.iter(|| {
+            let mut buf = std::hint::black_box(vec![0; DATAGRAM_BUFFER_SIZE]);
+            buf[..DATAGRAM_SIZE].copy_from_slice(&datagram);
+            // Queued for pacing.
+            std::hint::black_box(buf[..DATAGRAM_SIZE].to_vec())
+        })
+    });
+    let mut pool = BufferPool::default();
+    group.bench_function("pool", |bencher| {
+        bencher.iter(|| {
+            let mut buf = std::hint::black_box(pool.take());
+            buf.extend_from_slice(&datagram);
+            let datagram: Bytes = std::hint::black_box(buf.freeze());
+            pool.give_bytes(datagram);
+        })
+    });
+    group.finish();
+}
+
+struct Tunnel {
+    streamer: UdpSocket,
+    destination: UdpSocket,
+    _relay: Relay,
+}
+
+async fn start_tunnel() -> Tunnel {
+    let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let listen_address: SocketAddr = {
+        // Any free port.
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    };
+    let relay = Relay::new();
+    relay.set_bind_address("127.0.0.1".to_string()).await;
+    let static_tunnel: StaticTunnel =
+        format!("{}->{}", listen_address, destination.local_addr().unwrap())
+            .parse()
+            .unwrap();
+    relay.start_static_tunnel(static_tunnel).await.unwrap();
+    let streamer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    streamer.connect(listen_address).await.unwrap();
+    Tunnel {
+        streamer,
+        destination,
+        _relay: relay,
+    }
+}
+
+/// Lost datagrams are not waited for.
+async fn forward(tunnel: &Tunnel, datagrams: u64) -> Duration {
+    let datagram = [0x47; DATAGRAM_SIZE];
+    let mut buf = [0; DATAGRAM_BUFFER_SIZE];
+    let start = Instant::now();
+    let mut remaining = datagrams as usize;
+    while remaining > 0 {
+        let batch_size = remaining.min(BATCH_SIZE);
+        for _ in 0..batch_size {
+            tunnel.streamer.send(&datagram).await.unwrap();
+        }
+        for _ in 0..batch_size {
+            let received = tokio::time::timeout(
+                Duration::from_millis(100),
+                tunnel.destination.recv_from(&mut buf),
+            )
+            .await;
+            if received.is_err() {
+                break;
+            }
+        }
+        remaining -= batch_size;
+    }
+    start.elapsed()
+}
+
+fn static_tunnel(criterion: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = criterion.benchmark_group("static_tunnel");
+    group.throughput(Throughput::Bytes(DATAGRAM_SIZE as u64));
+    let tunnel = runtime.block_on(start_tunnel());
+    // The tunnel is started by the first datagram.
+    runtime.block_on(forward(&tunnel, 1));
+    group.bench_function("udp", |bencher| {
+        bencher.iter_custom(|datagrams| runtime.block_on(forward(&tunnel, datagrams)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, datagram_buffers, static_tunnel);
+criterion_main!(benches);
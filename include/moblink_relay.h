@@ -0,0 +1,88 @@
+#ifndef MOBLINK_RELAY_H
+#define MOBLINK_RELAY_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum MoblinkRelayResult {
+  MOBLINK_RELAY_RESULT_OK = 0,
+  // A null pointer, a string that is not UTF-8 or an invalid relay id.
+  MOBLINK_RELAY_RESULT_INVALID_ARGUMENT,
+  MOBLINK_RELAY_RESULT_INVALID_STREAMER_URL,
+  MOBLINK_RELAY_RESULT_INVALID_BIND_ADDRESS,
+  MOBLINK_RELAY_RESULT_NO_NETWORK_INTERFACE,
+  MOBLINK_RELAY_RESULT_EMPTY_NAME,
+  MOBLINK_RELAY_RESULT_INVALID_PACING,
+  MOBLINK_RELAY_RESULT_WRONG_PASSWORD,
+  MOBLINK_RELAY_RESULT_STOPPED,
+  // A bug in the relay, which should be freed.
+  MOBLINK_RELAY_RESULT_PANIC,
+} MoblinkRelayResult;
+
+typedef struct MoblinkRelay MoblinkRelay;
+
+// Called with the status text, which is only valid during the call, and the
+// user data given to `moblink_relay_setup()`. The other functions may be
+// called from it, but then return before they are done.
+typedef void (*MoblinkRelayStatusCallback)(const char *status, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a relay. Returns null if its runtime could not be created. Free
+// with `moblink_relay_free()`.
+struct MoblinkRelay *moblink_relay_new(void);
+
+// Stops and frees given relay. Does nothing if null. Called from the status
+// callback, the relay is stopped and freed on another thread.
+//
+// # Safety
+//
+// `relay` must be null or returned by `moblink_relay_new()`, and must not be
+// used after this.
+void moblink_relay_free(struct MoblinkRelay *relay);
+
+// Sets the streamer to connect to and the identity of the relay. A null
+// `relay_id` gives a random one, and `on_status_updated` may be null. Called
+// from the status callback, the relay is set up later and `Ok` is returned
+// if the arguments are valid.
+//
+// # Safety
+//
+// `relay` must be returned by `moblink_relay_new()` and the strings must be
+// null terminated. `user_data` must be safe to pass to `on_status_updated`
+// from any thread until the relay is freed.
+enum MoblinkRelayResult moblink_relay_setup(struct MoblinkRelay *relay,
+                                            const char *streamer_url,
+                                            const char *password,
+                                            const char *relay_id,
+                                            const char *name,
+                                            MoblinkRelayStatusCallback on_status_updated,
+                                            void *user_data);
+
+// Connects to the streamer, and reconnects until stopped. Called from the
+// status callback, the relay is started later and `Ok` is returned.
+//
+// # Safety
+//
+// `relay` must be returned by `moblink_relay_new()`.
+enum MoblinkRelayResult moblink_relay_start(struct MoblinkRelay *relay);
+
+// Disconnects from the streamer and stops the tunnel, if any. Called from
+// the status callback, the relay is stopped later.
+//
+// # Safety
+//
+// `relay` must be returned by `moblink_relay_new()`.
+void moblink_relay_stop(struct MoblinkRelay *relay);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MOBLINK_RELAY_H */
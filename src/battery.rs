@@ -0,0 +1,39 @@
+use starship_battery::Manager;
+use starship_battery::units::ratio::percent;
+use tracing::debug;
+
+use crate::relay::{GetStatusClosure, Status};
+
+/// Reports the battery level of this machine, for example a laptop, on Linux,
+/// macOS and Windows. The average if it has several batteries, and unknown if
+/// it has none.
+pub fn create_battery_get_status_closure() -> GetStatusClosure {
+    Box::new(|| {
+        Box::pin(async {
+            tokio::task::spawn_blocking(get_battery_status)
+                .await
+                .unwrap_or_default()
+        })
+    })
+}
+
+fn get_battery_status() -> Status {
+    let batteries = match Manager::new().and_then(|manager| manager.batteries()) {
+        Ok(batteries) => batteries,
+        Err(error) => {
+            debug!("Failed to get batteries with error: {}", error);
+            return Default::default();
+        }
+    };
+    let percentages: Vec<f32> = batteries
+        .flatten()
+        .map(|battery| battery.state_of_charge().get::<percent>())
+        .collect();
+    if percentages.is_empty() {
+        return Default::default();
+    }
+    let percentage = percentages.iter().sum::<f32>() / percentages.len() as f32;
+    Status {
+        battery_percentage: Some(percentage.round() as i32),
+    }
+}
@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+use moblink_rust::bonding::BondingReceiver;
+use moblink_rust::logging::{LoggingArgs, setup_logging};
+
+/// Receives SRT bonded by moblink-streamer --bonding-address across relays,
+/// and sends it to an SRT listener as a single stream.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address to receive from the relays on. The streamer's destination.
+    #[arg(long, default_value = "0.0.0.0:5000")]
+    listen_address: SocketAddr,
+
+    /// Address of the SRT listener, for example an SRT server or OBS.
+    #[arg(long)]
+    srt_listener: SocketAddr,
+
+    #[command(flatten)]
+    logging: LoggingArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+    setup_logging(&args.logging);
+    let receiver = BondingReceiver::start(args.listen_address, args.srt_listener).await?;
+    receiver.wait().await
+}
@@ -0,0 +1,253 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Parser;
+use moblink_rust::logging::{LoggingArgs, setup_logging};
+use moblink_rust::testing::{EchoDestination, MockStreamer};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::time::{Instant, MissedTickBehavior, interval, sleep_until};
+
+/// Sequence number and send time in microseconds.
+const HEADER_SIZE: usize = 16;
+const SEND_INTERVAL: Duration = Duration::from_millis(10);
+/// Echoes arriving later than this after the last datagram are lost.
+const DRAIN_TIME: Duration = Duration::from_secs(2);
+
+/// Accepts relays like a streamer, asks them to start a tunnel and measures
+/// the throughput through it, to validate a relay box before an event.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Password
+    #[arg(long, default_value = "1234")]
+    password: String,
+
+    /// Websocket server listener address.
+    #[arg(long, default_value = "0.0.0.0")]
+    websocket_server_address: String,
+
+    /// Websocket server listener port
+    #[arg(long, default_value = "7777")]
+    websocket_server_port: u16,
+
+    /// Port of the built-in UDP echo server the tunnel goes to. Ephemeral if
+    /// 0.
+    #[arg(long, default_value_t = 0)]
+    echo_port: u16,
+
+    /// Send to this UDP destination instead of the built-in echo server, for
+    /// example host:port of a real ingest. Only the sent bitrate is measured
+    /// unless it echoes.
+    #[arg(long)]
+    destination: Option<String>,
+
+    /// Bits per second to send through the tunnel.
+    #[arg(long, default_value_t = 10_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    bitrate: u64,
+
+    /// Datagram size in bytes. 1316 is seven MPEG-TS packets, as in SRT.
+    #[arg(long, default_value_t = 1316, value_parser = clap::value_parser!(u16).range(16..=1472))]
+    packet_size: u16,
+
+    /// Seconds to send for.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    duration: u64,
+
+    #[command(flatten)]
+    logging: LoggingArgs,
+}
+
+#[derive(Default)]
+struct Totals {
+    packets: u64,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct RoundTripTimes {
+    count: u64,
+    sum: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl RoundTripTimes {
+    fn add(&mut self, round_trip_time: Duration) {
+        self.count += 1;
+        self.sum += round_trip_time;
+        self.min = Some(
+            self.min
+                .map_or(round_trip_time, |min| min.min(round_trip_time)),
+        );
+        self.max = self.max.max(round_trip_time);
+    }
+
+    fn average(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+    setup_logging(&args.logging);
+
+    let (destination, echo) = match &args.destination {
+        Some(destination) => {
+            let address = lookup_host(destination)
+                .await?
+                .next()
+                .ok_or_else(|| format!("Destination '{}' not found", destination))?;
+            (address, None)
+        }
+        None => {
+            let echo =
+                EchoDestination::bind(SocketAddr::new("0.0.0.0".parse()?, args.echo_port)).await?;
+            (echo.address(), Some(echo))
+        }
+    };
+    let address = format!(
+        "{}:{}",
+        args.websocket_server_address, args.websocket_server_port
+    );
+    let listen_address = lookup_host(&address)
+        .await?
+        .next()
+        .ok_or_else(|| format!("Address '{}' not found", address))?;
+    let streamer = MockStreamer::bind(listen_address, &args.password, destination).await?;
+    println!(
+        "Waiting for a relay, for example moblink-relay --streamer-url ws://<this host>:{} --password <password>",
+        listen_address.port()
+    );
+    let tunnel = streamer.wait_for_tunnel().await?;
+    println!(
+        "Tunnel started at {}, sending {:.2} Mbit/s for {} s",
+        tunnel,
+        args.bitrate as f64 / 1e6,
+        args.duration
+    );
+    let ok = measure(&args, tunnel, echo.is_some()).await?;
+    std::process::exit(if ok { 0 } else { 1 })
+}
+
+/// Prints the throughput every second and a summary at the end. Fails if
+/// nothing was echoed.
+async fn measure(
+    args: &Args,
+    tunnel: SocketAddr,
+    echoed: bool,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let socket = UdpSocket::bind(SocketAddr::new(
+        if tunnel.is_ipv6() {
+            "::".parse()?
+        } else {
+            "0.0.0.0".parse()?
+        },
+        0,
+    ))
+    .await?;
+    socket.connect(tunnel).await?;
+    let packet_size = usize::from(args.packet_size);
+    let duration = Duration::from_secs(args.duration);
+    let start = Instant::now();
+    let mut packet = vec![0; packet_size];
+    let mut buffer = vec![0; 2048];
+    let mut sender = interval(SEND_INTERVAL);
+    sender.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut reporter = interval(Duration::from_secs(1));
+    reporter.tick().await;
+    let mut sent = Totals::default();
+    let mut received = Totals::default();
+    let mut latest_sent_bytes = 0;
+    let mut latest_received_bytes = 0;
+    let mut round_trip_times = RoundTripTimes::default();
+    let mut second_round_trip_times = RoundTripTimes::default();
+    let mut seconds = 0;
+    let mut sending = true;
+    loop {
+        tokio::select! {
+            _ = sender.tick(), if sending => {
+                let elapsed = start.elapsed();
+                if elapsed >= duration {
+                    sending = false;
+                    continue;
+                }
+                let due = (elapsed.as_secs_f64() * args.bitrate as f64 / 8.0
+                    / packet_size as f64) as u64;
+                while sent.packets < due {
+                    packet[..8].copy_from_slice(&sent.packets.to_be_bytes());
+                    let now = start.elapsed().as_micros() as u64;
+                    packet[8..HEADER_SIZE].copy_from_slice(&now.to_be_bytes());
+                    socket.send(&packet).await?;
+                    sent.packets += 1;
+                    sent.bytes += packet_size as u64;
+                }
+            }
+            result = socket.recv(&mut buffer) => {
+                let Ok(size) = result else {
+                    // For example ICMP port unreachable until the relay
+                    // forwards.
+                    continue;
+                };
+                if size < HEADER_SIZE {
+                    continue;
+                }
+                let sent_at = u64::from_be_bytes(buffer[8..HEADER_SIZE].try_into()?);
+                let round_trip_time = start.elapsed().saturating_sub(Duration::from_micros(sent_at));
+                round_trip_times.add(round_trip_time);
+                second_round_trip_times.add(round_trip_time);
+                received.packets += 1;
+                received.bytes += size as u64;
+            }
+            _ = reporter.tick(), if seconds < args.duration => {
+                seconds += 1;
+                print!(
+                    "{:3} s: sent {:.2} Mbit/s",
+                    seconds,
+                    8.0 * (sent.bytes - latest_sent_bytes) as f64 / 1e6
+                );
+                if echoed {
+                    print!(
+                        ", received {:.2} Mbit/s",
+                        8.0 * (received.bytes - latest_received_bytes) as f64 / 1e6
+                    );
+                    if let Some(average) = second_round_trip_times.average() {
+                        print!(", RTT {:.1} ms", average.as_secs_f64() * 1000.0);
+                    }
+                }
+                println!();
+                latest_sent_bytes = sent.bytes;
+                latest_received_bytes = received.bytes;
+                second_round_trip_times = RoundTripTimes::default();
+            }
+            _ = sleep_until(start + duration + DRAIN_TIME) => break,
+        }
+    }
+    let seconds = duration.as_secs_f64();
+    print!(
+        "Sent {} packets ({:.2} Mbit/s)",
+        sent.packets,
+        8.0 * sent.bytes as f64 / seconds / 1e6
+    );
+    if !echoed {
+        println!();
+        return Ok(true);
+    }
+    let lost = sent.packets.saturating_sub(received.packets);
+    print!(
+        ", received {} ({:.2} Mbit/s), lost {:.1} %",
+        received.packets,
+        8.0 * received.bytes as f64 / seconds / 1e6,
+        100.0 * lost as f64 / sent.packets.max(1) as f64
+    );
+    if let (Some(min), Some(average)) = (round_trip_times.min, round_trip_times.average()) {
+        print!(
+            ", RTT min/avg/max {:.1}/{:.1}/{:.1} ms",
+            min.as_secs_f64() * 1000.0,
+            average.as_secs_f64() * 1000.0,
+            round_trip_times.max.as_secs_f64() * 1000.0
+        );
+    }
+    println!();
+    Ok(received.packets > 0)
+}
@@ -2,8 +2,10 @@ use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::Parser;
-use moblink_rust::relay::create_get_status_closure;
+use moblink_rust::logging::{LoggingArgs, setup_logging};
+use moblink_rust::relay::{StatusExecutable, create_get_status_closure};
 use moblink_rust::relay_service::RelayService;
+use tracing::info;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -12,6 +14,11 @@ struct Args {
     #[arg(long, default_value = "1234")]
     password: String,
 
+    /// Name of the relay on each network interface. {hostname}, {interface},
+    /// {ip} and {carrier} are replaced with the values of the interface.
+    #[arg(long, default_value = "{interface}")]
+    name: String,
+
     /// Network interfaces to allow as a regex (^ prefix and $ suffix are added
     /// automatically). Localhost is never allowed.
     #[arg(long)]
@@ -22,56 +29,89 @@ struct Args {
     #[arg(long)]
     network_interfaces_to_ignore: Vec<String>,
 
-    /// Log level
-    #[arg(long, default_value = "info")]
-    log_level: String,
-
-    /// No log timestamps
-    #[arg(long)]
-    no_log_timestamps: bool,
+    #[command(flatten)]
+    logging: LoggingArgs,
 
     /// Status executable.
     /// Print status to standard output on format {"batteryPercentage": 93}.
     #[arg(long)]
     status_executable: Option<String>,
 
+    /// Argument to the status executable. Can be given multiple times.
+    #[arg(long, allow_hyphen_values = true)]
+    status_executable_arg: Vec<String>,
+
+    /// The status executable is killed if it runs for longer than this, in
+    /// seconds.
+    #[arg(long, default_value_t = 5)]
+    status_timeout: u64,
+
     /// Status file.
     /// Contains status on format {"batteryPercentage": 93}.
     #[arg(long)]
     status_file: Option<String>,
 
+    /// How often to run the status executable, in seconds.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    status_interval: u64,
+
+    /// For how long the latest output of the status executable is used, in
+    /// seconds.
+    #[arg(long, default_value_t = 30)]
+    status_ttl: u64,
+
     /// Database with relay ids
     #[arg(long, default_value = "moblink-relay-service.json")]
     database: PathBuf,
 }
 
-fn setup_logging(timestamps: bool, log_level: &str) {
-    let mut builder = env_logger::builder();
-    if timestamps {
-        builder.format_timestamp_millis()
-    } else {
-        builder.format_timestamp(None)
-    }
-    .parse_filters(log_level)
-    .init();
-}
-
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    setup_logging(!args.no_log_timestamps, &args.log_level);
+    setup_logging(&args.logging);
 
     let relay_service = RelayService::new(
         args.password,
         args.network_interfaces_to_allow,
         args.network_interfaces_to_ignore,
-        create_get_status_closure(&args.status_executable, &args.status_file),
+        create_get_status_closure(
+            &args.status_executable.map(|path| StatusExecutable {
+                path,
+                arguments: args.status_executable_arg,
+                timeout: Duration::from_secs(args.status_timeout),
+                interval: Duration::from_secs(args.status_interval),
+                ttl: Duration::from_secs(args.status_ttl),
+            }),
+            &args.status_file,
+        ),
         args.database,
     )
     .await;
+    relay_service.set_name_template(args.name).await;
     relay_service.start().await;
+    wait_for_shutdown_signal().await;
+    info!("Shutting down");
+    relay_service.stop().await;
+}
+
+/// SIGINT or SIGTERM.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
 
-    loop {
-        tokio::time::sleep(Duration::from_secs(3600)).await;
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 }
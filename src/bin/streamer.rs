@@ -1,8 +1,10 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::Parser;
 use gethostname::gethostname;
+use moblink_rust::logging::{LoggingArgs, setup_logging};
 use moblink_rust::streamer;
 
 fn hostname() -> String {
@@ -53,30 +55,20 @@ struct Args {
     #[arg(long, default_value = "/opt/belaUI/config.json")]
     belabox_config: PathBuf,
 
-    /// Log level
-    #[arg(long, default_value = "info")]
-    log_level: String,
+    /// Bond SRT sent by the encoder to this address, for example
+    /// 0.0.0.0:6000, across all relays. The destination must be a
+    /// moblink-bonding-receiver.
+    #[arg(long, conflicts_with = "belabox")]
+    bonding_address: Option<SocketAddr>,
 
-    /// No log timestamps
-    #[arg(long)]
-    no_log_timestamps: bool,
-}
-
-fn setup_logging(timestamps: bool, log_level: &str) {
-    let mut builder = env_logger::builder();
-    if timestamps {
-        builder.format_timestamp_millis()
-    } else {
-        builder.format_timestamp(None)
-    }
-    .parse_filters(log_level)
-    .init();
+    #[command(flatten)]
+    logging: LoggingArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
-    setup_logging(!args.no_log_timestamps, &args.log_level);
+    setup_logging(&args.logging);
 
     if !args.belabox {
         if args.destination_address.is_none() {
@@ -99,6 +91,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         args.belabox,
         args.belabox_config,
     )?;
+    if let Some(bonding_address) = args.bonding_address {
+        streamer.set_bonding_address(bonding_address).await;
+    }
     streamer.start().await?;
 
     loop {
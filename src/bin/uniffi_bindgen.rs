@@ -0,0 +1,7 @@
+//! Generates the Kotlin and Swift bindings of the library, for example with
+//! `cargo run --features uniffi --bin uniffi-bindgen generate --library
+//! target/release/libmoblink_rust.so --language kotlin --out-dir out`.
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
@@ -0,0 +1,502 @@
+//! SRT bonding over Moblink relays, for a complete self-hosted bonding setup
+//! without SRTLA.
+//!
+//! The streamer side (`BondingSender`) receives SRT from the encoder and
+//! spreads the datagrams across the tunnels of all relays. The relays forward
+//! them to a `BondingReceiver` next to the SRT listener, for example on the
+//! ingest server, which sends them all from one socket to the listener, as if
+//! they came over a single path. SRT puts them back in order and retransmits
+//! lost ones. Replies from the listener go back through the relays.
+//!
+//! The receiver only accepts datagrams from relays that have completed a
+//! handshake, so that spoofed source addresses cannot join and be sent the
+//! listener's replies. The sender probes the receiver through each relay, the
+//! receiver answers with a cookie derived from the relay's address and the
+//! sender echoes it back.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, interval};
+use tracing::{Instrument, debug, info};
+
+use crate::multipath::Scheduler;
+use crate::utils::{AbortOnDrop, AnyError};
+
+/// The weight of relays that have not reported a quality score.
+const DEFAULT_WEIGHT: u32 = 50;
+/// Relays that have not sent anything for this long are no longer sent
+/// control packets by the receiver.
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Well within `LINK_TIMEOUT`, so that links that stop carrying the stream
+/// stay joined.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// An SRT control packet of a type that SRT listeners ignore.
+const HANDSHAKE_MAGIC: &[u8] = b"\xff\xffmoblink";
+const HANDSHAKE_PROBE: u8 = 1;
+const HANDSHAKE_COOKIE: u8 = 2;
+const HANDSHAKE_COOKIE_ECHO: u8 = 3;
+/// Larger than the cookie, so that the receiver cannot be used to amplify
+/// traffic.
+const PROBE_SIZE: usize = 64;
+
+type Cookie = [u8; 32];
+
+enum Handshake {
+    Probe,
+    Cookie(Cookie),
+    CookieEcho(Cookie),
+}
+
+fn parse_handshake(datagram: &[u8]) -> Option<Handshake> {
+    let (&kind, rest) = datagram.strip_prefix(HANDSHAKE_MAGIC)?.split_first()?;
+    match kind {
+        HANDSHAKE_PROBE if datagram.len() == PROBE_SIZE => Some(Handshake::Probe),
+        HANDSHAKE_COOKIE => Some(Handshake::Cookie(rest.try_into().ok()?)),
+        HANDSHAKE_COOKIE_ECHO => Some(Handshake::CookieEcho(rest.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn handshake(kind: u8, cookie: &[u8]) -> Vec<u8> {
+    let mut datagram = HANDSHAKE_MAGIC.to_vec();
+    datagram.push(kind);
+    datagram.extend_from_slice(cookie);
+    datagram
+}
+
+fn probe() -> Vec<u8> {
+    let mut datagram = handshake(HANDSHAKE_PROBE, &[]);
+    datagram.resize(PROBE_SIZE, 0);
+    datagram
+}
+
+fn cookie(secret: &[u8], address: SocketAddr) -> Cookie {
+    Sha256::new()
+        .chain_update(secret)
+        .chain_update(address.to_string())
+        .finalize()
+        .into()
+}
+
+struct Link {
+    id: u32,
+    socket: Arc<UdpSocket>,
+    weight: u32,
+    backup: bool,
+    /// Forwards datagrams from the relay to the encoder and does the
+    /// handshake with the receiver.
+    _receiver: AbortOnDrop,
+}
+
+#[derive(Default)]
+struct Links {
+    links: Vec<Link>,
+    /// Of the links in use.
+    indexes: Vec<usize>,
+    scheduler: Option<Scheduler>,
+}
+
+impl Links {
+    /// Backup relays are only used when there are no main relays.
+    fn update_scheduler(&mut self) {
+        let backup = self.links.iter().all(|link| link.backup);
+        self.indexes = (0..self.links.len())
+            .filter(|index| self.links[*index].backup == backup)
+            .collect();
+        let weights: Vec<u32> = self
+            .indexes
+            .iter()
+            .map(|index| self.links[*index].weight)
+            .collect();
+        self.scheduler = (!weights.is_empty()).then(|| Scheduler::new(&weights));
+    }
+
+    fn next(&mut self) -> Option<Arc<UdpSocket>> {
+        let index = self.scheduler.as_mut()?.next();
+        Some(self.links[self.indexes[index]].socket.clone())
+    }
+}
+
+/// Streamer side. Receives SRT from the encoder and distributes it across the
+/// relay tunnels, weighted by the quality scores of the relays, see
+/// `Relay::get_quality_score()`.
+pub(crate) struct BondingSender {
+    socket: Arc<UdpSocket>,
+    encoder_address: Arc<Mutex<Option<SocketAddr>>>,
+    links: Arc<Mutex<Links>>,
+    task: JoinHandle<()>,
+}
+
+impl BondingSender {
+    /// The encoder sends SRT to given address, in caller mode.
+    pub(crate) async fn start(address: SocketAddr) -> Result<Self, AnyError> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        info!("Bonding SRT from the encoder on {}", socket.local_addr()?);
+        let encoder_address = Arc::new(Mutex::new(None));
+        let links = Arc::new(Mutex::new(Links::default()));
+        let task = tokio::spawn(
+            distribute(socket.clone(), encoder_address.clone(), links.clone()).in_current_span(),
+        );
+        Ok(Self {
+            socket,
+            encoder_address,
+            links,
+            task,
+        })
+    }
+
+    /// Adds the tunnel of a relay, connected to the relay's tunnel port.
+    /// `id` is unique per relay. Backup relays are only used when no main
+    /// relay is.
+    pub(crate) fn add_link(
+        &self,
+        id: u32,
+        relay_socket: Arc<UdpSocket>,
+        quality_score: Option<u8>,
+        backup: bool,
+    ) {
+        let receiver = tokio::spawn(
+            serve_link(
+                relay_socket.clone(),
+                self.socket.clone(),
+                self.encoder_address.clone(),
+            )
+            .in_current_span(),
+        );
+        let mut links = self.links.lock().unwrap();
+        links.links.retain(|link| link.id != id);
+        links.links.push(Link {
+            id,
+            socket: relay_socket,
+            weight: weight(quality_score),
+            backup,
+            _receiver: AbortOnDrop(receiver),
+        });
+        links.update_scheduler();
+        info!(
+            "Number of bonded relays: {} ({} in use)",
+            links.links.len(),
+            links.indexes.len()
+        );
+    }
+
+    pub(crate) fn remove_link(&self, id: u32) {
+        let mut links = self.links.lock().unwrap();
+        links.links.retain(|link| link.id != id);
+        links.update_scheduler();
+        info!(
+            "Number of bonded relays: {} ({} in use)",
+            links.links.len(),
+            links.indexes.len()
+        );
+    }
+
+    pub(crate) fn set_quality_score(&self, id: u32, quality_score: Option<u8>) {
+        let mut links = self.links.lock().unwrap();
+        let Some(link) = links.links.iter_mut().find(|link| link.id == id) else {
+            return;
+        };
+        let weight = weight(quality_score);
+        if link.weight != weight {
+            link.weight = weight;
+            links.update_scheduler();
+        }
+    }
+}
+
+impl Drop for BondingSender {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Unusable relays still get a few datagrams, so that their score can
+/// recover.
+fn weight(quality_score: Option<u8>) -> u32 {
+    quality_score.map_or(DEFAULT_WEIGHT, |score| u32::from(score).max(1))
+}
+
+async fn distribute(
+    socket: Arc<UdpSocket>,
+    encoder_address: Arc<Mutex<Option<SocketAddr>>>,
+    links: Arc<Mutex<Links>>,
+) {
+    let mut buffer = vec![0; 2048];
+    loop {
+        let (size, address) = match socket.recv_from(&mut buffer).await {
+            Ok(result) => result,
+            Err(error) => {
+                debug!("Failed to receive from the encoder with error: {}", error);
+                continue;
+            }
+        };
+        if encoder_address.lock().unwrap().replace(address) != Some(address) {
+            info!("Encoder is {}", address);
+        }
+        let Some(relay_socket) = links.lock().unwrap().next() else {
+            continue;
+        };
+        // A failing relay is removed when its tunnel is destroyed.
+        relay_socket.send(&buffer[..size]).await.ok();
+    }
+}
+
+async fn serve_link(
+    relay_socket: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    encoder_address: Arc<Mutex<Option<SocketAddr>>>,
+) {
+    let mut buffer = vec![0; 2048];
+    let mut probe_timer = interval(PROBE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = probe_timer.tick() => {
+                relay_socket.send(&probe()).await.ok();
+            }
+            result = relay_socket.recv(&mut buffer) => {
+                let Ok(size) = result else {
+                    break;
+                };
+                let datagram = &buffer[..size];
+                if let Some(Handshake::Cookie(cookie)) = parse_handshake(datagram) {
+                    relay_socket
+                        .send(&handshake(HANDSHAKE_COOKIE_ECHO, &cookie))
+                        .await
+                        .ok();
+                    continue;
+                }
+                let encoder_address = *encoder_address.lock().unwrap();
+                if let Some(encoder_address) = encoder_address {
+                    socket.send_to(datagram, encoder_address).await.ok();
+                }
+            }
+        }
+    }
+}
+
+/// Ingest side. Sends datagrams from all relays to an SRT listener from one
+/// socket, and replies from the listener back through the relays. Control
+/// packets, for example acknowledgements, are sent through all relays that
+/// are active, so that a lost relay does not stall the stream, and other
+/// packets through the relay that was active most recently. All relays are
+/// assumed to carry the same stream.
+pub struct BondingReceiver {
+    task: JoinHandle<Result<(), AnyError>>,
+}
+
+impl BondingReceiver {
+    /// Receives from the relays on given address.
+    pub async fn start(address: SocketAddr, srt_listener: SocketAddr) -> Result<Self, AnyError> {
+        let relay_socket = UdpSocket::bind(address).await?;
+        let listener_socket = UdpSocket::bind(SocketAddr::new(
+            if srt_listener.is_ipv6() {
+                "::".parse()?
+            } else {
+                "0.0.0.0".parse()?
+            },
+            0,
+        ))
+        .await?;
+        listener_socket.connect(srt_listener).await?;
+        info!(
+            "Bonding relays on {} to the SRT listener at {}",
+            relay_socket.local_addr()?,
+            srt_listener
+        );
+        let task = tokio::spawn(reassemble(relay_socket, listener_socket).in_current_span());
+        Ok(Self { task })
+    }
+
+    /// Waits until the receiver fails.
+    pub async fn wait(self) -> Result<(), AnyError> {
+        self.task.await?
+    }
+}
+
+async fn reassemble(relay_socket: UdpSocket, listener_socket: UdpSocket) -> Result<(), AnyError> {
+    let secret: [u8; 32] = rand::random();
+    // When relays last completed the handshake.
+    let mut handshakes: HashMap<SocketAddr, Instant> = HashMap::new();
+    let mut links: HashMap<SocketAddr, Instant> = HashMap::new();
+    let mut latest_link = None;
+    let mut relay_buffer = vec![0; 2048];
+    let mut listener_buffer = vec![0; 2048];
+    loop {
+        tokio::select! {
+            result = relay_socket.recv_from(&mut relay_buffer) => {
+                // For example ICMP port unreachable from a relay that left.
+                let (size, address) = match result {
+                    Ok(result) => result,
+                    Err(error) => {
+                        debug!("Failed to receive from the relays with error: {}", error);
+                        continue;
+                    }
+                };
+                let datagram = &relay_buffer[..size];
+                match parse_handshake(datagram) {
+                    Some(Handshake::Probe) => {
+                        let cookie = cookie(&secret, address);
+                        relay_socket
+                            .send_to(&handshake(HANDSHAKE_COOKIE, &cookie), address)
+                            .await
+                            .ok();
+                        continue;
+                    }
+                    Some(Handshake::CookieEcho(echoed)) => {
+                        if echoed == cookie(&secret, address) {
+                            handshakes.insert(address, Instant::now());
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                if handshakes
+                    .get(&address)
+                    .is_none_or(|handshake| handshake.elapsed() >= LINK_TIMEOUT)
+                {
+                    continue;
+                }
+                if links.insert(address, Instant::now()).is_none() {
+                    info!("Relay {} joined", address);
+                }
+                latest_link = Some(address);
+                // For example ICMP port unreachable while the listener is
+                // down.
+                listener_socket.send(datagram).await.ok();
+            }
+            result = listener_socket.recv(&mut listener_buffer) => {
+                let Ok(size) = result else {
+                    continue;
+                };
+                let packet = &listener_buffer[..size];
+                links.retain(|address, last_seen| {
+                    let active = last_seen.elapsed() < LINK_TIMEOUT;
+                    if !active {
+                        info!("Relay {} left", address);
+                    }
+                    active
+                });
+                handshakes.retain(|_, handshake| handshake.elapsed() < LINK_TIMEOUT);
+                // The highest bit is set in SRT control packets.
+                if packet.first().is_some_and(|byte| byte & 0x80 != 0) {
+                    for address in links.keys() {
+                        relay_socket.send_to(packet, address).await.ok();
+                    }
+                } else if let Some(address) = latest_link {
+                    relay_socket.send_to(packet, address).await.ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct Setup {
+        relay: UdpSocket,
+        listener: UdpSocket,
+        _receiver: AbortOnDrop,
+    }
+
+    async fn setup() -> Setup {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        listener_socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        relay
+            .connect(relay_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        listener
+            .connect(listener_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        let receiver = tokio::spawn(async move {
+            reassemble(relay_socket, listener_socket).await.ok();
+        });
+        Setup {
+            relay,
+            listener,
+            _receiver: AbortOnDrop(receiver),
+        }
+    }
+
+    async fn receive(socket: &UdpSocket, duration: Duration) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; 2048];
+        let size = tokio::time::timeout(duration, socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buffer[..size].to_vec())
+    }
+
+    #[tokio::test]
+    async fn relays_without_handshake_are_ignored() {
+        let setup = setup().await;
+        setup.relay.send(b"data").await.unwrap();
+        assert_eq!(
+            receive(&setup.listener, Duration::from_millis(200)).await,
+            None
+        );
+        setup
+            .relay
+            .send(&handshake(HANDSHAKE_COOKIE_ECHO, &[0; 32]))
+            .await
+            .unwrap();
+        setup.relay.send(b"data").await.unwrap();
+        assert_eq!(
+            receive(&setup.listener, Duration::from_millis(200)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_with_handshake_are_bonded() {
+        let setup = setup().await;
+        setup.relay.send(&probe()).await.unwrap();
+        let Some(Handshake::Cookie(cookie)) =
+            parse_handshake(&receive(&setup.relay, TIMEOUT).await.unwrap())
+        else {
+            panic!("Expected cookie");
+        };
+        setup
+            .relay
+            .send(&handshake(HANDSHAKE_COOKIE_ECHO, &cookie))
+            .await
+            .unwrap();
+        setup.relay.send(b"data").await.unwrap();
+        assert_eq!(
+            receive(&setup.listener, TIMEOUT).await,
+            Some(b"data".to_vec())
+        );
+        setup.listener.send(&[0x80, 1]).await.unwrap();
+        assert_eq!(receive(&setup.relay, TIMEOUT).await, Some(vec![0x80, 1]));
+    }
+
+    #[tokio::test]
+    async fn short_probes_are_not_answered() {
+        let setup = setup().await;
+        setup
+            .relay
+            .send(&handshake(HANDSHAKE_PROBE, &[]))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive(&setup.relay, Duration::from_millis(200)).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use bytes::{Bytes, BytesMut};
+
+/// Large enough for any datagram in a tunnel.
+pub const DATAGRAM_BUFFER_SIZE: usize = 2048;
+
+/// Reusable datagram buffers for a forwarding loop. Datagrams are received
+/// directly into buffers from the pool, without zeroing or allocating a
+/// buffer per datagram, and queued datagrams are shared as `Bytes` instead
+/// of copied. Buffers are returned to the pool once sent.
+pub struct BufferPool {
+    free: Vec<BytesMut>,
+    buffer_size: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// Keeps at most `max_free` unused buffers of given size.
+    pub fn new(buffer_size: usize, max_free: usize) -> Self {
+        Self {
+            free: Vec::new(),
+            buffer_size,
+            max_free,
+        }
+    }
+
+    /// An empty buffer with room for one datagram.
+    pub fn take(&mut self) -> BytesMut {
+        self.free
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.buffer_size))
+    }
+
+    pub fn give(&mut self, mut buffer: BytesMut) {
+        buffer.clear();
+        if self.free.len() < self.max_free && buffer.capacity() >= self.buffer_size {
+            self.free.push(buffer);
+        }
+    }
+
+    /// Reused if nothing else refers to it anymore.
+    pub fn give_bytes(&mut self, bytes: Bytes) {
+        if let Ok(buffer) = bytes.try_into_mut() {
+            self.give(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DATAGRAM_BUFFER_SIZE, 64)
+    }
+}
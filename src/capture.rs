@@ -0,0 +1,251 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+
+/// Raw IPv4 or IPv6 packets without a link layer header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+/// Datagrams are dropped instead of slowing down forwarding if the disk
+/// cannot keep up.
+const QUEUE_SIZE: usize = 4096;
+/// Written datagrams reach the file at least this often, so that a capture
+/// is useful also if the relay is killed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where and how much to capture.
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    pub path: PathBuf,
+    /// A new file is started when the current one reaches this size.
+    pub max_file_size: u64,
+    /// A new file is started when the current one is this old, if given.
+    pub max_file_age: Option<Duration>,
+    /// Number of earlier files to keep, `<path>.1` being the newest. The
+    /// oldest is removed when a new file is started.
+    pub files: usize,
+}
+
+struct Record {
+    timestamp: SystemTime,
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: Vec<u8>,
+}
+
+/// Writes relayed datagrams to pcap files, with synthetic IP and UDP headers
+/// from the streamer and destination addresses, for analysis in for example
+/// Wireshark. Files are rotated by size and age, keeping a limited number of
+/// them, so that a capture never fills the disk. Written on a dedicated
+/// thread, which stops when the capture is dropped.
+pub struct Capture {
+    records: SyncSender<Record>,
+}
+
+impl Capture {
+    /// An existing capture file is kept as the newest earlier file.
+    pub fn start(config: CaptureConfig) -> Result<Self, std::io::Error> {
+        if config.path.exists() {
+            rotate(&config)?;
+        }
+        let writer = Writer::create(config)?;
+        let (records, receiver) = sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("capture".into())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self { records })
+    }
+
+    pub(crate) fn record(&self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        self.records
+            .try_send(Record {
+                timestamp: SystemTime::now(),
+                source,
+                destination,
+                payload: payload.to_vec(),
+            })
+            .ok();
+    }
+}
+
+struct Writer {
+    config: CaptureConfig,
+    file: BufWriter<File>,
+    size: u64,
+    created_at: Instant,
+}
+
+impl Writer {
+    fn create(config: CaptureConfig) -> Result<Self, std::io::Error> {
+        let mut file = BufWriter::new(File::create(&config.path)?);
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Time zone and timestamp accuracy.
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            config,
+            file,
+            size: header.len() as u64,
+            created_at: Instant::now(),
+        })
+    }
+
+    fn run(mut self, records: Receiver<Record>) {
+        info!(
+            "Capturing tunnel datagrams to {}",
+            self.config.path.display()
+        );
+        loop {
+            let result = match records.recv_timeout(FLUSH_INTERVAL) {
+                Ok(record) if self.is_full() => match self.rotated() {
+                    Ok(writer) => {
+                        self = writer;
+                        self.write(record)
+                    }
+                    Err(error) => {
+                        warn!("Capture stopped with error: {}", error);
+                        return;
+                    }
+                },
+                Ok(record) => self.write(record),
+                Err(RecvTimeoutError::Timeout) => self.file.flush(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(error) = result {
+                warn!("Capture stopped with error: {}", error);
+                return;
+            }
+        }
+        self.file.flush().ok();
+    }
+
+    fn is_full(&self) -> bool {
+        self.size >= self.config.max_file_size
+            || self
+                .config
+                .max_file_age
+                .is_some_and(|max_file_age| self.created_at.elapsed() >= max_file_age)
+    }
+
+    /// Closed before renamed, as open files cannot be renamed on Windows.
+    fn rotated(mut self) -> Result<Self, std::io::Error> {
+        self.file.flush()?;
+        let config = self.config.clone();
+        drop(self);
+        rotate(&config)?;
+        Writer::create(config)
+    }
+
+    fn write(&mut self, record: Record) -> Result<(), std::io::Error> {
+        let packet = create_packet(record.source, record.destination, &record.payload);
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let length = packet.len().min(SNAPLEN as usize);
+        self.file
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.file
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(length as u32).to_le_bytes())?;
+        self.file.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.file.write_all(&packet[..length])?;
+        self.size += 16 + length as u64;
+        Ok(())
+    }
+}
+
+/// Renames `<path>.<n>` to `<path>.<n + 1>`, and the capture file to
+/// `<path>.1`, removing the oldest.
+fn rotate(config: &CaptureConfig) -> Result<(), std::io::Error> {
+    if config.files == 0 {
+        return remove_if_exists(&config.path);
+    }
+    remove_if_exists(&numbered_path(&config.path, config.files))?;
+    for number in (1..config.files).rev() {
+        rename_if_exists(
+            &numbered_path(&config.path, number),
+            &numbered_path(&config.path, number + 1),
+        )?;
+    }
+    rename_if_exists(&config.path, &numbered_path(&config.path, 1))
+}
+
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", number));
+    path.into()
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    match fs::rename(from, to) {
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// An IPv4 packet if both addresses are IPv4, otherwise IPv6 with IPv4
+/// addresses mapped. The UDP checksum is left out, which Wireshark does not
+/// check by default.
+fn create_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = (8 + payload.len()).min(u16::MAX.into()) as u16;
+    let mut packet = Vec::with_capacity(48 + payload.len());
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            let mut header = [0; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&udp_length.saturating_add(20).to_be_bytes());
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&source_ip.octets());
+            header[16..20].copy_from_slice(&destination_ip.octets());
+            let checksum = ipv4_header_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (source_ip, destination_ip) => {
+            let to_ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&udp_length.to_be_bytes());
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&to_ipv6(source_ip).octets());
+            packet.extend_from_slice(&to_ipv6(destination_ip).octets());
+        }
+    }
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&payload[..usize::from(udp_length) - 8]);
+    packet
+}
+
+fn ipv4_header_checksum(header: &[u8; 20]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
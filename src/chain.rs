@@ -0,0 +1,410 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, UdpSocket, lookup_host};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, accept_async, connect_async};
+use tracing::{Instrument, debug, info, info_span};
+
+use crate::obfuscation::Obfuscation;
+use crate::protocol::*;
+use crate::secret::SecretString;
+use crate::utils::{AnyError, random_string, resolve_host};
+
+pub(crate) type WebSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Relay chaining, for setups where the relay cannot reach the destination
+/// directly, but another relay (typically on a jump host) can.
+///
+/// The downstream relay connects to the upstream relay's chain server and
+/// plays the streamer role of the Moblink protocol. It asks the upstream relay
+/// to start a nested tunnel to the destination and then sends the tunnel
+/// traffic to the upstream relay instead of to the destination. The traffic
+/// between the relays is obfuscated if both have the same obfuscation key.
+pub struct ChainServer {
+    server: JoinHandle<()>,
+}
+
+impl ChainServer {
+    /// Accepts downstream relays on given address. Nested tunnel traffic to
+    /// destinations is sent from given bind address. Downstream relays may
+    /// obfuscate nested tunnels with given key, see
+    /// `Relay::set_obfuscation_key()`.
+    pub async fn start(
+        address: &str,
+        password: String,
+        bind_address: String,
+        obfuscation_key: Option<SecretString>,
+    ) -> Result<Self, AnyError> {
+        let obfuscation = obfuscation_key.as_ref().map(Obfuscation::new);
+        let listener = TcpListener::bind(address).await?;
+        info!("Chain server listening on '{}'", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            while let Ok((tcp_stream, downstream_address)) = listener.accept().await {
+                let password = password.clone();
+                let bind_address = bind_address.clone();
+                let obfuscation = obfuscation.clone();
+                let span = info_span!("chain", downstream = %downstream_address);
+                tokio::spawn(
+                    async move {
+                        info!("Downstream relay connected");
+                        match accept_async(MaybeTlsStream::Plain(tcp_stream)).await {
+                            Ok(websocket) => {
+                                if let Err(error) = serve_downstream(
+                                    websocket,
+                                    &password,
+                                    &bind_address,
+                                    obfuscation,
+                                )
+                                .await
+                                {
+                                    info!("Downstream relay failed with error: {}", error);
+                                }
+                            }
+                            Err(error) => {
+                                info!("WebSocket handshake failed with error: {}", error);
+                            }
+                        }
+                        info!("Downstream relay disconnected");
+                    }
+                    .instrument(span),
+                );
+            }
+        });
+        Ok(Self { server })
+    }
+
+    pub fn stop(&self) {
+        self.server.abort();
+    }
+}
+
+/// Upstream side. Plays the relay role, but also challenges the downstream
+/// relay before accepting its start tunnel request.
+async fn serve_downstream(
+    mut websocket: WebSocket,
+    password: &str,
+    bind_address: &str,
+    obfuscation: Option<Obfuscation>,
+) -> Result<(), AnyError> {
+    let MessageToRelay::Hello(hello) = receive(&mut websocket).await? else {
+        return Err("Expected hello".into());
+    };
+    let challenge = random_string();
+    let salt = random_string();
+    let identify = Identify {
+        api_version: Some(API_VERSION.to_string()),
+        id: uuid::Uuid::new_v4(),
+        name: "Chain".to_string(),
+        authentication: calculate_authentication(
+            password,
+            &hello.authentication.salt,
+            &hello.authentication.challenge,
+        ),
+        compression: None,
+        capabilities: obfuscation
+            .iter()
+            .map(|_| CAPABILITY_OBFUSCATION.to_string())
+            .collect(),
+        priority: None,
+        group: None,
+        challenge: Some(Authentication {
+            challenge: challenge.clone(),
+            salt: salt.clone(),
+        }),
+    };
+    send(&mut websocket, &MessageToStreamer::Identify(identify)).await?;
+    let MessageToRelay::Identified(identified) = receive(&mut websocket).await? else {
+        return Err("Expected identified".into());
+    };
+    if let MoblinkResult::WrongPassword(_) = identified.result {
+        return Err("Wrong password".into());
+    }
+    // Anyone can connect, so no tunnel is started for downstream relays that
+    // do not know the password.
+    if identified.authentication != Some(calculate_authentication(password, &salt, &challenge)) {
+        return Err("Downstream relay has wrong password".into());
+    }
+    let MessageToRelay::Request(request) = receive(&mut websocket).await? else {
+        return Err("Expected request".into());
+    };
+    let MessageRequestData::StartTunnel(start_tunnel) = &request.data else {
+        return Err("Expected start tunnel request".into());
+    };
+    let obfuscation = if start_tunnel.obfuscated {
+        Some(obfuscation.ok_or("Obfuscation requested without obfuscation key")?)
+    } else {
+        None
+    };
+    let destination_address = SocketAddr::new(
+        resolve_host(&start_tunnel.address).await?.parse()?,
+        start_tunnel.port,
+    );
+    let downstream_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let destination_socket = Arc::new(UdpSocket::bind(format!("{}:0", bind_address)).await?);
+    let port = downstream_socket.local_addr()?.port();
+    let response =
+        request.to_ok_response(ResponseData::StartTunnel(StartTunnelResponseData { port }));
+    send(&mut websocket, &MessageToStreamer::Response(response)).await?;
+    info!(
+        "Nested tunnel on port {} to {} started{}",
+        port,
+        destination_address,
+        if obfuscation.is_some() {
+            ", obfuscated"
+        } else {
+            ""
+        }
+    );
+    let forwarder = tokio::spawn(
+        forward(
+            downstream_socket,
+            destination_socket,
+            destination_address,
+            obfuscation,
+        )
+        .in_current_span(),
+    );
+    // The nested tunnel lives as long as the connection.
+    while let Some(message) = websocket.next().await {
+        if let Message::Close(_) = message? {
+            break;
+        }
+    }
+    forwarder.abort();
+    Ok(())
+}
+
+async fn forward(
+    downstream_socket: Arc<UdpSocket>,
+    destination_socket: Arc<UdpSocket>,
+    destination_address: SocketAddr,
+    obfuscation: Option<Obfuscation>,
+) -> Result<(), AnyError> {
+    let mut downstream_address = None;
+    let mut downstream_buffer = [0; 2048];
+    let mut destination_buffer = [0; 2048];
+    loop {
+        tokio::select! {
+            result = downstream_socket.recv_from(&mut downstream_buffer) => {
+                let (size, address) = result?;
+                downstream_address = Some(address);
+                let datagram = &downstream_buffer[..size];
+                let deobfuscated;
+                let datagram = match &obfuscation {
+                    Some(obfuscation) => {
+                        let Some(payload) = obfuscation.deobfuscate(datagram) else {
+                            debug!("Dropping too short obfuscated datagram");
+                            continue;
+                        };
+                        deobfuscated = payload;
+                        &deobfuscated
+                    }
+                    None => datagram,
+                };
+                destination_socket
+                    .send_to(datagram, destination_address)
+                    .await?;
+            }
+            result = destination_socket.recv(&mut destination_buffer) => {
+                let size = result?;
+                if let Some(address) = downstream_address {
+                    let datagram = &destination_buffer[..size];
+                    let obfuscated;
+                    let datagram = match &obfuscation {
+                        Some(obfuscation) => {
+                            obfuscated = obfuscation.obfuscate(datagram);
+                            &obfuscated
+                        }
+                        None => datagram,
+                    };
+                    downstream_socket.send_to(datagram, address).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Downstream side. Plays the streamer role, but also proves that it knows the
+/// password by answering the upstream relay's challenge. Returns the connection, which
+/// must be kept open for the nested tunnel's lifetime, the upstream relay's
+/// tunnel address to send traffic to and whether the traffic is obfuscated.
+/// Only obfuscated if asked to and the upstream relay supports it.
+pub(crate) async fn start_nested_tunnel(
+    upstream_url: &str,
+    password: &str,
+    destination_address: &str,
+    destination_port: u16,
+    obfuscate: bool,
+) -> Result<(WebSocket, SocketAddr, bool), AnyError> {
+    let url = url::Url::parse(upstream_url)?;
+    let host = url.host_str().ok_or("No host in upstream relay URL")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let upstream_ip = lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or("Upstream relay host not found")?
+        .ip();
+    let (mut websocket, _) =
+        timeout(Duration::from_secs(10), connect_async(upstream_url)).await??;
+    let challenge = random_string();
+    let salt = random_string();
+    let hello = Hello {
+        api_version: API_VERSION.to_string(),
+        authentication: Authentication {
+            challenge: challenge.clone(),
+            salt: salt.clone(),
+        },
+        capabilities: Vec::new(),
+    };
+    send(&mut websocket, &MessageToRelay::Hello(hello)).await?;
+    let MessageToStreamer::Identify(identify) = receive(&mut websocket).await? else {
+        return Err("Expected identify".into());
+    };
+    if identify.authentication != calculate_authentication(password, &salt, &challenge) {
+        let identified = Identified {
+            result: MoblinkResult::WrongPassword(Present {}),
+            authentication: None,
+        };
+        send(&mut websocket, &MessageToRelay::Identified(identified)).await?;
+        return Err("Upstream relay has wrong password".into());
+    }
+    let Some(upstream_challenge) = &identify.challenge else {
+        return Err("Upstream relay sent no challenge".into());
+    };
+    let identified = Identified {
+        result: MoblinkResult::Ok(Present {}),
+        authentication: Some(calculate_authentication(
+            password,
+            &upstream_challenge.salt,
+            &upstream_challenge.challenge,
+        )),
+    };
+    send(&mut websocket, &MessageToRelay::Identified(identified)).await?;
+    let obfuscated = obfuscate
+        && identify
+            .capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_OBFUSCATION);
+    let request = MessageRequest {
+        id: 1,
+        data: MessageRequestData::StartTunnel(StartTunnelRequest {
+            address: destination_address.to_string(),
+            port: destination_port,
+            obfuscated,
+            paired_ports: false,
+        }),
+    };
+    send(&mut websocket, &MessageToRelay::Request(request)).await?;
+    let MessageToStreamer::Response(MessageResponse {
+        result: MoblinkResult::Ok(_),
+        data: ResponseData::StartTunnel(data),
+        ..
+    }) = receive(&mut websocket).await?
+    else {
+        return Err("Upstream relay failed to start tunnel".into());
+    };
+    debug!("Nested tunnel on upstream port {}", data.port);
+    Ok((
+        websocket,
+        SocketAddr::new(upstream_ip, data.port),
+        obfuscated,
+    ))
+}
+
+pub(crate) async fn send<T: serde::Serialize>(
+    websocket: &mut WebSocket,
+    message: &T,
+) -> Result<(), AnyError> {
+    let text = serde_json::to_string(message)?;
+    websocket.send(Message::Text(text.into())).await?;
+    Ok(())
+}
+
+pub(crate) async fn receive<T: serde::de::DeserializeOwned>(
+    websocket: &mut WebSocket,
+) -> Result<T, AnyError> {
+    loop {
+        let message = timeout(Duration::from_secs(10), websocket.next())
+            .await?
+            .ok_or("Connection closed")??;
+        match message {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(_) => return Err("Connection closed".into()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start_upstream() -> (String, JoinHandle<Result<(), AnyError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let upstream = tokio::spawn(async move {
+            let (tcp_stream, _) = listener.accept().await?;
+            let websocket = accept_async(MaybeTlsStream::Plain(tcp_stream)).await?;
+            serve_downstream(websocket, "1234", "127.0.0.1", None).await
+        });
+        (url, upstream)
+    }
+
+    #[tokio::test]
+    async fn nested_tunnel_is_started_with_password() {
+        let (url, _upstream) = start_upstream().await;
+        let (_websocket, address, obfuscated) =
+            start_nested_tunnel(&url, "1234", "127.0.0.1", 9999, false)
+                .await
+                .unwrap();
+        assert!(address.ip().is_loopback());
+        assert_ne!(address.port(), 0);
+        assert!(!obfuscated);
+    }
+
+    #[tokio::test]
+    async fn downstream_without_password_is_rejected() {
+        let (url, upstream) = start_upstream().await;
+        let (mut websocket, _) = connect_async(&url).await.unwrap();
+        let hello = Hello {
+            api_version: API_VERSION.to_string(),
+            authentication: Authentication {
+                challenge: random_string(),
+                salt: random_string(),
+            },
+            capabilities: Vec::new(),
+        };
+        send(&mut websocket, &MessageToRelay::Hello(hello))
+            .await
+            .unwrap();
+        let MessageToStreamer::Identify(identify) = receive(&mut websocket).await.unwrap() else {
+            panic!("Expected identify");
+        };
+        assert!(identify.challenge.is_some());
+        let identified = Identified {
+            result: MoblinkResult::Ok(Present {}),
+            authentication: Some("wrong".to_string()),
+        };
+        send(&mut websocket, &MessageToRelay::Identified(identified))
+            .await
+            .unwrap();
+        let request = MessageRequest {
+            id: 1,
+            data: MessageRequestData::StartTunnel(StartTunnelRequest {
+                address: "127.0.0.1".to_string(),
+                port: 9999,
+                obfuscated: false,
+                paired_ports: false,
+            }),
+        };
+        send(&mut websocket, &MessageToRelay::Request(request))
+            .await
+            .ok();
+        assert!(upstream.await.unwrap().is_err());
+        assert!(receive::<MessageToStreamer>(&mut websocket).await.is_err());
+    }
+}
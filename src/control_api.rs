@@ -0,0 +1,336 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::data_usage::InterfaceDataUsage;
+use crate::http_server::{HttpRequest, HttpResponse, http_get, start_http_server};
+use crate::metrics::InMemoryMetricsSink;
+use crate::modem_manager::ModemStatus;
+use crate::pacing::Pacing;
+use crate::relay::{Relay, RelayState};
+use crate::secret::SecretString;
+use crate::streamer_url::normalize_streamer_url;
+use crate::stun::NatInfo;
+use crate::thermal::ThermalZone;
+use crate::utils::AnyError;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct State {
+    started: bool,
+    connected: bool,
+    #[serde(flatten)]
+    state: RelayState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    battery_percentage: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nat: Option<NatInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    modems: Vec<ModemStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    thermal_zones: Vec<ThermalZone>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_usage: Option<InterfaceDataUsage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Settings {
+    streamer_url: Option<String>,
+    password: Option<SecretString>,
+}
+
+#[derive(Deserialize)]
+struct Name {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Annotation {
+    annotation: Option<String>,
+}
+
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Local HTTP API to control a relay.
+///
+/// - `GET /` serves a dashboard.
+/// - `GET /state`, `GET /stats`, `GET /tunnels` and `GET /reconnects` return
+///   JSON.
+/// - `GET /metrics` returns metrics in the Prometheus text format, if a metrics
+///   sink is given.
+/// - `POST /start` and `POST /stop` starts and stops the relay.
+/// - `POST /pause` and `POST /resume` pauses and resumes relaying. The relay
+///   stays connected to the streamer while paused.
+/// - `POST /duplication/on` and `POST /duplication/off` starts and stops
+///   sending every datagram to the destination twice, or on all multipath
+///   paths.
+/// - `POST /pacing` with `{"bitrate": 8000000}`, `{"gapUs": 500}` or `null`
+///   sets or disables pacing of datagrams to the destination.
+/// - `POST /tunnels/{id}/annotation` with `{"annotation": "camera 2"}` sets the
+///   annotation of given tunnel. `null` removes it.
+/// - `POST /name` with `{"name": "Phone 2"}` renames the relay without
+///   reconnecting, if the streamer supports it.
+/// - `POST /settings` with `{"streamerUrl": "...", "password": "..."}` updates
+///   the settings and reconnects. The streamer URL is normalized and any
+///   corrections are listed in the response.
+///
+/// `POST` requests must have the content type `application/json`, also
+/// without a body, and if they have an `Origin` header, it must be the same
+/// host as the `Host` header. Browsers then do not send them from other web
+/// pages without a CORS preflight, which is not answered.
+pub struct ControlApi {
+    server: JoinHandle<()>,
+}
+
+impl ControlApi {
+    pub async fn start(
+        address: &str,
+        relay: Relay,
+        metrics: Option<Arc<InMemoryMetricsSink>>,
+    ) -> Result<Self, AnyError> {
+        let server = start_http_server(
+            address,
+            Arc::new(move |request| {
+                let relay = relay.clone();
+                let metrics = metrics.clone();
+                Box::pin(async move { handle_request(request, relay, metrics).await })
+            }),
+        )
+        .await?;
+        Ok(Self { server })
+    }
+
+    pub fn stop(&self) {
+        self.server.abort();
+    }
+}
+
+/// The `/state` of the relay with the control API at given address, as JSON.
+pub async fn get_state(address: &str) -> Result<serde_json::Value, AnyError> {
+    let (status, body) = http_get(address, "/state").await?;
+    if status != 200 {
+        return Err(format!("Unexpected status {}", status).into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// What `GET /state` returns.
+pub(crate) async fn get_relay_state(relay: &Relay) -> State {
+    State {
+        started: relay.is_started().await,
+        connected: relay.is_connected().await,
+        state: relay.state().await,
+        battery_percentage: relay.get_status().await.battery_percentage,
+        nat: relay.get_nat().await,
+        modems: relay.get_modems().await,
+        thermal_zones: relay.get_thermal_zones().await,
+        data_usage: relay.get_data_usage().await,
+    }
+}
+
+async fn handle_request(
+    request: HttpRequest,
+    relay: Relay,
+    metrics: Option<Arc<InMemoryMetricsSink>>,
+) -> HttpResponse {
+    if request.method == "POST"
+        && let Err(response) = check_post_request(&request)
+    {
+        return response;
+    }
+    if let Some(id) = request
+        .path
+        .strip_prefix("/tunnels/")
+        .and_then(|path| path.strip_suffix("/annotation"))
+    {
+        return handle_annotation_request(&request, id, relay).await;
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => HttpResponse::new(200, "text/html", DASHBOARD_HTML.into()),
+        ("GET", "/state") => HttpResponse::json(&get_relay_state(&relay).await),
+        ("GET", "/stats") => HttpResponse::json(&relay.get_stats().await),
+        ("GET", "/tunnels") => HttpResponse::json(&relay.get_tunnels().await),
+        ("GET", "/reconnects") => HttpResponse::json(&relay.get_reconnects().await),
+        ("GET", "/metrics") => match metrics {
+            Some(metrics) => HttpResponse::new(
+                200,
+                "text/plain; version=0.0.4",
+                metrics.to_prometheus_text().into_bytes(),
+            ),
+            None => HttpResponse::not_found(),
+        },
+        ("POST", "/start") => match relay.start().await {
+            Ok(()) => HttpResponse::text(200, "Started"),
+            Err(error) => HttpResponse::text(400, &error.to_string()),
+        },
+        ("POST", "/stop") => {
+            relay.stop().await;
+            HttpResponse::text(200, "Stopped")
+        }
+        ("POST", "/pause") => {
+            relay.pause().await;
+            HttpResponse::text(200, "Paused")
+        }
+        ("POST", "/resume") => {
+            relay.resume().await;
+            HttpResponse::text(200, "Resumed")
+        }
+        ("POST", "/duplication/on") => {
+            relay.set_duplication(true).await;
+            HttpResponse::text(200, "Duplicating")
+        }
+        ("POST", "/duplication/off") => {
+            relay.set_duplication(false).await;
+            HttpResponse::text(200, "Not duplicating")
+        }
+        ("POST", "/pacing") => match serde_json::from_slice::<Option<Pacing>>(&request.body) {
+            Ok(pacing) => match relay.set_pacing(pacing).await {
+                Ok(()) => HttpResponse::text(200, "Pacing updated"),
+                Err(error) => HttpResponse::text(400, &error.to_string()),
+            },
+            Err(error) => HttpResponse::text(400, &format!("Invalid pacing: {}", error)),
+        },
+        ("POST", "/name") => match serde_json::from_slice::<Name>(&request.body) {
+            Ok(name) => match relay.set_name(name.name).await {
+                Ok(()) => HttpResponse::text(200, "Renamed"),
+                Err(error) => HttpResponse::text(400, &error.to_string()),
+            },
+            Err(error) => HttpResponse::text(400, &format!("Invalid name: {}", error)),
+        },
+        ("POST", "/settings") => match serde_json::from_slice::<Settings>(&request.body) {
+            Ok(settings) => {
+                let mut corrections = Vec::new();
+                let streamer_url =
+                    match settings.streamer_url.as_deref().map(normalize_streamer_url) {
+                        Some(Ok(streamer_url)) => {
+                            corrections = streamer_url.corrections;
+                            Some(streamer_url.url)
+                        }
+                        Some(Err(error)) => return HttpResponse::text(400, &error.to_string()),
+                        None => None,
+                    };
+                if let Err(error) = relay.update_settings(streamer_url, settings.password).await {
+                    return HttpResponse::text(400, &error.to_string());
+                }
+                let mut text = "Settings updated".to_string();
+                for correction in corrections {
+                    text += &format!("\n{}", correction);
+                }
+                HttpResponse::text(200, &text)
+            }
+            Err(error) => HttpResponse::text(400, &format!("Invalid settings: {}", error)),
+        },
+        (
+            _,
+            "/" | "/state" | "/stats" | "/tunnels" | "/reconnects" | "/metrics" | "/start"
+            | "/stop" | "/pause" | "/resume" | "/duplication/on" | "/duplication/off" | "/pacing"
+            | "/name" | "/settings",
+        ) => HttpResponse::text(405, "Method not allowed"),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+/// Rejects cross-site requests, see `ControlApi`.
+fn check_post_request(request: &HttpRequest) -> Result<(), HttpResponse> {
+    let is_json = request.header("content-type").is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Err(HttpResponse::text(
+            415,
+            "Content type must be application/json",
+        ));
+    }
+    if let Some(origin) = request.header("origin") {
+        let origin_host = origin.split_once("://").map(|(_, host)| host);
+        if origin_host.is_none() || origin_host != request.header("host") {
+            return Err(HttpResponse::text(403, "Cross origin request"));
+        }
+    }
+    Ok(())
+}
+
+async fn handle_annotation_request(request: &HttpRequest, id: &str, relay: Relay) -> HttpResponse {
+    if request.method != "POST" {
+        return HttpResponse::text(405, "Method not allowed");
+    }
+    let Ok(id) = id.parse() else {
+        return HttpResponse::not_found();
+    };
+    match serde_json::from_slice::<Annotation>(&request.body) {
+        Ok(annotation) => match relay.set_tunnel_annotation(id, annotation.annotation).await {
+            Ok(()) => HttpResponse::text(200, "Annotation updated"),
+            Err(error) => HttpResponse::text(404, &error.to_string()),
+        },
+        Err(error) => HttpResponse::text(400, &format!("Invalid annotation: {}", error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: "POST".to_string(),
+            path: "/settings".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    fn status(request: &HttpRequest) -> Option<u16> {
+        check_post_request(request)
+            .err()
+            .map(|response| response.status)
+    }
+
+    #[test]
+    fn accepts_json_posts() {
+        assert_eq!(status(&post(&[("content-type", "application/json")])), None);
+        assert_eq!(
+            status(&post(&[
+                ("Content-Type", "Application/JSON; charset=utf-8"),
+                ("Host", "127.0.0.1:8081"),
+                ("Origin", "http://127.0.0.1:8081"),
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_other_content_types() {
+        assert_eq!(status(&post(&[])), Some(415));
+        assert_eq!(status(&post(&[("Content-Type", "text/plain")])), Some(415));
+    }
+
+    #[test]
+    fn rejects_cross_origin_posts() {
+        assert_eq!(
+            status(&post(&[
+                ("Content-Type", "application/json"),
+                ("Host", "127.0.0.1:8081"),
+                ("Origin", "https://example.com"),
+            ])),
+            Some(403)
+        );
+        assert_eq!(
+            status(&post(&[
+                ("Content-Type", "application/json"),
+                ("Host", "127.0.0.1:8081"),
+                ("Origin", "null"),
+            ])),
+            Some(403)
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::fs;
+
+use crate::utils::AnyError;
+
+/// A monthly data cap per network interface, typically of a cellular plan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataQuota {
+    pub bytes: u64,
+    /// Warn when this many percent of the quota is used.
+    pub warning_percentage: u8,
+    /// Pause relaying when the quota is used up, until the next month.
+    pub pause_when_exceeded: bool,
+}
+
+/// Bytes relayed on a network interface this month.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceDataUsage {
+    pub interface: String,
+    /// For example `2025-06`.
+    pub month: String,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+}
+
+/// Months, as `2025-06`, to interfaces to bytes.
+type Months = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// Bytes relayed per network interface and calendar month (UTC), persisted
+/// as JSON. Shared by all relays of a process, see `Relay::set_data_usage()`.
+///
+/// Both directions between the relay and the destination are counted, as
+/// both are billed on cellular plans.
+pub struct DataUsage {
+    path: PathBuf,
+    quota: Option<DataQuota>,
+    months: Mutex<Months>,
+}
+
+impl DataUsage {
+    /// Loads the usage from given file, which is created when first saved.
+    pub fn load(path: PathBuf, quota: Option<DataQuota>) -> Result<Arc<Self>, AnyError> {
+        let months = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(|error| {
+                format!("Invalid data usage in '{}': {}", path.display(), error)
+            })?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Months::new(),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(Arc::new(Self {
+            path,
+            quota,
+            months: Mutex::new(months),
+        }))
+    }
+
+    pub fn quota(&self) -> Option<DataQuota> {
+        self.quota
+    }
+
+    /// Adds given bytes to the usage of given interface, and returns its usage
+    /// this month.
+    pub(crate) fn add(&self, interface: &str, bytes: u64) -> InterfaceDataUsage {
+        let month = current_month();
+        let mut months = self.months.lock().unwrap();
+        let used = months
+            .entry(month.clone())
+            .or_default()
+            .entry(interface.to_string())
+            .or_default();
+        *used += bytes;
+        InterfaceDataUsage {
+            interface: interface.to_string(),
+            month,
+            bytes: *used,
+            quota_bytes: self.quota.map(|quota| quota.bytes),
+        }
+    }
+
+    /// The usage of given interface this month.
+    pub fn get(&self, interface: &str) -> InterfaceDataUsage {
+        let month = current_month();
+        let bytes = self
+            .months
+            .lock()
+            .unwrap()
+            .get(&month)
+            .and_then(|interfaces| interfaces.get(interface))
+            .copied()
+            .unwrap_or_default();
+        InterfaceDataUsage {
+            interface: interface.to_string(),
+            month,
+            bytes,
+            quota_bytes: self.quota.map(|quota| quota.bytes),
+        }
+    }
+
+    /// Replaces the file atomically, so that it is never left half written.
+    pub(crate) async fn save(&self) -> Result<(), AnyError> {
+        let content = serde_json::to_vec_pretty(&*self.months.lock().unwrap())?;
+        if let Some(directory) = self.path.parent()
+            && !directory.as_os_str().is_empty()
+        {
+            fs::create_dir_all(directory).await?;
+        }
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, content).await?;
+        fs::rename(&temporary_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Parses a number of bytes like `100GB`, `1.5T`, `500M` or `1000000`.
+/// Units are powers of 1000, as used by carriers.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let number = value.trim();
+    let number = number.strip_suffix(['B', 'b']).unwrap_or(number).trim_end();
+    let (number, multiplier) = match number.chars().last().map(|unit| unit.to_ascii_uppercase()) {
+        Some('K') => (&number[..number.len() - 1], 1e3),
+        Some('M') => (&number[..number.len() - 1], 1e6),
+        Some('G') => (&number[..number.len() - 1], 1e9),
+        Some('T') => (&number[..number.len() - 1], 1e12),
+        _ => (number, 1.0),
+    };
+    match number.trim_end().parse::<f64>() {
+        Ok(number) if number.is_finite() && number > 0.0 => Ok((number * multiplier) as u64),
+        _ => Err(format!("Invalid number of bytes '{}'", value)),
+    }
+}
+
+/// The current calendar month in UTC, as `2025-06`.
+fn current_month() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (year, month) = year_and_month(days as i64);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// The year and month of given number of days since 1970-01-01, with the
+/// civil from days algorithm by Howard Hinnant.
+fn year_and_month(days: i64) -> (i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Starting with March.
+    let month = (5 * day_of_year + 2) / 153;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
@@ -0,0 +1,145 @@
+#[cfg(not(windows))]
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+
+use crate::interfaces::InterfacePolicy;
+#[cfg(not(windows))]
+use crate::interfaces::system_interfaces;
+
+/// IPv4 addresses of the network interface to bind to by default, selected
+/// with given policy. `None` if no interface is up.
+#[cfg(not(windows))]
+pub(crate) fn get_default_interface_ipv4_addresses(
+    policy: &InterfacePolicy,
+) -> Option<Vec<Ipv4Addr>> {
+    let interfaces: Vec<_> = system_interfaces()
+        .into_iter()
+        .filter(|interface| {
+            interface.is_up && !interface.is_loopback && !interface.addresses.is_empty()
+        })
+        .collect();
+    let names: Vec<&str> = interfaces
+        .iter()
+        .map(|interface| interface.name.as_str())
+        .collect();
+    let interface = &interfaces[policy.select(&names)?];
+    Some(
+        interface
+            .addresses
+            .iter()
+            .filter_map(|address| match address {
+                IpAddr::V4(address) => Some(*address),
+                IpAddr::V6(_) => None,
+            })
+            .collect(),
+    )
+}
+
+/// Interface flags are not reported reliably on Windows, so use the IP helper
+/// API instead. Prefers adapters with a default gateway, and then the one
+/// with the lowest metric, as Windows does when routing.
+#[cfg(windows)]
+pub(crate) fn get_default_interface_ipv4_addresses(
+    policy: &InterfacePolicy,
+) -> Option<Vec<Ipv4Addr>> {
+    use windows_sys::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GAA_FLAG_INCLUDE_GATEWAYS, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER,
+        GAA_FLAG_SKIP_MULTICAST, GetAdaptersAddresses, IF_TYPE_SOFTWARE_LOOPBACK,
+        IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows_sys::Win32::NetworkManagement::Ndis::IfOperStatusUp;
+    use windows_sys::Win32::Networking::WinSock::AF_INET;
+
+    let flags = GAA_FLAG_INCLUDE_GATEWAYS
+        | GAA_FLAG_SKIP_ANYCAST
+        | GAA_FLAG_SKIP_MULTICAST
+        | GAA_FLAG_SKIP_DNS_SERVER;
+    // Recommended initial size. u64 for alignment.
+    let mut size = 15_000;
+    let mut buffer: Vec<u64> = Vec::new();
+    // The needed size grows if adapters are added in between.
+    for attempt in 1..=3 {
+        buffer = vec![0; (size as usize).div_ceil(size_of::<u64>())];
+        // SAFETY: The buffer is at least size bytes.
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_INET as u32,
+                flags,
+                std::ptr::null(),
+                buffer.as_mut_ptr().cast(),
+                &mut size,
+            )
+        };
+        match result {
+            ERROR_SUCCESS => break,
+            ERROR_BUFFER_OVERFLOW if attempt < 3 => continue,
+            _ => return None,
+        }
+    }
+    let mut candidates = Vec::new();
+    let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+    while !adapter.is_null() {
+        // SAFETY: Adapters form a linked list in the buffer.
+        let current = unsafe { &*adapter };
+        adapter = current.Next;
+        if current.OperStatus != IfOperStatusUp || current.IfType == IF_TYPE_SOFTWARE_LOOPBACK {
+            continue;
+        }
+        let mut addresses = Vec::new();
+        let mut unicast_address = current.FirstUnicastAddress;
+        while !unicast_address.is_null() {
+            // SAFETY: Unicast addresses form a linked list in the buffer.
+            let current = unsafe { &*unicast_address };
+            unicast_address = current.Next;
+            // SAFETY: Points to a socket address in the buffer, if not null.
+            if let Some(address) = unsafe { to_ipv4_address(current.Address.lpSockaddr) } {
+                addresses.push(address);
+            }
+        }
+        if !addresses.is_empty() {
+            let has_default_gateway = !current.FirstGatewayAddress.is_null();
+            // SAFETY: Points to a null terminated string in the buffer.
+            let name = unsafe { from_wide(current.FriendlyName) };
+            candidates.push((!has_default_gateway, current.Ipv4Metric, name, addresses));
+        }
+    }
+    candidates.sort_by_key(|(no_default_gateway, metric, _, _)| (*no_default_gateway, *metric));
+    let names: Vec<&str> = candidates
+        .iter()
+        .map(|(_, _, name, _)| name.as_str())
+        .collect();
+    let index = policy.select(&names)?;
+    Some(candidates.swap_remove(index).3)
+}
+
+#[cfg(windows)]
+unsafe fn from_wide(string: *const u16) -> String {
+    if string.is_null() {
+        return String::new();
+    }
+    let mut length = 0;
+    // SAFETY: Null terminated.
+    while unsafe { *string.add(length) } != 0 {
+        length += 1;
+    }
+    // SAFETY: length characters before the null terminator.
+    String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(string, length) })
+}
+
+#[cfg(windows)]
+unsafe fn to_ipv4_address(
+    address: *const windows_sys::Win32::Networking::WinSock::SOCKADDR,
+) -> Option<Ipv4Addr> {
+    use windows_sys::Win32::Networking::WinSock::{AF_INET, SOCKADDR_IN};
+
+    if address.is_null() || unsafe { (*address).sa_family } != AF_INET {
+        return None;
+    }
+    // SAFETY: Checked to be an IPv4 socket address above.
+    let address = unsafe { &*(address as *const SOCKADDR_IN) };
+    // SAFETY: All fields of the union are the same four bytes, in network
+    // byte order.
+    let address = unsafe { address.sin_addr.S_un.S_addr };
+    Some(Ipv4Addr::from(u32::from_be(address)))
+}
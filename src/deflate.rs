@@ -0,0 +1,123 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::utils::AnyError;
+
+/// Ends every flushed block. Not sent, as in permessage-deflate.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Protects against decompression bombs.
+const MAXIMUM_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Compresses the messages of one connection as permessage-deflate (RFC 7692)
+/// does, but inside binary WebSocket frames, as tungstenite does not support
+/// the extension. The sliding window is kept between messages, so small and
+/// similar messages, which the control messages are, compress well.
+pub(crate) struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub(crate) fn new() -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, AnyError> {
+        let mut output = Vec::with_capacity(data.len() + 64);
+        let total_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - total_in) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)?;
+            // The flush is complete once there is room left in the output.
+            if output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity());
+        }
+        if output.ends_with(&TRAILER) {
+            output.truncate(output.len() - TRAILER.len());
+        }
+        Ok(output)
+    }
+
+    pub(crate) fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, AnyError> {
+        let input = [data, &TRAILER].concat();
+        let mut output = Vec::with_capacity(4 * input.len());
+        let total_in = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let status = self.decompress.decompress_vec(
+                &input[consumed..],
+                &mut output,
+                FlushDecompress::Sync,
+            )?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let is_output_full = output.len() == output.capacity();
+            if consumed == input.len() && !is_output_full {
+                break;
+            }
+            if status == Status::StreamEnd || (status == Status::BufError && !is_output_full) {
+                return Err("Corrupt compressed message".into());
+            }
+            if output.len() >= MAXIMUM_MESSAGE_SIZE {
+                return Err("Decompressed message too big".into());
+            }
+            if is_output_full {
+                output.reserve(output.capacity());
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_messages() {
+        let mut sender = Deflate::new();
+        let mut receiver = Deflate::new();
+        let messages: [&[u8]; 3] = [
+            br#"{"request":{"id":1,"data":{"startTunnel":{"address":"10.0.0.1","port":5000}}}}"#,
+            br#"{"request":{"id":2,"data":{"startTunnel":{"address":"10.0.0.1","port":5000}}}}"#,
+            b"",
+        ];
+        for message in messages {
+            let compressed = sender.compress(message).unwrap();
+            assert!(!compressed.ends_with(&TRAILER));
+            assert_eq!(receiver.decompress(&compressed).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn similar_messages_compress_better_later() {
+        let mut deflate = Deflate::new();
+        let message = br#"{"status":{"batteryPercentage":93,"name":"Relay"}}"#;
+        let first = deflate.compress(message).unwrap();
+        let second = deflate.compress(message).unwrap();
+        assert!(second.len() < first.len());
+    }
+
+    #[test]
+    fn round_trips_large_message() {
+        let message: Vec<u8> = (0..200_000).map(|index| (index * 7 % 251) as u8).collect();
+        let compressed = Deflate::new().compress(&message).unwrap();
+        assert_eq!(Deflate::new().decompress(&compressed).unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_too_big_message() {
+        let message = vec![0; 2 * MAXIMUM_MESSAGE_SIZE];
+        let compressed = Deflate::new().compress(&message).unwrap();
+        assert!(Deflate::new().decompress(&compressed).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_message() {
+        assert!(Deflate::new().decompress(&[0xff; 16]).is_err());
+    }
+}
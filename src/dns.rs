@@ -0,0 +1,114 @@
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "doh")]
+use std::str::FromStr;
+
+use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use tokio::net::lookup_host;
+
+use crate::utils::AnyError;
+
+/// Resolves host names, either with the system resolver or with given DNS
+/// servers. Answers from given servers are cached until their TTLs expire,
+/// and the system resolver caches as configured, so resolving again on every
+/// reconnect picks up changed addresses.
+#[derive(Clone, Default)]
+pub struct DnsResolver {
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl DnsResolver {
+    /// The system resolver, `getaddrinfo()` on unix.
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.resolver.is_none()
+    }
+
+    /// Plain DNS over UDP, falling back to TCP for truncated answers.
+    pub fn with_servers(servers: &[SocketAddr]) -> Self {
+        let mut name_servers = NameServerConfigGroup::new();
+        for server in servers {
+            name_servers.merge(NameServerConfigGroup::from_ips_clear(
+                &[server.ip()],
+                server.port(),
+                true,
+            ));
+        }
+        Self::with_name_servers(name_servers)
+    }
+
+    /// DNS over HTTPS, for networks that intercept or break plain DNS.
+    #[cfg(feature = "doh")]
+    pub fn with_https(server: &DnsOverHttpsServer) -> Self {
+        Self::with_name_servers(NameServerConfigGroup::from_ips_https(
+            &server.ips,
+            443,
+            server.name.clone(),
+            true,
+        ))
+    }
+
+    fn with_name_servers(name_servers: NameServerConfigGroup) -> Self {
+        let config = ResolverConfig::from_parts(None, vec![], name_servers);
+        Self {
+            resolver: Some(TokioAsyncResolver::tokio(config, ResolverOpts::default())),
+        }
+    }
+
+    /// Addresses of given host name or IP address, in preference order.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, AnyError> {
+        if let Ok(address) = host.parse() {
+            return Ok(vec![address]);
+        }
+        match &self.resolver {
+            Some(resolver) => Ok(resolver.lookup_ip(host).await?.iter().collect()),
+            None => Ok(lookup_host((host, 0))
+                .await?
+                .map(|address| address.ip())
+                .collect()),
+        }
+    }
+}
+
+/// A DNS over HTTPS server. Parsed from `cloudflare`, `google`, `quad9` or
+/// `<ip>,<ip>#<name>`, where the name is the one in the server's certificate.
+/// The addresses are given as the system resolver may be the broken one.
+#[cfg(feature = "doh")]
+#[derive(Clone, Debug)]
+pub struct DnsOverHttpsServer {
+    pub ips: Vec<IpAddr>,
+    pub name: String,
+}
+
+#[cfg(feature = "doh")]
+impl FromStr for DnsOverHttpsServer {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ips, name): (&[&str], &str) = match value {
+            "cloudflare" => (&["1.1.1.1", "1.0.0.1"], "cloudflare-dns.com"),
+            "google" => (&["8.8.8.8", "8.8.4.4"], "dns.google"),
+            "quad9" => (&["9.9.9.9", "149.112.112.112"], "dns.quad9.net"),
+            _ => {
+                let (ips, name) = value
+                    .split_once('#')
+                    .ok_or("Expected cloudflare, google, quad9 or <ip>,<ip>#<name>")?;
+                return Ok(Self {
+                    ips: ips
+                        .split(',')
+                        .map(|ip| ip.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|error| format!("Invalid address: {}", error))?,
+                    name: name.to_string(),
+                });
+            }
+        };
+        Ok(Self {
+            ips: ips.iter().filter_map(|ip| ip.parse().ok()).collect(),
+            name: name.to_string(),
+        })
+    }
+}
@@ -0,0 +1,64 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use sentry::types::{Dsn, ParseDsnError};
+use sentry::{ClientInitGuard, ClientOptions, Hub, Level};
+
+/// Reports are sent in the background, so they are waited for this long
+/// before the process may exit.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reports panics and errors that make the relay exit to Sentry, to catch
+/// rare failures in a fleet of relays. Reports contain the error or panic
+/// message with a backtrace, the relay name and version, the host name and
+/// the operating system. Logs and the configuration, and so the password, are
+/// never sent.
+pub struct ErrorReporting {
+    _guard: ClientInitGuard,
+}
+
+impl ErrorReporting {
+    pub fn start(dsn: &str) -> Result<Self, ParseDsnError> {
+        let dsn: Dsn = dsn.parse()?;
+        let guard = sentry::init(ClientOptions {
+            dsn: Some(dsn),
+            release: Some(Cow::Borrowed(concat!(
+                "moblink-relay@",
+                env!("CARGO_PKG_VERSION")
+            ))),
+            send_default_pii: false,
+            ..Default::default()
+        });
+        // Chained after the hook of the panic integration, which reports the
+        // panic. The process may exit when the hook returns.
+        let report_panic = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            report_panic(info);
+            flush();
+        }));
+        Ok(Self { _guard: guard })
+    }
+
+    /// Names of the relays in this process, added to all reports.
+    pub fn set_relay_name(&self, relay_name: &str) {
+        Hub::main().configure_scope(|scope| scope.set_tag("relay_name", relay_name));
+    }
+
+    /// Reports given error, which makes the relay exit, and waits for it to
+    /// be sent.
+    pub fn report_terminal_error(&self, error: &str) {
+        Hub::main().capture_message(error, Level::Fatal);
+        flush();
+    }
+
+    /// Waits for reports to be sent, before exiting.
+    pub fn flush(&self) {
+        flush();
+    }
+}
+
+fn flush() {
+    if let Some(client) = Hub::main().client() {
+        client.flush(Some(FLUSH_TIMEOUT));
+    }
+}
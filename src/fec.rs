@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+
+use tracing::debug;
+
+use crate::obfuscation::Obfuscation;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_PARITY: u8 = 1;
+/// Type, group and index or count.
+const HEADER_SIZE: usize = 4;
+/// Plus the XOR of the payload lengths.
+const PARITY_HEADER_SIZE: usize = HEADER_SIZE + 2;
+/// Older groups are forgotten.
+const MAXIMUM_NUMBER_OF_GROUPS: usize = 16;
+
+/// Forward error correction between two Moblink relays. After every group of
+/// data packets a parity packet with the XOR of their payloads is sent, so
+/// that one lost packet per group can be recovered. Costs one packet per
+/// group.
+///
+/// Data packets are `[0, group (u16), index, payload]` and parity packets
+/// `[1, group (u16), count, XOR of payload lengths (u16), XOR of payloads]`.
+///
+/// Also obfuscates the legs, outside of FEC, see `Obfuscation`.
+pub(crate) struct Fec {
+    decoder: Option<FecDecoder>,
+    encoder: Option<FecEncoder>,
+    deobfuscation: Option<Obfuscation>,
+    obfuscation: Option<Obfuscation>,
+}
+
+impl Fec {
+    /// Decodes received packets and/or encodes them before sending, and
+    /// deobfuscates received packets and/or obfuscates them before sending.
+    /// `None` if none of it.
+    pub(crate) fn new(
+        decode: bool,
+        encode_group_size: Option<u8>,
+        deobfuscation: Option<Obfuscation>,
+        obfuscation: Option<Obfuscation>,
+    ) -> Option<Self> {
+        if !decode
+            && encode_group_size.is_none()
+            && deobfuscation.is_none()
+            && obfuscation.is_none()
+        {
+            return None;
+        }
+        Some(Self {
+            decoder: decode.then(FecDecoder::new),
+            encoder: encode_group_size.map(FecEncoder::new),
+            deobfuscation,
+            obfuscation,
+        })
+    }
+
+    /// Returns the datagrams to send. Given closure is called with every
+    /// decoded payload.
+    pub(crate) fn process<F>(&mut self, packet: &[u8], mut on_payload: F) -> Vec<Vec<u8>>
+    where
+        F: FnMut(&[u8]),
+    {
+        let deobfuscated;
+        let packet = match &self.deobfuscation {
+            Some(deobfuscation) => match deobfuscation.deobfuscate(packet) {
+                Some(packet) => {
+                    deobfuscated = packet;
+                    &deobfuscated
+                }
+                None => {
+                    debug!("Dropping too short obfuscated packet");
+                    return Vec::new();
+                }
+            },
+            None => packet,
+        };
+        let payloads = match &mut self.decoder {
+            Some(decoder) => decoder.decode(packet),
+            None => vec![packet.to_vec()],
+        };
+        let mut datagrams = Vec::new();
+        for payload in payloads {
+            on_payload(&payload);
+            match &mut self.encoder {
+                Some(encoder) => encoder.encode(&payload, &mut datagrams),
+                None => datagrams.push(payload),
+            }
+        }
+        if let Some(obfuscation) = &self.obfuscation {
+            for datagram in &mut datagrams {
+                *datagram = obfuscation.obfuscate(datagram);
+            }
+        }
+        datagrams
+    }
+}
+
+struct FecEncoder {
+    group_size: u8,
+    group: u16,
+    index: u8,
+    length_parity: u16,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    fn new(group_size: u8) -> Self {
+        Self {
+            group_size: group_size.max(1),
+            group: 0,
+            index: 0,
+            length_parity: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, payload: &[u8], datagrams: &mut Vec<Vec<u8>>) {
+        let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+        data.push(TYPE_DATA);
+        data.extend_from_slice(&self.group.to_be_bytes());
+        data.push(self.index);
+        data.extend_from_slice(payload);
+        datagrams.push(data);
+        xor_into(&mut self.parity, payload);
+        self.length_parity ^= payload.len() as u16;
+        self.index += 1;
+        if self.index < self.group_size {
+            return;
+        }
+        let mut parity = Vec::with_capacity(PARITY_HEADER_SIZE + self.parity.len());
+        parity.push(TYPE_PARITY);
+        parity.extend_from_slice(&self.group.to_be_bytes());
+        parity.push(self.group_size);
+        parity.extend_from_slice(&self.length_parity.to_be_bytes());
+        parity.append(&mut self.parity);
+        datagrams.push(parity);
+        self.group = self.group.wrapping_add(1);
+        self.index = 0;
+        self.length_parity = 0;
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    payloads: Vec<Option<Vec<u8>>>,
+    /// Count, XOR of payload lengths and XOR of payloads.
+    parity: Option<(u8, u16, Vec<u8>)>,
+    done: bool,
+}
+
+struct FecDecoder {
+    groups: VecDeque<(u16, Group)>,
+}
+
+impl FecDecoder {
+    fn new() -> Self {
+        Self {
+            groups: VecDeque::new(),
+        }
+    }
+
+    /// Data payloads are returned immediately, and lost ones once recovered.
+    fn decode(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet.len() < HEADER_SIZE {
+            debug!("Dropping too short FEC packet");
+            return Vec::new();
+        }
+        let group_number = u16::from_be_bytes([packet[1], packet[2]]);
+        let mut payloads = Vec::new();
+        match packet[0] {
+            TYPE_DATA => {
+                let index = packet[3] as usize;
+                let payload = &packet[HEADER_SIZE..];
+                payloads.push(payload.to_vec());
+                let group = self.get_group(group_number);
+                if group.payloads.len() <= index {
+                    group.payloads.resize(index + 1, None);
+                }
+                group.payloads[index] = Some(payload.to_vec());
+            }
+            TYPE_PARITY => {
+                if packet.len() < PARITY_HEADER_SIZE {
+                    debug!("Dropping too short FEC parity packet");
+                    return Vec::new();
+                }
+                let count = packet[3];
+                let length_parity = u16::from_be_bytes([packet[4], packet[5]]);
+                let parity = packet[PARITY_HEADER_SIZE..].to_vec();
+                self.get_group(group_number).parity = Some((count, length_parity, parity));
+            }
+            kind => {
+                debug!("Dropping FEC packet of unknown type {}", kind);
+                return Vec::new();
+            }
+        }
+        if let Some(payload) = self.get_group(group_number).recover() {
+            payloads.push(payload);
+        }
+        payloads
+    }
+
+    fn get_group(&mut self, group_number: u16) -> &mut Group {
+        let position = match self
+            .groups
+            .iter()
+            .position(|(number, _)| *number == group_number)
+        {
+            Some(position) => position,
+            None => {
+                if self.groups.len() == MAXIMUM_NUMBER_OF_GROUPS {
+                    self.groups.pop_front();
+                }
+                self.groups.push_back((group_number, Group::default()));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[position].1
+    }
+}
+
+impl Group {
+    /// The lost payload, if exactly one is missing and the parity is known.
+    fn recover(&mut self) -> Option<Vec<u8>> {
+        if self.done {
+            return None;
+        }
+        let (count, length_parity, parity) = self.parity.as_ref()?;
+        let count = *count as usize;
+        let received: Vec<&Vec<u8>> = self.payloads.iter().take(count).flatten().collect();
+        if received.len() == count {
+            self.done = true;
+            return None;
+        }
+        if received.len() + 1 < count {
+            return None;
+        }
+        let mut payload = parity.clone();
+        let mut length = *length_parity;
+        for received in received {
+            xor_into(&mut payload, received);
+            length ^= received.len() as u16;
+        }
+        payload.truncate(length as usize);
+        self.done = true;
+        Some(payload)
+    }
+}
+
+/// Pads the accumulator with zeros if shorter than given data.
+fn xor_into(accumulator: &mut Vec<u8>, data: &[u8]) {
+    if accumulator.len() < data.len() {
+        accumulator.resize(data.len(), 0);
+    }
+    for (accumulator, data) in accumulator.iter_mut().zip(data) {
+        *accumulator ^= data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(payloads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut encoder = FecEncoder::new(payloads.len() as u8);
+        let mut datagrams = Vec::new();
+        for payload in payloads {
+            encoder.encode(payload, &mut datagrams);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn parity_follows_group() {
+        let datagrams = encode(&[b"a", b"bc"]);
+        assert_eq!(
+            datagrams,
+            vec![
+                vec![TYPE_DATA, 0, 0, 0, b'a'],
+                vec![TYPE_DATA, 0, 0, 1, b'b', b'c'],
+                vec![TYPE_PARITY, 0, 0, 2, 0, 3, b'a' ^ b'b', b'c'],
+            ]
+        );
+    }
+
+    #[test]
+    fn recovers_one_lost_payload() {
+        let datagrams = encode(&[b"first", b"second payload", b"third"]);
+        let mut decoder = FecDecoder::new();
+        assert_eq!(decoder.decode(&datagrams[0]), vec![b"first".to_vec()]);
+        assert_eq!(decoder.decode(&datagrams[2]), vec![b"third".to_vec()]);
+        assert_eq!(
+            decoder.decode(&datagrams[3]),
+            vec![b"second payload".to_vec()]
+        );
+    }
+
+    #[test]
+    fn recovers_with_parity_before_data() {
+        let datagrams = encode(&[b"first", b"second"]);
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.decode(&datagrams[2]).is_empty());
+        assert_eq!(
+            decoder.decode(&datagrams[1]),
+            vec![b"second".to_vec(), b"first".to_vec()]
+        );
+    }
+
+    #[test]
+    fn does_not_recover_two_lost_payloads() {
+        let datagrams = encode(&[b"first", b"second", b"third"]);
+        let mut decoder = FecDecoder::new();
+        assert_eq!(decoder.decode(&datagrams[0]), vec![b"first".to_vec()]);
+        assert!(decoder.decode(&datagrams[3]).is_empty());
+    }
+
+    #[test]
+    fn nothing_recovered_without_loss() {
+        let datagrams = encode(&[b"first", b"second"]);
+        let mut decoder = FecDecoder::new();
+        for datagram in &datagrams[..2] {
+            assert_eq!(decoder.decode(datagram).len(), 1);
+        }
+        assert!(decoder.decode(&datagrams[2]).is_empty());
+    }
+
+    #[test]
+    fn drops_malformed_packets() {
+        let mut decoder = FecDecoder::new();
+        assert!(decoder.decode(&[TYPE_DATA, 0, 0]).is_empty());
+        assert!(decoder.decode(&[TYPE_PARITY, 0, 0, 2, 0]).is_empty());
+        assert!(decoder.decode(&[7, 0, 0, 0, 1]).is_empty());
+    }
+}
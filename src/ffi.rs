@@ -0,0 +1,274 @@
+//! C API for embedding the relay in native applications. See
+//! `include/moblink_relay.h`.
+//!
+//! A relay has its own runtime, on which all its tasks run and the status
+//! callback is called. The callback is called with the relay locked, so
+//! functions called from it cannot wait for the relay and return before they
+//! are done instead.
+//!
+//! Panics are caught, as they must not unwind into C.
+
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::relay::{Relay, RelayError};
+
+pub struct MoblinkRelay {
+    relay: Relay,
+    runtime: Runtime,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoblinkRelayResult {
+    Ok = 0,
+    /// A null pointer, a string that is not UTF-8 or an invalid relay id.
+    InvalidArgument,
+    InvalidStreamerUrl,
+    InvalidBindAddress,
+    NoNetworkInterface,
+    EmptyName,
+    InvalidPacing,
+    WrongPassword,
+    Stopped,
+    /// A bug in the relay, which should be freed.
+    Panic,
+}
+
+impl From<RelayError> for MoblinkRelayResult {
+    fn from(error: RelayError) -> Self {
+        match error {
+            RelayError::InvalidStreamerUrl { .. } => MoblinkRelayResult::InvalidStreamerUrl,
+            RelayError::InvalidBindAddress { .. } => MoblinkRelayResult::InvalidBindAddress,
+            RelayError::NoNetworkInterface => MoblinkRelayResult::NoNetworkInterface,
+            RelayError::EmptyName => MoblinkRelayResult::EmptyName,
+            RelayError::InvalidPacing => MoblinkRelayResult::InvalidPacing,
+            RelayError::WrongPassword => MoblinkRelayResult::WrongPassword,
+            RelayError::Stopped => MoblinkRelayResult::Stopped,
+        }
+    }
+}
+
+/// Called with the status text, which is only valid during the call, and the
+/// user data given to `moblink_relay_setup()`. The other functions may be
+/// called from it, but then return before they are done.
+pub type MoblinkRelayStatusCallback =
+    Option<unsafe extern "C" fn(status: *const c_char, user_data: *mut c_void)>;
+
+/// Passed to the status callback, which the caller makes safe to call from
+/// the relay's runtime.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    /// Captures all of the user data in closures, not just the pointer.
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+impl MoblinkRelay {
+    /// Runs given future to completion, or only spawns it if called from the
+    /// status callback, which runs on the runtime, where blocking panics.
+    fn run<F>(&self, future: F) -> Option<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            self.runtime.spawn(future);
+            None
+        } else {
+            Some(self.runtime.block_on(future))
+        }
+    }
+}
+
+/// Creates a relay. Returns null if its runtime could not be created. Free
+/// with `moblink_relay_free()`.
+#[unsafe(no_mangle)]
+pub extern "C" fn moblink_relay_new() -> *mut MoblinkRelay {
+    // The runtime cannot be created and dropped on another runtime, for
+    // example in the status callback.
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return std::thread::spawn(|| moblink_relay_new() as usize)
+            .join()
+            .map_or(std::ptr::null_mut(), |relay| relay as *mut MoblinkRelay);
+    }
+    catch_panic(std::ptr::null_mut(), || {
+        let runtime = match tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("moblink-relay")
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let relay = Relay::new();
+        runtime.block_on(relay.set_runtime(runtime.handle().clone()));
+        Box::into_raw(Box::new(MoblinkRelay { relay, runtime }))
+    })
+}
+
+/// Stops and frees given relay. Does nothing if null. Called from the status
+/// callback, the relay is stopped and freed on another thread.
+///
+/// # Safety
+///
+/// `relay` must be null or returned by `moblink_relay_new()`, and must not be
+/// used after this.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn moblink_relay_free(relay: *mut MoblinkRelay) {
+    if relay.is_null() {
+        return;
+    }
+    // SAFETY: Created by moblink_relay_new().
+    let relay = unsafe { Box::from_raw(relay) };
+    let free = move || {
+        catch_panic((), || {
+            relay.runtime.block_on(relay.relay.stop());
+        })
+    };
+    // The runtime cannot be dropped on itself either.
+    if tokio::runtime::Handle::try_current().is_ok() {
+        std::thread::spawn(free);
+    } else {
+        free();
+    }
+}
+
+/// Sets the streamer to connect to and the identity of the relay. A null
+/// `relay_id` gives a random one, and `on_status_updated` may be null. Called
+/// from the status callback, the relay is set up later and `Ok` is returned
+/// if the arguments are valid.
+///
+/// # Safety
+///
+/// `relay` must be returned by `moblink_relay_new()` and the strings must be
+/// null terminated. `user_data` must be safe to pass to `on_status_updated`
+/// from any thread until the relay is freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn moblink_relay_setup(
+    relay: *mut MoblinkRelay,
+    streamer_url: *const c_char,
+    password: *const c_char,
+    relay_id: *const c_char,
+    name: *const c_char,
+    on_status_updated: MoblinkRelayStatusCallback,
+    user_data: *mut c_void,
+) -> MoblinkRelayResult {
+    // SAFETY: Valid as documented.
+    let Some(relay) = (unsafe { relay.as_ref() }) else {
+        return MoblinkRelayResult::InvalidArgument;
+    };
+    // SAFETY: Valid as documented.
+    let (Some(streamer_url), Some(password), Some(name)) = (unsafe {
+        (
+            to_string(streamer_url),
+            to_string(password),
+            to_string(name),
+        )
+    }) else {
+        return MoblinkRelayResult::InvalidArgument;
+    };
+    let relay_id = if relay_id.is_null() {
+        Uuid::new_v4()
+    } else {
+        // SAFETY: Valid as documented.
+        match unsafe { to_string(relay_id) }.and_then(|relay_id| relay_id.parse().ok()) {
+            Some(relay_id) => relay_id,
+            None => return MoblinkRelayResult::InvalidArgument,
+        }
+    };
+    let user_data = UserData(user_data);
+    let on_status_updated = move |status: String| {
+        let Some(on_status_updated) = on_status_updated else {
+            return;
+        };
+        let status = CString::new(status).unwrap_or_default();
+        // SAFETY: The caller makes the user data safe to use from any thread.
+        unsafe { on_status_updated(status.as_ptr(), user_data.get()) };
+    };
+    catch_panic(MoblinkRelayResult::Panic, || {
+        let setup = relay.relay.clone();
+        let result = relay.run(async move {
+            setup
+                .setup(
+                    streamer_url,
+                    password,
+                    relay_id,
+                    name,
+                    on_status_updated,
+                    None,
+                )
+                .await
+        });
+        to_result(result.unwrap_or(Ok(())))
+    })
+}
+
+/// Connects to the streamer, and reconnects until stopped. Called from the
+/// status callback, the relay is started later and `Ok` is returned.
+///
+/// # Safety
+///
+/// `relay` must be returned by `moblink_relay_new()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn moblink_relay_start(relay: *mut MoblinkRelay) -> MoblinkRelayResult {
+    // SAFETY: Valid as documented.
+    let Some(relay) = (unsafe { relay.as_ref() }) else {
+        return MoblinkRelayResult::InvalidArgument;
+    };
+    catch_panic(MoblinkRelayResult::Panic, || {
+        let start = relay.relay.clone();
+        let result = relay.run(async move { start.start().await });
+        to_result(result.unwrap_or(Ok(())))
+    })
+}
+
+/// Disconnects from the streamer and stops the tunnel, if any. Called from
+/// the status callback, the relay is stopped later.
+///
+/// # Safety
+///
+/// `relay` must be returned by `moblink_relay_new()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn moblink_relay_stop(relay: *mut MoblinkRelay) {
+    // SAFETY: Valid as documented.
+    if let Some(relay) = unsafe { relay.as_ref() } {
+        catch_panic((), || {
+            let stop = relay.relay.clone();
+            relay.run(async move { stop.stop().await });
+        });
+    }
+}
+
+/// `fallback` if `function` panics.
+fn catch_panic<T>(fallback: T, function: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(function)).unwrap_or(fallback)
+}
+
+/// `None` if null or not UTF-8.
+unsafe fn to_string(string: *const c_char) -> Option<String> {
+    if string.is_null() {
+        return None;
+    }
+    // SAFETY: A null terminated string.
+    unsafe { CStr::from_ptr(string) }
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+fn to_result(result: Result<(), RelayError>) -> MoblinkRelayResult {
+    match result {
+        Ok(()) => MoblinkRelayResult::Ok,
+        Err(error) => error.into(),
+    }
+}
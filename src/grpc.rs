@@ -0,0 +1,260 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures_util::Stream;
+use futures_util::stream;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+use crate::relay::Relay;
+use crate::secret::SecretString;
+use crate::streamer_url::normalize_streamer_url;
+use crate::utils::AnyError;
+
+mod proto {
+    tonic::include_proto!("moblink.v1");
+}
+
+use proto::relay_control_server::{RelayControl, RelayControlServer};
+use proto::{
+    ListRelaysRequest, ListRelaysResponse, RelayInfo, RelayRequest, RelayResponse, RelayStats,
+    StatsUpdate, StreamStatsRequest, UpdateSettingsRequest, UpdateSettingsResponse,
+};
+
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// gRPC server to manage the relays of this process, for example from a fleet
+/// management system. See `proto/moblink.proto`.
+///
+/// Relays are identified by name.
+///
+/// With a token, requests must have the metadata `authorization: Bearer
+/// <token>`. A token is required unless listening on a loopback address, as
+/// anyone reaching the server could otherwise point the relays at their own
+/// streamer.
+pub struct GrpcServer {
+    server: JoinHandle<()>,
+}
+
+impl GrpcServer {
+    pub async fn start(
+        address: &str,
+        relays: Vec<(String, Relay)>,
+        token: Option<SecretString>,
+    ) -> Result<Self, AnyError> {
+        let address: SocketAddr = address.parse()?;
+        if token.is_none() && !address.ip().is_loopback() {
+            return Err(format!("A token is required to serve gRPC on {}", address).into());
+        }
+        let listener = TcpListener::bind(address).await?;
+        info!("gRPC server listening on '{}'", listener.local_addr()?);
+        let incoming = TcpIncoming::from_listener(listener, true, None)?;
+        let authorization = token.map(|token| format!("Bearer {}", token.expose_secret()));
+        #[allow(clippy::result_large_err)]
+        let service = RelayControlServer::with_interceptor(
+            RelayControlService { relays },
+            move |request: Request<()>| authorize(request, authorization.as_deref()),
+        );
+        let server = tokio::spawn(async move {
+            if let Err(error) = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming)
+                .await
+            {
+                error!("gRPC server failed with error: {}", error);
+            }
+        });
+        Ok(Self { server })
+    }
+
+    pub fn stop(&self) {
+        self.server.abort();
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn authorize(request: Request<()>, authorization: Option<&str>) -> Result<Request<()>, Status> {
+    let Some(authorization) = authorization else {
+        return Ok(request);
+    };
+    match request.metadata().get("authorization") {
+        Some(value) if value.as_bytes() == authorization.as_bytes() => Ok(request),
+        _ => Err(Status::unauthenticated("Invalid or missing token")),
+    }
+}
+
+struct RelayControlService {
+    relays: Vec<(String, Relay)>,
+}
+
+impl RelayControlService {
+    #[allow(clippy::result_large_err)]
+    fn get(&self, name: &str) -> Result<&Relay, Status> {
+        self.relays
+            .iter()
+            .find(|(relay_name, _)| relay_name == name)
+            .map(|(_, relay)| relay)
+            .ok_or_else(|| Status::not_found(format!("No relay named '{}'", name)))
+    }
+}
+
+type StatsStream = Pin<Box<dyn Stream<Item = Result<StatsUpdate, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl RelayControl for RelayControlService {
+    type StreamStatsStream = StatsStream;
+
+    async fn list_relays(
+        &self,
+        _request: Request<ListRelaysRequest>,
+    ) -> Result<Response<ListRelaysResponse>, Status> {
+        let mut relays = Vec::new();
+        for (name, relay) in &self.relays {
+            let state = relay.state().await;
+            relays.push(RelayInfo {
+                name: name.clone(),
+                relay_id: state.relay_id.to_string(),
+                streamer_url: state.streamer_url,
+                connection_state: state.connection.to_string(),
+                status: state.status,
+                paused: state.paused,
+                last_error: state.last_error,
+                quality_score: state.quality_score.map(Into::into),
+            });
+        }
+        Ok(Response::new(ListRelaysResponse { relays }))
+    }
+
+    async fn stream_stats(
+        &self,
+        request: Request<StreamStatsRequest>,
+    ) -> Result<Response<Self::StreamStatsStream>, Status> {
+        let request = request.into_inner();
+        let relays = if request.name.is_empty() {
+            self.relays.clone()
+        } else {
+            vec![(request.name.clone(), self.get(&request.name)?.clone())]
+        };
+        let period = match request.interval_ms {
+            0 => DEFAULT_STATS_INTERVAL,
+            interval_ms => Duration::from_millis(interval_ms.into()),
+        };
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let updates = stream::unfold((relays, ticker), |(relays, mut ticker)| async move {
+            ticker.tick().await;
+            let mut update = StatsUpdate { relays: Vec::new() };
+            for (name, relay) in &relays {
+                let stats = relay.get_stats().await;
+                update.relays.push(RelayStats {
+                    name: name.clone(),
+                    bytes_to_destination: stats.bytes_to_destination,
+                    packets_to_destination: stats.packets_to_destination,
+                    bytes_to_streamer: stats.bytes_to_streamer,
+                    packets_to_streamer: stats.packets_to_streamer,
+                    reconnects: stats.reconnects,
+                    authentication_failures: stats.authentication_failures,
+                    tunnel_restarts: stats.tunnel_restarts,
+                    destination_timeouts: stats.destination_timeouts,
+                    oversized_datagrams: stats.oversized_datagrams,
+                    round_trip_time_ms: stats.round_trip_time_ms,
+                });
+            }
+            Some((Ok(update), (relays, ticker)))
+        });
+        Ok(Response::new(Box::pin(updates)))
+    }
+
+    async fn start(
+        &self,
+        request: Request<RelayRequest>,
+    ) -> Result<Response<RelayResponse>, Status> {
+        self.get(&request.get_ref().name)?
+            .start()
+            .await
+            .map_err(|error| Status::failed_precondition(error.to_string()))?;
+        Ok(Response::new(RelayResponse {}))
+    }
+
+    async fn stop(
+        &self,
+        request: Request<RelayRequest>,
+    ) -> Result<Response<RelayResponse>, Status> {
+        self.get(&request.get_ref().name)?.stop().await;
+        Ok(Response::new(RelayResponse {}))
+    }
+
+    async fn update_settings(
+        &self,
+        request: Request<UpdateSettingsRequest>,
+    ) -> Result<Response<UpdateSettingsResponse>, Status> {
+        let request = request.into_inner();
+        let relay = self.get(&request.name)?;
+        let mut corrections = Vec::new();
+        let streamer_url = match request.streamer_url.as_deref().map(normalize_streamer_url) {
+            Some(Ok(streamer_url)) => {
+                corrections = streamer_url.corrections;
+                Some(streamer_url.url)
+            }
+            Some(Err(error)) => return Err(Status::invalid_argument(error.to_string())),
+            None => None,
+        };
+        relay
+            .update_settings(streamer_url, request.password.map(Into::into))
+            .await
+            .map_err(|error| Status::invalid_argument(error.to_string()))?;
+        Ok(Response::new(UpdateSettingsResponse {
+            corrections: corrections
+                .into_iter()
+                .map(|correction| correction.to_string())
+                .collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn accepts_any_request_without_token() {
+        assert!(authorize(request(None), None).is_ok());
+    }
+
+    #[test]
+    fn checks_token() {
+        let authorization = Some("Bearer secret");
+        assert!(authorize(request(Some("Bearer secret")), authorization).is_ok());
+        assert!(authorize(request(Some("Bearer other")), authorization).is_err());
+        assert!(authorize(request(None), authorization).is_err());
+    }
+
+    #[tokio::test]
+    async fn requires_token_unless_loopback() {
+        assert!(
+            GrpcServer::start("0.0.0.0:0", Vec::new(), None)
+                .await
+                .is_err()
+        );
+        let server = GrpcServer::start("127.0.0.1:0", Vec::new(), None)
+            .await
+            .unwrap();
+        server.stop();
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, sleep};
+use tracing::debug;
+
+use crate::multipath::recv_from_any_with_index;
+use crate::stats::Counters;
+
+/// Delay before trying the next address, as recommended in RFC 8305.
+pub(crate) const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Destination addresses in the order to try them in. IPv4-mapped IPv6
+/// addresses are unmapped, duplicates removed and the families interleaved,
+/// starting with the family of the first address, as in RFC 8305. Addresses
+/// that cannot be reached from given bind address are removed, as an IPv4
+/// socket cannot send to IPv6 addresses and vice versa. Only the unspecified
+/// IPv6 address is dual-stack.
+pub(crate) fn sort_addresses(addresses: &[IpAddr], bind_address: IpAddr) -> Vec<IpAddr> {
+    let mut first_family = Vec::new();
+    let mut second_family = Vec::new();
+    for address in addresses {
+        let address = address.to_canonical();
+        let reachable = match bind_address {
+            IpAddr::V4(_) => address.is_ipv4(),
+            IpAddr::V6(bind_address) => bind_address.is_unspecified() || address.is_ipv6(),
+        };
+        if !reachable || first_family.contains(&address) || second_family.contains(&address) {
+            continue;
+        }
+        if first_family
+            .first()
+            .is_none_or(|first: &IpAddr| first.is_ipv4() == address.is_ipv4())
+        {
+            first_family.push(address);
+        } else {
+            second_family.push(address);
+        }
+    }
+    let mut sorted = Vec::new();
+    let mut first_family = first_family.into_iter();
+    let mut second_family = second_family.into_iter();
+    loop {
+        match (first_family.next(), second_family.next()) {
+            (None, None) => return sorted,
+            (first, second) => sorted.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Forwards datagrams from the streamer to the destination sockets, each
+/// connected to one address of the destination, until the destination answers
+/// on one of them. Another address is added every `CONNECTION_ATTEMPT_DELAY`.
+/// The answer is forwarded to the streamer. Returns the index of the socket
+/// that was answered on.
+pub(crate) async fn race_destination_sockets(
+    streamer_socket: &UdpSocket,
+    destination_sockets: &[Arc<UdpSocket>],
+    counters: &Counters,
+) -> Result<usize, std::io::Error> {
+    let mut streamer_buf = [0; 2048];
+    let mut destination_buf = [0; 2048];
+    let mut streamer_address = None;
+    let mut first_sent_at = None;
+    loop {
+        let attempts = match first_sent_at {
+            Some(first_sent_at) => {
+                let elapsed = Instant::now().duration_since(first_sent_at);
+                let started = elapsed.as_millis() / CONNECTION_ATTEMPT_DELAY.as_millis() + 1;
+                (started as usize).min(destination_sockets.len())
+            }
+            None => 1,
+        };
+        tokio::select! {
+            result = streamer_socket.recv_from(&mut streamer_buf) => {
+                let (size, address) = result?;
+                streamer_address = Some(address);
+                first_sent_at.get_or_insert_with(Instant::now);
+                for destination_socket in &destination_sockets[..attempts] {
+                    // For example no IPv6 route. Other addresses may work.
+                    match destination_socket.send(&streamer_buf[..size]).await {
+                        Ok(_) => counters.add_to_destination(size),
+                        Err(error) => debug!("Send to destination failed with error: {}", error),
+                    }
+                }
+            }
+            result = recv_from_any_with_index(
+                &destination_sockets[..attempts],
+                &mut destination_buf
+            ) => {
+                let (index, size) = match result {
+                    Ok(result) => result,
+                    Err(error) if error.kind() == ErrorKind::ConnectionRefused => continue,
+                    Err(error) => return Err(error),
+                };
+                if let Some(streamer_address) = streamer_address {
+                    streamer_socket
+                        .send_to(&destination_buf[..size], streamer_address)
+                        .await?;
+                    counters.add_to_streamer(size);
+                }
+                return Ok(index);
+            }
+        }
+    }
+}
+
+/// Connects to the addresses in order with `connect`, starting the next
+/// attempt after `CONNECTION_ATTEMPT_DELAY` or when an attempt fails. The
+/// first connection wins and the other attempts are cancelled.
+pub(crate) async fn connect_first<T, F, Fut>(
+    addresses: &[SocketAddr],
+    connect: F,
+) -> Result<T, std::io::Error>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, std::io::Error>>,
+{
+    let mut addresses = addresses.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(address) = addresses.next() {
+            attempts.push(connect(*address));
+        } else if attempts.is_empty() {
+            return Err(
+                last_error.unwrap_or_else(|| std::io::Error::other("No destination address"))
+            );
+        }
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(connection) => return Ok(connection),
+                Err(error) => {
+                    debug!("Connection attempt failed with error: {}", error);
+                    last_error = Some(error);
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY) => {}
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use tokio::task::JoinHandle;
+
+use crate::http_server::{HttpRequest, HttpResponse, start_http_server};
+use crate::relay::Relay;
+use crate::utils::AnyError;
+
+/// Serves `/healthz` (process alive) and `/readyz` (connected and identified
+/// to the streamer).
+pub struct HealthServer {
+    server: JoinHandle<()>,
+}
+
+impl HealthServer {
+    pub async fn start(address: &str, relay: Relay) -> Result<Self, AnyError> {
+        let server = start_http_server(
+            address,
+            Arc::new(move |request| {
+                let relay = relay.clone();
+                Box::pin(async move { handle_request(request, relay).await })
+            }),
+        )
+        .await?;
+        Ok(Self { server })
+    }
+
+    pub fn stop(&self) {
+        self.server.abort();
+    }
+}
+
+async fn handle_request(request: HttpRequest, relay: Relay) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::text(405, "Method not allowed");
+    }
+    match request.path.as_str() {
+        "/healthz" => HttpResponse::text(200, "OK"),
+        "/readyz" => {
+            if relay.is_connected().await {
+                HttpResponse::text(200, "Ready")
+            } else {
+                HttpResponse::text(503, "Not ready")
+            }
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
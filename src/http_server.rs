@@ -0,0 +1,205 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+use tracing::{debug, error, info};
+
+use crate::utils::AnyError;
+
+const MAXIMUM_REQUEST_SIZE: usize = 65536;
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// Names and values as received.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// The value of the first header with given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Self::new(status, "text/plain", format!("{}\n", text).into_bytes())
+    }
+
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::new(200, "application/json", body),
+            Err(error) => Self::text(500, &error.to_string()),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "Not found")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            415 => "Unsupported Media Type",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+}
+
+pub type HttpHandler =
+    Arc<dyn Fn(HttpRequest) -> Pin<Box<dyn Future<Output = HttpResponse> + Send>> + Send + Sync>;
+
+/// A minimal HTTP/1.1 server answering one request per connection.
+pub async fn start_http_server(
+    address: &str,
+    handler: HttpHandler,
+) -> Result<JoinHandle<()>, AnyError> {
+    let listener = TcpListener::bind(address).await?;
+    info!("HTTP server listening on '{}'", listener.local_addr()?);
+
+    Ok(tokio::spawn(async move {
+        while let Ok((tcp_stream, client_address)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(error) = handle_connection(tcp_stream, handler).await {
+                    debug!(
+                        "HTTP client {} failed with error: {}",
+                        client_address, error
+                    );
+                }
+            });
+        }
+        error!("HTTP server stopped accepting connections");
+    }))
+}
+
+async fn handle_connection(
+    mut tcp_stream: TcpStream,
+    handler: HttpHandler,
+) -> Result<(), AnyError> {
+    let request = timeout(Duration::from_secs(10), read_request(&mut tcp_stream)).await??;
+    let response = handler(request).await;
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    tcp_stream.write_all(header.as_bytes()).await?;
+    tcp_stream.write_all(&response.body).await?;
+    tcp_stream.shutdown().await?;
+    Ok(())
+}
+
+/// A minimal HTTP/1.1 GET for the server above. Returns the status code and
+/// the body.
+pub async fn http_get(address: &str, path: &str) -> Result<(u16, Vec<u8>), AnyError> {
+    let mut tcp_stream = TcpStream::connect(address).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, address
+    );
+    tcp_stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    timeout(
+        Duration::from_secs(10),
+        tcp_stream.read_to_end(&mut response),
+    )
+    .await??;
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("Incomplete response")?;
+    let header = String::from_utf8(response[..header_end].to_vec())?;
+    let status = header
+        .split(' ')
+        .nth(1)
+        .ok_or("No status in response")?
+        .parse()?;
+    Ok((status, response[header_end + 4..].to_vec()))
+}
+
+async fn read_request(tcp_stream: &mut TcpStream) -> Result<HttpRequest, AnyError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        let length = tcp_stream.read(&mut chunk).await?;
+        if length == 0 {
+            return Err("Connection closed before request was complete".into());
+        }
+        buffer.extend_from_slice(&chunk[..length]);
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+        if buffer.len() > MAXIMUM_REQUEST_SIZE {
+            return Err("Request header too big".into());
+        }
+    };
+    let header = String::from_utf8(buffer[..header_end].to_vec())?;
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut content_length = 0;
+    for (name, value) in &headers {
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse()?;
+        }
+    }
+    if content_length > MAXIMUM_REQUEST_SIZE {
+        return Err("Request body too big".into());
+    }
+    let mut body = buffer[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let length = tcp_stream.read(&mut chunk).await?;
+        if length == 0 {
+            return Err("Connection closed before body was complete".into());
+        }
+        body.extend_from_slice(&chunk[..length]);
+    }
+    body.truncate(content_length);
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
@@ -0,0 +1,308 @@
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(all(unix, not(feature = "pnet")))]
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use regex::Regex;
+
+/// What kind of link a network interface looks like, guessed from its name
+/// and, on Linux, sysfs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterfaceKind {
+    Loopback,
+    Cellular,
+    Wifi,
+    Ethernet,
+    Unknown,
+}
+
+impl fmt::Display for InterfaceKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            InterfaceKind::Loopback => "loopback",
+            InterfaceKind::Cellular => "cellular",
+            InterfaceKind::Wifi => "wifi",
+            InterfaceKind::Ethernet => "ethernet",
+            InterfaceKind::Unknown => "unknown",
+        };
+        write!(formatter, "{}", kind)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub is_up: bool,
+    pub kind: InterfaceKind,
+}
+
+/// How to select the network interface to bind to when no bind address is
+/// given. Only interfaces that are up, not loopback and have addresses are
+/// considered. Parsed from `first`, `match:<regex>`, `cellular`,
+/// `default-route` or `exclude:<name>,<name>`.
+#[derive(Clone, Debug, Default)]
+pub enum InterfacePolicy {
+    /// The first interface.
+    #[default]
+    First,
+    /// Prefer the first interface whose name matches given regex.
+    Matching(Regex),
+    /// Prefer the first interface that looks like a cellular modem.
+    Cellular,
+    /// Prefer the interface with the default route. Windows always does.
+    DefaultRoute,
+    /// The first interface not in given list of names.
+    Exclude(Vec<String>),
+}
+
+impl FromStr for InterfacePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = value.strip_prefix("match:") {
+            return Regex::new(regex)
+                .map(InterfacePolicy::Matching)
+                .map_err(|error| format!("Invalid regex: {}", error));
+        }
+        if let Some(names) = value.strip_prefix("exclude:") {
+            return Ok(InterfacePolicy::Exclude(
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .collect(),
+            ));
+        }
+        match value {
+            "first" => Ok(InterfacePolicy::First),
+            "cellular" => Ok(InterfacePolicy::Cellular),
+            "default-route" => Ok(InterfacePolicy::DefaultRoute),
+            _ => Err(
+                "Expected first, match:<regex>, cellular, default-route or exclude:<names>"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl InterfacePolicy {
+    /// Index of the interface to select among the names of the candidates.
+    /// Falls back to the first candidate if no preferred one is found.
+    pub(crate) fn select(&self, names: &[&str]) -> Option<usize> {
+        let preferred = match self {
+            InterfacePolicy::First => None,
+            InterfacePolicy::Matching(regex) => names.iter().position(|name| regex.is_match(name)),
+            InterfacePolicy::Cellular => names
+                .iter()
+                .position(|name| guess_kind(name) == InterfaceKind::Cellular),
+            InterfacePolicy::DefaultRoute => get_default_route_interface()
+                .and_then(|interface| names.iter().position(|name| *name == interface)),
+            InterfacePolicy::Exclude(excluded) => {
+                return names
+                    .iter()
+                    .position(|name| !excluded.iter().any(|excluded| excluded == name));
+            }
+        };
+        preferred.or((!names.is_empty()).then_some(0))
+    }
+}
+
+/// All network interfaces of this machine.
+pub fn list_interfaces() -> Vec<InterfaceInfo> {
+    system_interfaces()
+        .into_iter()
+        .map(|interface| InterfaceInfo {
+            kind: if interface.is_loopback {
+                InterfaceKind::Loopback
+            } else {
+                guess_kind(&interface.name)
+            },
+            addresses: interface.addresses,
+            is_up: interface.is_up,
+            name: interface.name,
+        })
+        .collect()
+}
+
+/// A network interface as reported by the operating system.
+pub(crate) struct SystemInterface {
+    pub(crate) name: String,
+    pub(crate) addresses: Vec<IpAddr>,
+    pub(crate) is_up: bool,
+    pub(crate) is_loopback: bool,
+}
+
+#[cfg(feature = "pnet")]
+pub(crate) fn system_interfaces() -> Vec<SystemInterface> {
+    pnet::datalink::interfaces()
+        .into_iter()
+        .map(|interface| SystemInterface {
+            addresses: interface.ips.iter().map(|ip| ip.ip()).collect(),
+            is_up: interface.is_up(),
+            is_loopback: interface.is_loopback(),
+            name: interface.name,
+        })
+        .collect()
+}
+
+/// Interfaces are listed in the order the system reports them, with the
+/// addresses of each merged.
+#[cfg(all(unix, not(feature = "pnet")))]
+pub(crate) fn system_interfaces() -> Vec<SystemInterface> {
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: Freed with freeifaddrs() below.
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Vec::new();
+    }
+    let mut interfaces: Vec<SystemInterface> = Vec::new();
+    let mut addr = addrs;
+    while !addr.is_null() {
+        // SAFETY: Entries form a linked list until freed.
+        let current = unsafe { &*addr };
+        addr = current.ifa_next;
+        // SAFETY: A null terminated string.
+        let name = unsafe { std::ffi::CStr::from_ptr(current.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let index = match interfaces
+            .iter()
+            .position(|interface| interface.name == name)
+        {
+            Some(index) => index,
+            None => {
+                let flags = current.ifa_flags as libc::c_int;
+                interfaces.push(SystemInterface {
+                    name,
+                    addresses: Vec::new(),
+                    is_up: flags & libc::IFF_UP != 0,
+                    is_loopback: flags & libc::IFF_LOOPBACK != 0,
+                });
+                interfaces.len() - 1
+            }
+        };
+        // SAFETY: Points to a socket address of its family, if not null.
+        if let Some(address) = unsafe { to_ip_address(current.ifa_addr) } {
+            interfaces[index].addresses.push(address);
+        }
+    }
+    // SAFETY: Allocated by getifaddrs() above and not used after this.
+    unsafe { libc::freeifaddrs(addrs) };
+    interfaces
+}
+
+#[cfg(all(unix, not(feature = "pnet")))]
+unsafe fn to_ip_address(address: *const libc::sockaddr) -> Option<IpAddr> {
+    if address.is_null() {
+        return None;
+    }
+    match unsafe { (*address).sa_family } as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: Checked to be an IPv4 socket address above.
+            let address = unsafe { &*(address as *const libc::sockaddr_in) };
+            // In network byte order.
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                address.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: Checked to be an IPv6 socket address above.
+            let address = unsafe { &*(address as *const libc::sockaddr_in6) };
+            Some(IpAddr::from(address.sin6_addr.s6_addr))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an adapter is up is not reported, so all are assumed to be.
+#[cfg(all(windows, not(feature = "pnet")))]
+pub(crate) fn system_interfaces() -> Vec<SystemInterface> {
+    use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+
+    NetworkInterface::show()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|interface| SystemInterface {
+            addresses: interface
+                .addr
+                .iter()
+                .map(|addr| match addr {
+                    Addr::V4(addr) => IpAddr::V4(addr.ip),
+                    Addr::V6(addr) => IpAddr::V6(addr.ip),
+                })
+                .collect(),
+            is_up: true,
+            is_loopback: interface.internal,
+            name: interface.name,
+        })
+        .collect()
+}
+
+fn guess_kind(name: &str) -> InterfaceKind {
+    #[cfg(target_os = "linux")]
+    {
+        let path = std::path::Path::new("/sys/class/net").join(name);
+        if path.join("wireless").exists() || path.join("phy80211").exists() {
+            return InterfaceKind::Wifi;
+        }
+        // USB tethering and QMI modems.
+        if let Ok(driver) = std::fs::read_link(path.join("device/driver"))
+            && let Some(driver) = driver.file_name().and_then(|driver| driver.to_str())
+            && ["qmi_wwan", "cdc_mbim", "cdc_ether", "rndis_host", "ipheth"].contains(&driver)
+        {
+            return InterfaceKind::Cellular;
+        }
+    }
+    const CELLULAR: [&str; 6] = ["wwan", "rmnet", "ccmni", "pdp_ip", "ppp", "usb"];
+    const WIFI: [&str; 3] = ["wlan", "wlp", "wifi"];
+    const ETHERNET: [&str; 4] = ["eth", "enp", "eno", "ens"];
+    let matches = |prefixes: &[&str]| prefixes.iter().any(|prefix| name.starts_with(prefix));
+    if matches(&CELLULAR) {
+        InterfaceKind::Cellular
+    } else if matches(&WIFI) {
+        InterfaceKind::Wifi
+    } else if matches(&ETHERNET) {
+        InterfaceKind::Ethernet
+    } else {
+        InterfaceKind::Unknown
+    }
+}
+
+/// Name of the interface with the lowest metric default route.
+#[cfg(target_os = "linux")]
+fn get_default_route_interface() -> Option<String> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|route| {
+            let columns: Vec<&str> = route.split_whitespace().collect();
+            // Interface, destination, gateway, flags, refcnt, use, metric and
+            // mask.
+            if columns.len() < 8 || columns[1] != "00000000" || columns[7] != "00000000" {
+                return None;
+            }
+            let metric: u32 = columns[6].parse().ok()?;
+            Some((metric, columns[0].to_string()))
+        })
+        .min()
+        .map(|(_, interface)| interface)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn get_default_route_interface() -> Option<String> {
+    let output = std::process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("interface:"))
+        .map(|interface| interface.trim().to_string())
+}
+
+/// Adapters are already sorted by default gateway and metric.
+#[cfg(windows)]
+fn get_default_route_interface() -> Option<String> {
+    None
+}
@@ -1,7 +1,74 @@
+#[cfg(feature = "battery")]
+pub mod battery;
+pub mod bonding;
+pub mod buffer_pool;
+pub mod capture;
+pub mod chain;
+pub mod control_api;
+pub mod data_usage;
+mod default_interface;
+mod deflate;
+pub mod dns;
+#[cfg(feature = "sentry")]
+pub mod error_reporting;
+mod fec;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod happy_eyeballs;
+pub mod health;
+mod http_server;
+pub mod interfaces;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "uniffi")]
+pub mod mobile;
+pub mod modem_manager;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+mod mtu;
+pub mod multipath;
+pub mod name_template;
+mod network_monitor;
+mod obfuscation;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pacing;
+mod preflight;
 mod protocol;
+pub mod proxy;
+#[cfg(feature = "python")]
+mod python;
+mod quality;
 pub mod relay;
+pub mod relay_id;
+pub mod relay_manager;
 pub mod relay_service;
+pub mod secret;
+#[cfg(windows)]
+pub mod service;
+pub mod srt;
+mod srtla;
+pub mod stats;
+pub mod statsd;
+#[cfg(all(target_os = "linux", feature = "gpio"))]
+pub mod status_led;
+pub mod stdio_control;
 pub mod streamer;
+pub mod streamer_url;
+pub mod stun;
+mod tcp_tunnel;
+pub mod test_mode;
+pub mod testing;
+pub mod thermal;
+#[cfg(unix)]
+pub mod upgrade;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod utils;
 pub use utils::MDNS_SERVICE_TYPE;
 mod belaui;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
@@ -0,0 +1,565 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::data_usage::parse_bytes;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    File,
+    Syslog,
+    Journald,
+    EventLog,
+}
+
+#[derive(Args, Debug)]
+pub struct LoggingArgs {
+    /// Log level
+    #[arg(short, long, default_value = "info")]
+    pub log_level: String,
+
+    /// Log levels per module on top of the log level, for example
+    /// moblink_rust::tunnel=trace,tungstenite=warn
+    #[arg(long)]
+    pub log_filter: Option<String>,
+
+    /// No log timestamps
+    #[arg(long)]
+    pub no_log_timestamps: bool,
+
+    /// Log format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Log target. journald adds native fields like TUNNEL_ID and STATE.
+    /// event-log is the Windows event log, and the default in service mode.
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    pub log_target: LogTarget,
+
+    /// Log file, used when the log target is file
+    #[arg(long, required_if_eq("log_target", "file"))]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file before it grows larger than this, for example
+    /// 10M. Units are powers of 1000.
+    #[arg(long, value_parser = parse_bytes, requires = "log_file")]
+    pub log_file_max_size: Option<u64>,
+
+    /// Rotate the log file when it is this many seconds old, for example
+    /// 86400 for a file per day.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), requires = "log_file")]
+    pub log_file_max_age: Option<u64>,
+
+    /// Number of rotated log files to keep, named as the log file with .1,
+    /// the newest, .2 and so on appended.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub log_file_keep: u32,
+
+    /// Compress rotated log files with gzip, appending .gz to their names.
+    #[arg(long, requires = "log_file")]
+    pub log_file_compress: bool,
+}
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Setup logging for the binaries. The log level is either a level (error,
+/// warn, info, debug or trace) or a filter like `moblink_rust=debug,info`.
+/// The directives of the log filter, if any, are added to it.
+pub fn setup_logging(args: &LoggingArgs) {
+    setup_logging_with_layer(args, None);
+}
+
+/// As `setup_logging()`, but also with given layer, for example to export
+/// spans. It gets the same log level.
+pub fn setup_logging_with_layer(args: &LoggingArgs, extra_layer: Option<BoxedLayer>) {
+    let layer = create_layer(args).unwrap_or_else(|error| {
+        eprintln!(
+            "Failed to log to {:?} ({}), logging to stderr instead",
+            args.log_target, error
+        );
+        create_stderr_layer(args)
+    });
+    let mut layers = vec![layer.with_filter(create_filter(args)).boxed()];
+    if let Some(extra_layer) = extra_layer {
+        layers.push(extra_layer.with_filter(create_filter(args)).boxed());
+    }
+    tracing_subscriber::registry().with(layers).init();
+}
+
+fn create_filter(args: &LoggingArgs) -> EnvFilter {
+    let mut filter = EnvFilter::try_new(&args.log_level).unwrap_or_else(|error| {
+        eprintln!("Invalid log level '{}': {}", args.log_level, error);
+        EnvFilter::new("info")
+    });
+    let directives = args
+        .log_filter
+        .iter()
+        .flat_map(|log_filter| log_filter.split(','))
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty());
+    for directive in directives {
+        match directive.parse() {
+            Ok(parsed) => filter = filter.add_directive(parsed),
+            Err(error) => eprintln!("Invalid log filter directive '{}': {}", directive, error),
+        }
+    }
+    filter
+}
+
+fn create_layer(args: &LoggingArgs) -> Result<BoxedLayer, std::io::Error> {
+    match args.log_target {
+        LogTarget::Stderr => Ok(create_stderr_layer(args)),
+        LogTarget::File => {
+            let Some(path) = &args.log_file else {
+                return Err(std::io::Error::other("No log file given"));
+            };
+            let file = rotating_file::RotatingFile::open(
+                path.clone(),
+                args.log_file_max_size,
+                args.log_file_max_age.map(Duration::from_secs),
+                args.log_file_keep,
+                args.log_file_compress,
+            )?;
+            Ok(create_fmt_layer(args, Mutex::new(file), false, true))
+        }
+        LogTarget::Syslog => create_syslog_layer(args),
+        LogTarget::Journald => create_journald_layer(),
+        LogTarget::EventLog => create_event_log_layer(args),
+    }
+}
+
+fn create_stderr_layer(args: &LoggingArgs) -> BoxedLayer {
+    create_fmt_layer(
+        args,
+        std::io::stderr,
+        std::io::stderr().is_terminal(),
+        !args.no_log_timestamps,
+    )
+}
+
+fn create_fmt_layer<W>(args: &LoggingArgs, writer: W, ansi: bool, timestamps: bool) -> BoxedLayer
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match (args.log_format, timestamps) {
+        (LogFormat::Text, true) => layer.boxed(),
+        (LogFormat::Text, false) => layer.without_time().boxed(),
+        (LogFormat::Json, true) => layer.json().boxed(),
+        (LogFormat::Json, false) => layer.json().without_time().boxed(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_journald_layer() -> Result<BoxedLayer, std::io::Error> {
+    Ok(tracing_journald::layer()?.with_field_prefix(None).boxed())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_journald_layer() -> Result<BoxedLayer, std::io::Error> {
+    Err(std::io::Error::other("journald is only available on Linux"))
+}
+
+#[cfg(unix)]
+fn create_syslog_layer(args: &LoggingArgs) -> Result<BoxedLayer, std::io::Error> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect("/dev/log")?;
+    let writer = syslog::SyslogMakeWriter::new(socket);
+    // Syslog adds its own timestamps.
+    Ok(create_fmt_layer(args, writer, false, false))
+}
+
+#[cfg(not(unix))]
+fn create_syslog_layer(_args: &LoggingArgs) -> Result<BoxedLayer, std::io::Error> {
+    Err(std::io::Error::other("syslog is only available on Unix"))
+}
+
+#[cfg(windows)]
+fn create_event_log_layer(args: &LoggingArgs) -> Result<BoxedLayer, std::io::Error> {
+    let writer = event_log::EventLogMakeWriter::new()?;
+    // The event log adds its own timestamps.
+    Ok(create_fmt_layer(args, writer, false, false))
+}
+
+#[cfg(not(windows))]
+fn create_event_log_layer(_args: &LoggingArgs) -> Result<BoxedLayer, std::io::Error> {
+    Err(std::io::Error::other(
+        "the event log is only available on Windows",
+    ))
+}
+
+mod rotating_file {
+    use std::ffi::OsString;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, SystemTime};
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    /// A log file that is rotated when it would grow too large or is too
+    /// old. Rotated files are renamed to `<path>.1`, the newest, `<path>.2`
+    /// and so on, and optionally compressed in the background, so that
+    /// logging is not blocked. Rotation failures are printed to stderr and
+    /// logging continues in the current file.
+    pub(super) struct RotatingFile {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        created_at: SystemTime,
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+        keep: u32,
+        compress: bool,
+        compressor: Option<JoinHandle<()>>,
+    }
+
+    impl RotatingFile {
+        pub(super) fn open(
+            path: PathBuf,
+            max_size: Option<u64>,
+            max_age: Option<Duration>,
+            keep: u32,
+            compress: bool,
+        ) -> Result<Self, io::Error> {
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let metadata = file.metadata()?;
+            let mut rotating_file = Self {
+                path,
+                file,
+                size: metadata.len(),
+                // Of the file appended to, if known.
+                created_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+                max_size,
+                max_age,
+                keep,
+                compress,
+                compressor: None,
+            };
+            // Left uncompressed if the process exited while compressing.
+            let newest = rotating_file.rotated_path(1, "");
+            if compress && newest.exists() {
+                rotating_file.start_compressor(newest);
+            }
+            Ok(rotating_file)
+        }
+
+        fn should_rotate(&self, size: usize) -> bool {
+            if self.size == 0 {
+                return false;
+            }
+            let too_large = self
+                .max_size
+                .is_some_and(|max_size| self.size + size as u64 > max_size);
+            let too_old = self.max_age.is_some_and(|max_age| {
+                self.created_at
+                    .elapsed()
+                    .is_ok_and(|elapsed| elapsed >= max_age)
+            });
+            too_large || too_old
+        }
+
+        fn rotate(&mut self) -> Result<(), io::Error> {
+            if let Some(compressor) = self.compressor.take() {
+                compressor.join().ok();
+            }
+            let extension = if self.compress { ".gz" } else { "" };
+            let oldest = self.rotated_path(self.keep, extension);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.keep).rev() {
+                let rotated = self.rotated_path(index, extension);
+                if rotated.exists() {
+                    fs::rename(&rotated, self.rotated_path(index + 1, extension))?;
+                }
+            }
+            let newest = self.rotated_path(1, "");
+            fs::rename(&self.path, &newest)?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.size = 0;
+            self.created_at = SystemTime::now();
+            if self.compress {
+                self.start_compressor(newest);
+            }
+            Ok(())
+        }
+
+        fn start_compressor(&mut self, path: PathBuf) {
+            self.compressor = Some(std::thread::spawn(move || {
+                if let Err(error) = compress(&path) {
+                    eprintln!("Failed to compress {:?} ({})", path, error);
+                }
+            }));
+        }
+
+        /// For example `relay.log.2.gz`.
+        fn rotated_path(&self, index: u32, extension: &str) -> PathBuf {
+            let mut path = OsString::from(self.path.as_os_str());
+            path.push(format!(".{}{}", index, extension));
+            PathBuf::from(path)
+        }
+    }
+
+    impl Write for RotatingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.should_rotate(buf.len())
+                && let Err(error) = self.rotate()
+            {
+                eprintln!("Failed to rotate {:?} ({})", self.path, error);
+                // Not retried for every line.
+                self.size = 0;
+                self.created_at = SystemTime::now();
+            }
+            let size = self.file.write(buf)?;
+            self.size += size as u64;
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    /// Replaces given file with a gzip compressed copy with .gz appended to
+    /// its name.
+    fn compress(path: &Path) -> Result<(), io::Error> {
+        let mut compressed_path = OsString::from(path.as_os_str());
+        compressed_path.push(".gz");
+        let mut encoder = GzEncoder::new(File::create(&compressed_path)?, Compression::default());
+        io::copy(&mut File::open(path)?, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(unix)]
+mod syslog {
+    use std::io::Write;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::Arc;
+
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Facility user.
+    const FACILITY: u8 = 1;
+
+    pub(super) struct SyslogMakeWriter {
+        socket: Arc<UnixDatagram>,
+        identifier: String,
+    }
+
+    impl SyslogMakeWriter {
+        pub(super) fn new(socket: UnixDatagram) -> Self {
+            let identifier = std::env::current_exe()
+                .ok()
+                .and_then(|path| {
+                    path.file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                })
+                .unwrap_or("moblink".to_string());
+            Self {
+                socket: Arc::new(socket),
+                identifier,
+            }
+        }
+
+        fn create_writer(&self, severity: u8) -> SyslogWriter {
+            SyslogWriter {
+                socket: self.socket.clone(),
+                header: format!(
+                    "<{}>{}[{}]: ",
+                    FACILITY * 8 + severity,
+                    self.identifier,
+                    std::process::id()
+                ),
+                buffer: Vec::new(),
+            }
+        }
+    }
+
+    impl<'writer> MakeWriter<'writer> for SyslogMakeWriter {
+        type Writer = SyslogWriter;
+
+        fn make_writer(&'writer self) -> Self::Writer {
+            self.create_writer(6)
+        }
+
+        fn make_writer_for(&'writer self, metadata: &Metadata<'_>) -> Self::Writer {
+            let severity = match *metadata.level() {
+                Level::ERROR => 3,
+                Level::WARN => 4,
+                Level::INFO => 6,
+                Level::DEBUG | Level::TRACE => 7,
+            };
+            self.create_writer(severity)
+        }
+    }
+
+    /// Sends one syslog message per log line when dropped.
+    pub(super) struct SyslogWriter {
+        socket: Arc<UnixDatagram>,
+        header: String,
+        buffer: Vec<u8>,
+    }
+
+    impl Write for SyslogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for SyslogWriter {
+        fn drop(&mut self) {
+            let message = String::from_utf8_lossy(&self.buffer);
+            for line in message.lines().filter(|line| !line.is_empty()) {
+                let datagram = format!("{}{}", self.header, line);
+                self.socket.send(datagram.as_bytes()).ok();
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod event_log {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE, RegisterEventSourceW, ReportEventW,
+    };
+
+    const SOURCE: &str = "Moblink relay";
+
+    struct EventSource(HANDLE);
+
+    // The handle may be used from any thread.
+    unsafe impl Send for EventSource {}
+    unsafe impl Sync for EventSource {}
+
+    impl Drop for EventSource {
+        fn drop(&mut self) {
+            unsafe { DeregisterEventSource(self.0) };
+        }
+    }
+
+    pub(super) struct EventLogMakeWriter {
+        source: Arc<EventSource>,
+    }
+
+    impl EventLogMakeWriter {
+        pub(super) fn new() -> Result<Self, std::io::Error> {
+            let source = to_wide(SOURCE);
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+            if handle.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self {
+                source: Arc::new(EventSource(handle)),
+            })
+        }
+
+        fn create_writer(&self, event_type: REPORT_EVENT_TYPE) -> EventLogWriter {
+            EventLogWriter {
+                source: self.source.clone(),
+                event_type,
+                buffer: Vec::new(),
+            }
+        }
+    }
+
+    impl<'writer> MakeWriter<'writer> for EventLogMakeWriter {
+        type Writer = EventLogWriter;
+
+        fn make_writer(&'writer self) -> Self::Writer {
+            self.create_writer(EVENTLOG_INFORMATION_TYPE)
+        }
+
+        fn make_writer_for(&'writer self, metadata: &Metadata<'_>) -> Self::Writer {
+            let event_type = match *metadata.level() {
+                Level::ERROR => EVENTLOG_ERROR_TYPE,
+                Level::WARN => EVENTLOG_WARNING_TYPE,
+                Level::INFO | Level::DEBUG | Level::TRACE => EVENTLOG_INFORMATION_TYPE,
+            };
+            self.create_writer(event_type)
+        }
+    }
+
+    /// Reports one event per log line when dropped.
+    pub(super) struct EventLogWriter {
+        source: Arc<EventSource>,
+        event_type: REPORT_EVENT_TYPE,
+        buffer: Vec<u8>,
+    }
+
+    impl Write for EventLogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for EventLogWriter {
+        fn drop(&mut self) {
+            let message = String::from_utf8_lossy(&self.buffer);
+            for line in message.lines().filter(|line| !line.is_empty()) {
+                let line = to_wide(line);
+                let strings = [line.as_ptr()];
+                unsafe {
+                    ReportEventW(
+                        self.source.0,
+                        self.event_type,
+                        0,
+                        0,
+                        std::ptr::null_mut(),
+                        1,
+                        0,
+                        strings.as_ptr(),
+                        std::ptr::null(),
+                    )
+                };
+            }
+        }
+    }
+
+    fn to_wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+}
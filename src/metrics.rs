@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+pub const BYTES_TO_DESTINATION: &str = "moblink_relay_bytes_to_destination_total";
+pub const PACKETS_TO_DESTINATION: &str = "moblink_relay_packets_to_destination_total";
+pub const BYTES_TO_STREAMER: &str = "moblink_relay_bytes_to_streamer_total";
+pub const PACKETS_TO_STREAMER: &str = "moblink_relay_packets_to_streamer_total";
+pub const RECONNECTS: &str = "moblink_relay_reconnects_total";
+pub const AUTHENTICATION_FAILURES: &str = "moblink_relay_authentication_failures_total";
+pub const TUNNEL_RESTARTS: &str = "moblink_relay_tunnel_restarts_total";
+pub const DESTINATION_TIMEOUTS: &str = "moblink_relay_destination_timeouts_total";
+pub const OVERSIZED_DATAGRAMS: &str = "moblink_relay_oversized_datagrams_total";
+pub const ROUND_TRIP_TIME: &str = "moblink_relay_round_trip_time_seconds";
+pub const QUALITY_SCORE: &str = "moblink_relay_quality_score";
+pub const SRT_DATA_PACKETS: &str = "moblink_relay_srt_data_packets_total";
+pub const SRT_RETRANSMITTED_PACKETS: &str = "moblink_relay_srt_retransmitted_packets_total";
+pub const SRT_LOST_PACKETS: &str = "moblink_relay_srt_lost_packets_total";
+pub const SRT_JITTER: &str = "moblink_relay_srt_jitter_seconds";
+pub const MODEM_SIGNAL_QUALITY: &str = "moblink_relay_modem_signal_quality_percent";
+pub const MODEM_BEARER_CONNECTED: &str = "moblink_relay_modem_bearer_connected";
+pub const DATA_USAGE: &str = "moblink_relay_data_usage_bytes";
+pub const THERMAL_ZONE_TEMPERATURE: &str = "moblink_relay_thermal_zone_temperature_celsius";
+pub const DATAGRAM_SIZE: &str = "moblink_relay_datagram_size_bytes";
+pub const DATAGRAM_INTER_ARRIVAL_TIME: &str = "moblink_relay_datagram_inter_arrival_time_seconds";
+
+/// Receives all relay metrics. Implement this to feed relay metrics into an
+/// existing metrics registry (Prometheus, statsd, OTLP, ...).
+///
+/// Per-datagram metrics are published every second rather than from the
+/// forwarding path, but implementations should still not block.
+pub trait MetricsSink: Send + Sync {
+    fn increment_counter(&self, name: &str, labels: &[(&str, &str)], value: u64);
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Observes a value of a histogram with given bucket upper bounds, which
+    /// are the same in all calls for a name. Ignored by default.
+    fn observe_histogram(
+        &self,
+        _name: &str,
+        _labels: &[(&str, &str)],
+        _buckets: &[f64],
+        _value: f64,
+    ) {
+    }
+
+    /// Adds observations of a histogram, aggregated per bucket. `counts` and
+    /// `sums` have one element per bucket, and a last one for values above
+    /// all buckets. Observes the mean of each bucket by default, which keeps
+    /// both the counts and the sum.
+    fn add_histogram_observations(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        counts: &[u64],
+        sums: &[f64],
+    ) {
+        for (count, sum) in counts.iter().zip(sums) {
+            for _ in 0..*count {
+                self.observe_histogram(name, labels, buckets, sum / *count as f64);
+            }
+        }
+    }
+}
+
+/// Log-linear bucket upper bounds, as in HDR histograms. Each power of two
+/// from `minimum` up to `maximum`, and halfway to the next one, so that the
+/// relative error is the same everywhere.
+pub fn log_linear_buckets(minimum: f64, maximum: f64) -> Vec<f64> {
+    let mut buckets = Vec::new();
+    let mut bound = minimum;
+    while bound <= maximum {
+        buckets.push(bound);
+        if 1.5 * bound <= maximum {
+            buckets.push(1.5 * bound);
+        }
+        bound *= 2.0;
+    }
+    buckets
+}
+
+type MetricKey = (String, Vec<(String, String)>);
+
+fn create_key(name: &str, labels: &[(&str, &str)]) -> MetricKey {
+    (
+        name.to_string(),
+        labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+}
+
+struct Histogram {
+    buckets: Vec<f64>,
+    /// Per bucket, and the last one for values above all buckets.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Keeps all metrics in memory. Can be rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct InMemoryMetricsSink {
+    counters: Mutex<BTreeMap<MetricKey, u64>>,
+    gauges: Mutex<BTreeMap<MetricKey, f64>>,
+    histograms: Mutex<BTreeMap<MetricKey, Histogram>>,
+}
+
+impl InMemoryMetricsSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters
+            .get(&create_key(name, labels))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn get_gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let gauges = self.gauges.lock().unwrap();
+        gauges.get(&create_key(name, labels)).copied()
+    }
+
+    pub fn to_prometheus_text(&self) -> String {
+        let mut text = String::new();
+        let counters = self.counters.lock().unwrap();
+        let mut latest_name = None;
+        for ((name, labels), value) in counters.iter() {
+            if latest_name != Some(name) {
+                writeln!(text, "# TYPE {} counter", name).ok();
+                latest_name = Some(name);
+            }
+            writeln!(text, "{}{} {}", name, format_labels(labels), value).ok();
+        }
+        let gauges = self.gauges.lock().unwrap();
+        let mut latest_name = None;
+        for ((name, labels), value) in gauges.iter() {
+            if latest_name != Some(name) {
+                writeln!(text, "# TYPE {} gauge", name).ok();
+                latest_name = Some(name);
+            }
+            writeln!(text, "{}{} {}", name, format_labels(labels), value).ok();
+        }
+        let histograms = self.histograms.lock().unwrap();
+        let mut latest_name = None;
+        for ((name, labels), histogram) in histograms.iter() {
+            if latest_name != Some(name) {
+                writeln!(text, "# TYPE {} histogram", name).ok();
+                latest_name = Some(name);
+            }
+            let mut count = 0;
+            let upper_bounds = histogram
+                .buckets
+                .iter()
+                .map(|bound| bound.to_string())
+                .chain(std::iter::once("+Inf".to_string()));
+            for (upper_bound, bucket_count) in upper_bounds.zip(&histogram.counts) {
+                count += bucket_count;
+                let mut labels = labels.clone();
+                labels.push(("le".to_string(), upper_bound));
+                writeln!(text, "{}_bucket{} {}", name, format_labels(&labels), count).ok();
+            }
+            let labels = format_labels(labels);
+            writeln!(text, "{}_sum{} {}", name, labels, histogram.sum).ok();
+            writeln!(text, "{}_count{} {}", name, labels, histogram.count).ok();
+        }
+        text
+    }
+}
+
+impl MetricsSink for InMemoryMetricsSink {
+    fn increment_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(create_key(name, labels)).or_default() += value;
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.insert(create_key(name, labels), value);
+    }
+
+    fn observe_histogram(&self, name: &str, labels: &[(&str, &str)], buckets: &[f64], value: f64) {
+        let index = buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(buckets.len());
+        let mut counts = vec![0; buckets.len() + 1];
+        counts[index] = 1;
+        let mut sums = vec![0.0; buckets.len() + 1];
+        sums[index] = value;
+        self.add_histogram_observations(name, labels, buckets, &counts, &sums);
+    }
+
+    fn add_histogram_observations(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        counts: &[u64],
+        sums: &[f64],
+    ) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry(create_key(name, labels))
+            .or_insert_with(|| Histogram {
+                buckets: buckets.to_vec(),
+                counts: vec![0; buckets.len() + 1],
+                sum: 0.0,
+                count: 0,
+            });
+        for (index, count) in counts.iter().enumerate() {
+            histogram.counts[index] += count;
+            histogram.count += count;
+        }
+        histogram.sum += sums.iter().sum::<f64>();
+    }
+}
+
+/// Forwards all metrics to several sinks, for example to both serve them
+/// and export them.
+pub struct FanOutMetricsSink {
+    sinks: Vec<Arc<dyn MetricsSink>>,
+}
+
+impl FanOutMetricsSink {
+    pub fn new(sinks: Vec<Arc<dyn MetricsSink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl MetricsSink for FanOutMetricsSink {
+    fn increment_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        for sink in &self.sinks {
+            sink.increment_counter(name, labels, value);
+        }
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        for sink in &self.sinks {
+            sink.set_gauge(name, labels, value);
+        }
+    }
+
+    fn observe_histogram(&self, name: &str, labels: &[(&str, &str)], buckets: &[f64], value: f64) {
+        for sink in &self.sinks {
+            sink.observe_histogram(name, labels, buckets, value);
+        }
+    }
+
+    fn add_histogram_observations(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        counts: &[u64],
+        sums: &[f64],
+    ) {
+        for sink in &self.sinks {
+            sink.add_histogram_observations(name, labels, buckets, counts, sums);
+        }
+    }
+}
+
+fn format_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
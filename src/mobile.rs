@@ -0,0 +1,108 @@
+//! Kotlin and Swift bindings for Android and iOS apps, generated with
+//! `uniffi-bindgen`.
+//!
+//! Async methods are suspend functions in Kotlin and async functions in
+//! Swift, and run the relay on a Tokio runtime shared by all relays.
+
+use std::fmt;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::relay::{Relay, RelayError};
+
+/// Implemented by the app to follow the status of a relay. Called from the
+/// relay's threads.
+#[uniffi::export(with_foreign)]
+pub trait StatusListener: Send + Sync {
+    fn on_status_updated(&self, status: String);
+}
+
+#[derive(Debug, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum MobileRelayError {
+    /// Not a UUID.
+    InvalidRelayId,
+    Relay(RelayError),
+}
+
+impl fmt::Display for MobileRelayError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MobileRelayError::InvalidRelayId => write!(formatter, "Invalid relay id"),
+            MobileRelayError::Relay(error) => write!(formatter, "{}", error),
+        }
+    }
+}
+
+impl From<RelayError> for MobileRelayError {
+    fn from(error: RelayError) -> Self {
+        MobileRelayError::Relay(error)
+    }
+}
+
+#[derive(uniffi::Object)]
+pub struct MobileRelay {
+    relay: Relay,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl MobileRelay {
+    #[uniffi::constructor]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            relay: Relay::new(),
+        })
+    }
+
+    /// Sets the streamer to connect to and the identity of the relay. A
+    /// random relay id is used if none is given.
+    pub async fn setup(
+        &self,
+        streamer_url: String,
+        password: String,
+        relay_id: Option<String>,
+        name: String,
+        listener: Option<Arc<dyn StatusListener>>,
+    ) -> Result<(), MobileRelayError> {
+        let relay_id = match relay_id {
+            Some(relay_id) => relay_id
+                .parse()
+                .map_err(|_| MobileRelayError::InvalidRelayId)?,
+            None => Uuid::new_v4(),
+        };
+        self.relay
+            .setup(
+                streamer_url,
+                password,
+                relay_id,
+                name,
+                move |status| {
+                    if let Some(listener) = &listener {
+                        listener.on_status_updated(status);
+                    }
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Connects to the streamer, and reconnects until stopped.
+    pub async fn start(&self) -> Result<(), MobileRelayError> {
+        Ok(self.relay.start().await?)
+    }
+
+    pub async fn stop(&self) {
+        self.relay.stop().await;
+    }
+
+    /// Reconnects now, for example when the app knows the network changed.
+    pub async fn reconnect(&self) {
+        self.relay.reconnect().await;
+    }
+
+    pub async fn status(&self) -> String {
+        self.relay.get_status_text().await
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+
+use crate::utils::AnyError;
+
+/// Status of one cellular modem, as reported by ModemManager.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModemStatus {
+    /// Manufacturer and model, or the D-Bus object path if not known.
+    pub name: String,
+    pub signal_quality_percent: u32,
+    /// `5G`, `LTE`, `3G`, `2G` or `Unknown`.
+    pub access_technology: String,
+    pub operator_name: String,
+    /// At least one data bearer is connected.
+    pub bearer_connected: bool,
+}
+
+/// Queries ModemManager over the system D-Bus.
+#[cfg(all(target_os = "linux", feature = "modemmanager"))]
+pub(crate) async fn get_modems() -> Result<Vec<ModemStatus>, AnyError> {
+    dbus::get_modems().await
+}
+
+#[cfg(not(all(target_os = "linux", feature = "modemmanager")))]
+pub(crate) async fn get_modems() -> Result<Vec<ModemStatus>, AnyError> {
+    Err("Built without ModemManager support (the modemmanager feature)".into())
+}
+
+#[cfg(all(target_os = "linux", feature = "modemmanager"))]
+mod dbus {
+    use std::collections::HashMap;
+
+    use zbus::Connection;
+    use zbus::fdo::{ObjectManagerProxy, PropertiesProxy};
+    use zbus::names::InterfaceName;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+    use super::ModemStatus;
+    use crate::utils::AnyError;
+
+    const SERVICE: &str = "org.freedesktop.ModemManager1";
+    const PATH: &str = "/org/freedesktop/ModemManager1";
+    const MODEM_INTERFACE: &str = "org.freedesktop.ModemManager1.Modem";
+    const MODEM_3GPP_INTERFACE: &str = "org.freedesktop.ModemManager1.Modem.Modem3gpp";
+    const BEARER_INTERFACE: &str = "org.freedesktop.ModemManager1.Bearer";
+    // MMModemAccessTechnology flags.
+    const ACCESS_TECHNOLOGIES_2G: u32 = 0x0000_041e;
+    const ACCESS_TECHNOLOGIES_3G: u32 = 0x0000_3be0;
+    const ACCESS_TECHNOLOGIES_LTE: u32 = 0x0003_4000;
+    const ACCESS_TECHNOLOGY_5GNR: u32 = 0x0000_8000;
+
+    type Properties = HashMap<String, OwnedValue>;
+
+    pub(super) async fn get_modems() -> Result<Vec<ModemStatus>, AnyError> {
+        let connection = Connection::system().await?;
+        let object_manager = ObjectManagerProxy::builder(&connection)
+            .destination(SERVICE)?
+            .path(PATH)?
+            .build()
+            .await?;
+        let mut modems = Vec::new();
+        for (path, interfaces) in object_manager.get_managed_objects().await? {
+            let Some(modem) = interfaces
+                .iter()
+                .find(|(name, _)| name.as_str() == MODEM_INTERFACE)
+                .map(|(_, properties)| properties)
+            else {
+                continue;
+            };
+            let modem_3gpp = interfaces
+                .iter()
+                .find(|(name, _)| name.as_str() == MODEM_3GPP_INTERFACE)
+                .map(|(_, properties)| properties);
+            let (signal_quality_percent, _recent) =
+                get::<(u32, bool)>(modem, "SignalQuality").unwrap_or_default();
+            let access_technologies = get::<u32>(modem, "AccessTechnologies").unwrap_or_default();
+            let bearers = get::<Vec<OwnedObjectPath>>(modem, "Bearers").unwrap_or_default();
+            modems.push(ModemStatus {
+                name: get_name(modem).unwrap_or_else(|| path.to_string()),
+                signal_quality_percent,
+                access_technology: access_technology_name(access_technologies).to_string(),
+                operator_name: modem_3gpp
+                    .and_then(|properties| get::<String>(properties, "OperatorName"))
+                    .unwrap_or_default(),
+                bearer_connected: is_any_bearer_connected(&connection, bearers).await,
+            });
+        }
+        Ok(modems)
+    }
+
+    fn get<T>(properties: &Properties, name: &str) -> Option<T>
+    where
+        T: TryFrom<OwnedValue>,
+    {
+        T::try_from(properties.get(name)?.try_clone().ok()?).ok()
+    }
+
+    fn get_name(modem: &Properties) -> Option<String> {
+        let manufacturer = get::<String>(modem, "Manufacturer").unwrap_or_default();
+        let model = get::<String>(modem, "Model").unwrap_or_default();
+        let name = format!("{} {}", manufacturer.trim(), model.trim());
+        let name = name.trim();
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    fn access_technology_name(access_technologies: u32) -> &'static str {
+        // 5G non-standalone reports both LTE and 5GNR.
+        if access_technologies & ACCESS_TECHNOLOGY_5GNR != 0 {
+            "5G"
+        } else if access_technologies & ACCESS_TECHNOLOGIES_LTE != 0 {
+            "LTE"
+        } else if access_technologies & ACCESS_TECHNOLOGIES_3G != 0 {
+            "3G"
+        } else if access_technologies & ACCESS_TECHNOLOGIES_2G != 0 {
+            "2G"
+        } else {
+            "Unknown"
+        }
+    }
+
+    async fn is_any_bearer_connected(
+        connection: &Connection,
+        bearers: Vec<OwnedObjectPath>,
+    ) -> bool {
+        for bearer in bearers {
+            let Ok(builder) = PropertiesProxy::builder(connection)
+                .destination(SERVICE)
+                .and_then(|builder| builder.path(bearer))
+            else {
+                continue;
+            };
+            let Ok(properties) = builder.build().await else {
+                continue;
+            };
+            let Ok(interface) = InterfaceName::try_from(BEARER_INTERFACE) else {
+                continue;
+            };
+            if let Ok(connected) = properties.get(interface, "Connected").await
+                && bool::try_from(connected).unwrap_or_default()
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{debug, info};
+
+use crate::stats::Counters;
+use crate::utils::AnyError;
+
+/// How often the path MTU the kernel knows is read. It changes when routers
+/// answer with ICMP fragmentation needed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the largest datagram that is not fragmented on the way to the
+/// destination given socket is connected to in given counters, so that
+/// larger datagrams are warned about. Optionally probes the path MTU. Only
+/// known on Linux.
+pub(crate) async fn monitor_path_mtu(
+    destination_socket: Arc<UdpSocket>,
+    counters: Arc<Counters>,
+    probe: bool,
+) -> Result<(), AnyError> {
+    let destination_address = destination_socket.peer_addr()?;
+    let mut latest_probe: Option<Instant> = None;
+    let mut latest_maximum_size = None;
+    loop {
+        if probe && latest_probe.is_none_or(|latest_probe| latest_probe.elapsed() >= PROBE_INTERVAL)
+        {
+            latest_probe = Some(Instant::now());
+            let bind_address = SocketAddr::new(destination_socket.local_addr()?.ip(), 0);
+            if let Err(error) = probe_path_mtu(bind_address, destination_address).await {
+                debug!("Path MTU probe failed with error: {}", error);
+            }
+        }
+        let maximum_size = maximum_datagram_size(&destination_socket);
+        if maximum_size != latest_maximum_size {
+            match maximum_size {
+                Some(maximum_size) => info!(
+                    "Datagrams up to {} bytes are not fragmented on the way to {}",
+                    maximum_size, destination_address
+                ),
+                None => debug!("Path MTU to {} is unknown", destination_address),
+            }
+            latest_maximum_size = maximum_size;
+            counters.set_maximum_datagram_size(maximum_size);
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// The path MTU minus the IP and UDP headers.
+fn maximum_datagram_size(socket: &UdpSocket) -> Option<u64> {
+    let ipv4 = match socket.peer_addr().ok()? {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(address) => address.ip().to_ipv4_mapped().is_some(),
+    };
+    let headers_size = if ipv4 { 20 + 8 } else { 40 + 8 };
+    platform::path_mtu(socket)?.checked_sub(headers_size)
+}
+
+/// Sends a datagram of the largest size the kernel thinks fits, with
+/// fragmentation prohibited, until the kernel lowers the path MTU no more.
+/// Routers that cannot forward it answer with ICMP fragmentation needed,
+/// which lowers the path MTU of the destination for all sockets. The
+/// datagram is filled with zeros, which SRT and RIST receivers ignore.
+async fn probe_path_mtu(
+    bind_address: SocketAddr,
+    destination_address: SocketAddr,
+) -> Result<(), AnyError> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(bind_address),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if bind_address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    platform::prohibit_fragmentation(&socket)?;
+    socket.bind(&bind_address.into())?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.connect(destination_address).await?;
+    let mut latest_maximum_size = None;
+    for _ in 0..5 {
+        let Some(maximum_size) = maximum_datagram_size(&socket) else {
+            return Ok(());
+        };
+        if latest_maximum_size == Some(maximum_size) {
+            break;
+        }
+        latest_maximum_size = Some(maximum_size);
+        debug!("Probing path MTU with a {} bytes datagram", maximum_size);
+        if let Err(error) = socket.send(&vec![0; maximum_size as usize]).await {
+            // The kernel already knew better.
+            debug!("Path MTU probe not sent with error: {}", error);
+            continue;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    fn get_int(fd: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> Option<u64> {
+        let mut value: libc::c_int = 0;
+        let mut length = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: The value is a c_int of given length.
+        let result = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        (result == 0).then_some(value as u64)
+    }
+
+    fn set_int(
+        fd: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> Result<(), io::Error> {
+        // SAFETY: The value is a c_int of given length.
+        let result = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn is_ipv6(fd: &impl AsRawFd) -> bool {
+        get_int(fd, libc::SOL_SOCKET, libc::SO_DOMAIN) == Some(libc::AF_INET6 as u64)
+    }
+
+    /// Of a connected socket.
+    pub(super) fn path_mtu(socket: &impl AsRawFd) -> Option<u64> {
+        if is_ipv6(socket) {
+            get_int(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU)
+        } else {
+            get_int(socket, libc::IPPROTO_IP, libc::IP_MTU)
+        }
+    }
+
+    pub(super) fn prohibit_fragmentation(socket: &impl AsRawFd) -> Result<(), io::Error> {
+        if is_ipv6(socket) {
+            set_int(
+                socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            )?;
+        }
+        // Also for IPv4 destinations of dual stack sockets.
+        set_int(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+
+    pub(super) fn path_mtu(_socket: &tokio::net::UdpSocket) -> Option<u64> {
+        None
+    }
+
+    pub(super) fn prohibit_fragmentation(_socket: &socket2::Socket) -> Result<(), io::Error> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures_util::future::select_all;
+use tokio::net::UdpSocket;
+
+/// One local interface to send to the destination from in multipath mode.
+/// Parsed from `192.168.1.10`, or `192.168.1.10=3` where 3 is the weight.
+#[derive(Clone, Debug)]
+pub struct MultipathPath {
+    pub bind_address: IpAddr,
+    /// Relative share of the datagrams. 1 by default.
+    pub weight: u32,
+}
+
+impl FromStr for MultipathPath {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (bind_address, weight) = match value.split_once('=') {
+            Some((bind_address, weight)) => (
+                bind_address,
+                weight
+                    .trim()
+                    .parse()
+                    .map_err(|error| format!("Invalid weight: {}", error))?,
+            ),
+            None => (value, 1),
+        };
+        if weight == 0 {
+            return Err("Weight must be at least 1".into());
+        }
+        Ok(Self {
+            bind_address: bind_address
+                .trim()
+                .parse()
+                .map_err(|error| format!("Invalid bind address: {}", error))?,
+            weight,
+        })
+    }
+}
+
+/// Smooth weighted round robin, as in nginx. Interleaves the paths instead of
+/// sending bursts on each of them.
+pub(crate) struct Scheduler {
+    weights: Vec<i64>,
+    current_weights: Vec<i64>,
+    total_weight: i64,
+}
+
+impl Scheduler {
+    pub(crate) fn new(weights: &[u32]) -> Self {
+        let weights: Vec<i64> = weights.iter().map(|weight| *weight as i64).collect();
+        Self {
+            current_weights: vec![0; weights.len()],
+            total_weight: weights.iter().sum(),
+            weights,
+        }
+    }
+
+    /// Index of the path to send the next datagram on.
+    pub(crate) fn next(&mut self) -> usize {
+        let mut best = 0;
+        for (index, weight) in self.weights.iter().enumerate() {
+            self.current_weights[index] += weight;
+            if self.current_weights[index] > self.current_weights[best] {
+                best = index;
+            }
+        }
+        self.current_weights[best] -= self.total_weight;
+        best
+    }
+}
+
+/// Receives a datagram from whichever socket has one first.
+pub(crate) async fn recv_from_any(
+    sockets: &[Arc<UdpSocket>],
+    buf: &mut [u8],
+) -> Result<usize, std::io::Error> {
+    if let [socket] = sockets {
+        return socket.recv(buf).await;
+    }
+    Ok(recv_from_any_with_index(sockets, buf).await?.1)
+}
+
+/// As `recv_from_any()`, but also returns the index of the socket.
+pub(crate) async fn recv_from_any_with_index(
+    sockets: &[Arc<UdpSocket>],
+    buf: &mut [u8],
+) -> Result<(usize, usize), std::io::Error> {
+    loop {
+        let (result, index, _) =
+            select_all(sockets.iter().map(|socket| Box::pin(socket.readable()))).await;
+        result?;
+        match sockets[index].try_recv(buf) {
+            Err(error) if error.kind() == ErrorKind::WouldBlock => continue,
+            result => return result.map(|size| (index, size)),
+        }
+    }
+}
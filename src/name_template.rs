@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+
+use gethostname::gethostname;
+use tracing::debug;
+
+use crate::interfaces::list_interfaces;
+use crate::modem_manager::get_modems;
+
+const UNKNOWN: &str = "unknown";
+
+/// Values of the placeholders in relay names. `{hostname}`, `{interface}`,
+/// `{ip}` and `{carrier}` are supported.
+#[derive(Clone, Debug, Default)]
+pub struct NameVariables {
+    pub hostname: String,
+    pub interface: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Operator of the cellular modem.
+    pub carrier: Option<String>,
+}
+
+impl NameVariables {
+    /// The variables of the relay binding to given address. The interface is
+    /// the one with given address. The carrier is the operator of the first
+    /// modem ModemManager reports one for, and is only looked up if used in
+    /// given template.
+    pub async fn lookup(template: &str, ip: Option<IpAddr>) -> Self {
+        let interface = ip.and_then(|ip| {
+            list_interfaces()
+                .into_iter()
+                .find(|interface| interface.addresses.contains(&ip))
+                .map(|interface| interface.name)
+        });
+        let carrier = if template.contains("{carrier}") {
+            match get_modems().await {
+                Ok(modems) => modems
+                    .into_iter()
+                    .map(|modem| modem.operator_name)
+                    .find(|operator_name| !operator_name.is_empty()),
+                Err(error) => {
+                    debug!("No carrier for relay name: {}", error);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Self {
+            hostname: gethostname().to_str().unwrap_or(UNKNOWN).to_string(),
+            interface,
+            ip,
+            carrier,
+        }
+    }
+}
+
+/// Replaces the placeholders in given relay name. Unknown values are replaced
+/// with `unknown`.
+pub fn expand_name(template: &str, variables: &NameVariables) -> String {
+    let ip = variables.ip.map(|ip| ip.to_string());
+    template
+        .replace("{hostname}", &variables.hostname)
+        .replace(
+            "{interface}",
+            variables.interface.as_deref().unwrap_or(UNKNOWN),
+        )
+        .replace("{ip}", ip.as_deref().unwrap_or(UNKNOWN))
+        .replace("{carrier}", variables.carrier.as_deref().unwrap_or(UNKNOWN))
+}
@@ -0,0 +1,165 @@
+use std::future::pending;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::io;
+
+use tracing::debug;
+
+/// Waits until an address or a route is added, typically when an uplink
+/// returns after a network hiccup. Uses netlink on Linux and a routing socket
+/// on macOS. Pending forever elsewhere, or if monitoring fails.
+pub(crate) async fn wait_for_network_up() {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    match platform::wait_for_network_up().await {
+        Ok(()) => return,
+        Err(error) => debug!("Network monitoring failed with error: {}", error),
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    debug!("Network monitoring not supported");
+    pending().await
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn receive(fd: &std::os::fd::OwnedFd, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: The buffer is valid for its length.
+    let size = unsafe { libc::recv(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size as usize)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    use tokio::io::Interest;
+    use tokio::io::unix::AsyncFd;
+
+    use super::receive;
+
+    pub(super) async fn wait_for_network_up() -> Result<(), io::Error> {
+        // SAFETY: No pointers.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Just created, and owned by nothing else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: All zeros is a valid sockaddr_nl.
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as u16;
+        address.nl_groups = (libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+        // SAFETY: The address is a sockaddr_nl of given size.
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+        let mut buffer = vec![0; 16384];
+        loop {
+            let mut guard = fd.readable().await?;
+            let Ok(size) = guard.try_io(|fd| receive(fd.get_ref(), &mut buffer)) else {
+                continue;
+            };
+            if contains_new_address_or_route(&buffer[..size?]) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn contains_new_address_or_route(mut messages: &[u8]) -> bool {
+        while messages.len() >= size_of::<libc::nlmsghdr>() {
+            // SAFETY: At least one header is left.
+            let header: libc::nlmsghdr =
+                unsafe { std::ptr::read_unaligned(messages.as_ptr().cast()) };
+            if header.nlmsg_type == libc::RTM_NEWADDR || header.nlmsg_type == libc::RTM_NEWROUTE {
+                return true;
+            }
+            let length = (header.nlmsg_len as usize).next_multiple_of(4);
+            if length == 0 || length > messages.len() {
+                break;
+            }
+            messages = &messages[length..];
+        }
+        false
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    use tokio::io::Interest;
+    use tokio::io::unix::AsyncFd;
+
+    use super::receive;
+
+    // From net/route.h.
+    const RTM_ADD: u8 = 0x1;
+    const RTM_NEWADDR: u8 = 0xc;
+
+    pub(super) async fn wait_for_network_up() -> Result<(), io::Error> {
+        // SAFETY: No pointers.
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Just created, and owned by nothing else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: No pointers.
+        let result = unsafe {
+            libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+        let mut buffer = vec![0; 16384];
+        loop {
+            let mut guard = fd.readable().await?;
+            let Ok(size) = guard.try_io(|fd| receive(fd.get_ref(), &mut buffer)) else {
+                continue;
+            };
+            if contains_new_address_or_route(&buffer[..size?]) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// All routing messages start with their length (u16), version (u8) and
+    /// type (u8).
+    fn contains_new_address_or_route(mut messages: &[u8]) -> bool {
+        while messages.len() >= 4 {
+            let message_type = messages[3];
+            if message_type == RTM_ADD || message_type == RTM_NEWADDR {
+                return true;
+            }
+            let length = u16::from_ne_bytes([messages[0], messages[1]]) as usize;
+            if length == 0 || length > messages.len() {
+                break;
+            }
+            messages = &messages[length..];
+        }
+        false
+    }
+}
@@ -0,0 +1,48 @@
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::secret::SecretString;
+
+const NONCE_SIZE: usize = 12;
+
+/// Obfuscation of tunnel datagrams with a pre-shared key, so that carriers
+/// cannot recognize and throttle for example SRT.
+///
+/// Datagrams are `[nonce (12 bytes), payload]`, where the payload is XORed
+/// with the ChaCha20 key stream of the random nonce and the SHA-256 of the
+/// key. Not authenticated, so modified datagrams are not detected, which SRT
+/// does anyway.
+#[derive(Clone)]
+pub(crate) struct Obfuscation {
+    key: [u8; 32],
+}
+
+impl Obfuscation {
+    pub(crate) fn new(key: &SecretString) -> Self {
+        Self {
+            key: Sha256::digest(key.expose_secret().as_bytes()).into(),
+        }
+    }
+
+    pub(crate) fn obfuscate(&self, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; NONCE_SIZE + payload.len()];
+        let (nonce, data) = datagram.split_at_mut(NONCE_SIZE);
+        rand::rng().fill_bytes(nonce);
+        data.copy_from_slice(payload);
+        ChaCha20::new(&self.key.into(), (&*nonce).into()).apply_keystream(data);
+        datagram
+    }
+
+    /// `None` if given datagram is too short to be obfuscated.
+    pub(crate) fn deobfuscate(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, data) = datagram.split_at(NONCE_SIZE);
+        let mut payload = data.to_vec();
+        ChaCha20::new(&self.key.into(), nonce.into()).apply_keystream(&mut payload);
+        Some(payload)
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter, MeterProvider};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{InstrumentationScope, KeyValue};
+use opentelemetry_otlp::{ExporterBuildError, MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing_subscriber::Layer;
+
+use crate::logging::BoxedLayer;
+use crate::metrics::MetricsSink;
+
+const SERVICE_NAME: &str = "moblink-relay";
+
+/// Exports traces and metrics to an OpenTelemetry collector over OTLP/HTTP.
+/// The connection and tunnel spans become traces, and all relay metrics are
+/// exported with the same names as in `/metrics`, without the `_total`
+/// suffix of counters. Exported in the background, also when the collector
+/// is unreachable.
+pub struct Otel {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Otel {
+    /// Given endpoint is the base URL of the collector, for example
+    /// `http://localhost:4318`.
+    pub fn start(endpoint: &str) -> Result<Self, ExporterBuildError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::builder()
+            .with_service_name(SERVICE_NAME)
+            .with_attributes([
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+                KeyValue::new(
+                    "host.name",
+                    gethostname::gethostname().to_string_lossy().to_string(),
+                ),
+            ])
+            .build();
+        let span_exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter)
+            .with_resource(resource.clone())
+            .build();
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(metric_exporter)
+            .with_resource(resource)
+            .build();
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Add to the tracing subscriber to export spans, see
+    /// `setup_logging_with_layer()`.
+    pub fn tracing_layer(&self) -> BoxedLayer {
+        let tracer = self.tracer_provider.tracer(SERVICE_NAME);
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed()
+    }
+
+    pub fn metrics_sink(&self) -> Arc<dyn MetricsSink> {
+        let scope = InstrumentationScope::builder(SERVICE_NAME)
+            .with_version(env!("CARGO_PKG_VERSION"))
+            .build();
+        Arc::new(OtelMetricsSink {
+            meter: self.meter_provider.meter_with_scope(scope),
+            counters: Default::default(),
+            gauges: Default::default(),
+            histograms: Default::default(),
+        })
+    }
+
+    /// Exports what has not been exported yet. Blocks until done.
+    pub fn shutdown(&self) {
+        self.tracer_provider.shutdown().ok();
+        self.meter_provider.shutdown().ok();
+    }
+}
+
+/// Instruments are created when first used.
+struct OtelMetricsSink {
+    meter: Meter,
+    counters: Mutex<HashMap<String, Counter<u64>>>,
+    gauges: Mutex<HashMap<String, Gauge<f64>>>,
+    histograms: Mutex<HashMap<String, Histogram<f64>>>,
+}
+
+fn create_attributes(labels: &[(&str, &str)]) -> Vec<KeyValue> {
+    labels
+        .iter()
+        .map(|(name, value)| KeyValue::new(name.to_string(), value.to_string()))
+        .collect()
+}
+
+impl MetricsSink for OtelMetricsSink {
+    fn increment_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(name) {
+            let otel_name = name.strip_suffix("_total").unwrap_or(name).to_string();
+            let counter = self.meter.u64_counter(otel_name).build();
+            counters.insert(name.to_string(), counter);
+        }
+        counters[name].add(value, &create_attributes(labels));
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        if !gauges.contains_key(name) {
+            let gauge = self.meter.f64_gauge(name.to_string()).build();
+            gauges.insert(name.to_string(), gauge);
+        }
+        gauges[name].record(value, &create_attributes(labels));
+    }
+
+    fn observe_histogram(&self, name: &str, labels: &[(&str, &str)], buckets: &[f64], value: f64) {
+        self.get_histogram(name, buckets)
+            .record(value, &create_attributes(labels));
+    }
+
+    /// OpenTelemetry histograms only record single values, so the mean of
+    /// each bucket is recorded, with the attributes created once.
+    fn add_histogram_observations(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        counts: &[u64],
+        sums: &[f64],
+    ) {
+        let histogram = self.get_histogram(name, buckets);
+        let attributes = create_attributes(labels);
+        for (count, sum) in counts.iter().zip(sums) {
+            for _ in 0..*count {
+                histogram.record(sum / *count as f64, &attributes);
+            }
+        }
+    }
+}
+
+impl OtelMetricsSink {
+    fn get_histogram(&self, name: &str, buckets: &[f64]) -> Histogram<f64> {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                self.meter
+                    .f64_histogram(name.to_string())
+                    .with_boundaries(buckets.to_vec())
+                    .build()
+            })
+            .clone()
+    }
+}
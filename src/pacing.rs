@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::stats::Counters;
+
+/// Datagrams that have been queued for longer than this are not paced fast
+/// enough, and new datagrams are dropped until the queue drains.
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// How datagrams to the destination are paced. Serialized as
+/// `{"bitrate": 8000000}` or `{"gapUs": 500}`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Pacing {
+    /// At most this many bits per second.
+    Bitrate(u64),
+    /// At least this many microseconds between datagrams.
+    GapUs(u64),
+}
+
+impl Pacing {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Pacing::Bitrate(bitrate) => *bitrate > 0,
+            Pacing::GapUs(gap_us) => *gap_us > 0,
+        }
+    }
+
+    /// The time it takes to send a datagram of given size.
+    fn interval(&self, size: usize) -> Duration {
+        match self {
+            Pacing::Bitrate(bitrate) => {
+                Duration::from_secs_f64(8.0 * size as f64 / *bitrate as f64)
+            }
+            Pacing::GapUs(gap_us) => Duration::from_micros(*gap_us),
+        }
+    }
+}
+
+/// Delay induced by pacing since the relay was created.
+#[derive(Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PacingStats {
+    pub packets: u64,
+    /// Dropped as the queue was full.
+    pub dropped_packets: u64,
+    pub average_delay_ms: f64,
+    pub max_delay_ms: f64,
+}
+
+/// Smooths datagrams to the destination as a leaky bucket. Datagrams are
+/// queued and leave the queue no faster than the pacing allows. The pacing
+/// can be changed at any time, and the queue is flushed when pacing is
+/// disabled. Queued datagrams are shared, not copied.
+pub(crate) struct Pacer {
+    pacing: Arc<RwLock<Option<Pacing>>>,
+    /// With the time each datagram was queued.
+    queue: VecDeque<(Instant, Bytes)>,
+    next_send_at: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(pacing: Arc<RwLock<Option<Pacing>>>) -> Self {
+        Self {
+            pacing,
+            queue: VecDeque::new(),
+            next_send_at: Instant::now(),
+        }
+    }
+
+    fn pacing(&self) -> Option<Pacing> {
+        *self.pacing.read().unwrap()
+    }
+
+    /// Datagrams must be queued when pacing, and also after pacing was
+    /// disabled until the queue is empty, to keep them in order.
+    pub(crate) fn is_queueing(&self) -> bool {
+        !self.queue.is_empty() || self.pacing().is_some()
+    }
+
+    pub(crate) fn push(&mut self, datagram: Bytes, counters: &Counters) {
+        let now = Instant::now();
+        if self
+            .queue
+            .front()
+            .is_some_and(|(queued_at, _)| now.duration_since(*queued_at) > MAX_QUEUE_DELAY)
+        {
+            counters.add_pacing_drop();
+            return;
+        }
+        self.queue.push_back((now, datagram));
+    }
+
+    /// When to send the next datagram in the queue, if any.
+    pub(crate) fn send_at(&self) -> Option<Instant> {
+        self.queue.front()?;
+        match self.pacing() {
+            Some(_) => Some(self.next_send_at),
+            None => Some(Instant::now()),
+        }
+    }
+
+    /// Takes the next datagram from the queue. Call at `send_at()`.
+    pub(crate) fn pop(&mut self, counters: &Counters) -> Option<Bytes> {
+        let (queued_at, datagram) = self.queue.pop_front()?;
+        let now = Instant::now();
+        counters.add_pacing_delay(now.duration_since(queued_at));
+        if let Some(pacing) = self.pacing() {
+            self.next_send_at = self.next_send_at.max(now) + pacing.interval(datagram.len());
+        }
+        Some(datagram)
+    }
+}
@@ -0,0 +1,82 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::debug;
+
+use crate::relay::create_dual_stack_udp_socket;
+
+/// How long to wait for an ICMP error. Answers from the destination are
+/// rare, as most protocols ignore an empty datagram, so this delays every
+/// start of a tunnel to a reachable destination.
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Sends an empty datagram from given bind address to each destination
+/// address and waits for an ICMP error. Fails only if every address is
+/// clearly unreachable, that is, sending failed or an ICMP port, host or
+/// network unreachable was received. Silence is not an error, as a
+/// destination does not have to answer.
+pub(crate) async fn probe_destination(
+    bind_address: SocketAddr,
+    addresses: &[SocketAddr],
+) -> Result<(), std::io::Error> {
+    let mut probes: FuturesUnordered<_> = addresses
+        .iter()
+        .map(|address| probe_address(bind_address, *address))
+        .collect();
+    let mut last_error = None;
+    while let Some(result) = probes.next().await {
+        match result {
+            Ok(()) => return Ok(()),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| std::io::Error::other("No destination address")))
+}
+
+async fn probe_address(
+    bind_address: SocketAddr,
+    address: SocketAddr,
+) -> Result<(), std::io::Error> {
+    let socket = create_dual_stack_udp_socket(bind_address).await?;
+    socket.connect(address).await?;
+    socket.send(&[]).await?;
+    match timeout(PROBE_TIMEOUT, wait_for_answer(&socket)).await {
+        Ok(Err(error)) if is_unreachable(&error) => {
+            debug!("Probe to {} failed with error: {}", address, error);
+            Err(error)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// An ICMP error only makes the socket ready with an error, which a receive
+/// does not wait for.
+async fn wait_for_answer(socket: &UdpSocket) -> Result<(), std::io::Error> {
+    let mut buf = [0; 1];
+    loop {
+        let ready = socket.ready(Interest::READABLE | Interest::ERROR).await?;
+        if ready.is_error() {
+            return match socket.take_error()? {
+                Some(error) => Err(error),
+                None => Ok(()),
+            };
+        }
+        match socket.try_recv(&mut buf) {
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+fn is_unreachable(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable
+    )
+}
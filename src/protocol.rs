@@ -1,19 +1,227 @@
 use base64::Engine as _;
 use base64::engine::general_purpose;
-use serde::{Deserialize, Serialize};
+use clap::ValueEnum;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::protocol::Message;
 use uuid::Uuid;
 
+use crate::test_mode::TestReport;
+use crate::utils::AnyError;
+
+/// Peers with a newer API version may send fields, message types and values
+/// that are not understood. Unknown fields are ignored, and unknown message
+/// types and values are deserialized as `Unknown` variants, or `None` for
+/// optional fields, instead of failing the whole message.
 pub const API_VERSION: &str = "1.0";
+/// The streamer accepts unsolicited `MessageToStreamer::Status` messages.
+pub const CAPABILITY_STATUS_PUSH: &str = "statusPush";
+/// The streamer accepts CBOR encoded messages in binary WebSocket frames, and
+/// answers in CBOR once it has received one.
+pub const CAPABILITY_CBOR: &str = "cbor";
+/// The streamer accepts `COMPRESSION_DEFLATE` in the identify message.
+pub const CAPABILITY_DEFLATE: &str = "deflate";
+/// All messages after the identify message are compressed with `Deflate` in
+/// binary WebSocket frames, in both directions.
+pub const COMPRESSION_DEFLATE: &str = "deflate";
+/// The relay accepts `MessageRequestData::StartTcpTunnel` requests.
+pub const CAPABILITY_TCP_TUNNEL: &str = "tcpTunnel";
+/// The relay accepts `MessageRequestData::StopTunnel` requests.
+pub const CAPABILITY_STOP_TUNNEL: &str = "stopTunnel";
+/// The streamer accepts `MessageToStreamer::Rename` messages.
+pub const CAPABILITY_RENAME: &str = "rename";
+/// The streamer accepts `MoblinkResult::Error` in responses.
+pub const CAPABILITY_ERROR_RESULT: &str = "errorResult";
+/// The streamer accepts `MoblinkResult::Busy` in responses, and requests a
+/// tunnel again later.
+pub const CAPABILITY_BUSY_RESULT: &str = "busyResult";
+/// The streamer accepts `qualityScore` in status responses and pushes.
+pub const CAPABILITY_QUALITY_SCORE: &str = "qualityScore";
+/// The relay accepts `StartTunnelRequest::obfuscated`. Only announced by
+/// relays with an obfuscation key.
+pub const CAPABILITY_OBFUSCATION: &str = "obfuscation";
+/// The relay accepts `StartTunnelRequest::paired_ports`.
+pub const CAPABILITY_PAIRED_PORTS: &str = "pairedPorts";
+/// The streamer accepts `priority` and `group` in the identify message.
+pub const CAPABILITY_PRIORITY: &str = "priority";
+/// The streamer accepts `MessageToStreamer::TestReport` messages.
+pub const CAPABILITY_TEST_REPORT: &str = "testReport";
+
+/// Encoding of control messages.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// In text WebSocket frames.
+    #[default]
+    Json,
+    /// In binary WebSocket frames. Smaller than JSON.
+    Cbor,
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Message, AnyError> {
+        match self {
+            Encoding::Json => Ok(Message::Text(serde_json::to_string(message)?.into())),
+            Encoding::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(message, &mut data)?;
+                Ok(Message::Binary(data.into()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, AnyError> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(data)?),
+            Encoding::Cbor => decode_cbor(data),
+        }
+    }
+}
+
+/// How the streamer should use a relay.
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum RelayPriority {
+    /// Used whenever connected.
+    #[default]
+    Main,
+    /// Only used when no main relay is.
+    Backup,
+}
+
+pub fn decode_cbor<T: DeserializeOwned>(data: &[u8]) -> Result<T, AnyError> {
+    Ok(ciborium::from_reader(data)?)
+}
+
+/// `true` if given API version of a peer, `major.minor`, is newer than
+/// `API_VERSION`.
+pub fn is_newer_api_version(api_version: &str) -> bool {
+    parse_api_version(api_version) > parse_api_version(API_VERSION)
+}
+
+fn parse_api_version(api_version: &str) -> (u32, u32) {
+    let mut parts = api_version
+        .split('.')
+        .map(|part| part.trim().parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// For optional fields with values newer peers may add to. Values that are
+/// not understood are `None`.
+fn deserialize_unknown_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value<T> {
+        Known(T),
+        Unknown(IgnoredAny),
+    }
+
+    match Option::<Value<T>>::deserialize(deserializer)? {
+        Some(Value::Known(value)) => Ok(Some(value)),
+        Some(Value::Unknown(_)) | None => Ok(None),
+    }
+}
+
+/// Deserializes an externally tagged enum as derived, but with message types
+/// and values of newer peers as its `Unknown` variant. Known ones with
+/// malformed content are still rejected, as only the key is matched.
+macro_rules! deserialize_tagged {
+    ($type:ident { $($key:literal => $variant:ident,)* }) => {
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                struct TaggedVisitor;
+
+                impl<'de> Visitor<'de> for TaggedVisitor {
+                    type Value = $type;
+
+                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                        formatter.write_str(concat!("enum ", stringify!($type)))
+                    }
+
+                    fn visit_map<A>(self, mut map: A) -> Result<$type, A::Error>
+                    where
+                        A: MapAccess<'de>,
+                    {
+                        let Some(key) = map.next_key::<String>()? else {
+                            return Err(de::Error::invalid_length(0, &self));
+                        };
+                        let value = match key.as_str() {
+                            $($key => $type::$variant(map.next_value()?),)*
+                            _ => $type::Unknown(map.next_value()?),
+                        };
+                        if map.next_key::<IgnoredAny>()?.is_some() {
+                            return Err(de::Error::invalid_length(2, &self));
+                        }
+                        Ok(value)
+                    }
+                }
+
+                deserializer.deserialize_map(TaggedVisitor)
+            }
+        }
+    };
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Present {}
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum MoblinkResult {
     Ok(Present),
     WrongPassword(Present),
+    /// The relay does not start the tunnel, for example because it is paused.
+    /// Only sent if the streamer has the `CAPABILITY_BUSY_RESULT` capability,
+    /// and requests are not answered otherwise.
+    Busy(Present),
+    /// The relay failed to handle the request. Only sent if the streamer has
+    /// the `CAPABILITY_ERROR_RESULT` capability, and `Busy` otherwise.
+    Error(ErrorResult),
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(skip_serializing)]
+    Unknown(IgnoredAny),
+}
+
+deserialize_tagged!(MoblinkResult {
+    "ok" => Ok,
+    "wrongPassword" => WrongPassword,
+    "busy" => Busy,
+    "error" => Error,
+});
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ErrorResult {
+    pub code: ErrorCode,
+    /// Details for humans, for example to log.
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The destination of a tunnel cannot be reached from the relay.
+    DestinationUnreachable,
+    /// The destination of a tunnel could not be resolved.
+    ResolveFailed,
+    /// The relay failed to bind the port to receive tunneled packets on.
+    BindFailed,
+    /// All ports to receive tunneled packets on are in use.
+    NoPortsAvailable,
+    /// No tunnel receives packets on the port in a stop tunnel request.
+    UnknownTunnel,
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(untagged, skip_serializing)]
+    Unknown(IgnoredAny),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,29 +233,74 @@ pub struct Authentication {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Hello {
     #[serde(rename = "apiVersion")]
-    #[allow(dead_code)]
     pub api_version: String,
     pub authentication: Authentication,
+    /// Optional protocol features the streamer supports. Missing in old
+    /// streamers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Identified {
     pub result: MoblinkResult,
+    /// Answer to the `challenge` in `Identify`. Only sent by downstream
+    /// relays to chain servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StartTunnelRequest {
     pub address: String,
     pub port: u16,
+    /// Tunnel datagrams from and to the streamer are obfuscated with the
+    /// pre-shared obfuscation key. Only if the relay has the
+    /// `CAPABILITY_OBFUSCATION` capability.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub obfuscated: bool,
+    /// Also forward the next port, for RTCP of RIST and RTP. The tunnel port
+    /// in the response is then even and the next port forwards to the next
+    /// port of the destination. Only if the relay has the
+    /// `CAPABILITY_PAIRED_PORTS` capability.
+    #[serde(
+        rename = "pairedPorts",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub paired_ports: bool,
 }
 
+/// The tunnel is identified by the port in the start tunnel response.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct StopTunnelRequest {
+    pub port: u16,
+}
+
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum MessageRequestData {
     StartTunnel(StartTunnelRequest),
     Status(Present),
+    /// Like `StartTunnel`, but the relay listens for TCP connections and
+    /// forwards each of them over TCP to the destination. Only if the relay
+    /// has the `CAPABILITY_TCP_TUNNEL` capability.
+    StartTcpTunnel(StartTunnelRequest),
+    /// Stops a UDP or TCP tunnel. Only if the relay has the
+    /// `CAPABILITY_STOP_TUNNEL` capability.
+    StopTunnel(StopTunnelRequest),
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(skip_serializing)]
+    Unknown(IgnoredAny),
 }
 
+deserialize_tagged!(MessageRequestData {
+    "startTunnel" => StartTunnel,
+    "status" => Status,
+    "startTcpTunnel" => StartTcpTunnel,
+    "stopTunnel" => StopTunnel,
+});
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageRequest {
     pub id: u32,
@@ -62,6 +315,22 @@ impl MessageRequest {
             data,
         }
     }
+
+    pub fn to_busy_response(&self, data: ResponseData) -> MessageResponse {
+        MessageResponse {
+            id: self.id,
+            result: MoblinkResult::Busy(Present {}),
+            data,
+        }
+    }
+
+    pub fn to_error_response(&self, data: ResponseData, error: ErrorResult) -> MessageResponse {
+        MessageResponse {
+            id: self.id,
+            result: MoblinkResult::Error(error),
+            data,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -69,20 +338,36 @@ pub struct StartTunnelResponseData {
     pub port: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct StatusResponseData {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub battery_percentage: Option<i32>,
+    /// From 0 to 100, see `Relay::get_quality_score()`. Only if the streamer
+    /// has the `CAPABILITY_QUALITY_SCORE` capability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality_score: Option<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum ResponseData {
     StartTunnel(StartTunnelResponseData),
     Status(StatusResponseData),
+    StartTcpTunnel(StartTunnelResponseData),
+    StopTunnel(Present),
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(skip_serializing)]
+    Unknown(IgnoredAny),
 }
 
+deserialize_tagged!(ResponseData {
+    "startTunnel" => StartTunnel,
+    "status" => Status,
+    "startTcpTunnel" => StartTcpTunnel,
+    "stopTunnel" => StopTunnel,
+});
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageResponse {
     pub id: u32,
@@ -90,28 +375,104 @@ pub struct MessageResponse {
     pub data: ResponseData,
 }
 
+/// Bitrates are in bits per second since the previous push.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StatusPush {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_percentage: Option<i32>,
+    pub bitrate_to_destination: u64,
+    pub bitrate_to_streamer: u64,
+    /// As in `StatusResponseData`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality_score: Option<u8>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Identify {
+    /// `API_VERSION` of the relay. Missing in old relays.
+    #[serde(
+        rename = "apiVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub api_version: Option<String>,
     pub id: Uuid,
     pub name: String,
     pub authentication: String,
+    /// Only if the streamer has the `CAPABILITY_DEFLATE` capability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// Optional protocol features the relay supports. Missing in old relays.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    /// Only if the streamer has the `CAPABILITY_PRIORITY` capability.
+    /// Missing or unknown means main.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_unknown_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub priority: Option<RelayPriority>,
+    /// Free-form group of the relay, for example the vehicle or backpack it
+    /// is in, for streamers to organize relays by. Only if the streamer has
+    /// the `CAPABILITY_PRIORITY` capability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Only sent by chain servers, which do not accept requests until the
+    /// downstream relay has answered it in `Identified`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<Authentication>,
 }
 
+/// The relay's new display name, without identifying again.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Rename {
+    pub name: String,
+}
+
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum MessageToRelay {
     Hello(Hello),
     Identified(Identified),
     Request(MessageRequest),
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(skip_serializing)]
+    Unknown(IgnoredAny),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+deserialize_tagged!(MessageToRelay {
+    "hello" => Hello,
+    "identified" => Identified,
+    "request" => Request,
+});
+
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum MessageToStreamer {
     Identify(Identify),
     Response(MessageResponse),
+    /// Only sent in test mode, and if the streamer has the
+    /// `CAPABILITY_TEST_REPORT` capability.
+    TestReport(TestReport),
+    /// Only sent if the streamer has the `CAPABILITY_STATUS_PUSH` capability.
+    Status(StatusPush),
+    /// Only sent if the streamer has the `CAPABILITY_RENAME` capability.
+    Rename(Rename),
+    /// Of a newer peer, see `is_newer_api_version()`. Never sent.
+    #[serde(skip_serializing)]
+    Unknown(IgnoredAny),
 }
 
+deserialize_tagged!(MessageToStreamer {
+    "identify" => Identify,
+    "response" => Response,
+    "testReport" => TestReport,
+    "status" => Status,
+    "rename" => Rename,
+});
+
 pub fn calculate_authentication(password: &str, salt: &str, challenge: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(format!("{}{}", password, salt).as_bytes());
@@ -120,3 +481,96 @@ pub fn calculate_authentication(password: &str, salt: &str, challenge: &str) ->
     let hash2 = hasher.finalize();
     general_purpose::STANDARD.encode(hash2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode<T: DeserializeOwned>(json: &str) -> Result<T, AnyError> {
+        Encoding::Json.decode(json.as_bytes())
+    }
+
+    #[test]
+    fn unknown_message_type_is_unknown() {
+        let message: MessageToRelay = decode(r#"{"newMessage":{"value":1}}"#).unwrap();
+        assert!(matches!(message, MessageToRelay::Unknown(_)));
+        let message: MessageToStreamer = decode(r#"{"newMessage":[1,2]}"#).unwrap();
+        assert!(matches!(message, MessageToStreamer::Unknown(_)));
+    }
+
+    #[test]
+    fn unknown_request_is_unknown() {
+        let message: MessageToRelay =
+            decode(r#"{"request":{"id":3,"data":{"newRequest":{}}}}"#).unwrap();
+        let MessageToRelay::Request(request) = message else {
+            panic!("Expected request");
+        };
+        assert_eq!(request.id, 3);
+        assert!(matches!(request.data, MessageRequestData::Unknown(_)));
+    }
+
+    #[test]
+    fn unknown_result_and_error_code_are_unknown() {
+        let response: MessageResponse =
+            decode(r#"{"id":1,"result":{"newResult":{}},"data":{"stopTunnel":{}}}"#).unwrap();
+        assert!(matches!(response.result, MoblinkResult::Unknown(_)));
+        let response: MessageResponse = decode(
+            r#"{"id":1,"result":{"error":{"code":"newCode","message":"New"}},"data":{"newData":{}}}"#,
+        )
+        .unwrap();
+        let MoblinkResult::Error(error) = response.result else {
+            panic!("Expected error");
+        };
+        assert!(matches!(error.code, ErrorCode::Unknown(_)));
+        assert!(matches!(response.data, ResponseData::Unknown(_)));
+    }
+
+    #[test]
+    fn extra_fields_are_ignored() {
+        let message: MessageToRelay = decode(
+            r#"{"request":{"id":1,"new":true,"data":{"startTunnel":{"address":"a","port":5,"new":1}}}}"#,
+        )
+        .unwrap();
+        let MessageToRelay::Request(MessageRequest {
+            data: MessageRequestData::StartTunnel(start_tunnel),
+            ..
+        }) = message
+        else {
+            panic!("Expected start tunnel request");
+        };
+        assert_eq!(start_tunnel.address, "a");
+        assert_eq!(start_tunnel.port, 5);
+    }
+
+    #[test]
+    fn malformed_known_message_is_rejected() {
+        assert!(
+            decode::<MessageToRelay>(
+                r#"{"request":{"id":1,"data":{"startTunnel":{"address":"a","port":"5"}}}}"#
+            )
+            .is_err()
+        );
+        assert!(decode::<MessageToRelay>(r#"{"hello":{"apiVersion":1}}"#).is_err());
+        assert!(decode::<MessageToRelay>(r#"{"hello":{},"request":{}}"#).is_err());
+        assert!(decode::<MessageToRelay>(r#"{}"#).is_err());
+    }
+
+    #[test]
+    fn cbor_round_trip() {
+        let message = MessageToRelay::Request(MessageRequest {
+            id: 7,
+            data: MessageRequestData::StopTunnel(StopTunnelRequest { port: 9 }),
+        });
+        let Message::Binary(data) = Encoding::Cbor.encode(&message).unwrap() else {
+            panic!("Expected binary");
+        };
+        let message: MessageToRelay = Encoding::Cbor.decode(&data).unwrap();
+        let MessageToRelay::Request(MessageRequest {
+            id: 7,
+            data: MessageRequestData::StopTunnel(StopTunnelRequest { port: 9 }),
+        }) = message
+        else {
+            panic!("Expected stop tunnel request");
+        };
+    }
+}
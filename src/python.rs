@@ -0,0 +1,190 @@
+//! The `moblink_relay` Python module, built with maturin.
+//!
+//! Relay methods return asyncio futures, and the relays run on a Tokio
+//! runtime shared by all of them. The runtime is shut down when the
+//! interpreter exits, as its threads must not use Python objects after that.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use pyo3::exceptions::{PyBaseException, PyException, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::{IntoPyObjectExt, create_exception};
+use tokio::runtime::Runtime;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::relay::{self, Relay};
+
+create_exception!(moblink_relay, RelayError, PyException);
+
+impl From<relay::RelayError> for PyErr {
+    fn from(error: relay::RelayError) -> Self {
+        RelayError::new_err(error.to_string())
+    }
+}
+
+/// Relays still running this long after the interpreter started exiting are
+/// dropped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Created when the module is imported, and taken at exit.
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+/// Registered with atexit, which runs before the interpreter starts shutting
+/// down. Releases the GIL so that running tasks can finish.
+#[pyfunction]
+fn shutdown(py: Python<'_>) {
+    let runtime = RUNTIME.lock().unwrap().take();
+    if let Some(runtime) = runtime {
+        py.allow_threads(|| runtime.shutdown_timeout(SHUTDOWN_TIMEOUT));
+    }
+}
+
+/// Runs given future on the runtime and returns an asyncio future of its
+/// result.
+fn into_asyncio_future<'py, F, T>(py: Python<'py>, future: F) -> PyResult<Bound<'py, PyAny>>
+where
+    F: Future<Output = PyResult<T>> + Send + 'static,
+    T: for<'a> IntoPyObject<'a> + Send + 'static,
+{
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let asyncio_future = event_loop.call_method0("create_future")?;
+    let event_loop = event_loop.unbind();
+    let completed_future = asyncio_future.clone().unbind();
+    let runtime = RUNTIME.lock().unwrap();
+    let runtime = runtime
+        .as_ref()
+        .ok_or_else(|| PyRuntimeError::new_err("Interpreter is exiting"))?;
+    runtime.spawn(async move {
+        let result = future.await;
+        Python::with_gil(|py| {
+            let (value, error) = match result.and_then(|value| value.into_py_any(py)) {
+                Ok(value) => (value, None),
+                Err(error) => (py.None(), Some(error.into_value(py))),
+            };
+            let completed = wrap_pyfunction!(complete, py).and_then(|complete| {
+                event_loop.call_method1(
+                    py,
+                    "call_soon_threadsafe",
+                    (complete, completed_future, value, error),
+                )
+            });
+            if let Err(error) = completed {
+                warn!("Failed to complete future with error: {}", error);
+            }
+        });
+    });
+    Ok(asyncio_future)
+}
+
+/// Called in the event loop. Does nothing if the future was cancelled.
+#[pyfunction]
+fn complete(
+    future: &Bound<'_, PyAny>,
+    value: PyObject,
+    error: Option<Bound<'_, PyBaseException>>,
+) -> PyResult<()> {
+    if future.call_method0("done")?.is_truthy()? {
+        return Ok(());
+    }
+    match error {
+        Some(error) => future.call_method1("set_exception", (error,))?,
+        None => future.call_method1("set_result", (value,))?,
+    };
+    Ok(())
+}
+
+#[pyclass(name = "Relay")]
+struct PyRelay {
+    relay: Relay,
+}
+
+#[pymethods]
+impl PyRelay {
+    #[new]
+    fn new() -> Self {
+        Self {
+            relay: Relay::new(),
+        }
+    }
+
+    /// Sets the streamer to connect to and the identity of the relay. A random
+    /// relay id is used if none is given. `on_status_updated` is called with
+    /// the status text from the relay's threads.
+    #[pyo3(signature = (streamer_url, password, name, relay_id=None, on_status_updated=None))]
+    fn setup<'py>(
+        &self,
+        py: Python<'py>,
+        streamer_url: String,
+        password: String,
+        name: String,
+        relay_id: Option<String>,
+        on_status_updated: Option<PyObject>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let relay_id = match relay_id {
+            Some(relay_id) => relay_id
+                .parse()
+                .map_err(|_| PyValueError::new_err("Invalid relay id"))?,
+            None => Uuid::new_v4(),
+        };
+        let relay = self.relay.clone();
+        into_asyncio_future(py, async move {
+            relay
+                .setup(
+                    streamer_url,
+                    password,
+                    relay_id,
+                    name,
+                    move |status| {
+                        let Some(on_status_updated) = &on_status_updated else {
+                            return;
+                        };
+                        Python::with_gil(|py| {
+                            if let Err(error) = on_status_updated.call1(py, (status,)) {
+                                warn!("Status callback failed with error: {}", error);
+                            }
+                        });
+                    },
+                    None,
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    /// Connects to the streamer, and reconnects until stopped.
+    fn start<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let relay = self.relay.clone();
+        into_asyncio_future(py, async move { Ok(relay.start().await?) })
+    }
+
+    fn stop<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let relay = self.relay.clone();
+        into_asyncio_future(py, async move {
+            relay.stop().await;
+            Ok(())
+        })
+    }
+
+    fn status<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let relay = self.relay.clone();
+        into_asyncio_future(py, async move { Ok(relay.get_status_text().await) })
+    }
+}
+
+#[pymodule]
+fn moblink_relay(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("moblink-relay")
+        .build()?;
+    *RUNTIME.lock().unwrap() = Some(runtime);
+    module
+        .py()
+        .import("atexit")?
+        .call_method1("register", (wrap_pyfunction!(shutdown, module.py())?,))?;
+    module.add_class::<PyRelay>()?;
+    module.add("RelayError", module.py().get_type::<RelayError>())?;
+    Ok(())
+}
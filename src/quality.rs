@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use tokio::time::Instant;
+
+/// The score is computed over this many samples, taken every ping interval.
+const WINDOW_SAMPLES: usize = 12;
+const GOOD_ROUND_TRIP_TIME_MS: f64 = 50.0;
+const BAD_ROUND_TRIP_TIME_MS: f64 = 1000.0;
+/// SRT retransmissions of this share of the data packets or more score 0.
+const BAD_RETRANSMISSION_PERCENTAGE: f64 = 10.0;
+/// Stability is only scored when relaying at least this many bits per second
+/// on average, as idle links are not unstable.
+const MINIMUM_BITRATE: f64 = 100_000.0;
+const ROUND_TRIP_TIME_WEIGHT: f64 = 0.4;
+const RETRANSMISSION_WEIGHT: f64 = 0.4;
+const STABILITY_WEIGHT: f64 = 0.2;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QualitySample {
+    pub(crate) time: Instant,
+    pub(crate) round_trip_time_ms: Option<f64>,
+    pub(crate) bytes_to_destination: u64,
+    /// SRT data and retransmitted packets to the destination, if inspected.
+    pub(crate) srt_packets: Option<(u64, u64)>,
+}
+
+/// See `Relay::get_quality_score()`. Parts without data, for example
+/// retransmissions without SRT inspection, are left out of the weighted
+/// average.
+#[derive(Default)]
+pub(crate) struct QualityScorer {
+    samples: VecDeque<QualitySample>,
+}
+
+impl QualityScorer {
+    /// Adds given sample and returns the new score, if any part could be
+    /// scored.
+    pub(crate) fn add(&mut self, sample: QualitySample) -> Option<u8> {
+        if self.samples.len() == WINDOW_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.score()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    fn score(&self) -> Option<u8> {
+        let parts = [
+            (self.round_trip_time_score(), ROUND_TRIP_TIME_WEIGHT),
+            (self.retransmission_score(), RETRANSMISSION_WEIGHT),
+            (self.stability_score(), STABILITY_WEIGHT),
+        ];
+        let (sum, weights) = parts
+            .iter()
+            .filter_map(|(score, weight)| score.map(|score| (score * weight, *weight)))
+            .fold((0.0, 0.0), |(sum, weights), (score, weight)| {
+                (sum + score, weights + weight)
+            });
+        if weights == 0.0 {
+            None
+        } else {
+            Some((sum / weights).round() as u8)
+        }
+    }
+
+    /// Of the latest sample, as the round trip time is already smoothed.
+    fn round_trip_time_score(&self) -> Option<f64> {
+        let round_trip_time_ms = self.samples.back()?.round_trip_time_ms?;
+        Some(linear_score(
+            round_trip_time_ms,
+            GOOD_ROUND_TRIP_TIME_MS,
+            BAD_ROUND_TRIP_TIME_MS,
+        ))
+    }
+
+    fn retransmission_score(&self) -> Option<f64> {
+        let (first_data, first_retransmitted) = self.samples.front()?.srt_packets?;
+        let (last_data, last_retransmitted) = self.samples.back()?.srt_packets?;
+        let data = last_data.saturating_sub(first_data);
+        if data == 0 {
+            return None;
+        }
+        let retransmitted = last_retransmitted.saturating_sub(first_retransmitted);
+        let retransmission_percentage = 100.0 * retransmitted as f64 / data as f64;
+        Some(linear_score(
+            retransmission_percentage,
+            0.0,
+            BAD_RETRANSMISSION_PERCENTAGE,
+        ))
+    }
+
+    /// From the coefficient of variation of the bitrates between samples.
+    fn stability_score(&self) -> Option<f64> {
+        let bitrates: Vec<f64> = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .filter_map(|(previous, sample)| {
+                let elapsed = sample.time.duration_since(previous.time).as_secs_f64();
+                let bytes = sample
+                    .bytes_to_destination
+                    .saturating_sub(previous.bytes_to_destination);
+                (elapsed > 0.0).then(|| 8.0 * bytes as f64 / elapsed)
+            })
+            .collect();
+        if bitrates.len() < 2 {
+            return None;
+        }
+        let mean = bitrates.iter().sum::<f64>() / bitrates.len() as f64;
+        if mean < MINIMUM_BITRATE {
+            return None;
+        }
+        let variance = bitrates
+            .iter()
+            .map(|bitrate| (bitrate - mean).powi(2))
+            .sum::<f64>()
+            / bitrates.len() as f64;
+        Some(100.0 * (1.0 - (variance.sqrt() / mean).min(1.0)))
+    }
+}
+
+/// 100 at or below `good`, 0 at or above `bad`, and linear in between.
+fn linear_score(value: f64, good: f64, bad: f64) -> f64 {
+    100.0 * (1.0 - ((value - good) / (bad - good)).clamp(0.0, 1.0))
+}
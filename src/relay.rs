@@ -1,52 +1,691 @@
+use std::collections::VecDeque;
+use std::fmt;
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use async_trait::async_trait;
+use bytes::Bytes;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use log::{debug, error, info};
-use serde::Deserialize;
+use gethostname::gethostname;
+use ipnetwork::Ipv4Network;
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::process::Command;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep, timeout};
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, sleep, timeout};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
 use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, client_async, connect_async};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
 use uuid::Uuid;
 
+use crate::buffer_pool::{BufferPool, DATAGRAM_BUFFER_SIZE};
+use crate::capture::Capture;
+use crate::chain::{WebSocket, start_nested_tunnel};
+use crate::data_usage::{DataQuota, DataUsage, InterfaceDataUsage};
+use crate::default_interface::get_default_interface_ipv4_addresses;
+use crate::deflate::Deflate;
+use crate::dns::DnsResolver;
+use crate::fec::Fec;
+use crate::happy_eyeballs::{race_destination_sockets, sort_addresses};
+use crate::interfaces::{InterfacePolicy, list_interfaces};
+use crate::metrics::{self, MetricsSink};
+use crate::modem_manager::{ModemStatus, get_modems};
+use crate::mtu::monitor_path_mtu;
+use crate::multipath::{MultipathPath, Scheduler, recv_from_any};
+use crate::network_monitor::wait_for_network_up;
+use crate::obfuscation::Obfuscation;
+use crate::pacing::{Pacer, Pacing};
+use crate::preflight::probe_destination;
 use crate::protocol::*;
-use crate::utils::{AnyError, resolve_host};
+pub use crate::protocol::{Encoding, RelayPriority};
+use crate::proxy::Proxy;
+use crate::quality::{QualitySample, QualityScorer};
+use crate::secret::SecretString;
+use crate::srt::{Direction, SrtInspector};
+use crate::srtla;
+use crate::stats::{Counters, RelayStats};
+use crate::stun::{NatInfo, NatType, discover_nat};
+use crate::tcp_tunnel::relay_tcp_connections;
+use crate::test_mode::ProbeStats;
+use crate::thermal::{ThermalConfig, ThermalZone, get_thermal_zones, hottest};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::UringForwarder;
+use crate::utils::{AbortOnDrop, AnyError, now_us, resolve_host_addresses};
 
-#[derive(Default, Deserialize, Clone)]
+#[derive(Default, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Status {
     pub battery_percentage: Option<i32>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TunnelProtocol {
+    #[default]
+    Udp,
+    /// Each connection from the streamer is forwarded over its own connection
+    /// to the destination, for example for RTMP.
+    Tcp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelInfo {
+    /// Unique within the relay.
+    pub id: u64,
+    #[serde(default)]
+    pub protocol: TunnelProtocol,
+    pub streamer_port: u16,
+    pub destination_address: SocketAddr,
+    /// Freeform, for example "camera 2". Set with
+    /// `Relay::set_tunnel_annotation()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<String>,
+}
+
+/// A tunnel and its sockets, moved between relay processes during an upgrade.
+pub struct TunnelSockets {
+    pub info: TunnelInfo,
+    pub streamer_socket: Arc<UdpSocket>,
+    /// Other sockets bound to the port of `streamer_socket` with
+    /// SO_REUSEPORT, each forwarded by its own worker task. Empty otherwise.
+    pub streamer_worker_sockets: Vec<Arc<UdpSocket>>,
+    pub destination_socket: Arc<UdpSocket>,
+    /// All sockets to the destination with their weights in multipath mode,
+    /// starting with `destination_socket`. Empty otherwise.
+    pub multipath_sockets: Vec<(Arc<UdpSocket>, u32)>,
+    /// Other addresses of the destination, raced against
+    /// `info.destination_address` until the destination answers on one of
+    /// them. Empty otherwise.
+    pub other_destination_addresses: Vec<SocketAddr>,
+}
+
+/// Everything a new relay process needs to take over from an old one.
+pub struct Handover {
+    pub relay_id: Uuid,
+    pub name: String,
+    pub tunnel: Option<TunnelSockets>,
+}
+
+/// A tunnel between a fixed listen address and a fixed destination, without a
+/// streamer. Parsed from `0.0.0.0:5000->example.com:5000`, or
+/// `tcp:0.0.0.0:1935->example.com:1935` for TCP. IPv6 addresses are given in
+/// brackets.
+#[derive(Clone, Debug)]
+pub struct StaticTunnel {
+    pub protocol: TunnelProtocol,
+    pub listen_address: SocketAddr,
+    pub destination_host: String,
+    pub destination_port: u16,
+}
+
+impl FromStr for StaticTunnel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (protocol, value) = match value.trim().strip_prefix("tcp:") {
+            Some(value) => (TunnelProtocol::Tcp, value),
+            None => (TunnelProtocol::Udp, value),
+        };
+        let (listen_address, destination) = value
+            .split_once("->")
+            .ok_or("Expected <listen address>-><destination host>:<port>")?;
+        let listen_address = listen_address
+            .trim()
+            .parse()
+            .map_err(|error| format!("Invalid listen address: {}", error))?;
+        let (destination_host, destination_port) = destination
+            .trim()
+            .rsplit_once(':')
+            .ok_or("No port in destination")?;
+        // IPv6 addresses are given in brackets, as in `[::1]:5000`.
+        let destination_host = destination_host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(destination_host);
+        if destination_host.is_empty() {
+            return Err("No host in destination".to_string());
+        }
+        Ok(Self {
+            protocol,
+            listen_address,
+            destination_host: destination_host.to_string(),
+            destination_port: destination_port
+                .parse()
+                .map_err(|error| format!("Invalid destination port: {}", error))?,
+        })
+    }
+}
+
+/// How to pick the bind address when the automatically selected network
+/// interface has several IPv4 addresses.
+#[derive(Clone, Debug, Default)]
+pub enum AddressSelection {
+    /// The first address.
+    #[default]
+    First,
+    /// The first address in given network, or the first address if none
+    /// matches.
+    PreferNetwork(Ipv4Network),
+    /// The address at given index, or the first address if out of range.
+    Index(usize),
+}
+
+impl AddressSelection {
+    fn select(&self, addresses: &[Ipv4Addr]) -> Option<Ipv4Addr> {
+        let address = match self {
+            AddressSelection::First => None,
+            AddressSelection::PreferNetwork(network) => addresses
+                .iter()
+                .find(|address| network.contains(**address))
+                .copied(),
+            AddressSelection::Index(index) => addresses.get(*index).copied(),
+        };
+        address.or(addresses.first().copied())
+    }
+}
+
+/// Ports to bind the streamer facing sockets of tunnels to, so that they can be
+/// opened in firewalls. Parsed from `20000` or `20000-20010`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|error| format!("Invalid port '{}': {}", port, error))
+        };
+        let (first, last) = match value.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(value)?, parse(value)?),
+        };
+        if first == 0 || first > last {
+            return Err(format!("Invalid port range '{}'", value));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(formatter, "{}", self.first)
+        } else {
+            write!(formatter, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// The bind address the relay selects when none is given.
+pub fn default_bind_address(
+    interface_policy: &InterfacePolicy,
+    address_selection: &AddressSelection,
+) -> Result<String, RelayError> {
+    RelayInner::get_default_bind_address(interface_policy, address_selection)
+}
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Reconnecting is attempted this long after a network change instead.
+const NETWORK_SETTLE_TIME: Duration = Duration::from_secs(1);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_DESTINATION_TIMEOUT: Duration = Duration::from_secs(30);
+const MODEM_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const THERMAL_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// No longer overheating when this much below the warning temperature.
+const THERMAL_HYSTERESIS_CELSIUS: f64 = 5.0;
+/// Data usage is accounted and saved this often.
+const DATA_USAGE_INTERVAL: Duration = Duration::from_secs(30);
+/// Per-datagram metrics are published to the metrics sink this often.
+const METRICS_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// Shorter status executable intervals are raised to this, so that the
+/// executable is never run in a tight loop.
+const MINIMUM_STATUS_INTERVAL: Duration = Duration::from_millis(100);
+/// Forwarding tasks not stopped this long after being cancelled are aborted.
+const TASK_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+/// Binding an ephemeral even port with a free next port.
+const MAXIMUM_NUMBER_OF_PAIRED_PORTS_ATTEMPTS: usize = 16;
+
+/// Consecutive connect failures before failing over to the next streamer URL.
+const MAXIMUM_NUMBER_OF_CONNECT_FAILURES: u32 = 3;
+
+/// Number of reconnects kept in the history.
+const MAXIMUM_NUMBER_OF_RECONNECTS: usize = 50;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconnect {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub reason: String,
+}
+
+/// Where a relay is in its connection to the streamer. Serialized with a
+/// `phase` field, for example `{"phase":"reconnecting","attempt":2,...}`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(
+    tag = "phase",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ConnectionState {
+    /// Not started, or stopped.
+    Idle,
+    Connecting,
+    /// Connected to the streamer, but not yet identified.
+    Identifying,
+    Connected,
+    /// Waiting to connect again after failing to connect or losing the
+    /// connection.
+    Reconnecting {
+        /// Reconnects since last connected, starting at 1.
+        attempt: u32,
+        /// Milliseconds since the Unix epoch. Earlier if the network changes.
+        next_at: u64,
+    },
+    /// The streamer rejected the password. Not reconnecting until the
+    /// settings are updated.
+    WrongPassword,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Idle => write!(formatter, "idle"),
+            ConnectionState::Connecting => write!(formatter, "connecting"),
+            ConnectionState::Identifying => write!(formatter, "identifying"),
+            ConnectionState::Connected => write!(formatter, "connected"),
+            ConnectionState::Reconnecting { attempt, .. } => {
+                write!(formatter, "reconnecting (attempt {})", attempt)
+            }
+            ConnectionState::WrongPassword => write!(formatter, "wrong password"),
+        }
+    }
+}
+
+/// A snapshot of the relay, for example to show in a user interface.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayState {
+    #[serde(flatten)]
+    pub connection: ConnectionState,
+    pub relay_id: Uuid,
+    pub name: String,
+    /// The active streamer URL.
+    pub streamer_url: String,
+    pub paused: bool,
+    pub duplicating: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pacing: Option<Pacing>,
+    /// As given to the status updated callback.
+    pub status: String,
+    pub tunnel: Option<TunnelInfo>,
+    /// Why the relay last disconnected or failed to connect. Kept after
+    /// reconnecting.
+    pub last_error: Option<String>,
+    /// See `Relay::get_quality_score()`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality_score: Option<u8>,
+}
+
+/// Events emitted by a relay. Serialized as JSON with an `event` field.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum RelayEvent {
+    /// The relay is configured and is about to connect to the streamer.
+    #[serde(rename_all = "camelCase")]
+    Ready {
+        bind_address: String,
+        relay_id: Uuid,
+        name: String,
+        streamer_url: String,
+    },
+    /// Connected and identified to given streamer.
+    #[serde(rename_all = "camelCase")]
+    StreamerConnected {
+        streamer_url: String,
+    },
+    /// Repeatedly failed to connect to a streamer. Trying the next one.
+    FailedOver {
+        from: String,
+        to: String,
+    },
+    /// Includes the port the streamer sends to and the resolved destination
+    /// address.
+    TunnelStarted {
+        tunnel: TunnelInfo,
+    },
+    TunnelStopped {
+        tunnel: TunnelInfo,
+        reason: TunnelStopReason,
+    },
+    TunnelAnnotated {
+        tunnel: TunnelInfo,
+    },
+    /// Nothing received from the destination within the destination timeout,
+    /// typically because the stream ended. The tunnel is kept.
+    TunnelIdle {
+        tunnel: TunnelInfo,
+    },
+    /// Received from the destination again after being idle.
+    TunnelActive {
+        tunnel: TunnelInfo,
+    },
+    /// NAT discovery with STUN finished. Done when connected to the streamer.
+    NatDiscovered {
+        nat: NatInfo,
+    },
+    /// The connection state changed.
+    ConnectionStateChanged {
+        state: ConnectionState,
+    },
+    /// The hottest thermal zone reached the warning temperature of the
+    /// thermal monitoring. Pacing is throttled, if configured.
+    Overheating {
+        zone: ThermalZone,
+    },
+    /// Cooled down to 5 °C below the warning temperature after overheating.
+    CooledDown {
+        zone: ThermalZone,
+    },
+    /// The warning percentage of the data quota is used on the interface
+    /// this month.
+    DataQuotaWarning {
+        usage: InterfaceDataUsage,
+    },
+    /// The data quota is used up on the interface this month. Relaying is
+    /// paused until the next month, if configured.
+    DataQuotaExceeded {
+        usage: InterfaceDataUsage,
+    },
+    /// The streamer rejected the password. The relay does not reconnect
+    /// until the streamer URL or password is changed with
+    /// `Relay::update_settings()`, or it is stopped and started again.
+    #[serde(rename_all = "camelCase")]
+    AuthenticationFailed {
+        streamer_url: String,
+    },
+}
+
+pub type OnEventClosure = Box<dyn Fn(RelayEvent) + Send + Sync>;
+
+/// Why a tunnel stopped.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TunnelStopReason {
+    /// The streamer requested a new tunnel.
+    Replaced,
+    /// Disconnected from the streamer, also when the relay is stopped or
+    /// reconnects.
+    Disconnected,
+    Paused,
+    /// Forwarding failed and the relay reconnects.
+    Failed,
+    /// The streamer requested it.
+    Requested,
+}
+
+impl fmt::Display for TunnelStopReason {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelStopReason::Replaced => write!(formatter, "replaced"),
+            TunnelStopReason::Disconnected => write!(formatter, "disconnected"),
+            TunnelStopReason::Paused => write!(formatter, "paused"),
+            TunnelStopReason::Failed => write!(formatter, "failed"),
+            TunnelStopReason::Requested => write!(formatter, "requested"),
+        }
+    }
+}
+
+/// A problem that retrying will not fix.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayError {
+    InvalidStreamerUrl {
+        url: String,
+        reason: String,
+    },
+    InvalidBindAddress {
+        address: String,
+    },
+    /// No network interface to select the bind address from.
+    NoNetworkInterface,
+    EmptyName,
+    InvalidPacing,
+    /// The streamer rejected the password.
+    WrongPassword,
+    /// Stopped while waiting for the relay to connect.
+    Stopped,
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::InvalidStreamerUrl { url, reason } => {
+                write!(formatter, "Invalid streamer URL '{}': {}", url, reason)
+            }
+            RelayError::InvalidBindAddress { address } => {
+                write!(formatter, "Invalid bind address '{}'", address)
+            }
+            RelayError::NoNetworkInterface => write!(formatter, "No network interface found"),
+            RelayError::EmptyName => write!(formatter, "Empty relay name"),
+            RelayError::InvalidPacing => write!(formatter, "Pacing must be greater than zero"),
+            RelayError::WrongPassword => write!(formatter, "Wrong password"),
+            RelayError::Stopped => write!(formatter, "Stopped"),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+/// Provides the status reported to the streamer, for example the battery
+/// level.
+#[async_trait]
+pub trait StatusProvider: Send + Sync {
+    async fn status(&self) -> Status;
+}
+
 pub type GetStatusClosure =
-    Box<dyn Fn() -> Pin<Box<dyn Future<Output = Status> + Send + Sync>> + Send + Sync>;
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = Status> + Send>> + Send + Sync>;
+
+#[async_trait]
+impl StatusProvider for GetStatusClosure {
+    async fn status(&self) -> Status {
+        self().await
+    }
+}
 
 struct RelayInner {
     me: Weak<Mutex<Self>>,
     /// Store a local IP address  for binding UDP sockets
     bind_address: String,
+    /// Selects the bind address when the relay is started, if not set
+    /// explicitly.
+    address_selection: Option<AddressSelection>,
+    /// Selects the network interface to bind to, if the bind address is not
+    /// set explicitly.
+    interface_policy: InterfacePolicy,
+    /// Resolves the streamer URL and the tunnel destinations.
+    dns_resolver: DnsResolver,
     relay_id: Uuid,
+    /// The active streamer URL.
     streamer_url: String,
-    password: String,
+    /// The primary streamer URL followed by backups.
+    streamer_urls: Vec<String>,
+    streamer_url_index: usize,
+    connect_failures: u32,
+    password: SecretString,
     name: String,
     on_status_updated: Option<Box<dyn Fn(String) + Send + Sync>>,
-    get_status: Option<Arc<GetStatusClosure>>,
+    on_event: Option<OnEventClosure>,
+    status_provider: Option<Arc<dyn StatusProvider>>,
+    /// Used instead of the status provider, if any.
+    status_cache: Option<StatusCache>,
     ws_writer: Option<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
-    started: bool,
-    connected: bool,
-    wrong_password: bool,
+    /// Changed when the WebSocket connection is closed, so that its receiver
+    /// does not act on the next connection.
+    connection_id: u64,
+    state: ConnectionState,
+    /// Always the current state, for waiters.
+    state_sender: watch::Sender<ConnectionState>,
+    /// Reconnects since last connected.
+    reconnect_attempt: u32,
+    /// Why the relay last disconnected or failed to connect.
+    last_error: Option<String>,
+    /// Connected, but not relaying.
+    paused: bool,
+    /// A start tunnel request received while paused, from a streamer that
+    /// does not understand `MoblinkResult::Busy`. Handled when resumed.
+    deferred_request: Option<MessageRequest>,
     reconnect_on_tunnel_error: Arc<Mutex<bool>>,
-    start_on_reconnect_soon: Arc<Mutex<bool>>,
+    /// Cancelled when disconnected, stopping the WebSocket receiver and, as
+    /// its parent, the tunnel.
+    connection_token: CancellationToken,
+    /// Cancelled when stopped, so that a pending reconnect is not made.
+    reconnect_token: CancellationToken,
+    /// Cancelled when the tunnel stops, stopping its forwarding tasks.
+    tunnel_token: CancellationToken,
     relay_to_destination: Option<tokio::task::JoinHandle<Result<(), AnyError>>>,
+    tunnel: Option<TunnelInfo>,
+    /// Streamer and destination sockets.
+    tunnel_sockets: Option<(Arc<UdpSocket>, Arc<UdpSocket>)>,
+    /// Send to the destination from several local interfaces. Disabled if
+    /// empty.
+    multipath: Vec<MultipathPath>,
+    /// FEC group sizes on the legs to the destination and the streamer, if
+    /// enabled. The other end must be a Moblink relay with FEC enabled.
+    destination_fec: Option<u8>,
+    streamer_fec: Option<u8>,
+    /// Send every datagram to the destination on all paths. Can be changed
+    /// while a tunnel is running.
+    duplication: Arc<AtomicBool>,
+    /// Pace datagrams to the destination, if any. Can be changed while a
+    /// tunnel is running.
+    pacing: Arc<RwLock<Option<Pacing>>>,
+    /// As given to `set_pacing()`. `pacing` is throttled while overheating.
+    requested_pacing: Option<Pacing>,
+    /// Streamer sockets per UDP tunnel, each forwarded by its own task.
+    workers: usize,
+    /// Forward tunnel datagrams with io_uring when possible.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    io_uring: bool,
+    /// Relayed UDP datagrams are written to this capture, if any.
+    capture: Option<Arc<Capture>>,
+    tunnel_span: Span,
+    next_tunnel_id: u64,
+    counters: Arc<Counters>,
+    /// A tunnel is idle if nothing is received from the destination for this
+    /// long. Never if `None`.
+    destination_timeout: Option<Duration>,
+    /// Send keepalives to the streamer if nothing else is sent to it for this
+    /// long. Never if `None`.
+    nat_keepalive_interval: Option<Duration>,
+    /// Ports for the streamer facing sockets of tunnels. Any free port if
+    /// `None`.
+    tunnel_ports: Option<PortRange>,
+    /// Address for the streamer facing sockets of tunnels.
+    streamer_bind_address: IpAddr,
+    tunnel_idle: bool,
+    /// Send probes to the destination and report latency and loss to the
+    /// streamer when the tunnel is stopped.
+    test_mode: bool,
+    probe_stats: Option<Arc<Mutex<ProbeStats>>>,
+    /// Probe the destination before accepting a start tunnel request.
+    preflight_check: bool,
+    /// Used for the WebSocket connection to the streamer, if any.
+    proxy: Option<Proxy>,
+    /// Extra headers in the WebSocket handshake with the streamer.
+    headers: Vec<(String, String)>,
+    /// URL and password of the relay to forward tunnels through, if any.
+    upstream_relay: Option<(String, SecretString)>,
+    /// Keeps the nested tunnel in the upstream relay alive.
+    upstream_connection: Option<tokio::task::JoinHandle<()>>,
+    /// Pre-shared key to obfuscate nested tunnels with, if the upstream
+    /// relay supports it.
+    obfuscation_key: Option<SecretString>,
+    /// Of the leg to the upstream relay in the current tunnel.
+    destination_obfuscation: Option<Obfuscation>,
+    /// The destination is an SRTLA receiver.
+    srtla: bool,
+    /// Probe the path MTU to the destination, instead of only using what
+    /// the kernel knows.
+    path_mtu_probing: bool,
+    /// `host:port`. NAT discovery is disabled if empty.
+    stun_servers: Vec<String>,
+    nat: Option<NatInfo>,
+    /// Polls ModemManager, if enabled.
+    modem_monitor: Option<AbortOnDrop>,
+    modems: Vec<ModemStatus>,
+    /// Polls the thermal zones, if enabled.
+    thermal_monitor: Option<AbortOnDrop>,
+    thermal_zones: Vec<ThermalZone>,
+    thermal: Option<ThermalConfig>,
+    /// Reached the warning temperature and has not cooled down since.
+    overheating: bool,
+    /// Accounts relayed bytes to the interface of the bind address, if
+    /// enabled.
+    data_usage: Option<Arc<DataUsage>>,
+    data_usage_monitor: Option<AbortOnDrop>,
+    /// Relayed bytes already accounted.
+    data_usage_bytes: u64,
+    /// The interface of the latest accounting.
+    data_usage_interface: Option<String>,
+    /// The month the quota events below were emitted in.
+    data_quota_month: String,
+    data_quota_warned: bool,
+    data_quota_exceeded: bool,
+    /// Paused as the data quota was exceeded, and resumed next month.
+    paused_by_data_quota: bool,
+    /// Estimates loss and jitter from SRT headers, if enabled.
+    srt_inspector: Option<Arc<SrtInspector>>,
+    prober: Option<tokio::task::JoinHandle<()>>,
+    reconnects: VecDeque<Reconnect>,
+    pinger: Option<tokio::task::JoinHandle<()>>,
+    nat_discovery: Option<tokio::task::JoinHandle<()>>,
+    /// Push status to the streamer this often, if it supports it.
+    status_push_interval: Option<Duration>,
+    streamer_capabilities: Vec<String>,
+    /// Used if the streamer supports it.
+    preferred_encoding: Encoding,
+    /// Of the current connection to the streamer.
+    encoding: Encoding,
+    /// Compress control messages if the streamer supports it.
+    compression: bool,
+    /// Announced to the streamer if it supports it.
+    priority: RelayPriority,
+    group: Option<String>,
+    /// Of the current connection to the streamer, if compressed.
+    deflate: Option<Deflate>,
+    status_pusher: Option<tokio::task::JoinHandle<()>>,
+    metrics_publisher: Option<tokio::task::JoinHandle<()>>,
+    /// Smoothed as TCP's SRTT.
+    round_trip_time: Option<Duration>,
+    quality_scorer: QualityScorer,
+    /// Sampled every ping interval while connected.
+    quality_score: Option<u8>,
+    /// The round trip time in the latest status update.
+    reported_round_trip_time: Option<Duration>,
+    /// Tasks are spawned on this runtime, or on the caller's if none.
+    runtime: Option<Handle>,
 }
 
 impl RelayInner {
@@ -54,532 +693,3782 @@ impl RelayInner {
         Arc::new_cyclic(|me| {
             Mutex::new(Self {
                 me: me.clone(),
-                bind_address: Self::get_default_bind_address(),
+                bind_address: "".to_string(),
+                address_selection: Some(AddressSelection::First),
+                interface_policy: InterfacePolicy::First,
+                dns_resolver: DnsResolver::system(),
                 relay_id: Uuid::new_v4(),
                 streamer_url: "".to_string(),
-                password: "".to_string(),
+                streamer_urls: vec!["".to_string()],
+                streamer_url_index: 0,
+                connect_failures: 0,
+                password: SecretString::default(),
                 name: "".to_string(),
                 on_status_updated: None,
-                get_status: None,
+                on_event: None,
+                status_provider: None,
+                status_cache: None,
                 ws_writer: None,
-                started: false,
-                connected: false,
-                wrong_password: false,
+                connection_id: 0,
+                state: ConnectionState::Idle,
+                state_sender: watch::Sender::new(ConnectionState::Idle),
+                reconnect_attempt: 0,
+                last_error: None,
+                paused: false,
+                deferred_request: None,
                 reconnect_on_tunnel_error: Arc::new(Mutex::new(false)),
-                start_on_reconnect_soon: Arc::new(Mutex::new(false)),
+                connection_token: CancellationToken::new(),
+                reconnect_token: CancellationToken::new(),
+                tunnel_token: CancellationToken::new(),
                 relay_to_destination: None,
+                tunnel: None,
+                tunnel_sockets: None,
+                multipath: Vec::new(),
+                destination_fec: None,
+                streamer_fec: None,
+                duplication: Arc::new(AtomicBool::new(false)),
+                pacing: Arc::new(RwLock::new(None)),
+                requested_pacing: None,
+                workers: 1,
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                io_uring: false,
+                capture: None,
+                tunnel_span: Span::none(),
+                next_tunnel_id: 0,
+                counters: Default::default(),
+                destination_timeout: Some(DEFAULT_DESTINATION_TIMEOUT),
+                nat_keepalive_interval: None,
+                tunnel_ports: None,
+                streamer_bind_address: Ipv4Addr::UNSPECIFIED.into(),
+                tunnel_idle: false,
+                test_mode: false,
+                probe_stats: None,
+                preflight_check: false,
+                proxy: None,
+                headers: Vec::new(),
+                upstream_relay: None,
+                upstream_connection: None,
+                obfuscation_key: None,
+                destination_obfuscation: None,
+                srtla: false,
+                path_mtu_probing: false,
+                stun_servers: Vec::new(),
+                nat: None,
+                modem_monitor: None,
+                modems: Vec::new(),
+                thermal_monitor: None,
+                thermal_zones: Vec::new(),
+                thermal: None,
+                overheating: false,
+                data_usage: None,
+                data_usage_monitor: None,
+                data_usage_bytes: 0,
+                data_usage_interface: None,
+                data_quota_month: String::new(),
+                data_quota_warned: false,
+                data_quota_exceeded: false,
+                paused_by_data_quota: false,
+                srt_inspector: None,
+                prober: None,
+                reconnects: VecDeque::new(),
+                pinger: None,
+                nat_discovery: None,
+                status_push_interval: None,
+                streamer_capabilities: Vec::new(),
+                preferred_encoding: Encoding::Json,
+                encoding: Encoding::Json,
+                compression: false,
+                priority: RelayPriority::Main,
+                group: None,
+                deflate: None,
+                status_pusher: None,
+                metrics_publisher: None,
+                round_trip_time: None,
+                quality_scorer: QualityScorer::default(),
+                quality_score: None,
+                reported_round_trip_time: None,
+                runtime: None,
             })
         })
     }
 
     fn set_bind_address(&mut self, address: String) {
         self.bind_address = address;
+        self.address_selection = None;
     }
 
-    async fn setup<F>(
-        &mut self,
-        streamer_url: String,
-        password: String,
-        relay_id: Uuid,
-        name: String,
-        on_status_updated: F,
-        get_status: Option<GetStatusClosure>,
-    ) where
-        F: Fn(String) + Send + Sync + 'static,
-    {
-        self.on_status_updated = Some(Box::new(on_status_updated));
-        self.get_status = get_status.map(Arc::new);
-        self.relay_id = relay_id;
-        self.streamer_url = streamer_url;
-        self.password = password;
-        self.name = name;
+    fn set_address_selection(&mut self, address_selection: AddressSelection) {
+        self.address_selection = Some(address_selection);
     }
 
-    fn is_started(&self) -> bool {
-        self.started
+    fn set_interface_policy(&mut self, interface_policy: InterfacePolicy) {
+        self.interface_policy = interface_policy;
     }
 
-    async fn start(&mut self) {
-        if !self.started {
-            self.started = true;
-            self.start_internal().await;
-        }
+    fn set_dns_resolver(&mut self, dns_resolver: DnsResolver) {
+        self.dns_resolver = dns_resolver;
     }
 
-    async fn stop(&mut self) {
-        if self.started {
-            self.started = false;
-            self.stop_internal().await;
+    fn set_runtime(&mut self, runtime: Handle) {
+        self.runtime = Some(runtime);
+    }
+
+    fn spawn<F>(&self, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.runtime {
+            Some(runtime) => runtime.spawn(future),
+            None => tokio::spawn(future),
         }
     }
 
-    fn get_default_bind_address() -> String {
-        // Get main network interface
-        let interfaces = pnet::datalink::interfaces();
-        let interface = interfaces.iter().find(|interface| {
-            interface.is_up() && !interface.is_loopback() && !interface.ips.is_empty()
-        });
+    fn set_test_mode(&mut self, enabled: bool) {
+        self.test_mode = enabled;
+    }
 
-        // Only ipv4 addresses are supported
-        let ipv4_addresses: Vec<String> = interface
-            .expect("No available network interfaces found")
-            .ips
-            .iter()
-            .filter_map(|ip| {
-                let ip = ip.ip();
-                ip.is_ipv4().then(|| ip.to_string())
-            })
-            .collect();
+    fn set_preflight_check(&mut self, enabled: bool) {
+        self.preflight_check = enabled;
+    }
 
-        // Return the first address
-        ipv4_addresses
-            .first()
-            .cloned()
-            .unwrap_or("0.0.0.0:0".to_string())
+    fn set_destination_timeout(&mut self, destination_timeout: Option<Duration>) {
+        self.destination_timeout = destination_timeout;
     }
 
-    async fn start_internal(&mut self) {
-        if !self.started {
-            self.stop_internal().await;
-            return;
-        }
+    fn set_nat_keepalive_interval(&mut self, nat_keepalive_interval: Option<Duration>) {
+        self.nat_keepalive_interval = nat_keepalive_interval;
+    }
 
-        let request = match url::Url::parse(&self.streamer_url) {
-            Ok(url) => url,
-            Err(e) => {
-                error!("Failed to parse URL: {}", e);
-                return;
-            }
-        };
+    fn set_tunnel_ports(&mut self, tunnel_ports: Option<PortRange>) {
+        self.tunnel_ports = tunnel_ports;
+    }
 
-        match timeout(Duration::from_secs(10), connect_async(request.to_string())).await {
-            Ok(Ok((ws_stream, _))) => {
-                debug!("Connected to {}", self.streamer_url);
-                let (writer, reader) = ws_stream.split();
-                self.ws_writer = Some(writer);
-                self.start_websocket_receiver(reader);
-            }
-            Ok(Err(error)) => {
-                debug!(
-                    "Failed to connect to {} with error: {}",
-                    self.streamer_url, error
-                );
-                self.reconnect_soon().await;
-            }
-            Err(_elapsed) => {
-                debug!(
-                    "Failed to connect to {} within 10 seconds",
-                    self.streamer_url
-                );
-                self.reconnect_soon().await;
-            }
-        }
+    fn set_streamer_bind_address(&mut self, streamer_bind_address: IpAddr) {
+        self.streamer_bind_address = streamer_bind_address;
     }
 
-    fn start_websocket_receiver(
-        &mut self,
-        mut reader: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    ) {
-        // Task to process messages received from the channel.
-        let relay = self.me.clone();
+    fn set_status_push_interval(&mut self, status_push_interval: Option<Duration>) {
+        self.status_push_interval = status_push_interval;
+    }
 
-        tokio::spawn(async move {
-            let Some(relay_arc) = relay.upgrade() else {
-                return;
-            };
+    fn set_preferred_encoding(&mut self, encoding: Encoding) {
+        self.preferred_encoding = encoding;
+    }
 
-            while let Some(result) = reader.next().await {
-                let mut relay = relay_arc.lock().await;
-                match result {
-                    Ok(message) => match message {
-                        Message::Text(text) => {
-                            match serde_json::from_str::<MessageToRelay>(&text) {
-                                Ok(message) => {
-                                    if let Err(error) = relay.handle_message(message).await {
-                                        error!("Message handling failed with error: {}", error);
-                                        relay.reconnect_soon().await;
-                                        break;
-                                    }
-                                }
-                                _ => {
-                                    error!("Failed to deserialize message: {}", text);
-                                }
-                            }
-                        }
-                        Message::Binary(data) => {
-                            debug!("Received binary message of length: {}", data.len());
-                        }
-                        Message::Ping(data) => {
-                            relay.send_message(Message::Pong(data)).await.ok();
-                        }
-                        Message::Pong(_) => {
-                            debug!("Received pong message");
-                        }
-                        Message::Close(frame) => {
-                            info!("Received close message: {:?}", frame);
-                            relay.reconnect_soon().await;
-                            break;
-                        }
-                        Message::Frame(_) => {
-                            unreachable!("This is never used")
-                        }
-                    },
-                    Err(e) => {
-                        debug!("Error processing message: {}", e);
-                        // TODO: There has to be a better way to handle this
-                        if e.to_string()
-                            .contains("Connection reset without closing handshake")
-                        {
-                            relay.reconnect_soon().await;
-                        }
-                        break;
-                    }
-                }
-            }
-        });
+    fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
     }
 
-    async fn stop_internal(&mut self) {
-        if let Some(mut ws_writer) = self.ws_writer.take() {
-            match ws_writer.close().await {
-                Err(e) => {
-                    error!("Error closing WebSocket: {}", e);
-                }
-                _ => {
-                    debug!("WebSocket closed successfully");
-                }
-            }
-        }
-        self.connected = false;
-        self.wrong_password = false;
-        *self.reconnect_on_tunnel_error.lock().await = false;
-        *self.start_on_reconnect_soon.lock().await = false;
-        if let Some(relay_to_destination) = self.relay_to_destination.take() {
-            relay_to_destination.abort();
-            relay_to_destination.await.ok();
-        }
-        self.update_status();
+    fn set_priority(&mut self, priority: RelayPriority, group: Option<String>) {
+        self.priority = priority;
+        self.group = group;
     }
 
-    fn update_status(&self) {
-        let Some(on_status_updated) = &self.on_status_updated else {
-            return;
-        };
-        let status = if self.connected {
-            "Connected to streamer"
-        } else if self.wrong_password {
-            "Wrong password"
-        } else if self.started {
-            "Connecting to streamer"
-        } else {
-            "Disconnected from streamer"
-        };
-        on_status_updated(status.to_string());
+    fn set_multipath(&mut self, multipath: Vec<MultipathPath>) {
+        self.multipath = multipath;
     }
 
-    async fn reconnect_soon(&mut self) {
-        self.stop_internal().await;
-        *self.start_on_reconnect_soon.lock().await = false;
-        let start_on_reconnect_soon = Arc::new(Mutex::new(true));
-        self.start_on_reconnect_soon = start_on_reconnect_soon.clone();
-        self.start_soon(start_on_reconnect_soon);
+    fn set_fec(&mut self, destination_fec: Option<u8>, streamer_fec: Option<u8>) {
+        self.destination_fec = destination_fec;
+        self.streamer_fec = streamer_fec;
     }
 
-    fn start_soon(&mut self, start_on_reconnect_soon: Arc<Mutex<bool>>) {
-        let relay = self.me.clone();
+    fn set_duplication(&mut self, enabled: bool) {
+        if self.duplication.swap(enabled, Ordering::Relaxed) != enabled {
+            info!(
+                "Packet duplication {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
 
-        tokio::spawn(async move {
-            sleep(Duration::from_secs(5)).await;
+    fn is_duplicating(&self) -> bool {
+        self.duplication.load(Ordering::Relaxed)
+    }
 
-            if *start_on_reconnect_soon.lock().await {
-                debug!("Reconnecting...");
-                if let Some(relay) = relay.upgrade() {
-                    relay.lock().await.start_internal().await;
-                }
-            }
-        });
+    fn set_pacing(&mut self, pacing: Option<Pacing>) -> Result<(), RelayError> {
+        if pacing.is_some_and(|pacing| !pacing.is_valid()) {
+            return Err(RelayError::InvalidPacing);
+        }
+        self.requested_pacing = pacing;
+        self.apply_pacing();
+        Ok(())
     }
 
-    async fn handle_message(&mut self, message: MessageToRelay) -> Result<(), AnyError> {
-        match message {
-            MessageToRelay::Hello(hello) => self.handle_message_hello(hello).await,
-            MessageToRelay::Identified(identified) => {
-                self.handle_message_identified(identified).await
+    /// The requested pacing, but at most the throttle bitrate while
+    /// overheating.
+    fn apply_pacing(&mut self) {
+        let throttle_bitrate = self
+            .thermal
+            .and_then(|thermal| thermal.throttle_bitrate)
+            .filter(|_| self.overheating);
+        let pacing = match (self.requested_pacing, throttle_bitrate) {
+            (Some(Pacing::Bitrate(bitrate)), Some(throttle_bitrate))
+                if bitrate <= throttle_bitrate =>
+            {
+                self.requested_pacing
             }
-            MessageToRelay::Request(request) => self.handle_message_request(request).await,
+            (_, Some(throttle_bitrate)) => Some(Pacing::Bitrate(throttle_bitrate)),
+            (pacing, None) => pacing,
+        };
+        let mut current = self.pacing.write().unwrap();
+        if *current != pacing {
+            match pacing {
+                Some(Pacing::Bitrate(bitrate)) => info!("Pacing at {} bit/s", bitrate),
+                Some(Pacing::GapUs(gap_us)) => info!("Pacing with {} us gaps", gap_us),
+                None => info!("Pacing disabled"),
+            }
+            *current = pacing;
         }
     }
 
-    async fn handle_message_hello(&mut self, hello: Hello) -> Result<(), AnyError> {
-        let authentication = calculate_authentication(
-            &self.password,
-            &hello.authentication.salt,
-            &hello.authentication.challenge,
-        );
-        let identify = Identify {
-            id: self.relay_id,
-            name: self.name.clone(),
-            authentication,
-        };
-        self.send(MessageToStreamer::Identify(identify)).await
+    fn get_pacing(&self) -> Option<Pacing> {
+        *self.pacing.read().unwrap()
     }
 
-    async fn handle_message_identified(&mut self, identified: Identified) -> Result<(), AnyError> {
-        match identified.result {
-            MoblinkResult::Ok(_) => {
-                self.connected = true;
-            }
-            MoblinkResult::WrongPassword(_) => {
-                self.wrong_password = true;
-            }
+    fn set_workers(&mut self, workers: usize) {
+        if cfg!(unix) {
+            self.workers = workers.max(1);
+        } else if workers > 1 {
+            warn!("Workers need SO_REUSEPORT, which is only available on Unix");
         }
-        self.update_status();
-        Ok(())
     }
 
-    async fn handle_message_request(&mut self, request: MessageRequest) -> Result<(), AnyError> {
-        match &request.data {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn set_io_uring(&mut self, enabled: bool) {
+        self.io_uring = enabled;
+    }
+
+    fn set_capture(&mut self, capture: Option<Arc<Capture>>) {
+        self.capture = capture;
+    }
+
+    fn set_proxy(&mut self, proxy: Option<Proxy>) {
+        self.proxy = proxy;
+    }
+
+    fn set_headers(&mut self, headers: Vec<(String, String)>) {
+        self.headers = headers;
+    }
+
+    fn set_upstream_relay(&mut self, url: String, password: SecretString) {
+        self.upstream_relay = Some((url, password));
+    }
+
+    fn set_obfuscation_key(&mut self, obfuscation_key: Option<SecretString>) {
+        self.obfuscation_key = obfuscation_key;
+    }
+
+    fn set_srtla(&mut self, srtla: bool) {
+        self.srtla = srtla;
+    }
+
+    fn set_path_mtu_probing(&mut self, enabled: bool) {
+        self.path_mtu_probing = enabled;
+    }
+
+    /// The upstream relay speaks SRTLA instead, and FEC packets cannot be
+    /// mixed with SRTLA packets.
+    fn is_srtla_destination(&self) -> bool {
+        self.srtla && self.upstream_relay.is_none() && self.destination_fec.is_none()
+    }
+
+    fn set_backup_streamer_urls(&mut self, streamer_urls: Vec<String>) {
+        self.streamer_urls.truncate(1);
+        self.streamer_urls.extend(streamer_urls);
+    }
+
+    fn set_primary_streamer_url(&mut self, streamer_url: String) {
+        self.streamer_urls[0] = streamer_url.clone();
+        self.streamer_url = streamer_url;
+        self.streamer_url_index = 0;
+        self.connect_failures = 0;
+    }
+
+    fn set_stun_servers(&mut self, stun_servers: Vec<String>) {
+        self.stun_servers = stun_servers;
+    }
+
+    fn set_modem_monitoring(&mut self, enabled: bool) {
+        if !enabled {
+            self.modem_monitor = None;
+            self.modems.clear();
+            return;
+        }
+        if self.modem_monitor.is_some() {
+            return;
+        }
+        let relay = self.me.clone();
+        let monitor = async move {
+            let mut failed = false;
+            loop {
+                let modems = match get_modems().await {
+                    Ok(modems) => {
+                        failed = false;
+                        modems
+                    }
+                    Err(error) => {
+                        // Only log the first of consecutive failures.
+                        if !failed {
+                            warn!("Failed to get modems from ModemManager: {}", error);
+                            failed = true;
+                        }
+                        Vec::new()
+                    }
+                };
+                let Some(relay) = relay.upgrade() else {
+                    break;
+                };
+                relay.lock().await.handle_modems(modems);
+                sleep(MODEM_POLL_INTERVAL).await;
+            }
+        };
+        self.modem_monitor = Some(AbortOnDrop(self.spawn(monitor.in_current_span())));
+    }
+
+    fn handle_modems(&mut self, modems: Vec<ModemStatus>) {
+        for modem in &modems {
+            let labels = [("modem", modem.name.as_str())];
+            self.counters.set_metric(
+                metrics::MODEM_SIGNAL_QUALITY,
+                &labels,
+                modem.signal_quality_percent as f64,
+            );
+            self.counters.set_metric(
+                metrics::MODEM_BEARER_CONNECTED,
+                &labels,
+                if modem.bearer_connected { 1.0 } else { 0.0 },
+            );
+        }
+        self.modems = modems;
+    }
+
+    fn get_modems(&self) -> Vec<ModemStatus> {
+        self.modems.clone()
+    }
+
+    fn set_thermal_monitoring(&mut self, thermal: Option<ThermalConfig>) -> Result<(), RelayError> {
+        if thermal.and_then(|thermal| thermal.throttle_bitrate) == Some(0) {
+            return Err(RelayError::InvalidPacing);
+        }
+        self.thermal = thermal;
+        if thermal.is_none() {
+            self.thermal_monitor = None;
+            self.thermal_zones.clear();
+            self.overheating = false;
+        } else if self.thermal_monitor.is_none() {
+            let relay = self.me.clone();
+            let monitor = async move {
+                let mut failed = false;
+                loop {
+                    let zones = match get_thermal_zones().await {
+                        Ok(zones) => {
+                            failed = false;
+                            zones
+                        }
+                        Err(error) => {
+                            // Only log the first of consecutive failures.
+                            if !failed {
+                                warn!("Failed to get thermal zones: {}", error);
+                                failed = true;
+                            }
+                            Vec::new()
+                        }
+                    };
+                    let Some(relay) = relay.upgrade() else {
+                        break;
+                    };
+                    relay.lock().await.handle_thermal_zones(zones);
+                    sleep(THERMAL_POLL_INTERVAL).await;
+                }
+            };
+            self.thermal_monitor = Some(AbortOnDrop(self.spawn(monitor.in_current_span())));
+        }
+        self.apply_pacing();
+        Ok(())
+    }
+
+    fn handle_thermal_zones(&mut self, zones: Vec<ThermalZone>) {
+        for zone in &zones {
+            self.counters.set_metric(
+                metrics::THERMAL_ZONE_TEMPERATURE,
+                &[("zone", zone.name.as_str())],
+                zone.temperature_celsius,
+            );
+        }
+        if let (Some(thermal), Some(zone)) = (self.thermal, hottest(&zones)) {
+            let zone = zone.clone();
+            if !self.overheating && zone.temperature_celsius >= thermal.warning_celsius {
+                self.overheating = true;
+                self.apply_pacing();
+                self.emit_event(RelayEvent::Overheating { zone });
+            } else if self.overheating
+                && zone.temperature_celsius < thermal.warning_celsius - THERMAL_HYSTERESIS_CELSIUS
+            {
+                self.overheating = false;
+                self.apply_pacing();
+                self.emit_event(RelayEvent::CooledDown { zone });
+            }
+        }
+        self.thermal_zones = zones;
+    }
+
+    fn get_thermal_zones(&self) -> Vec<ThermalZone> {
+        self.thermal_zones.clone()
+    }
+
+    fn set_data_usage(&mut self, data_usage: Option<Arc<DataUsage>>) {
+        self.data_usage_bytes = self.get_relayed_bytes();
+        self.data_usage_interface = None;
+        self.data_usage = data_usage;
+        if self.data_usage.is_none() {
+            self.data_usage_monitor = None;
+            return;
+        }
+        if self.data_usage_monitor.is_some() {
+            return;
+        }
+        let relay = self.me.clone();
+        let monitor = async move {
+            let mut failed = false;
+            loop {
+                let Some(relay) = relay.upgrade() else {
+                    break;
+                };
+                let data_usage = relay.lock().await.account_data_usage().await;
+                drop(relay);
+                if let Some(data_usage) = data_usage {
+                    match data_usage.save().await {
+                        Ok(()) => failed = false,
+                        Err(error) => {
+                            // Only log the first of consecutive failures.
+                            if !failed {
+                                warn!("Failed to save data usage: {}", error);
+                                failed = true;
+                            }
+                        }
+                    }
+                }
+                sleep(DATA_USAGE_INTERVAL).await;
+            }
+        };
+        self.data_usage_monitor = Some(AbortOnDrop(self.spawn(monitor.in_current_span())));
+    }
+
+    /// Both directions on the destination side.
+    fn get_relayed_bytes(&self) -> u64 {
+        let stats = self.counters.snapshot();
+        stats.bytes_to_destination + stats.bytes_to_streamer
+    }
+
+    /// The interface with the bind address, if any. `any` if bound to all
+    /// interfaces.
+    fn get_bind_interface(&self) -> Option<String> {
+        let address = parse_socket_addr(&self.bind_address).ok()?.ip();
+        if address.is_unspecified() {
+            return Some("any".to_string());
+        }
+        list_interfaces()
+            .into_iter()
+            .find(|interface| interface.addresses.contains(&address))
+            .map(|interface| interface.name)
+    }
+
+    /// Adds the bytes relayed since the previous call to the data usage and
+    /// checks the quota. Returns the data usage to save, if enabled.
+    async fn account_data_usage(&mut self) -> Option<Arc<DataUsage>> {
+        let data_usage = self.data_usage.clone()?;
+        let relayed_bytes = self.get_relayed_bytes();
+        let bytes = relayed_bytes.saturating_sub(self.data_usage_bytes);
+        self.data_usage_bytes = relayed_bytes;
+        if let Some(interface) = self.get_bind_interface() {
+            self.data_usage_interface = Some(interface);
+        }
+        let interface = self.data_usage_interface.clone()?;
+        let usage = data_usage.add(&interface, bytes);
+        self.counters.set_metric(
+            metrics::DATA_USAGE,
+            &[("interface", interface.as_str())],
+            usage.bytes as f64,
+        );
+        if let Some(quota) = data_usage.quota() {
+            self.check_data_quota(quota, usage).await;
+        }
+        Some(data_usage)
+    }
+
+    async fn check_data_quota(&mut self, quota: DataQuota, usage: InterfaceDataUsage) {
+        if usage.month != self.data_quota_month {
+            self.data_quota_month = usage.month.clone();
+            self.data_quota_warned = false;
+            self.data_quota_exceeded = false;
+            if self.paused_by_data_quota {
+                info!("New month, data quota reset");
+                self.paused_by_data_quota = false;
+                self.resume().await;
+            }
+        }
+        if !self.data_quota_exceeded && usage.bytes >= quota.bytes {
+            self.data_quota_warned = true;
+            self.data_quota_exceeded = true;
+            self.emit_event(RelayEvent::DataQuotaExceeded { usage });
+            if quota.pause_when_exceeded && !self.paused {
+                self.paused_by_data_quota = true;
+                self.pause().await;
+            }
+        } else if !self.data_quota_warned
+            && u128::from(usage.bytes) * 100
+                >= u128::from(quota.bytes) * u128::from(quota.warning_percentage)
+        {
+            self.data_quota_warned = true;
+            self.emit_event(RelayEvent::DataQuotaWarning { usage });
+        }
+    }
+
+    fn get_data_usage(&self) -> Option<InterfaceDataUsage> {
+        let data_usage = self.data_usage.as_ref()?;
+        Some(data_usage.get(self.data_usage_interface.as_ref()?))
+    }
+
+    fn set_srt_inspection(&mut self, enabled: bool) {
+        self.srt_inspector = enabled.then(Default::default);
+    }
+
+    fn set_on_event(&mut self, on_event: OnEventClosure) {
+        self.on_event = Some(on_event);
+    }
+
+    fn set_metrics_sink(&mut self, sink: Arc<dyn MetricsSink>) {
+        self.publish_metrics();
+        self.counters.set_metrics_sink(sink, self.name.clone());
+        if self.metrics_publisher.is_some() {
+            return;
+        }
+        let relay = self.me.clone();
+        let metrics_publisher = async move {
+            loop {
+                sleep(METRICS_PUBLISH_INTERVAL).await;
+                let Some(relay) = relay.upgrade() else {
+                    break;
+                };
+                relay.lock().await.publish_metrics();
+            }
+        };
+        self.metrics_publisher = Some(self.spawn(metrics_publisher.in_current_span()));
+    }
+
+    /// Per-datagram metrics are accumulated without locking and published
+    /// here, periodically and before their labels change.
+    fn publish_metrics(&self) {
+        self.counters.publish_metrics();
+        if let Some(srt_inspector) = &self.srt_inspector {
+            srt_inspector.publish_metrics(&self.counters);
+        }
+    }
+
+    async fn setup<F>(
+        &mut self,
+        streamer_url: String,
+        password: SecretString,
+        relay_id: Uuid,
+        name: String,
+        on_status_updated: F,
+        get_status: Option<GetStatusClosure>,
+    ) -> Result<(), RelayError>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        validate_streamer_url(&streamer_url)?;
+        if name.trim().is_empty() {
+            return Err(RelayError::EmptyName);
+        }
+        self.on_status_updated = Some(Box::new(on_status_updated));
+        self.status_provider = get_status.map(|get_status| Arc::new(get_status) as _);
+        self.relay_id = relay_id;
+        self.set_primary_streamer_url(streamer_url);
+        self.password = password;
+        self.publish_metrics();
+        self.counters.set_relay_name(name.clone());
+        self.name = name;
+        Ok(())
+    }
+
+    fn is_started(&self) -> bool {
+        self.state != ConnectionState::Idle
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state == ConnectionState::Connected
+    }
+
+    /// Logged and emitted as an event if changed.
+    fn set_state(&mut self, state: ConnectionState) {
+        if state == self.state {
+            return;
+        }
+        info!("Connection state changed to {}", state);
+        self.state = state;
+        self.state_sender.send_replace(state);
+        self.emit_event(RelayEvent::ConnectionStateChanged { state });
+    }
+
+    fn get_status_text(&self) -> String {
+        let connected = self.is_connected();
+        if connected && self.paused {
+            "Paused".to_string()
+        } else if connected {
+            let mut details = Vec::new();
+            if let Some(round_trip_time) = self.round_trip_time {
+                details.push(format!("RTT {} ms", round_trip_time.as_millis()));
+            }
+            if self.tunnel_idle {
+                details.push("tunnel idle".to_string());
+            }
+            if let Some(nat) = &self.nat {
+                details.push(nat.nat_type.to_string());
+            }
+            if details.is_empty() {
+                "Connected to streamer".to_string()
+            } else {
+                format!("Connected to streamer ({})", details.join(", "))
+            }
+        } else if self.state == ConnectionState::WrongPassword {
+            "Wrong password".to_string()
+        } else if self.is_started() {
+            "Connecting to streamer".to_string()
+        } else {
+            "Disconnected from streamer".to_string()
+        }
+    }
+
+    fn get_streamer_url(&self) -> String {
+        self.streamer_url.clone()
+    }
+
+    fn get_state(&self) -> RelayState {
+        RelayState {
+            connection: self.state,
+            relay_id: self.relay_id,
+            name: self.name.clone(),
+            streamer_url: self.streamer_url.clone(),
+            paused: self.paused,
+            duplicating: self.duplication.load(Ordering::Relaxed),
+            pacing: self.get_pacing(),
+            status: self.get_status_text(),
+            tunnel: self.tunnel.clone(),
+            last_error: self.last_error.clone(),
+            quality_score: self.quality_score,
+        }
+    }
+
+    fn set_last_error(&mut self, error: String) {
+        self.last_error = Some(error);
+    }
+
+    fn get_stats(&self) -> RelayStats {
+        let mut stats = self.counters.snapshot();
+        if let Some(srt_inspector) = &self.srt_inspector {
+            let (to_destination, to_streamer) = srt_inspector.snapshot();
+            stats.srt_to_destination = Some(to_destination);
+            stats.srt_to_streamer = Some(to_streamer);
+        }
+        stats
+    }
+
+    fn get_tunnels(&self) -> Vec<TunnelInfo> {
+        self.tunnel.iter().cloned().collect()
+    }
+
+    fn set_tunnel_annotation(
+        &mut self,
+        id: u64,
+        annotation: Option<String>,
+    ) -> Result<(), AnyError> {
+        let Some(tunnel) = self.tunnel.as_mut().filter(|tunnel| tunnel.id == id) else {
+            return Err(format!("No tunnel with id {}", id).into());
+        };
+        tunnel.annotation = annotation;
+        let tunnel = tunnel.clone();
+        self.counters
+            .set_tunnel_annotation(tunnel.annotation.clone());
+        self.emit_event(RelayEvent::TunnelAnnotated { tunnel });
+        Ok(())
+    }
+
+    fn get_reconnects(&self) -> Vec<Reconnect> {
+        self.reconnects.iter().cloned().collect()
+    }
+
+    fn set_status_provider(&mut self, status_provider: Arc<dyn StatusProvider>) {
+        self.status_provider = Some(status_provider);
+    }
+
+    fn set_status_executable(&mut self, status_executable: Option<StatusExecutable>) {
+        self.status_cache = status_executable.map(|status_executable| {
+            StatusCache::start(
+                status_executable,
+                Some(self.me.clone()),
+                self.runtime.clone(),
+            )
+        });
+    }
+
+    fn get_status_environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("MOBLINK_RELAY_NAME", self.name.clone()),
+            ("MOBLINK_RELAY_ID", self.relay_id.to_string()),
+            ("MOBLINK_STREAMER_URL", self.streamer_url.clone()),
+            ("MOBLINK_BIND_ADDRESS", self.bind_address.clone()),
+            ("MOBLINK_CONNECTED", self.is_connected().to_string()),
+            ("MOBLINK_PAUSED", self.paused.to_string()),
+        ]
+    }
+
+    async fn get_status(&self) -> Status {
+        if let Some(status_cache) = self.status_cache.as_ref() {
+            status_cache.get()
+        } else if let Some(status_provider) = self.status_provider.as_ref() {
+            status_provider.status().await
+        } else {
+            Status::default()
+        }
+    }
+
+    async fn update_settings(
+        &mut self,
+        streamer_url: Option<String>,
+        password: Option<SecretString>,
+    ) -> Result<(), RelayError> {
+        if let Some(streamer_url) = &streamer_url {
+            validate_streamer_url(streamer_url)?;
+        }
+        let streamer_url =
+            streamer_url.filter(|streamer_url| *streamer_url != self.streamer_urls[0]);
+        let password = password.filter(|password| *password != self.password);
+        if streamer_url.is_none() && password.is_none() {
+            return Ok(());
+        }
+        if let Some(streamer_url) = streamer_url {
+            info!("Streamer URL changed to {}", streamer_url);
+            self.set_primary_streamer_url(streamer_url);
+        }
+        if let Some(password) = password {
+            info!("Password changed");
+            self.password = password;
+        }
+        if self.is_started() {
+            self.record_reconnect("Settings updated");
+            self.stop_internal(ConnectionState::Connecting).await;
+            self.start_internal().await;
+        }
+        Ok(())
+    }
+
+    async fn set_name(&mut self, name: String) -> Result<(), RelayError> {
+        if name.trim().is_empty() {
+            return Err(RelayError::EmptyName);
+        }
+        if name == self.name {
+            return Ok(());
+        }
+        info!("Renamed from {} to {}", self.name, name);
+        self.publish_metrics();
+        self.counters.set_relay_name(name.clone());
+        self.name = name;
+        if self.is_connected()
+            && self
+                .streamer_capabilities
+                .iter()
+                .any(|capability| capability == CAPABILITY_RENAME)
+        {
+            let rename = Rename {
+                name: self.name.clone(),
+            };
+            if let Err(error) = self.send(MessageToStreamer::Rename(rename)).await {
+                warn!("Failed to send rename with error: {}", error);
+            }
+        } else if self.ws_writer.is_some() {
+            // Only the identify message carries the name.
+            debug!("Streamer does not support rename, reconnecting");
+            self.stop_internal(ConnectionState::Connecting).await;
+            self.start_internal().await;
+        }
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), RelayError> {
+        if !self.is_started() {
+            for streamer_url in &self.streamer_urls {
+                validate_streamer_url(streamer_url)?;
+            }
+            self.select_bind_address()?;
+            if parse_socket_addr(&self.bind_address).is_err() {
+                return Err(RelayError::InvalidBindAddress {
+                    address: self.bind_address.clone(),
+                });
+            }
+            self.reconnect_attempt = 0;
+            let primary_streamer_url = self.streamer_urls[0].clone();
+            self.set_primary_streamer_url(primary_streamer_url);
+            self.emit_event(RelayEvent::Ready {
+                bind_address: self.bind_address.clone(),
+                relay_id: self.relay_id,
+                name: self.name.clone(),
+                streamer_url: self.streamer_url.clone(),
+            });
+            self.set_state(ConnectionState::Connecting);
+            self.start_internal().await;
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        if self.is_started() {
+            self.stop_internal(ConnectionState::Idle).await;
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        if matches!(
+            self.state,
+            ConnectionState::Idle | ConnectionState::WrongPassword
+        ) {
+            return;
+        }
+        if let Err(error) = self.select_bind_address() {
+            warn!("Failed to select bind address with error: {}", error);
+        }
+        self.record_reconnect("Reconnect requested");
+        self.stop_internal(ConnectionState::Connecting).await;
+        self.start_internal().await;
+    }
+
+    /// Selects the bind address now, as the network interfaces may have
+    /// changed since configured.
+    fn select_bind_address(&mut self) -> Result<(), RelayError> {
+        if let Some(address_selection) = &self.address_selection {
+            self.bind_address =
+                Self::get_default_bind_address(&self.interface_policy, address_selection)?;
+        }
+        Ok(())
+    }
+
+    fn get_default_bind_address(
+        interface_policy: &InterfacePolicy,
+        address_selection: &AddressSelection,
+    ) -> Result<String, RelayError> {
+        // Only ipv4 addresses are supported
+        let ipv4_addresses = get_default_interface_ipv4_addresses(interface_policy)
+            .ok_or(RelayError::NoNetworkInterface)?;
+        Ok(address_selection
+            .select(&ipv4_addresses)
+            .map(|address| address.to_string())
+            .unwrap_or("0.0.0.0:0".to_string()))
+    }
+
+    async fn start_internal(&mut self) {
+        match self.state {
+            ConnectionState::Idle => {
+                self.stop_internal(ConnectionState::Idle).await;
+                return;
+            }
+            ConnectionState::WrongPassword => {
+                debug!("Not connecting after wrong password");
+                return;
+            }
+            _ => {}
+        }
+        self.set_state(ConnectionState::Connecting);
+
+        let request = match url::Url::parse(&self.streamer_url) {
+            Ok(url) => url,
+            Err(e) => {
+                // Validated in start(), but may have been changed since.
+                error!("Failed to parse URL: {}", e);
+                return;
+            }
+        };
+
+        match timeout(Duration::from_secs(10), self.connect_websocket(&request)).await {
+            Ok(Ok(ws_stream)) => {
+                debug!("Connected to {}", self.streamer_url);
+                let (writer, reader) = ws_stream.split();
+                self.ws_writer = Some(writer);
+                self.set_state(ConnectionState::Identifying);
+                self.start_websocket_receiver(reader);
+            }
+            Ok(Err(error)) => {
+                debug!(
+                    "Failed to connect to {} with error: {}",
+                    self.streamer_url, error
+                );
+                self.set_last_error(format!("Connect failed: {}", error));
+                self.handle_connect_failure();
+                self.reconnect_soon("Connect failed").await;
+            }
+            Err(_elapsed) => {
+                debug!(
+                    "Failed to connect to {} within 10 seconds",
+                    self.streamer_url
+                );
+                self.set_last_error("Connect timeout".to_string());
+                self.handle_connect_failure();
+                self.reconnect_soon("Connect timeout").await;
+            }
+        }
+    }
+
+    async fn connect_websocket(
+        &self,
+        url: &url::Url,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, AnyError> {
+        let mut request = url.as_str().into_client_request()?;
+        for (name, value) in &self.headers {
+            request.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if self.proxy.is_none() && self.dns_resolver.is_system() {
+            return Ok(connect_async(request).await?.0);
+        }
+        let host = url.host_str().ok_or("No host in streamer URL")?;
+        let port = url
+            .port_or_known_default()
+            .ok_or("No port in streamer URL")?;
+        let tcp_stream = if let Some(proxy) = &self.proxy {
+            debug!("Connecting to {}:{} through proxy {:?}", host, port, proxy);
+            proxy.connect(host, port).await?
+        } else {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let address = *self
+                .dns_resolver
+                .lookup(host)
+                .await?
+                .first()
+                .ok_or("No address found for streamer")?;
+            debug!("Resolved {} to {}", host, address);
+            TcpStream::connect((address, port)).await?
+        };
+        Ok(client_async(request, MaybeTlsStream::Plain(tcp_stream))
+            .await?
+            .0)
+    }
+
+    fn handle_connect_failure(&mut self) {
+        self.connect_failures += 1;
+        if self.connect_failures < MAXIMUM_NUMBER_OF_CONNECT_FAILURES
+            || self.streamer_urls.len() < 2
+        {
+            return;
+        }
+        self.connect_failures = 0;
+        self.streamer_url_index = (self.streamer_url_index + 1) % self.streamer_urls.len();
+        let streamer_url = self.streamer_urls[self.streamer_url_index].clone();
+        info!(
+            "Failing over from {} to {}",
+            self.streamer_url, streamer_url
+        );
+        let from = std::mem::replace(&mut self.streamer_url, streamer_url.clone());
+        self.emit_event(RelayEvent::FailedOver {
+            from,
+            to: streamer_url,
+        });
+    }
+
+    fn start_websocket_receiver(
+        &mut self,
+        mut reader: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    ) {
+        // Task to process messages received from the channel.
+        let relay = self.me.clone();
+        let connection_id = self.connection_id;
+        let token = self.connection_token.clone();
+        let span = info_span!("connection", streamer_url = %self.streamer_url);
+
+        let receiver = async move {
+            let Some(relay_arc) = relay.upgrade() else {
+                return;
+            };
+
+            while let Some(Some(result)) = token.run_until_cancelled(reader.next()).await {
+                let mut relay = relay_arc.lock().await;
+                if relay.connection_id != connection_id {
+                    debug!("Connection closed");
+                    break;
+                }
+                match result {
+                    Ok(message) => match message {
+                        Message::Text(text) => {
+                            match serde_json::from_str::<MessageToRelay>(&text) {
+                                Ok(message) => {
+                                    if let Err(error) = relay.handle_message(message).await {
+                                        error!("Message handling failed with error: {}", error);
+                                        relay.set_last_error(format!(
+                                            "Message handling failed: {}",
+                                            error
+                                        ));
+                                        relay.reconnect_soon("Message handling failed").await;
+                                        break;
+                                    }
+                                }
+                                _ => {
+                                    error!("Failed to deserialize message: {}", text);
+                                }
+                            }
+                        }
+                        Message::Binary(data) => match relay.decode_binary_message(&data) {
+                            Ok(message) => {
+                                if let Err(error) = relay.handle_message(message).await {
+                                    error!("Message handling failed with error: {}", error);
+                                    relay.set_last_error(format!(
+                                        "Message handling failed: {}",
+                                        error
+                                    ));
+                                    relay.reconnect_soon("Message handling failed").await;
+                                    break;
+                                }
+                            }
+                            Err(error) => {
+                                error!(
+                                    "Failed to deserialize binary message with error: {}",
+                                    error
+                                );
+                            }
+                        },
+                        Message::Ping(data) => {
+                            relay.send_message(Message::Pong(data)).await.ok();
+                        }
+                        Message::Pong(data) => {
+                            relay.handle_pong(&data);
+                        }
+                        Message::Close(frame) => {
+                            info!("Received close message: {:?}", frame);
+                            relay.set_last_error("Closed by streamer".to_string());
+                            relay.reconnect_soon("Close received").await;
+                            break;
+                        }
+                        Message::Frame(_) => {
+                            unreachable!("This is never used")
+                        }
+                    },
+                    Err(e) => {
+                        debug!("Error processing message: {}", e);
+                        // TODO: There has to be a better way to handle this
+                        if e.to_string()
+                            .contains("Connection reset without closing handshake")
+                        {
+                            relay.set_last_error("Connection reset".to_string());
+                            relay.reconnect_soon("Connection reset").await;
+                        }
+                        break;
+                    }
+                }
+            }
+        };
+        self.spawn(receiver.instrument(span));
+    }
+
+    /// Enters given state once disconnected.
+    async fn stop_internal(&mut self, state: ConnectionState) {
+        self.connection_id += 1;
+        self.deferred_request = None;
+        self.encoding = Encoding::Json;
+        self.deflate = None;
+        self.stop_prober().await;
+        self.stop_pinger().await;
+        self.stop_status_pusher().await;
+        self.stop_nat_discovery().await;
+        if let Some(mut ws_writer) = self.ws_writer.take() {
+            match ws_writer.close().await {
+                Err(e) => {
+                    error!("Error closing WebSocket: {}", e);
+                }
+                _ => {
+                    debug!("WebSocket closed successfully");
+                }
+            }
+        }
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        self.reconnect_token.cancel();
+        self.stop_tunnel(TunnelStopReason::Disconnected).await;
+        self.connection_token.cancel();
+        self.connection_token = CancellationToken::new();
+        self.set_state(state);
+        self.update_status();
+    }
+
+    /// Waits for the forwarding tasks to stop, so that the tunnel's sockets
+    /// are closed when done.
+    async fn stop_tunnel(&mut self, reason: TunnelStopReason) {
+        self.stop_prober().await;
+        self.tunnel_token.cancel();
+        if let Some(mut relay_to_destination) = self.relay_to_destination.take()
+            && timeout(TASK_STOP_TIMEOUT, &mut relay_to_destination)
+                .await
+                .is_err()
+        {
+            warn!("Forwarding did not stop in time, aborting it");
+            relay_to_destination.abort();
+            relay_to_destination.await.ok();
+        }
+        if let Some(tunnel) = self.tunnel.take() {
+            if matches!(
+                reason,
+                TunnelStopReason::Replaced | TunnelStopReason::Failed
+            ) {
+                self.counters.add_tunnel_restart(&reason.to_string());
+            }
+            self.emit_event(RelayEvent::TunnelStopped { tunnel, reason });
+        }
+        self.set_tunnel_idle(false);
+        self.publish_metrics();
+        self.counters.set_tunnel_annotation(None);
+        self.counters.reset_inter_arrival_times();
+        self.counters.set_maximum_datagram_size(None);
+        self.tunnel_sockets = None;
+        if let Some(upstream_connection) = self.upstream_connection.take() {
+            upstream_connection.abort();
+        }
+        self.destination_obfuscation = None;
+        self.tunnel_span = Span::none();
+    }
+
+    async fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+        info!("Pausing");
+        self.paused = true;
+        // Not a tunnel error.
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        self.stop_tunnel(TunnelStopReason::Paused).await;
+        self.update_status();
+    }
+
+    async fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        info!("Resuming");
+        self.paused = false;
+        self.update_status();
+        if let Some(request) = self.deferred_request.take()
+            && let Err(error) = self.handle_message_request(request).await
+        {
+            error!(
+                "Failed to handle start tunnel request with error: {}",
+                error
+            );
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn get_nat(&self) -> Option<NatInfo> {
+        self.nat.clone()
+    }
+
+    /// Uses a socket bound to the address of the streamer facing sockets of
+    /// tunnels, as their public mapping is what matters. Not a tunnel's
+    /// socket, as the STUN responses must not be relayed, and not a tunnel
+    /// port, as a tunnel may need it.
+    fn start_nat_discovery(&mut self) {
+        if self.stun_servers.is_empty() {
+            return;
+        }
+        let relay = self.me.clone();
+        let stun_servers = self.stun_servers.clone();
+        let address = SocketAddr::new(self.streamer_bind_address, 0);
+        let discovery = async move {
+            let socket = match create_streamer_udp_socket(address, false).await {
+                Ok(socket) => socket,
+                Err(error) => {
+                    error!("Failed to create NAT discovery socket: {}", error);
+                    return;
+                }
+            };
+            let nat = discover_nat(&socket, &stun_servers).await;
+            if let Some(relay) = relay.upgrade() {
+                relay.lock().await.handle_nat_discovered(nat);
+            }
+        };
+        self.nat_discovery = Some(self.spawn(discovery.in_current_span()));
+    }
+
+    async fn stop_nat_discovery(&mut self) {
+        if let Some(nat_discovery) = self.nat_discovery.take() {
+            nat_discovery.abort();
+            nat_discovery.await.ok();
+        }
+        self.nat = None;
+    }
+
+    fn handle_nat_discovered(&mut self, nat: NatInfo) {
+        match nat.nat_type {
+            NatType::EndpointDependent => warn!(
+                "Symmetric NAT detected (the public port differs per destination). Tunnels will \
+                 likely not work on this network."
+            ),
+            NatType::Blocked => warn!(
+                "No STUN server answered. UDP is probably blocked and tunnels will not work on \
+                 this network."
+            ),
+            _ => info!(
+                "NAT type {:?} with public address {:?}",
+                nat.nat_type, nat.public_address
+            ),
+        }
+        self.nat = Some(nat.clone());
+        self.emit_event(RelayEvent::NatDiscovered { nat });
+        self.update_status();
+    }
+
+    /// Pings the streamer periodically to measure the round trip time. The
+    /// ping payload is the send time.
+    fn start_pinger(&mut self) {
+        let relay = self.me.clone();
+        let pinger = async move {
+            loop {
+                let Some(relay) = relay.upgrade() else {
+                    break;
+                };
+                let payload = now_us().to_be_bytes().to_vec();
+                let mut relay = relay.lock().await;
+                relay.sample_quality();
+                if let Err(error) = relay.send_message(Message::Ping(payload.into())).await {
+                    debug!("Failed to send ping with error: {}", error);
+                }
+                drop(relay);
+                sleep(PING_INTERVAL).await;
+            }
+        };
+        self.pinger = Some(self.spawn(pinger.in_current_span()));
+    }
+
+    async fn stop_pinger(&mut self) {
+        if let Some(pinger) = self.pinger.take() {
+            pinger.abort();
+            pinger.await.ok();
+        }
+        self.round_trip_time = None;
+        self.reported_round_trip_time = None;
+        self.counters.set_round_trip_time(None);
+        self.quality_scorer.clear();
+        self.quality_score = None;
+        self.counters.set_metric(metrics::QUALITY_SCORE, &[], 0.0);
+    }
+
+    fn sample_quality(&mut self) {
+        let stats = self.counters.snapshot();
+        let srt_packets = self.srt_inspector.as_ref().map(|srt_inspector| {
+            let (to_destination, _) = srt_inspector.snapshot();
+            (
+                to_destination.data_packets,
+                to_destination.retransmitted_packets,
+            )
+        });
+        self.quality_score = self.quality_scorer.add(QualitySample {
+            time: Instant::now(),
+            round_trip_time_ms: stats.round_trip_time_ms,
+            bytes_to_destination: stats.bytes_to_destination,
+            srt_packets,
+        });
+        if let Some(quality_score) = self.quality_score {
+            self.counters
+                .set_metric(metrics::QUALITY_SCORE, &[], quality_score.into());
+        }
+    }
+
+    /// The quality score, if the streamer supports it.
+    fn get_reported_quality_score(&self) -> Option<u8> {
+        self.streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_QUALITY_SCORE)
+            .then_some(self.quality_score)
+            .flatten()
+    }
+
+    /// Sends status and bitrates to the streamer periodically, if enabled and
+    /// the streamer supports it. Old streamers would fail to parse the
+    /// message.
+    fn start_status_pusher(&mut self) {
+        let Some(interval) = self.status_push_interval else {
+            return;
+        };
+        if !self
+            .streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_STATUS_PUSH)
+        {
+            debug!("Streamer does not support status push");
+            return;
+        }
+        let relay = self.me.clone();
+        let mut previous_stats = self.counters.snapshot();
+        let mut previous_time = Instant::now();
+        let status_pusher = async move {
+            loop {
+                sleep(interval).await;
+                let Some(relay) = relay.upgrade() else {
+                    break;
+                };
+                let mut relay = relay.lock().await;
+                let stats = relay.counters.snapshot();
+                let now = Instant::now();
+                let elapsed = now.duration_since(previous_time).as_secs_f64();
+                let bitrate = |previous: u64, current: u64| {
+                    (current.saturating_sub(previous) as f64 * 8.0 / elapsed) as u64
+                };
+                let status = StatusPush {
+                    battery_percentage: relay.get_status().await.battery_percentage,
+                    bitrate_to_destination: bitrate(
+                        previous_stats.bytes_to_destination,
+                        stats.bytes_to_destination,
+                    ),
+                    bitrate_to_streamer: bitrate(
+                        previous_stats.bytes_to_streamer,
+                        stats.bytes_to_streamer,
+                    ),
+                    quality_score: relay.get_reported_quality_score(),
+                };
+                previous_stats = stats;
+                previous_time = now;
+                if let Err(error) = relay.send(MessageToStreamer::Status(status)).await {
+                    debug!("Failed to push status with error: {}", error);
+                }
+            }
+        };
+        self.status_pusher = Some(self.spawn(status_pusher.in_current_span()));
+    }
+
+    async fn stop_status_pusher(&mut self) {
+        if let Some(status_pusher) = self.status_pusher.take() {
+            status_pusher.abort();
+            status_pusher.await.ok();
+        }
+    }
+
+    fn handle_pong(&mut self, data: &[u8]) {
+        let Ok(sent) = <[u8; 8]>::try_from(data) else {
+            debug!("Received pong message of unexpected length {}", data.len());
+            return;
+        };
+        let sample = Duration::from_micros(now_us().saturating_sub(u64::from_be_bytes(sent)));
+        let round_trip_time = match self.round_trip_time {
+            Some(round_trip_time) => (round_trip_time * 7 + sample) / 8,
+            None => sample,
+        };
+        debug!(
+            "Round trip time {:?} (smoothed {:?})",
+            sample, round_trip_time
+        );
+        self.round_trip_time = Some(round_trip_time);
+        self.counters.set_round_trip_time(Some(round_trip_time));
+        // Only report significant changes to not flood the status callback.
+        let significant = match self.reported_round_trip_time {
+            Some(reported) => {
+                round_trip_time.as_millis() != reported.as_millis()
+                    && round_trip_time.abs_diff(reported) > reported / 10
+            }
+            None => true,
+        };
+        if significant {
+            self.reported_round_trip_time = Some(round_trip_time);
+            self.update_status();
+        }
+    }
+
+    fn start_prober(&mut self, destination_socket: Arc<UdpSocket>) {
+        let probe_stats = Arc::new(Mutex::new(ProbeStats::default()));
+        self.probe_stats = Some(probe_stats.clone());
+        let prober = async move {
+            loop {
+                let probe = probe_stats.lock().await.create_probe();
+                if let Err(error) = destination_socket.send(&probe).await {
+                    debug!("Failed to send probe with error: {}", error);
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+        self.prober = Some(self.spawn(prober.instrument(self.tunnel_span.clone())));
+    }
+
+    async fn stop_prober(&mut self) {
+        if let Some(prober) = self.prober.take() {
+            prober.abort();
+            prober.await.ok();
+        }
+        let Some(probe_stats) = self.probe_stats.take() else {
+            return;
+        };
+        let report = probe_stats.lock().await.report();
+        info!("Test report: {:?}", report);
+        if !self
+            .streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_TEST_REPORT)
+        {
+            return;
+        }
+        if let Err(error) = self.send(MessageToStreamer::TestReport(report)).await {
+            debug!("Failed to send test report with error: {}", error);
+        }
+    }
+
+    fn set_tunnel_idle(&mut self, idle: bool) {
+        if self.tunnel_idle != idle {
+            self.tunnel_idle = idle;
+            self.update_status();
+        }
+    }
+
+    fn handle_tunnel_idle(&mut self) {
+        self.set_tunnel_idle(true);
+        self.counters.add_destination_timeout();
+        if let Some(tunnel) = &self.tunnel {
+            self.emit_event(RelayEvent::TunnelIdle {
+                tunnel: tunnel.clone(),
+            });
+        }
+    }
+
+    fn handle_tunnel_active(&mut self) {
+        self.set_tunnel_idle(false);
+        if let Some(tunnel) = &self.tunnel {
+            self.emit_event(RelayEvent::TunnelActive {
+                tunnel: tunnel.clone(),
+            });
+        }
+    }
+
+    async fn handle_tunnel_error(
+        &mut self,
+        error: impl fmt::Display,
+        reconnect_on_tunnel_error: &Mutex<bool>,
+    ) {
+        self.set_last_error(format!("Tunnel failed: {}", error));
+        if *reconnect_on_tunnel_error.lock().await {
+            self.stop_tunnel(TunnelStopReason::Failed).await;
+            self.reconnect_soon("Tunnel error").await;
+        } else {
+            info!("Not reconnecting after tunnel error");
+        }
+    }
+
+    fn emit_event(&self, event: RelayEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(event);
+        }
+    }
+
+    fn update_status(&self) {
+        let Some(on_status_updated) = &self.on_status_updated else {
+            return;
+        };
+        on_status_updated(self.get_status_text());
+    }
+
+    async fn reconnect_soon(&mut self, reason: &str) {
+        self.record_reconnect(reason);
+        self.reconnect_attempt += 1;
+        let next_at = SystemTime::now() + RECONNECT_DELAY;
+        self.stop_internal(ConnectionState::Reconnecting {
+            attempt: self.reconnect_attempt,
+            next_at: next_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        })
+        .await;
+        self.reconnect_token = CancellationToken::new();
+        self.start_soon(self.reconnect_token.clone());
+    }
+
+    fn record_reconnect(&mut self, reason: &str) {
+        self.reconnects.push_back(Reconnect {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            reason: reason.to_string(),
+        });
+        if self.reconnects.len() > MAXIMUM_NUMBER_OF_RECONNECTS {
+            self.reconnects.pop_front();
+        }
+        self.counters.add_reconnect(reason);
+    }
+
+    fn start_soon(&mut self, token: CancellationToken) {
+        let relay = self.me.clone();
+
+        self.spawn(async move {
+            let network_up = tokio::select! {
+                _ = token.cancelled() => return,
+                _ = sleep(RECONNECT_DELAY) => false,
+                _ = wait_for_network_up() => true,
+            };
+            // Let the other addresses and routes of the uplink appear.
+            if network_up
+                && token
+                    .run_until_cancelled(sleep(NETWORK_SETTLE_TIME))
+                    .await
+                    .is_none()
+            {
+                return;
+            }
+            let Some(relay) = relay.upgrade() else {
+                return;
+            };
+            let mut relay = relay.lock().await;
+            // Stopped while waiting for the lock.
+            if token.is_cancelled() {
+                return;
+            }
+            debug!("Reconnecting...");
+            if network_up {
+                info!("Network changed, reconnecting now");
+                // The uplink may have a new address.
+                if let Err(error) = relay.select_bind_address() {
+                    warn!("Failed to select bind address with error: {}", error);
+                }
+            }
+            relay.start_internal().await;
+        });
+    }
+
+    async fn handle_message(&mut self, message: MessageToRelay) -> Result<(), AnyError> {
+        match message {
+            MessageToRelay::Hello(hello) => self.handle_message_hello(hello).await,
+            MessageToRelay::Identified(identified) => {
+                self.handle_message_identified(identified).await
+            }
+            MessageToRelay::Request(request) => self.handle_message_request(request).await,
+            MessageToRelay::Unknown(_) => {
+                warn!("Ignoring message of unknown type from the streamer");
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_message_hello(&mut self, hello: Hello) -> Result<(), AnyError> {
+        if is_newer_api_version(&hello.api_version) {
+            info!(
+                "Streamer has API version {}, newer than {}, ignoring what is not understood",
+                hello.api_version, API_VERSION
+            );
+        }
+        self.streamer_capabilities = hello.capabilities;
+        // The streamer answers in the encoding of the identify message.
+        if self.preferred_encoding == Encoding::Cbor
+            && self
+                .streamer_capabilities
+                .iter()
+                .any(|capability| capability == CAPABILITY_CBOR)
+        {
+            self.encoding = Encoding::Cbor;
+        }
+        let authentication = calculate_authentication(
+            self.password.expose_secret(),
+            &hello.authentication.salt,
+            &hello.authentication.challenge,
+        );
+        let compress = self.compression
+            && self
+                .streamer_capabilities
+                .iter()
+                .any(|capability| capability == CAPABILITY_DEFLATE);
+        let priority = self
+            .streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_PRIORITY);
+        let identify = Identify {
+            api_version: Some(API_VERSION.to_string()),
+            id: self.relay_id,
+            name: self.name.clone(),
+            authentication,
+            compression: compress.then(|| COMPRESSION_DEFLATE.to_string()),
+            capabilities: vec![
+                CAPABILITY_TCP_TUNNEL.to_string(),
+                CAPABILITY_STOP_TUNNEL.to_string(),
+                CAPABILITY_PAIRED_PORTS.to_string(),
+            ],
+            priority: priority.then_some(self.priority),
+            group: self.group.clone().filter(|_| priority),
+            challenge: None,
+        };
+        self.send(MessageToStreamer::Identify(identify)).await?;
+        if compress {
+            self.deflate = Some(Deflate::new());
+        }
+        Ok(())
+    }
+
+    async fn handle_message_identified(&mut self, identified: Identified) -> Result<(), AnyError> {
+        match identified.result {
+            MoblinkResult::Ok(_) => {
+                self.set_state(ConnectionState::Connected);
+                self.connect_failures = 0;
+                self.reconnect_attempt = 0;
+                self.emit_event(RelayEvent::StreamerConnected {
+                    streamer_url: self.streamer_url.clone(),
+                });
+                self.start_pinger();
+                self.start_status_pusher();
+                self.start_nat_discovery();
+            }
+            MoblinkResult::WrongPassword(_) => {
+                // Retrying with the same password is pointless.
+                self.set_last_error("Wrong password".to_string());
+                self.counters.add_authentication_failure();
+                self.emit_event(RelayEvent::AuthenticationFailed {
+                    streamer_url: self.streamer_url.clone(),
+                });
+                self.stop_internal(ConnectionState::WrongPassword).await;
+            }
+            MoblinkResult::Busy(_) | MoblinkResult::Error(_) | MoblinkResult::Unknown(_) => {
+                return Err("Unexpected identified result".into());
+            }
+        }
+        self.update_status();
+        Ok(())
+    }
+
+    async fn handle_message_request(&mut self, request: MessageRequest) -> Result<(), AnyError> {
+        if self.paused
+            && matches!(
+                request.data,
+                MessageRequestData::StartTunnel(_) | MessageRequestData::StartTcpTunnel(_)
+            )
+            && !self
+                .streamer_capabilities
+                .iter()
+                .any(|capability| capability == CAPABILITY_BUSY_RESULT)
+        {
+            info!("Paused, handling start tunnel request when resumed");
+            self.deferred_request = Some(request);
+            return Ok(());
+        }
+        match &request.data {
             MessageRequestData::StartTunnel(start_tunnel) => {
                 self.handle_message_request_start_tunnel(&request, start_tunnel)
                     .await
             }
-            MessageRequestData::Status(_) => self.handle_message_request_status(request).await,
-        }
+            MessageRequestData::Status(_) => self.handle_message_request_status(request).await,
+            MessageRequestData::StartTcpTunnel(start_tunnel) => {
+                self.handle_message_request_start_tcp_tunnel(&request, start_tunnel)
+                    .await
+            }
+            MessageRequestData::StopTunnel(stop_tunnel) => {
+                self.handle_message_request_stop_tunnel(&request, stop_tunnel)
+                    .await
+            }
+            // Not answered, as the response data of unknown requests is
+            // unknown too.
+            MessageRequestData::Unknown(_) => {
+                warn!("Ignoring request {} of unknown type", request.id);
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_message_request_start_tunnel(
+        &mut self,
+        request: &MessageRequest,
+        start_tunnel: &StartTunnelRequest,
+    ) -> Result<(), AnyError> {
+        let busy = if self.paused {
+            info!("Paused, rejecting start tunnel request");
+            true
+        } else if start_tunnel.paired_ports && self.upstream_relay.is_some() {
+            warn!("Paired ports cannot be forwarded through an upstream relay");
+            true
+        } else if start_tunnel.paired_ports && start_tunnel.port == u16::MAX {
+            warn!("No port after destination port {}", start_tunnel.port);
+            true
+        } else {
+            false
+        };
+        if busy {
+            let data = ResponseData::StartTunnel(StartTunnelResponseData { port: 0 });
+            return self.send_busy_response(request, data).await;
+        }
+        let rejected_data = || ResponseData::StartTunnel(StartTunnelResponseData { port: 0 });
+        // The upstream relay resolves the destination itself, and its nested
+        // tunnel is started before answering the streamer.
+        let mut upstream = None;
+        let mut destination_addresses = if let Some((url, password)) = self.upstream_relay.clone() {
+            match start_nested_tunnel(
+                &url,
+                password.expose_secret(),
+                &start_tunnel.address,
+                start_tunnel.port,
+                self.obfuscation_key.is_some(),
+            )
+            .await
+            {
+                Ok((connection, upstream_address, obfuscated)) => {
+                    info!(
+                        "Forwarding through upstream relay {} at {}",
+                        url, upstream_address
+                    );
+                    upstream = Some((connection, obfuscated));
+                    vec![upstream_address]
+                }
+                Err(error) => {
+                    let error = ErrorResult {
+                        code: ErrorCode::DestinationUnreachable,
+                        message: format!(
+                            "Failed to start nested tunnel in upstream relay {} with error: {}",
+                            url, error
+                        ),
+                    };
+                    return self.reject_request(request, rejected_data(), error).await;
+                }
+            }
+        } else {
+            match self
+                .resolve_destination_addresses(&start_tunnel.address, start_tunnel.port)
+                .await
+            {
+                Ok(destination_addresses) => destination_addresses,
+                Err(error) => {
+                    let error = ErrorResult {
+                        code: ErrorCode::ResolveFailed,
+                        message: format!(
+                            "Failed to resolve {} with error: {}",
+                            start_tunnel.address, error
+                        ),
+                    };
+                    return self.reject_request(request, rejected_data(), error).await;
+                }
+            }
+        };
+        let bind_failed = |what: &str, error: &dyn fmt::Display| ErrorResult {
+            code: ErrorCode::BindFailed,
+            message: format!("Failed to create {} with error: {}", what, error),
+        };
+        let destination_bind_address = match self.destination_bind_address() {
+            Ok(destination_bind_address) => destination_bind_address,
+            Err(error) => {
+                let error = bind_failed("destination socket", &error);
+                return self.reject_request(request, rejected_data(), error).await;
+            }
+        };
+        if self.preflight_check
+            && self.upstream_relay.is_none()
+            && let Err(error) =
+                probe_destination(destination_bind_address, &destination_addresses).await
+        {
+            let error = ErrorResult {
+                code: ErrorCode::DestinationUnreachable,
+                message: format!(
+                    "Destination {}:{} unreachable: {}",
+                    start_tunnel.address, start_tunnel.port, error
+                ),
+            };
+            return self.reject_request(request, rejected_data(), error).await;
+        }
+
+        // Create a UDP socket bound for receiving packets from the server.
+        // Use dual-stack socket creation. Paired ports are forwarded by one
+        // worker each.
+        let paired_ports = start_tunnel.paired_ports;
+        let reuse_port = self.workers > 1 && !paired_ports;
+        let streamer_sockets = if paired_ports {
+            self.bind_streamer_port(create_paired_streamer_udp_sockets)
+                .await
+                .map(|(streamer_socket, rtcp_socket)| (streamer_socket, Some(rtcp_socket)))
+        } else {
+            self.bind_streamer_port(|address| create_streamer_udp_socket(address, reuse_port))
+                .await
+                .map(|streamer_socket| (streamer_socket, None))
+        };
+        let (streamer_socket, rtcp_streamer_socket) = match streamer_sockets {
+            Ok(streamer_sockets) => streamer_sockets,
+            Err(error) => return self.reject_request(request, rejected_data(), error).await,
+        };
+        // Before the streamer sends anything, so that its datagrams are
+        // spread across all sockets from the start.
+        let streamer_worker_sockets = if reuse_port {
+            match self.create_streamer_worker_sockets(&streamer_socket) {
+                Ok(streamer_worker_sockets) => streamer_worker_sockets,
+                Err(error) => {
+                    let error = bind_failed("streamer worker sockets", &error);
+                    return self.reject_request(request, rejected_data(), error).await;
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        // Before answering, so that the streamer is only told about tunnels
+        // that can be started.
+        let (destination_socket, multipath_sockets) = match self.create_destination_sockets().await
+        {
+            Ok(destination_sockets) => destination_sockets,
+            Err(error) => {
+                let error = bind_failed("destination socket", &error);
+                return self.reject_request(request, rejected_data(), error).await;
+            }
+        };
+        let streamer_port = streamer_socket.local_addr()?.port();
+        debug!(
+            "Bound streamer socket on: {}, destination socket on: {}",
+            streamer_socket.local_addr()?,
+            self.bind_address
+        );
+        let streamer_socket = Arc::new(streamer_socket);
+
+        // Inform the server about the chosen port.
+        let data = ResponseData::StartTunnel(StartTunnelResponseData {
+            port: streamer_port,
+        });
+        let response = request.to_ok_response(data);
+        self.send(MessageToStreamer::Response(response)).await?;
+
+        let destination_address = destination_addresses.remove(0);
+        if paired_ports {
+            // The RTCP port follows the RTP port, so racing is not supported.
+            destination_addresses.clear();
+        }
+        if let Some((_, obfuscated)) = &upstream {
+            if *obfuscated {
+                self.destination_obfuscation = self.obfuscation_key.as_ref().map(Obfuscation::new);
+            } else if self.obfuscation_key.is_some() {
+                warn!("Upstream relay does not support obfuscation, not obfuscating");
+            }
+        }
+        self.next_tunnel_id += 1;
+        let tunnel = TunnelSockets {
+            info: TunnelInfo {
+                id: self.next_tunnel_id,
+                protocol: TunnelProtocol::Udp,
+                streamer_port,
+                destination_address,
+                annotation: None,
+            },
+            streamer_socket,
+            streamer_worker_sockets,
+            destination_socket,
+            multipath_sockets,
+            other_destination_addresses: destination_addresses,
+        };
+        self.start_tunnel(tunnel, false).await?;
+        self.tunnel_span
+            .in_scope(|| info!("Destination address: {}", destination_address));
+        if let Some((connection, _)) = upstream {
+            self.start_upstream_connection(connection);
+        }
+        if let Some(rtcp_streamer_socket) = rtcp_streamer_socket {
+            let rtcp_destination_address =
+                SocketAddr::new(destination_address.ip(), destination_address.port() + 1);
+            self.start_rtcp_forwarding(rtcp_streamer_socket, rtcp_destination_address)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message_request_start_tcp_tunnel(
+        &mut self,
+        request: &MessageRequest,
+        start_tunnel: &StartTunnelRequest,
+    ) -> Result<(), AnyError> {
+        let busy = if self.paused {
+            info!("Paused, rejecting start TCP tunnel request");
+            true
+        } else if self.upstream_relay.is_some() {
+            warn!("TCP tunnels cannot be forwarded through an upstream relay");
+            true
+        } else {
+            false
+        };
+        let rejected_data = || ResponseData::StartTcpTunnel(StartTunnelResponseData { port: 0 });
+        if busy {
+            return self.send_busy_response(request, rejected_data()).await;
+        }
+        let destination_addresses = match self
+            .resolve_destination_addresses(&start_tunnel.address, start_tunnel.port)
+            .await
+        {
+            Ok(destination_addresses) => destination_addresses,
+            Err(error) => {
+                let error = ErrorResult {
+                    code: ErrorCode::ResolveFailed,
+                    message: format!(
+                        "Failed to resolve {} with error: {}",
+                        start_tunnel.address, error
+                    ),
+                };
+                return self.reject_request(request, rejected_data(), error).await;
+            }
+        };
+        let listener = match self.bind_streamer_port(TcpListener::bind).await {
+            Ok(listener) => listener,
+            Err(error) => return self.reject_request(request, rejected_data(), error).await,
+        };
+        let streamer_port = listener.local_addr()?.port();
+        let data = ResponseData::StartTcpTunnel(StartTunnelResponseData {
+            port: streamer_port,
+        });
+        let response = request.to_ok_response(data);
+        self.send(MessageToStreamer::Response(response)).await?;
+        let destination_address = destination_addresses[0];
+        self.next_tunnel_id += 1;
+        let info = TunnelInfo {
+            id: self.next_tunnel_id,
+            protocol: TunnelProtocol::Tcp,
+            streamer_port,
+            destination_address,
+            annotation: None,
+        };
+        self.start_tcp_tunnel(info, destination_addresses, listener)
+            .await?;
+        self.tunnel_span
+            .in_scope(|| info!("Destination address: {}", destination_address));
+        Ok(())
+    }
+
+    /// The test report, if any, is sent before the confirmation.
+    async fn handle_message_request_stop_tunnel(
+        &mut self,
+        request: &MessageRequest,
+        stop_tunnel: &StopTunnelRequest,
+    ) -> Result<(), AnyError> {
+        let data = ResponseData::StopTunnel(Present {});
+        if self
+            .tunnel
+            .as_ref()
+            .is_none_or(|tunnel| tunnel.streamer_port != stop_tunnel.port)
+        {
+            let error = ErrorResult {
+                code: ErrorCode::UnknownTunnel,
+                message: format!("No tunnel on port {}", stop_tunnel.port),
+            };
+            return self.reject_request(request, data, error).await;
+        }
+        info!("Streamer stopped the tunnel on port {}", stop_tunnel.port);
+        // Not a tunnel error.
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        self.stop_tunnel(TunnelStopReason::Requested).await;
+        self.update_status();
+        let response = request.to_ok_response(data);
+        self.send(MessageToStreamer::Response(response)).await
+    }
+
+    /// Binds the streamer facing socket of a new tunnel with given function.
+    /// The current tunnel is stopped first, as the streamer only uses the
+    /// latest one, and with limited ports it may hold the only free one.
+    async fn bind_streamer_port<T, F, Fut>(&mut self, bind: F) -> Result<T, ErrorResult>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = Result<T, std::io::Error>>,
+    {
+        if let Some(tunnel) = &self.tunnel {
+            info!("Replacing tunnel {}", tunnel.id);
+        }
+        self.stop_tunnel(TunnelStopReason::Replaced).await;
+        let bind_failed = |address: SocketAddr, error: std::io::Error| ErrorResult {
+            code: ErrorCode::BindFailed,
+            message: format!("Failed to bind {} with error: {}", address, error),
+        };
+        let Some(tunnel_ports) = self.tunnel_ports else {
+            let address = SocketAddr::new(self.streamer_bind_address, 0);
+            return bind(address)
+                .await
+                .map_err(|error| bind_failed(address, error));
+        };
+        for port in tunnel_ports.first..=tunnel_ports.last {
+            let address = SocketAddr::new(self.streamer_bind_address, port);
+            match bind(address).await {
+                Ok(socket) => return Ok(socket),
+                Err(error) if error.kind() == ErrorKind::AddrInUse => continue,
+                Err(error) => return Err(bind_failed(address, error)),
+            }
+        }
+        Err(ErrorResult {
+            code: ErrorCode::NoPortsAvailable,
+            message: format!("No free tunnel port in {}", tunnel_ports),
+        })
+    }
+
+    /// With several workers, the kernel spreads the streamer's datagrams
+    /// across sockets bound to the same port by their source address and
+    /// port, so that the datagrams of each flow stay in order.
+    fn create_streamer_worker_sockets(
+        &self,
+        streamer_socket: &UdpSocket,
+    ) -> Result<Vec<Arc<UdpSocket>>, std::io::Error> {
+        let address = streamer_socket.local_addr()?;
+        (1..self.workers)
+            .map(|_| create_reuse_port_udp_socket(address).map(Arc::new))
+            .collect()
+    }
+
+    /// Replaces any previous tunnel, for example an adopted one.
+    fn set_tunnel(&mut self, info: TunnelInfo) {
+        self.tunnel_token.cancel();
+        if let Some(relay_to_destination) = self.relay_to_destination.take() {
+            relay_to_destination.abort();
+        }
+        self.tunnel_token = self.connection_token.child_token();
+        if let Some(tunnel) = self.tunnel.take() {
+            self.emit_event(RelayEvent::TunnelStopped {
+                tunnel,
+                reason: TunnelStopReason::Replaced,
+            });
+        }
+        self.set_tunnel_idle(false);
+        self.tunnel_span = info_span!(
+            "tunnel",
+            tunnel_id = info.id,
+            destination = %info.destination_address,
+            streamer_port = info.streamer_port
+        );
+        self.counters.set_tunnel_annotation(info.annotation.clone());
+        self.emit_event(RelayEvent::TunnelStarted {
+            tunnel: info.clone(),
+        });
+        self.tunnel = Some(info);
+    }
+
+    /// Keeps the nested tunnel in the upstream relay alive. The tunnel fails
+    /// if the upstream relay disconnects, and the streamer requests a new one,
+    /// and thereby a new nested tunnel, when the relay has reconnected.
+    fn start_upstream_connection(&mut self, mut connection: WebSocket) {
+        let relay = self.me.clone();
+        let reconnect_on_tunnel_error = self.reconnect_on_tunnel_error.clone();
+        let upstream_connection = async move {
+            while let Some(Ok(message)) = connection.next().await {
+                if let Message::Close(_) = message {
+                    break;
+                }
+            }
+            info!("Upstream relay disconnected");
+            let Some(relay) = relay.upgrade() else {
+                return;
+            };
+            let mut relay = relay.lock().await;
+            if *reconnect_on_tunnel_error.lock().await {
+                // Not aborted when the tunnel stops, as it is this task.
+                drop(relay.upstream_connection.take());
+            }
+            relay
+                .handle_tunnel_error("Upstream relay disconnected", &reconnect_on_tunnel_error)
+                .await;
+        };
+        self.upstream_connection =
+            Some(self.spawn(upstream_connection.instrument(self.tunnel_span.clone())));
+    }
+
+    /// The task ends when the tunnel stops, dropping the forwarding future at
+    /// its next await instead of aborting the task.
+    fn spawn_tunnel_task<F>(&self, forwarding: F) -> tokio::task::JoinHandle<Result<(), AnyError>>
+    where
+        F: Future<Output = Result<(), AnyError>> + Send + 'static,
+    {
+        let token = self.tunnel_token.clone();
+        let task = async move {
+            token
+                .run_until_cancelled(forwarding)
+                .await
+                .unwrap_or(Ok(()))
+        };
+        self.spawn(task.instrument(self.tunnel_span.clone()))
+    }
+
+    /// After the destination answered on another address than expected.
+    fn set_destination(&mut self, destination_socket: Arc<UdpSocket>, address: SocketAddr) {
+        if let Some(tunnel) = self.tunnel.as_mut() {
+            tunnel.destination_address = address;
+        }
+        if let Some((_, socket)) = self.tunnel_sockets.as_mut() {
+            *socket = destination_socket;
+        }
+    }
+
+    /// The address datagrams are sent to the destination from, or the first
+    /// multipath path's.
+    fn destination_bind_address(&self) -> Result<SocketAddr, std::io::Error> {
+        match self.multipath.first() {
+            Some(path) => Ok(SocketAddr::new(path.bind_address, 0)),
+            None => parse_socket_addr(&self.bind_address),
+        }
+    }
+
+    /// Answers with given error, or busy if the streamer does not understand
+    /// errors, so that it can use another relay.
+    async fn reject_request(
+        &mut self,
+        request: &MessageRequest,
+        data: ResponseData,
+        error: ErrorResult,
+    ) -> Result<(), AnyError> {
+        error!("{}, rejecting request", error.message);
+        self.set_last_error(error.message.clone());
+        if !self
+            .streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_ERROR_RESULT)
+        {
+            return self.send_busy_response(request, data).await;
+        }
+        let response = request.to_error_response(data, error);
+        self.send(MessageToStreamer::Response(response)).await
+    }
+
+    /// Streamers that do not understand `MoblinkResult::Busy` are not
+    /// answered, as before it existed.
+    async fn send_busy_response(
+        &mut self,
+        request: &MessageRequest,
+        data: ResponseData,
+    ) -> Result<(), AnyError> {
+        if !self
+            .streamer_capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_BUSY_RESULT)
+        {
+            debug!("Streamer does not support busy results, not answering");
+            return Ok(());
+        }
+        let response = request.to_busy_response(data);
+        self.send(MessageToStreamer::Response(response)).await
+    }
+
+    /// All addresses of the destination that can be reached from the bind
+    /// address, in the order to try them in. Never empty.
+    async fn resolve_destination_addresses(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, AnyError> {
+        let bind_address = self.destination_bind_address()?.ip();
+        let addresses = resolve_host_addresses(&self.dns_resolver, host).await?;
+        let addresses: Vec<SocketAddr> = sort_addresses(&addresses, bind_address)
+            .into_iter()
+            .map(|address| SocketAddr::new(address, port))
+            .collect();
+        if addresses.is_empty() {
+            return Err(format!("No address of {} reachable from {}", host, bind_address).into());
+        }
+        Ok(addresses)
+    }
+
+    /// One socket per multipath path, or one bound to the bind address.
+    async fn create_destination_sockets(
+        &self,
+    ) -> Result<(Arc<UdpSocket>, Vec<(Arc<UdpSocket>, u32)>), AnyError> {
+        if self.multipath.is_empty() {
+            let socket =
+                create_dual_stack_udp_socket(parse_socket_addr(&self.bind_address)?).await?;
+            return Ok((Arc::new(socket), Vec::new()));
+        }
+        let mut sockets = Vec::new();
+        for path in &self.multipath {
+            let socket =
+                create_dual_stack_udp_socket(SocketAddr::new(path.bind_address, 0)).await?;
+            sockets.push((Arc::new(socket), path.weight));
+        }
+        Ok((sockets[0].0.clone(), sockets))
+    }
+
+    /// Adopted tunnels are already registered with SRTLA receivers.
+    async fn start_tunnel(
+        &mut self,
+        tunnel: TunnelSockets,
+        adopted: bool,
+    ) -> Result<(), std::io::Error> {
+        let TunnelSockets {
+            info,
+            streamer_socket,
+            streamer_worker_sockets,
+            destination_socket,
+            mut multipath_sockets,
+            other_destination_addresses,
+        } = tunnel;
+        let srtla = self.is_srtla_destination();
+        // Racing sends the streamer's datagrams as is, and only to one path.
+        let race = !srtla
+            && multipath_sockets.is_empty()
+            && streamer_worker_sockets.is_empty()
+            && !self.test_mode
+            && self.streamer_fec.is_none()
+            && self.destination_fec.is_none();
+        if multipath_sockets.is_empty() {
+            multipath_sockets.push((destination_socket.clone(), 1));
+        }
+        // Connected to receive from the destination only, and to get ICMP
+        // errors.
+        for (socket, _) in &multipath_sockets {
+            socket.connect(info.destination_address).await?;
+        }
+        let mut other_destination_sockets = Vec::new();
+        if race {
+            let bind_address = SocketAddr::new(destination_socket.local_addr()?.ip(), 0);
+            for address in other_destination_addresses {
+                let socket = create_dual_stack_udp_socket(bind_address).await?;
+                socket.connect(address).await?;
+                other_destination_sockets.push((Arc::new(socket), address));
+            }
+        }
+        let srtla_sockets: Vec<Arc<UdpSocket>> = multipath_sockets
+            .iter()
+            .map(|(socket, _)| socket.clone())
+            .collect();
+        if srtla && !adopted {
+            srtla::register(&srtla_sockets).await?;
+        }
+        self.set_tunnel(info);
+        self.tunnel_sockets = Some((streamer_socket.clone(), destination_socket.clone()));
+        if srtla {
+            self.tunnel_span.in_scope(|| {
+                info!(
+                    "Registered {} connection(s) with the SRTLA receiver",
+                    srtla_sockets.len()
+                )
+            });
+            // Cancelled with the tunnel.
+            drop(self.spawn_tunnel_task(srtla::send_keepalives(srtla_sockets)));
+        }
+
+        // Cancelled with the tunnel.
+        drop(self.spawn_tunnel_task(monitor_path_mtu(
+            destination_socket.clone(),
+            self.counters.clone(),
+            self.path_mtu_probing,
+        )));
+        if self.test_mode {
+            self.start_prober(destination_socket);
+        }
+
+        self.relay_to_destination = Some(
+            self.start_relay_from_streamer_to_destination(
+                streamer_socket,
+                streamer_worker_sockets,
+                multipath_sockets,
+                other_destination_sockets,
+            )
+            .await,
+        );
+        Ok(())
+    }
+
+    /// Forwards the second port of a tunnel with paired ports, typically
+    /// RTCP. Stopped with the tunnel, and not passed to another process in
+    /// an upgrade.
+    async fn start_rtcp_forwarding(
+        &mut self,
+        streamer_socket: UdpSocket,
+        destination_address: SocketAddr,
+    ) -> Result<(), AnyError> {
+        let destination_socket =
+            create_dual_stack_udp_socket(self.destination_bind_address()?).await?;
+        destination_socket.connect(destination_address).await?;
+        let streamer_port = streamer_socket.local_addr()?.port();
+        self.tunnel_span.in_scope(|| {
+            info!(
+                "Forwarding port {} to {}",
+                streamer_port, destination_address
+            )
+        });
+        // Cancelled with the tunnel.
+        drop(self.spawn_tunnel_task(forward_rtcp(
+            streamer_socket,
+            destination_socket,
+            self.counters.clone(),
+        )));
+        Ok(())
+    }
+
+    /// TCP connections fail one by one without affecting the tunnel, and are
+    /// not passed to another process in an upgrade.
+    async fn start_tcp_tunnel(
+        &mut self,
+        info: TunnelInfo,
+        destination_addresses: Vec<SocketAddr>,
+        listener: TcpListener,
+    ) -> Result<(), AnyError> {
+        let bind_address = parse_socket_addr(&self.bind_address)?;
+        self.set_tunnel(info);
+        self.tunnel_sockets = None;
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        let relay_to_destination = relay_tcp_connections(
+            listener,
+            bind_address,
+            destination_addresses,
+            self.counters.clone(),
+        );
+        self.relay_to_destination = Some(self.spawn_tunnel_task(relay_to_destination));
+        Ok(())
+    }
+
+    /// Stops the relay, but keeps the tunnel sockets open so that they can be
+    /// passed to another process.
+    async fn hand_over(&mut self) -> Handover {
+        let tunnel = match (self.tunnel.take(), self.tunnel_sockets.take()) {
+            (Some(info), Some((streamer_socket, destination_socket))) => Some(TunnelSockets {
+                info,
+                streamer_socket,
+                streamer_worker_sockets: Vec::new(),
+                destination_socket,
+                multipath_sockets: Vec::new(),
+                other_destination_addresses: Vec::new(),
+            }),
+            _ => None,
+        };
+        self.stop().await;
+        Handover {
+            relay_id: self.relay_id,
+            name: self.name.clone(),
+            tunnel,
+        }
+    }
+
+    async fn start_static_tunnel(&mut self, static_tunnel: StaticTunnel) -> Result<(), AnyError> {
+        self.select_bind_address()?;
+        let mut destination_addresses = self
+            .resolve_destination_addresses(
+                &static_tunnel.destination_host,
+                static_tunnel.destination_port,
+            )
+            .await?;
+        let destination_address = destination_addresses[0];
+        self.next_tunnel_id += 1;
+        let info = TunnelInfo {
+            id: self.next_tunnel_id,
+            protocol: static_tunnel.protocol,
+            streamer_port: static_tunnel.listen_address.port(),
+            destination_address,
+            annotation: None,
+        };
+        match static_tunnel.protocol {
+            TunnelProtocol::Udp => {
+                let streamer_socket =
+                    create_streamer_udp_socket(static_tunnel.listen_address, self.workers > 1)
+                        .await?;
+                let streamer_worker_sockets =
+                    self.create_streamer_worker_sockets(&streamer_socket)?;
+                let (destination_socket, multipath_sockets) =
+                    self.create_destination_sockets().await?;
+                let tunnel = TunnelSockets {
+                    info,
+                    streamer_socket: Arc::new(streamer_socket),
+                    streamer_worker_sockets,
+                    destination_socket,
+                    multipath_sockets,
+                    other_destination_addresses: destination_addresses.split_off(1),
+                };
+                self.start_tunnel(tunnel, false).await?;
+            }
+            TunnelProtocol::Tcp => {
+                let listener = TcpListener::bind(static_tunnel.listen_address).await?;
+                self.start_tcp_tunnel(info, destination_addresses, listener)
+                    .await?;
+            }
+        }
+        // There is no streamer to reconnect to.
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        self.tunnel_span.in_scope(|| {
+            info!(
+                "Static tunnel from {} to {}",
+                static_tunnel.listen_address, destination_address
+            )
+        });
+        Ok(())
+    }
+
+    async fn adopt_tunnel(&mut self, tunnel: TunnelSockets) {
+        self.next_tunnel_id = self.next_tunnel_id.max(tunnel.info.id);
+        if let Err(error) = self.start_tunnel(tunnel, true).await {
+            error!("Failed to adopt tunnel with error: {}", error);
+            return;
+        }
+        self.tunnel_span.in_scope(|| info!("Adopted tunnel"));
+    }
+
+    /// Datagrams are scattered across the destination sockets by weight, and
+    /// the return traffic from all of them is merged. With other destination
+    /// sockets, the first one the destination answers on is used, see
+    /// `race_destination_sockets()`. Each streamer worker socket is forwarded
+    /// by its own task, and the tunnel fails if any of them does.
+    async fn start_relay_from_streamer_to_destination(
+        &mut self,
+        streamer_socket: Arc<UdpSocket>,
+        streamer_worker_sockets: Vec<Arc<UdpSocket>>,
+        destination_sockets: Vec<(Arc<UdpSocket>, u32)>,
+        other_destination_sockets: Vec<(Arc<UdpSocket>, SocketAddr)>,
+    ) -> tokio::task::JoinHandle<Result<(), AnyError>> {
+        *self.reconnect_on_tunnel_error.lock().await = false;
+        let reconnect_on_tunnel_error = Arc::new(Mutex::new(true));
+        self.reconnect_on_tunnel_error = reconnect_on_tunnel_error.clone();
+        let relay = self.me.clone();
+        let counters = self.counters.clone();
+        let probe_stats = self.probe_stats.clone();
+        let srt_inspector = self.srt_inspector.clone();
+        let destination_timeout = self.destination_timeout;
+        let nat_keepalive_interval = self.nat_keepalive_interval;
+        let destination_fec = self.destination_fec;
+        let streamer_fec = self.streamer_fec;
+        let destination_obfuscation = self.destination_obfuscation.clone();
+        let duplication = self.duplication.clone();
+        let pacing = self.pacing.clone();
+        let capture = self.capture.clone();
+        let srtla = self.is_srtla_destination();
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.io_uring {
+            if streamer_worker_sockets.is_empty()
+                && destination_sockets.len() == 1
+                && other_destination_sockets.is_empty()
+                && destination_fec.is_none()
+                && streamer_fec.is_none()
+                && destination_obfuscation.is_none()
+                && srt_inspector.is_none()
+                && probe_stats.is_none()
+                && nat_keepalive_interval.is_none()
+                && self.get_pacing().is_none()
+                && !duplication.load(Ordering::Relaxed)
+                && capture.is_none()
+                && !srtla
+            {
+                return self.start_uring_relay(
+                    streamer_socket,
+                    destination_sockets[0].0.clone(),
+                    reconnect_on_tunnel_error,
+                );
+            }
+            info!("Not using io_uring, as it does not support the tunnel options");
+        }
+
+        let create_worker = |streamer_socket: Arc<UdpSocket>| {
+            let relay = relay.clone();
+            let counters = counters.clone();
+            let probe_stats = probe_stats.clone();
+            let srt_inspector = srt_inspector.clone();
+            let duplication = duplication.clone();
+            let reconnect_on_tunnel_error = reconnect_on_tunnel_error.clone();
+            let mut destination_sockets = destination_sockets.clone();
+            let other_destination_sockets = other_destination_sockets.clone();
+            let capture = capture.clone();
+            let destination_obfuscation = destination_obfuscation.clone();
+            let mut pacer = Pacer::new(pacing.clone());
+            async move {
+                if !other_destination_sockets.is_empty() {
+                    let mut sockets = vec![destination_sockets[0].0.clone()];
+                    sockets.extend(
+                        other_destination_sockets
+                            .iter()
+                            .map(|(socket, _)| socket.clone()),
+                    );
+                    let index =
+                        race_destination_sockets(&streamer_socket, &sockets, &counters).await?;
+                    if index > 0 {
+                        let (socket, address) = other_destination_sockets[index - 1].clone();
+                        info!("Destination answered on {}", address);
+                        destination_sockets = vec![(socket.clone(), 1)];
+                        if let Some(relay) = relay.upgrade() {
+                            relay.lock().await.set_destination(socket, address);
+                        }
+                    }
+                }
+                let mut fec = Fec::new(
+                    streamer_fec.is_some(),
+                    destination_fec,
+                    None,
+                    destination_obfuscation.clone(),
+                );
+                let weights: Vec<u32> = destination_sockets
+                    .iter()
+                    .map(|(_, weight)| *weight)
+                    .collect();
+                let mut scheduler = Scheduler::new(&weights);
+                let destination_sockets: Vec<Arc<UdpSocket>> = destination_sockets
+                    .into_iter()
+                    .map(|(socket, _)| socket)
+                    .collect();
+                let destination_address = destination_sockets[0].peer_addr()?;
+                let streamer_address = Arc::new(Mutex::new(None));
+                // Stopped with this task.
+                let mut relay_to_streamer: Option<AbortOnDrop> = None;
+                let mut nat_keepalive: Option<AbortOnDrop> = None;
+                // Received into directly. Queued datagrams are handed over to
+                // the pacer and replaced by another buffer from the pool.
+                let mut pool = BufferPool::default();
+                let mut buf = pool.take();
+
+                loop {
+                    let (_, remote_addr) = tokio::select! {
+                        result = streamer_socket.recv_buf_from(&mut buf) => result?,
+                        _ = sleep_until_or_forever(pacer.send_at()) => {
+                            if let Some(datagram) = pacer.pop(&counters) {
+                                send_to_destination(
+                                    &destination_sockets,
+                                    &mut scheduler,
+                                    duplication.load(Ordering::Relaxed),
+                                    &datagram,
+                                    &counters,
+                                )
+                                .await?;
+                                pool.give_bytes(datagram);
+                            }
+                            continue;
+                        }
+                    };
+                    if let Some(capture) = &capture {
+                        capture.record(remote_addr, destination_address, &buf);
+                    }
+                    match &mut fec {
+                        Some(fec) => {
+                            let datagrams = fec.process(&buf, |payload| {
+                                if let Some(srt_inspector) = &srt_inspector {
+                                    srt_inspector.handle_packet(Direction::ToDestination, payload);
+                                }
+                            });
+                            buf.clear();
+                            for datagram in datagrams {
+                                if pacer.is_queueing() {
+                                    pacer.push(Bytes::from(datagram), &counters);
+                                    continue;
+                                }
+                                send_to_destination(
+                                    &destination_sockets,
+                                    &mut scheduler,
+                                    duplication.load(Ordering::Relaxed),
+                                    &datagram,
+                                    &counters,
+                                )
+                                .await?;
+                            }
+                        }
+                        None if pacer.is_queueing() => {
+                            if let Some(srt_inspector) = &srt_inspector {
+                                srt_inspector.handle_packet(Direction::ToDestination, &buf);
+                            }
+                            let datagram = std::mem::replace(&mut buf, pool.take());
+                            pacer.push(datagram.freeze(), &counters);
+                        }
+                        None => {
+                            send_to_destination(
+                                &destination_sockets,
+                                &mut scheduler,
+                                duplication.load(Ordering::Relaxed),
+                                &buf,
+                                &counters,
+                            )
+                            .await?;
+                            if let Some(srt_inspector) = &srt_inspector {
+                                srt_inspector.handle_packet(Direction::ToDestination, &buf);
+                            }
+                            buf.clear();
+                        }
+                    }
+                    streamer_address.lock().await.replace(remote_addr);
+                    if nat_keepalive.is_none()
+                        && let Some(nat_keepalive_interval) = nat_keepalive_interval
+                    {
+                        nat_keepalive = Some(AbortOnDrop(start_nat_keepalive(
+                            streamer_socket.clone(),
+                            streamer_address.clone(),
+                            counters.clone(),
+                            nat_keepalive_interval,
+                        )));
+                    }
+
+                    // Restarted if it failed without reconnecting, for example in
+                    // static tunnels.
+                    if relay_to_streamer
+                        .as_ref()
+                        .is_none_or(|relay_to_streamer| relay_to_streamer.0.is_finished())
+                    {
+                        relay_to_streamer =
+                            Some(AbortOnDrop(start_relay_from_destination_to_streamer(
+                                relay.clone(),
+                                streamer_socket.clone(),
+                                destination_sockets.clone(),
+                                streamer_address.clone(),
+                                reconnect_on_tunnel_error.clone(),
+                                counters.clone(),
+                                probe_stats.clone(),
+                                srt_inspector.clone(),
+                                destination_timeout,
+                                Fec::new(
+                                    destination_fec.is_some(),
+                                    streamer_fec,
+                                    destination_obfuscation.clone(),
+                                    None,
+                                ),
+                                capture.clone(),
+                                srtla,
+                            )));
+                    }
+                }
+            }
+        };
+        let span = self.tunnel_span.clone();
+        if streamer_worker_sockets.is_empty() {
+            return self.spawn_tunnel_task(create_worker(streamer_socket));
+        }
+        let workers = streamer_worker_sockets.len() + 1;
+        span.in_scope(|| info!("Forwarding with {} workers", workers));
+        let workers: Vec<_> = std::iter::once(streamer_socket)
+            .chain(streamer_worker_sockets)
+            .map(create_worker)
+            .collect();
+        let relay_to_destination = async move {
+            // The other workers are stopped when the set is dropped.
+            let mut join_set = JoinSet::new();
+            for worker in workers {
+                join_set.spawn(worker.in_current_span());
+            }
+            match join_set.join_next().await {
+                Some(result) => result?,
+                None => Ok(()),
+            }
+        };
+        self.spawn_tunnel_task(relay_to_destination)
+    }
+
+    /// Forwards on a dedicated io_uring thread, supervised by the returned
+    /// task.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn start_uring_relay(
+        &mut self,
+        streamer_socket: Arc<UdpSocket>,
+        destination_socket: Arc<UdpSocket>,
+        reconnect_on_tunnel_error: Arc<Mutex<bool>>,
+    ) -> tokio::task::JoinHandle<Result<(), AnyError>> {
+        let relay = self.me.clone();
+        let counters = self.counters.clone();
+        let destination_timeout = self.destination_timeout;
+        let relay_to_destination = async move {
+            // Stopped with this task.
+            let mut forwarder =
+                UringForwarder::start(streamer_socket, destination_socket, counters)?;
+            info!("Forwarding with io_uring");
+            let mut idle = false;
+            let error = loop {
+                tokio::select! {
+                    error = forwarder.wait() => break error,
+                    _ = sleep(Duration::from_secs(1)) => {
+                        let Some(destination_timeout) = destination_timeout else {
+                            continue;
+                        };
+                        if (forwarder.since_destination_received() > destination_timeout) == idle {
+                            continue;
+                        }
+                        idle = !idle;
+                        if let Some(relay) = relay.upgrade() {
+                            let mut relay = relay.lock().await;
+                            if idle {
+                                relay.handle_tunnel_idle();
+                            } else {
+                                relay.handle_tunnel_active();
+                            }
+                        }
+                    }
+                }
+            };
+            info!("(io_uring) Failed with error: {}", error);
+            if let Some(relay) = relay.upgrade() {
+                relay
+                    .lock()
+                    .await
+                    .handle_tunnel_error(error, &reconnect_on_tunnel_error)
+                    .await;
+            }
+            Ok(())
+        };
+        self.spawn_tunnel_task(relay_to_destination)
     }
 
-    async fn handle_message_request_start_tunnel(
+    async fn handle_message_request_status(
         &mut self,
-        request: &MessageRequest,
-        start_tunnel: &StartTunnelRequest,
+        request: MessageRequest,
     ) -> Result<(), AnyError> {
-        // Pick bind addresses from the relay
-        let local_bind_addr_for_streamer = parse_socket_addr("0.0.0.0")?;
-        let local_bind_addr_for_destination = parse_socket_addr(&self.bind_address)?;
-
-        debug!(
-            "Binding streamer socket on: {}, destination socket on: {}",
-            local_bind_addr_for_streamer, local_bind_addr_for_destination
-        );
-        // Create a UDP socket bound for receiving packets from the server.
-        // Use dual-stack socket creation.
-        let streamer_socket = create_dual_stack_udp_socket(local_bind_addr_for_streamer).await?;
-        let streamer_port = streamer_socket.local_addr()?.port();
-        let streamer_socket = Arc::new(streamer_socket);
-
-        // Inform the server about the chosen port.
-        let data = ResponseData::StartTunnel(StartTunnelResponseData {
-            port: streamer_port,
+        let battery_percentage = self.get_status().await.battery_percentage;
+        let data = ResponseData::Status(StatusResponseData {
+            battery_percentage,
+            quality_score: self.get_reported_quality_score(),
         });
         let response = request.to_ok_response(data);
-        self.send(MessageToStreamer::Response(response)).await?;
+        self.send(MessageToStreamer::Response(response)).await
+    }
 
-        // Create a new UDP socket for communication with the destination.
-        // Use dual-stack socket creation.
-        let destination_socket =
-            create_dual_stack_udp_socket(local_bind_addr_for_destination).await?;
-
-        let destination_socket = Arc::new(destination_socket);
-        let destination_address = resolve_host(&start_tunnel.address).await?;
-        let destination_address = match IpAddr::from_str(&destination_address)? {
-            IpAddr::V4(v4) => IpAddr::V4(v4),
-            IpAddr::V6(v6) => {
-                // If it’s an IPv4-mapped IPv6 like ::ffff:x.x.x.x, convert to real IPv4
-                if let Some(mapped_v4) = v6.to_ipv4() {
-                    IpAddr::V4(mapped_v4)
-                } else {
-                    // Otherwise, keep it as IPv6
-                    IpAddr::V6(v6)
-                }
-            }
+    async fn send(&mut self, message: MessageToStreamer) -> Result<(), AnyError> {
+        let mut message = self.encoding.encode(&message)?;
+        if let Some(deflate) = self.deflate.as_mut() {
+            message = Message::Binary(deflate.compress(&message.into_data())?.into());
+        }
+        self.send_message(message).await
+    }
+
+    fn decode_binary_message(&mut self, data: &[u8]) -> Result<MessageToRelay, AnyError> {
+        match self.deflate.as_mut() {
+            Some(deflate) => self.encoding.decode(&deflate.decompress(data)?),
+            None => decode_cbor(data),
+        }
+    }
+
+    async fn send_message(&mut self, message: Message) -> Result<(), AnyError> {
+        let Some(writer) = self.ws_writer.as_mut() else {
+            return Err("No websocket writer".into());
         };
+        writer.send(message).await?;
+        Ok(())
+    }
+}
 
-        let destination_address = SocketAddr::new(destination_address, start_tunnel.port);
-        info!("Destination address: {}", destination_address);
+#[derive(Clone)]
+pub struct Relay {
+    inner: Arc<Mutex<RelayInner>>,
+}
 
-        self.relay_to_destination = Some(
-            self.start_relay_from_streamer_to_destination(
-                streamer_socket,
-                destination_socket,
-                destination_address,
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self {
+            inner: RelayInner::new(),
+        }
+    }
+
+    pub fn builder(streamer_url: String) -> RelayBuilder {
+        RelayBuilder::new(streamer_url)
+    }
+
+    /// Runs given task on the runtime set with `set_runtime()`, if any, so
+    /// that the sockets it creates belong to that runtime.
+    async fn run_on_runtime<F>(&self, task: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let Some(runtime) = self.inner.lock().await.runtime.clone() else {
+            return task.await;
+        };
+        match runtime.spawn(task).await {
+            Ok(output) => output,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(_) => panic!("Relay runtime shut down"),
+        }
+    }
+
+    pub async fn set_bind_address(&self, address: String) {
+        self.inner.lock().await.set_bind_address(address);
+    }
+
+    /// Select the bind address among the addresses of the automatically
+    /// selected network interface when started. Overridden by
+    /// `set_bind_address()`.
+    pub async fn set_address_selection(&self, address_selection: AddressSelection) {
+        self.inner
+            .lock()
+            .await
+            .set_address_selection(address_selection);
+    }
+
+    /// Select the network interface to bind to with given policy when
+    /// started. Overridden by `set_bind_address()`.
+    pub async fn set_interface_policy(&self, interface_policy: InterfacePolicy) {
+        self.inner
+            .lock()
+            .await
+            .set_interface_policy(interface_policy);
+    }
+
+    /// Resolve the streamer URL and the tunnel destinations with given
+    /// resolver, on every connect and tunnel start. The system resolver by
+    /// default.
+    pub async fn set_dns_resolver(&self, dns_resolver: DnsResolver) {
+        self.inner.lock().await.set_dns_resolver(dns_resolver);
+    }
+
+    /// Spawn all tasks, and connect, on given runtime instead of the
+    /// caller's. The runtime must outlive the relay. Should be called before
+    /// `start()`.
+    pub async fn set_runtime(&self, runtime: Handle) {
+        self.inner.lock().await.set_runtime(runtime);
+    }
+
+    /// Measure latency and loss with probe packets sent to a cooperating
+    /// destination. For pre-show testing only.
+    pub async fn set_test_mode(&self, enabled: bool) {
+        self.inner.lock().await.set_test_mode(enabled);
+    }
+
+    /// Send an empty datagram to the destination before accepting a start
+    /// tunnel request, and reject the request if an ICMP error shows that
+    /// the destination is unreachable. Delays tunnel starts by up to half a
+    /// second. Not done with an upstream relay.
+    pub async fn set_preflight_check(&self, enabled: bool) {
+        self.inner.lock().await.set_preflight_check(enabled);
+    }
+
+    /// Encode control messages with given encoding if the streamer supports
+    /// it, and JSON otherwise. JSON by default. Applied on the next connection
+    /// to the streamer.
+    pub async fn set_preferred_encoding(&self, encoding: Encoding) {
+        self.inner.lock().await.set_preferred_encoding(encoding);
+    }
+
+    /// Experimental. Send to the destination from several local interfaces
+    /// instead of from the bind address, to aggregate weak uplinks. Datagrams
+    /// are scattered across the paths by weight, and the return traffic from
+    /// all of them is merged. Applied to the next tunnel.
+    pub async fn set_multipath(&self, multipath: Vec<MultipathPath>) {
+        self.inner.lock().await.set_multipath(multipath);
+    }
+
+    /// Experimental. Forward error correction with given group size on the
+    /// leg to the destination and/or the streamer. One parity packet is sent
+    /// per group, and one lost packet per group can be recovered. The other
+    /// end of the leg must be a Moblink relay with FEC enabled, typically a
+    /// static tunnel with FEC on the streamer leg in front of the real
+    /// destination. Applied to the next tunnel.
+    pub async fn set_fec(&self, destination_fec: Option<u8>, streamer_fec: Option<u8>) {
+        self.inner
+            .lock()
+            .await
+            .set_fec(destination_fec, streamer_fec);
+    }
+
+    /// Send every datagram to the destination on all multipath paths, or twice
+    /// without multipath, for example for the last kilometer of a marathon.
+    /// The destination must tolerate duplicates, as SRT does. Takes effect
+    /// immediately, also for a running tunnel.
+    pub async fn set_duplication(&self, enabled: bool) {
+        self.inner.lock().await.set_duplication(enabled);
+    }
+
+    pub async fn is_duplicating(&self) -> bool {
+        self.inner.lock().await.is_duplicating()
+    }
+
+    /// Smooth datagrams to the destination to given bitrate or gap between
+    /// datagrams, for example so that bursts from SRT senders do not
+    /// overflow the buffers of a cellular modem. Datagrams are queued for at
+    /// most a second, and dropped if the queue does not drain. Off by
+    /// default. Takes effect immediately, also for a running tunnel.
+    pub async fn set_pacing(&self, pacing: Option<Pacing>) -> Result<(), RelayError> {
+        self.inner.lock().await.set_pacing(pacing)
+    }
+
+    /// The pacing in effect, which is throttled while overheating, see
+    /// `set_thermal_monitoring()`.
+    pub async fn pacing(&self) -> Option<Pacing> {
+        self.inner.lock().await.get_pacing()
+    }
+
+    /// Forward the datagrams from the streamer in given number of tasks, each
+    /// with its own socket bound to the tunnel port with SO_REUSEPORT, for
+    /// very high bitrates. The kernel assigns each flow from the streamer to
+    /// one socket, so the datagrams of a flow stay in order. One by default.
+    /// Unix only. Applied to new tunnels.
+    pub async fn set_workers(&self, workers: usize) {
+        self.inner.lock().await.set_workers(workers);
+    }
+
+    /// Forward tunnel datagrams with io_uring on a dedicated thread, which
+    /// uses less CPU at high bitrates. Not used for tunnels with multipath,
+    /// FEC, SRT inspection, test mode, NAT keepalives, pacing, duplication or
+    /// several destination addresses, which are forwarded as usual. Pacing
+    /// and duplication are not started in tunnels forwarded with io_uring.
+    /// Applied to new tunnels.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub async fn set_io_uring(&self, enabled: bool) {
+        self.inner.lock().await.set_io_uring(enabled);
+    }
+
+    /// Write the datagrams relayed in UDP tunnels, in both directions, to
+    /// given capture. A capture may be shared by several relays. Tunnels
+    /// with a capture are not forwarded with io_uring. Applied to new
+    /// tunnels.
+    pub async fn set_capture(&self, capture: Option<Arc<Capture>>) {
+        self.inner.lock().await.set_capture(capture);
+    }
+
+    /// Compress control messages with deflate if the streamer supports it.
+    /// Off by default. Applied on the next connection to the streamer.
+    pub async fn set_compression(&self, enabled: bool) {
+        self.inner.lock().await.set_compression(enabled);
+    }
+
+    /// Ask the streamer to use this relay only when no main relay is
+    /// connected, with `RelayPriority::Backup`, and/or put it in given group.
+    /// Only if the streamer supports it. Main and no group by default.
+    /// Applied on the next connection to the streamer.
+    pub async fn set_priority(&self, priority: RelayPriority, group: Option<String>) {
+        self.inner.lock().await.set_priority(priority, group);
+    }
+
+    /// Proactively send status and bitrates to the streamer this often. Only
+    /// if the streamer supports it. Never if `None`, which is the default.
+    /// Applied on the next connection to the streamer.
+    pub async fn set_status_push_interval(&self, status_push_interval: Option<Duration>) {
+        self.inner
+            .lock()
+            .await
+            .set_status_push_interval(status_push_interval);
+    }
+
+    /// Emit `RelayEvent::TunnelIdle` if nothing is received from the
+    /// destination for given time. Never if `None`. 30 seconds by default.
+    pub async fn set_destination_timeout(&self, destination_timeout: Option<Duration>) {
+        self.inner
+            .lock()
+            .await
+            .set_destination_timeout(destination_timeout);
+    }
+
+    /// Send an empty datagram to the streamer if nothing has been sent to it
+    /// for given time, to keep NAT mappings on the way to the streamer alive
+    /// while the stream is paused. Never if `None`, which is the default.
+    pub async fn set_nat_keepalive_interval(&self, nat_keepalive_interval: Option<Duration>) {
+        self.inner
+            .lock()
+            .await
+            .set_nat_keepalive_interval(nat_keepalive_interval);
+    }
+
+    /// Bind the streamer facing sockets of tunnels to ports in given range.
+    /// Start tunnel requests are rejected with
+    /// `ErrorCode::NoPortsAvailable` if all of them are in use. Any free port
+    /// if `None`, which is the default.
+    pub async fn set_tunnel_ports(&self, tunnel_ports: Option<PortRange>) {
+        self.inner.lock().await.set_tunnel_ports(tunnel_ports);
+    }
+
+    /// Bind the streamer facing sockets of tunnels to given address, for
+    /// example the one on the network towards the streamer on a multi-homed
+    /// machine. All addresses (`0.0.0.0`) by default. The destination facing
+    /// sockets use the bind address.
+    pub async fn set_streamer_bind_address(&self, streamer_bind_address: IpAddr) {
+        self.inner
+            .lock()
+            .await
+            .set_streamer_bind_address(streamer_bind_address);
+    }
+
+    /// Connect to the streamer through given proxy. The UDP tunnels do not use
+    /// the proxy.
+    pub async fn set_proxy(&self, proxy: Option<Proxy>) {
+        self.inner.lock().await.set_proxy(proxy);
+    }
+
+    /// Extra headers in the WebSocket handshake with the streamer, for example
+    /// `Authorization` for streamers behind a reverse proxy.
+    pub async fn set_headers(&self, headers: Vec<(String, String)>) {
+        self.inner.lock().await.set_headers(headers);
+    }
+
+    /// Streamer URLs to fail over to, in order, when repeatedly failing to
+    /// connect to the primary streamer URL given to `setup()`.
+    pub async fn set_backup_streamer_urls(&self, streamer_urls: Vec<String>) {
+        self.inner
+            .lock()
+            .await
+            .set_backup_streamer_urls(streamer_urls);
+    }
+
+    /// Forward tunnels through another relay's chain server (see
+    /// `ChainServer`) instead of directly to the destination.
+    pub async fn set_upstream_relay(&self, url: String, password: impl Into<SecretString>) {
+        self.inner
+            .lock()
+            .await
+            .set_upstream_relay(url, password.into());
+    }
+
+    /// Obfuscate tunnel datagrams to the upstream relay with given pre-shared
+    /// key, so that carriers cannot recognize and throttle for example SRT.
+    /// Only if the upstream relay has the same key, and not obfuscated
+    /// otherwise. Takes effect for the next tunnel.
+    pub async fn set_obfuscation_key(&self, obfuscation_key: Option<SecretString>) {
+        self.inner.lock().await.set_obfuscation_key(obfuscation_key);
+    }
+
+    /// Speak SRTLA to the destination, which must be an SRTLA receiver, for
+    /// example srtla_rec. The relay registers with it as a group of one
+    /// connection, or one per multipath path, before forwarding. Not when
+    /// forwarding through an upstream relay, which should do it instead, or
+    /// with destination FEC. Takes effect for the next tunnel.
+    pub async fn set_srtla(&self, srtla: bool) {
+        self.inner.lock().await.set_srtla(srtla);
+    }
+
+    /// Probe the path MTU to the destination every minute, by sending a
+    /// datagram of the largest size the kernel thinks fits, filled with
+    /// zeros, with fragmentation prohibited. Without probing, only the path
+    /// MTU the kernel already knows is used to warn about datagrams that are
+    /// fragmented. Linux only. Off by default. Applied to new tunnels.
+    pub async fn set_path_mtu_probing(&self, enabled: bool) {
+        self.inner.lock().await.set_path_mtu_probing(enabled);
+    }
+
+    /// Discover the NAT type with given STUN servers (`host:port`) when
+    /// connected to the streamer. Give at least two to detect symmetric NAT.
+    pub async fn set_stun_servers(&self, stun_servers: Vec<String>) {
+        self.inner.lock().await.set_stun_servers(stun_servers);
+    }
+
+    /// Poll ModemManager for cellular modem status, see `get_modems()`.
+    /// Requires the `modemmanager` feature on Linux.
+    pub async fn set_modem_monitoring(&self, enabled: bool) {
+        self.inner.lock().await.set_modem_monitoring(enabled);
+    }
+
+    /// Poll the thermal zones, see `get_thermal_zones()`, and emit
+    /// `RelayEvent::Overheating` when the hottest zone reaches the warning
+    /// temperature. Disabled if `None`. Linux only.
+    pub async fn set_thermal_monitoring(
+        &self,
+        thermal: Option<ThermalConfig>,
+    ) -> Result<(), RelayError> {
+        self.inner.lock().await.set_thermal_monitoring(thermal)
+    }
+
+    /// Account the bytes relayed to and from the destination to the interface
+    /// of the bind address every 30 seconds, and check the quota of given
+    /// data usage. Disabled if `None`.
+    pub async fn set_data_usage(&self, data_usage: Option<Arc<DataUsage>>) {
+        self.inner.lock().await.set_data_usage(data_usage);
+    }
+
+    /// Inspect SRT headers to estimate loss and jitter, see `get_stats()`.
+    /// Packets are never modified.
+    pub async fn set_srt_inspection(&self, enabled: bool) {
+        self.inner.lock().await.set_srt_inspection(enabled);
+    }
+
+    pub async fn set_on_event<F>(&self, on_event: F)
+    where
+        F: Fn(RelayEvent) + Send + Sync + 'static,
+    {
+        self.inner.lock().await.set_on_event(Box::new(on_event));
+    }
+
+    /// Use given status provider instead of the status closure given to
+    /// `setup()`.
+    pub async fn set_status_provider(&self, status_provider: Arc<dyn StatusProvider>) {
+        self.inner.lock().await.set_status_provider(status_provider);
+    }
+
+    /// Use given status executable instead of any status provider. It gets
+    /// the relay state in the `MOBLINK_RELAY_NAME`, `MOBLINK_RELAY_ID`,
+    /// `MOBLINK_STREAMER_URL`, `MOBLINK_BIND_ADDRESS`, `MOBLINK_CONNECTED` and
+    /// `MOBLINK_PAUSED` environment variables.
+    pub async fn set_status_executable(&self, status_executable: Option<StatusExecutable>) {
+        self.inner
+            .lock()
+            .await
+            .set_status_executable(status_executable);
+    }
+
+    /// Report all metrics to given sink, labelled with the relay name.
+    pub async fn set_metrics_sink(&self, sink: Arc<dyn MetricsSink>) {
+        self.inner.lock().await.set_metrics_sink(sink);
+    }
+
+    pub async fn setup<F>(
+        &self,
+        streamer_url: String,
+        password: impl Into<SecretString>,
+        relay_id: Uuid,
+        name: String,
+        on_status_updated: F,
+        get_status: Option<GetStatusClosure>,
+    ) -> Result<(), RelayError>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        self.inner
+            .lock()
+            .await
+            .setup(
+                streamer_url,
+                password.into(),
+                relay_id,
+                name,
+                on_status_updated,
+                get_status,
             )
-            .await,
-        );
+            .await
+    }
+
+    pub async fn is_started(&self) -> bool {
+        self.inner.lock().await.is_started()
+    }
+
+    pub async fn is_connected(&self) -> bool {
+        self.inner.lock().await.is_connected()
+    }
+
+    pub async fn get_status_text(&self) -> String {
+        self.inner.lock().await.get_status_text()
+    }
+
+    pub async fn get_streamer_url(&self) -> String {
+        self.inner.lock().await.get_streamer_url()
+    }
+
+    /// Connection state, identity, tunnel and last error in one snapshot.
+    pub async fn state(&self) -> RelayState {
+        self.inner.lock().await.get_state()
+    }
+
+    /// Resolves once connected to and identified by the streamer, waiting
+    /// for `start()` if not started, for example to start an encoder only
+    /// when a relay is connected. Fails if the streamer rejects the password
+    /// or if the relay is stopped.
+    pub async fn await_connected(&self) -> Result<(), RelayError> {
+        let mut state = self.inner.lock().await.state_sender.subscribe();
+        let mut started = false;
+        let state = *state
+            .wait_for(|state| {
+                started |= *state != ConnectionState::Idle;
+                match state {
+                    ConnectionState::Connected | ConnectionState::WrongPassword => true,
+                    ConnectionState::Idle => started,
+                    _ => false,
+                }
+            })
+            .await
+            .map_err(|_| RelayError::Stopped)?;
+        match state {
+            ConnectionState::Connected => Ok(()),
+            ConnectionState::WrongPassword => Err(RelayError::WrongPassword),
+            _ => Err(RelayError::Stopped),
+        }
+    }
+
+    /// Resolves once stopped, with the connection to the streamer and the
+    /// tunnel closed. At once if not started.
+    pub async fn await_stopped(&self) {
+        let mut state = self.inner.lock().await.state_sender.subscribe();
+        state
+            .wait_for(|state| *state == ConnectionState::Idle)
+            .await
+            .ok();
+    }
+
+    pub async fn get_stats(&self) -> RelayStats {
+        self.inner.lock().await.get_stats()
+    }
+
+    pub async fn get_tunnels(&self) -> Vec<TunnelInfo> {
+        self.inner.lock().await.get_tunnels()
+    }
+
+    /// Attach a freeform annotation, for example "backup link", to the tunnel
+    /// with given id, or remove it with `None`.
+    pub async fn set_tunnel_annotation(
+        &self,
+        id: u64,
+        annotation: Option<String>,
+    ) -> Result<(), AnyError> {
+        self.inner
+            .lock()
+            .await
+            .set_tunnel_annotation(id, annotation)
+    }
+
+    /// The most recent reconnects, oldest first.
+    pub async fn get_reconnects(&self) -> Vec<Reconnect> {
+        self.inner.lock().await.get_reconnects()
+    }
+
+    pub async fn get_status(&self) -> Status {
+        self.inner.lock().await.get_status().await
+    }
+
+    /// Change the display name. Sent to the streamer at once if connected to
+    /// one that supports it, otherwise the relay reconnects if connected.
+    pub async fn set_name(&self, name: String) -> Result<(), RelayError> {
+        self.inner.lock().await.set_name(name).await
+    }
+
+    /// Apply a new streamer URL and/or password together, for example to
+    /// rotate the password or repoint the relay to another streamer. Nothing
+    /// is applied if the streamer URL is invalid. Reconnects at once if
+    /// started and anything changed, also after a wrong password.
+    pub async fn update_settings(
+        &self,
+        streamer_url: Option<String>,
+        password: Option<SecretString>,
+    ) -> Result<(), RelayError> {
+        let inner = self.inner.clone();
+        self.run_on_runtime(async move {
+            inner
+                .lock()
+                .await
+                .update_settings(streamer_url, password)
+                .await
+        })
+        .await
+    }
+
+    /// Stops relaying, but stays connected to the streamer. Start tunnel
+    /// requests are answered with `MoblinkResult::Busy` until resumed, or
+    /// when resumed if the streamer does not support it.
+    pub async fn pause(&self) {
+        self.inner.lock().await.pause().await;
+    }
+
+    /// Relays again, starting the tunnel requested while paused, if any.
+    pub async fn resume(&self) {
+        let inner = self.inner.clone();
+        self.run_on_runtime(async move { inner.lock().await.resume().await })
+            .await;
+    }
+
+    pub async fn is_paused(&self) -> bool {
+        self.inner.lock().await.is_paused()
+    }
+
+    /// The result of the latest NAT discovery, if any.
+    pub async fn get_nat(&self) -> Option<NatInfo> {
+        self.inner.lock().await.get_nat()
+    }
 
-        Ok(())
+    /// Cellular modems found by the latest ModemManager poll. Empty if modem
+    /// monitoring is disabled.
+    pub async fn get_modems(&self) -> Vec<ModemStatus> {
+        self.inner.lock().await.get_modems()
     }
 
-    async fn start_relay_from_streamer_to_destination(
-        &mut self,
-        streamer_socket: Arc<UdpSocket>,
-        destination_socket: Arc<UdpSocket>,
-        destination_addr: SocketAddr,
-    ) -> tokio::task::JoinHandle<Result<(), AnyError>> {
-        *self.reconnect_on_tunnel_error.lock().await = false;
-        let reconnect_on_tunnel_error = Arc::new(Mutex::new(true));
-        self.reconnect_on_tunnel_error = reconnect_on_tunnel_error.clone();
-        let relay = self.me.clone();
+    /// The data usage this month of the interface the relay relays on, if
+    /// known and data usage is enabled.
+    pub async fn get_data_usage(&self) -> Option<InterfaceDataUsage> {
+        self.inner.lock().await.get_data_usage()
+    }
 
-        tokio::spawn(async move {
-            let streamer_address = Arc::new(Mutex::new(None));
-            let mut relay_to_destination_started = false;
-            let mut buf = [0; 2048];
+    /// Rolling connection quality score from 0 (unusable) to 100 (perfect)
+    /// over the last minute, for ranking relays. Weighs the round trip time
+    /// to the streamer, SRT retransmissions to the destination (with SRT
+    /// inspection) and how stable the bitrate to the destination is. `None`
+    /// when not connected.
+    pub async fn get_quality_score(&self) -> Option<u8> {
+        self.inner.lock().await.quality_score
+    }
 
-            loop {
-                let (size, remote_addr) = streamer_socket.recv_from(&mut buf).await?;
-                destination_socket
-                    .send_to(&buf[..size], &destination_addr)
-                    .await?;
-                streamer_address.lock().await.replace(remote_addr);
-
-                if !relay_to_destination_started {
-                    start_relay_from_destination_to_streamer(
-                        relay.clone(),
-                        streamer_socket.clone(),
-                        destination_socket.clone(),
-                        streamer_address.clone(),
-                        reconnect_on_tunnel_error.clone(),
-                    );
-                    relay_to_destination_started = true;
-                }
-            }
-        })
+    /// Thermal zones read by the latest poll. Empty if thermal monitoring is
+    /// disabled.
+    pub async fn get_thermal_zones(&self) -> Vec<ThermalZone> {
+        self.inner.lock().await.get_thermal_zones()
     }
 
-    async fn handle_message_request_status(
-        &mut self,
-        request: MessageRequest,
-    ) -> Result<(), AnyError> {
-        let mut battery_percentage = None;
-        if let Some(get_status) = self.get_status.as_ref() {
-            battery_percentage = get_status().await.battery_percentage;
-        }
-        let data = ResponseData::Status(StatusResponseData { battery_percentage });
-        let response = request.to_ok_response(data);
-        self.send(MessageToStreamer::Response(response)).await
+    /// Connects to the streamer, and reconnects until stopped. Fails
+    /// immediately if the streamer URLs or bind address are invalid.
+    pub async fn start(&self) -> Result<(), RelayError> {
+        let inner = self.inner.clone();
+        self.run_on_runtime(async move { inner.lock().await.start().await })
+            .await
     }
 
-    async fn send(&mut self, message: MessageToStreamer) -> Result<(), AnyError> {
-        let text = serde_json::to_string(&message)?;
-        self.send_message(Message::Text(text.into())).await
+    /// Stops the relay and returns its identity and tunnel, if any, for
+    /// another relay to adopt. The tunnel sockets are kept open.
+    pub async fn hand_over(&self) -> Handover {
+        self.inner.lock().await.hand_over().await
     }
 
-    async fn send_message(&mut self, message: Message) -> Result<(), AnyError> {
-        let Some(writer) = self.ws_writer.as_mut() else {
-            return Err("No websocket writer".into());
-        };
-        writer.send(message).await?;
-        Ok(())
+    /// Forwards packets between given listen address and destination, without
+    /// connecting to a streamer. Do not call `start()`.
+    pub async fn start_static_tunnel(&self, static_tunnel: StaticTunnel) -> Result<(), AnyError> {
+        let inner = self.inner.clone();
+        self.run_on_runtime(
+            async move { inner.lock().await.start_static_tunnel(static_tunnel).await },
+        )
+        .await
     }
-}
 
-pub struct Relay {
-    inner: Arc<Mutex<RelayInner>>,
-}
+    /// Forwards packets on a tunnel handed over by another relay. Should be
+    /// called before `start()`.
+    pub async fn adopt_tunnel(&self, tunnel: TunnelSockets) {
+        self.inner.lock().await.adopt_tunnel(tunnel).await;
+    }
 
-impl Default for Relay {
-    fn default() -> Self {
-        Self::new()
+    pub async fn stop(&self) {
+        self.inner.lock().await.stop().await;
+    }
+
+    /// Reconnects to the streamer now if started, with a newly selected bind
+    /// address unless set with `set_bind_address()`, and resolves the
+    /// streamer URL again.
+    pub async fn reconnect(&self) {
+        let inner = self.inner.clone();
+        self.run_on_runtime(async move { inner.lock().await.reconnect().await })
+            .await;
     }
 }
 
-impl Relay {
-    pub fn new() -> Self {
+type OnStatusUpdatedClosure = Box<dyn Fn(String) + Send + Sync>;
+
+/// Creates and sets up a relay. Defaults are the same as for `Relay::new()`,
+/// except that a random relay id is used and the name is the host name.
+pub struct RelayBuilder {
+    streamer_url: String,
+    password: SecretString,
+    relay_id: Uuid,
+    name: String,
+    on_status_updated: OnStatusUpdatedClosure,
+    status_provider: Option<Arc<dyn StatusProvider>>,
+    headers: Vec<(String, String)>,
+    interface_policy: InterfacePolicy,
+    dns_resolver: DnsResolver,
+    runtime: Option<Handle>,
+}
+
+impl RelayBuilder {
+    pub fn new(streamer_url: String) -> Self {
         Self {
-            inner: RelayInner::new(),
+            streamer_url,
+            password: SecretString::default(),
+            relay_id: Uuid::new_v4(),
+            name: gethostname().to_str().unwrap_or("Moblink").to_string(),
+            on_status_updated: Box::new(|_| {}),
+            status_provider: None,
+            headers: Vec::new(),
+            interface_policy: InterfacePolicy::First,
+            dns_resolver: DnsResolver::system(),
+            runtime: None,
         }
     }
 
-    pub async fn set_bind_address(&self, address: String) {
-        self.inner.lock().await.set_bind_address(address);
+    pub fn password(mut self, password: impl Into<SecretString>) -> Self {
+        self.password = password.into();
+        self
     }
 
-    pub async fn setup<F>(
-        &self,
-        streamer_url: String,
-        password: String,
-        relay_id: Uuid,
-        name: String,
-        on_status_updated: F,
-        get_status: Option<GetStatusClosure>,
-    ) where
+    pub fn relay_id(mut self, relay_id: Uuid) -> Self {
+        self.relay_id = relay_id;
+        self
+    }
+
+    /// Defaults to the host name.
+    pub fn name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn on_status_updated<F>(mut self, on_status_updated: F) -> Self
+    where
         F: Fn(String) + Send + Sync + 'static,
     {
-        self.inner
-            .lock()
-            .await
-            .setup(
-                streamer_url,
-                password,
-                relay_id,
-                name,
-                on_status_updated,
-                get_status,
-            )
-            .await;
+        self.on_status_updated = Box::new(on_status_updated);
+        self
     }
 
-    pub async fn is_started(&self) -> bool {
-        self.inner.lock().await.is_started()
+    pub fn status_provider(mut self, status_provider: Arc<dyn StatusProvider>) -> Self {
+        self.status_provider = Some(status_provider);
+        self
     }
 
-    pub async fn start(&self) {
-        self.inner.lock().await.start().await;
+    /// Closure form of `status_provider()`.
+    pub fn get_status(mut self, get_status: Option<GetStatusClosure>) -> Self {
+        self.status_provider = get_status.map(|get_status| Arc::new(get_status) as _);
+        self
     }
 
-    pub async fn stop(&self) {
-        self.inner.lock().await.stop().await;
+    /// Adds a header to the WebSocket handshake with the streamer. Can be
+    /// called multiple times.
+    pub fn header(mut self, name: String, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// How to select the network interface to bind to.
+    pub fn interface_policy(mut self, interface_policy: InterfacePolicy) -> Self {
+        self.interface_policy = interface_policy;
+        self
+    }
+
+    /// How to resolve the streamer URL and the tunnel destinations.
+    pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
+        self.dns_resolver = dns_resolver;
+        self
+    }
+
+    /// The runtime to spawn tasks on, instead of the caller's.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub async fn build(self) -> Result<Relay, RelayError> {
+        let relay = Relay::new();
+        relay.set_headers(self.headers).await;
+        relay.set_interface_policy(self.interface_policy).await;
+        relay.set_dns_resolver(self.dns_resolver).await;
+        if let Some(runtime) = self.runtime {
+            relay.set_runtime(runtime).await;
+        }
+        relay
+            .setup(
+                self.streamer_url,
+                self.password,
+                self.relay_id,
+                self.name,
+                self.on_status_updated,
+                None,
+            )
+            .await?;
+        if let Some(status_provider) = self.status_provider {
+            relay.set_status_provider(status_provider).await;
+        }
+        Ok(relay)
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_relay_from_destination_to_streamer(
     relay: Weak<Mutex<RelayInner>>,
     streamer_socket: Arc<UdpSocket>,
-    destination_socket: Arc<UdpSocket>,
+    destination_sockets: Vec<Arc<UdpSocket>>,
     streamer_address: Arc<Mutex<Option<SocketAddr>>>,
     reconnect_on_tunnel_error: Arc<Mutex<bool>>,
-) {
-    tokio::spawn(async move {
-        loop {
-            if let Err(error) = relay_one_packet_from_destination_to_streamer(
+    counters: Arc<Counters>,
+    probe_stats: Option<Arc<Mutex<ProbeStats>>>,
+    srt_inspector: Option<Arc<SrtInspector>>,
+    destination_timeout: Option<Duration>,
+    mut fec: Option<Fec>,
+    capture: Option<Arc<Capture>>,
+    srtla: bool,
+) -> tokio::task::JoinHandle<()> {
+    let relay_to_streamer = async move {
+        let mut idle = false;
+        let mut buf = vec![0; DATAGRAM_BUFFER_SIZE];
+        let capture = capture.zip(destination_sockets[0].peer_addr().ok());
+        let error = loop {
+            match relay_one_packet_from_destination_to_streamer(
+                &mut buf,
                 &streamer_socket,
-                &destination_socket,
+                &destination_sockets,
                 &streamer_address,
+                &counters,
+                &probe_stats,
+                &srt_inspector,
+                destination_timeout,
+                &mut fec,
+                capture.as_ref(),
+                srtla,
             )
             .await
             {
-                info!("(relay_to_streamer) Failed with error: {}", error);
+                Ok(true) => {
+                    if idle {
+                        idle = false;
+                        if let Some(relay) = relay.upgrade() {
+                            relay.lock().await.handle_tunnel_active();
+                        }
+                    }
+                }
+                Ok(false) => {
+                    if !idle {
+                        idle = true;
+                        if let Some(relay) = relay.upgrade() {
+                            relay.lock().await.handle_tunnel_idle();
+                        }
+                    }
+                }
+                Err(error) => {
+                    info!("(relay_to_streamer) Failed with error: {}", error);
+                    break error;
+                }
+            }
+        };
+
+        if let Some(relay) = relay.upgrade() {
+            relay
+                .lock()
+                .await
+                .handle_tunnel_error(error, &reconnect_on_tunnel_error)
+                .await;
+        }
+    };
+    tokio::spawn(relay_to_streamer.in_current_span())
+}
+
+/// Empty datagrams are ignored by SRT and RIST receivers. Not counted as
+/// traffic.
+fn start_nat_keepalive(
+    streamer_socket: Arc<UdpSocket>,
+    streamer_address: Arc<Mutex<Option<SocketAddr>>>,
+    counters: Arc<Counters>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    let nat_keepalive = async move {
+        let mut packets_to_streamer = counters.snapshot().packets_to_streamer;
+        loop {
+            tokio::time::sleep(interval).await;
+            let latest_packets_to_streamer = counters.snapshot().packets_to_streamer;
+            if latest_packets_to_streamer != packets_to_streamer {
+                packets_to_streamer = latest_packets_to_streamer;
+                continue;
+            }
+            let Some(streamer_address) = *streamer_address.lock().await else {
+                continue;
+            };
+            debug!("Sending NAT keepalive to {}", streamer_address);
+            if let Err(error) = streamer_socket.send_to(&[], streamer_address).await {
+                info!("NAT keepalive failed with error: {}", error);
+            }
+        }
+    };
+    tokio::spawn(nat_keepalive.in_current_span())
+}
+
+async fn sleep_until_or_forever(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends on the next path, or on all paths when duplicating. Twice if there
+/// is only one path. The receiver removes the duplicates.
+async fn send_to_destination(
+    destination_sockets: &[Arc<UdpSocket>],
+    scheduler: &mut Scheduler,
+    duplicate: bool,
+    datagram: &[u8],
+    counters: &Counters,
+) -> Result<(), std::io::Error> {
+    if !duplicate {
+        let destination_socket = &destination_sockets[scheduler.next()];
+        return send_on_destination_socket(destination_socket, datagram, counters).await;
+    }
+    let copies = destination_sockets.len().max(2);
+    for destination_socket in destination_sockets.iter().cycle().take(copies) {
+        send_on_destination_socket(destination_socket, datagram, counters).await?;
+    }
+    Ok(())
+}
+
+/// Sends again after an ICMP error, as it is for an earlier datagram and
+/// fails the send instead of this datagram being sent.
+async fn send_on_destination_socket(
+    destination_socket: &UdpSocket,
+    datagram: &[u8],
+    counters: &Counters,
+) -> Result<(), std::io::Error> {
+    for _ in 0..2 {
+        match destination_socket.send(datagram).await {
+            Ok(_) => {
+                counters.add_to_destination(datagram.len());
                 break;
             }
+            Err(error) if is_destination_unreachable(&error) => {
+                debug!("Destination unreachable")
+            }
+            Err(error) => return Err(error),
         }
+    }
+    Ok(())
+}
 
-        if *reconnect_on_tunnel_error.lock().await {
-            if let Some(relay) = relay.upgrade() {
-                relay.lock().await.reconnect_soon().await;
+/// Skips ICMP errors, see `is_destination_unreachable()`.
+async fn recv_from_destination(
+    destination_sockets: &[Arc<UdpSocket>],
+    buf: &mut [u8],
+) -> Result<usize, std::io::Error> {
+    loop {
+        match recv_from_any(destination_sockets, buf).await {
+            Err(error) if is_destination_unreachable(&error) => {
+                debug!("Destination unreachable")
             }
-        } else {
-            info!("Not reconnecting after tunnel error");
+            result => return result,
         }
-    });
+    }
+}
+
+/// An ICMP port unreachable for a datagram fails the next send or receive on
+/// the connected destination socket. Not fatal, as the destination may just
+/// not be listening yet.
+fn is_destination_unreachable(error: &std::io::Error) -> bool {
+    error.kind() == ErrorKind::ConnectionRefused
 }
 
+/// Returns `false` if nothing was received within given timeout. Given
+/// buffer is reused for every datagram.
+#[allow(clippy::too_many_arguments)]
 async fn relay_one_packet_from_destination_to_streamer(
+    buf: &mut [u8],
     streamer_socket: &Arc<UdpSocket>,
-    destination_socket: &Arc<UdpSocket>,
+    destination_sockets: &[Arc<UdpSocket>],
     streamer_address: &Arc<Mutex<Option<SocketAddr>>>,
-) -> Result<(), AnyError> {
-    let mut buf = [0; 2048];
-    let size = timeout(Duration::from_secs(30), destination_socket.recv(&mut buf)).await??;
+    counters: &Counters,
+    probe_stats: &Option<Arc<Mutex<ProbeStats>>>,
+    srt_inspector: &Option<Arc<SrtInspector>>,
+    destination_timeout: Option<Duration>,
+    fec: &mut Option<Fec>,
+    capture: Option<&(Arc<Capture>, SocketAddr)>,
+    srtla: bool,
+) -> Result<bool, AnyError> {
+    let size = match destination_timeout {
+        Some(destination_timeout) => {
+            let Ok(result) = timeout(
+                destination_timeout,
+                recv_from_destination(destination_sockets, buf),
+            )
+            .await
+            else {
+                return Ok(false);
+            };
+            result?
+        }
+        None => recv_from_destination(destination_sockets, buf).await?,
+    };
+    if let Some(probe_stats) = probe_stats
+        && probe_stats.lock().await.handle_packet(&buf[..size])
+    {
+        return Ok(true);
+    }
+    if srtla && srtla::is_srtla_packet(&buf[..size]) {
+        return Ok(true);
+    }
     let streamer_addr = streamer_address
         .lock()
         .await
         .ok_or("Failed to get address lock")?;
-    streamer_socket
-        .send_to(&buf[..size], &streamer_addr)
-        .await?;
-    Ok(())
+    if let Some((capture, destination_address)) = capture {
+        capture.record(*destination_address, streamer_addr, &buf[..size]);
+    }
+    match fec {
+        Some(fec) => {
+            let datagrams = fec.process(&buf[..size], |payload| {
+                if let Some(srt_inspector) = srt_inspector {
+                    srt_inspector.handle_packet(Direction::ToStreamer, payload);
+                }
+            });
+            for datagram in datagrams {
+                streamer_socket.send_to(&datagram, &streamer_addr).await?;
+                counters.add_to_streamer(datagram.len());
+            }
+        }
+        None => {
+            streamer_socket
+                .send_to(&buf[..size], &streamer_addr)
+                .await?;
+            counters.add_to_streamer(size);
+            if let Some(srt_inspector) = srt_inspector {
+                srt_inspector.handle_packet(Direction::ToStreamer, &buf[..size]);
+            }
+        }
+    }
+    Ok(true)
 }
 
-async fn create_dual_stack_udp_socket(
+/// An even port and the next port, as RIST and RTP expect for RTP and RTCP.
+/// Odd ports are reported as in use, so that the next port of a port range
+/// is tried.
+async fn create_paired_streamer_udp_sockets(
+    address: SocketAddr,
+) -> Result<(UdpSocket, UdpSocket), std::io::Error> {
+    let next_address = |port: u16| SocketAddr::new(address.ip(), port + 1);
+    if address.port() != 0 {
+        if !address.port().is_multiple_of(2) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        let socket = create_dual_stack_udp_socket(address).await?;
+        let next_socket = create_dual_stack_udp_socket(next_address(address.port())).await?;
+        return Ok((socket, next_socket));
+    }
+    for _ in 0..MAXIMUM_NUMBER_OF_PAIRED_PORTS_ATTEMPTS {
+        let socket = create_dual_stack_udp_socket(address).await?;
+        let port = socket.local_addr()?.port();
+        if !port.is_multiple_of(2) {
+            continue;
+        }
+        match create_dual_stack_udp_socket(next_address(port)).await {
+            Ok(next_socket) => return Ok((socket, next_socket)),
+            Err(error) if error.kind() == ErrorKind::AddrInUse => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Err(ErrorKind::AddrInUse.into())
+}
+
+/// Forwards in both directions until failing. The streamer address is the
+/// latest one received from.
+async fn forward_rtcp(
+    streamer_socket: UdpSocket,
+    destination_socket: UdpSocket,
+    counters: Arc<Counters>,
+) -> Result<(), AnyError> {
+    let mut streamer_address = None;
+    let mut streamer_buffer = vec![0; DATAGRAM_BUFFER_SIZE];
+    let mut destination_buffer = vec![0; DATAGRAM_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            result = streamer_socket.recv_from(&mut streamer_buffer) => {
+                let (size, address) = result?;
+                streamer_address = Some(address);
+                destination_socket.send(&streamer_buffer[..size]).await?;
+                counters.add_to_destination(size);
+            }
+            result = destination_socket.recv(&mut destination_buffer) => {
+                let size = match result {
+                    Ok(size) => size,
+                    // The destination does not listen on the port (yet).
+                    Err(error) if error.kind() == ErrorKind::ConnectionRefused => continue,
+                    Err(error) => return Err(error.into()),
+                };
+                if let Some(address) = streamer_address {
+                    streamer_socket
+                        .send_to(&destination_buffer[..size], address)
+                        .await?;
+                    counters.add_to_streamer(size);
+                }
+            }
+        }
+    }
+}
+
+async fn create_streamer_udp_socket(
+    address: SocketAddr,
+    reuse_port: bool,
+) -> Result<UdpSocket, std::io::Error> {
+    if reuse_port {
+        create_reuse_port_udp_socket(address)
+    } else {
+        create_dual_stack_udp_socket(address).await
+    }
+}
+
+/// Dual-stack if IPv6, as `create_dual_stack_udp_socket()`.
+#[cfg(unix)]
+fn create_reuse_port_udp_socket(address: SocketAddr) -> Result<UdpSocket, std::io::Error> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(address),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn create_reuse_port_udp_socket(_address: SocketAddr) -> Result<UdpSocket, std::io::Error> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "SO_REUSEPORT is only available on Unix",
+    ))
+}
+
+pub(crate) async fn create_dual_stack_udp_socket(
     addr: SocketAddr,
 ) -> Result<tokio::net::UdpSocket, std::io::Error> {
     let socket = match addr.is_ipv4() {
@@ -609,6 +4498,22 @@ async fn create_dual_stack_udp_socket(
     Ok(socket)
 }
 
+/// Checks that given streamer URL is a WebSocket URL with a host.
+fn validate_streamer_url(streamer_url: &str) -> Result<(), RelayError> {
+    let error = |reason: &str| RelayError::InvalidStreamerUrl {
+        url: streamer_url.to_string(),
+        reason: reason.to_string(),
+    };
+    let url = url::Url::parse(streamer_url).map_err(|e| error(&e.to_string()))?;
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(error("Scheme must be ws or wss"));
+    }
+    if url.host_str().is_none() {
+        return Err(error("No host"));
+    }
+    Ok(())
+}
+
 // Helper function to parse a string into a SocketAddr, handling IP addresses
 // without ports.
 fn parse_socket_addr(addr_str: &str) -> Result<SocketAddr, std::io::Error> {
@@ -631,41 +4536,242 @@ fn parse_socket_addr(addr_str: &str) -> Result<SocketAddr, std::io::Error> {
     ))
 }
 
+/// An executable printing the status to standard output on format
+/// `{"batteryPercentage": 93}`. It is run every `interval` in the background,
+/// and status requests are answered with its latest status, which is used for
+/// at most `ttl`. Frequent status requests then never spawn processes.
+#[derive(Clone, Debug)]
+pub struct StatusExecutable {
+    pub path: String,
+    pub arguments: Vec<String>,
+    /// The executable is killed if it runs for longer.
+    pub timeout: Duration,
+    /// At least 100 milliseconds.
+    pub interval: Duration,
+    pub ttl: Duration,
+}
+
+impl StatusExecutable {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            arguments: Vec::new(),
+            timeout: Duration::from_secs(5),
+            interval: Duration::from_secs(5),
+            ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The status file is read for every request. Use
+/// `Relay::set_status_executable()` instead to give the status executable the
+/// relay state in environment variables.
 pub fn create_get_status_closure(
-    status_executable: &Option<String>,
+    status_executable: &Option<StatusExecutable>,
     status_file: &Option<String>,
 ) -> Option<GetStatusClosure> {
-    let status_executable = status_executable.clone();
+    if let Some(status_executable) = status_executable.clone() {
+        let cache = Arc::new(StatusCache::start(status_executable, None, None));
+        return Some(Box::new(move || {
+            let status = cache.get();
+            Box::pin(async move { status })
+        }));
+    }
     let status_file = status_file.clone();
     Some(Box::new(move || {
-        let status_executable = status_executable.clone();
         let status_file = status_file.clone();
         Box::pin(async move {
-            let output = if let Some(status_executable) = &status_executable {
-                let Ok(output) = Command::new(status_executable).output().await else {
-                    return Default::default();
-                };
-                output.stdout
-            } else if let Some(status_file) = &status_file {
-                let Ok(mut file) = File::open(status_file).await else {
-                    return Default::default();
-                };
-                let mut contents = vec![];
-                if file.read_to_end(&mut contents).await.is_err() {
-                    return Default::default();
-                }
-                contents
-            } else {
+            let Some(status_file) = &status_file else {
                 return Default::default();
             };
-            let output = String::from_utf8(output).unwrap_or_default();
-            match serde_json::from_str(&output) {
-                Ok(status) => status,
-                Err(e) => {
-                    error!("Failed to decode status with error: {e}");
-                    Default::default()
-                }
+            let Ok(mut file) = File::open(status_file).await else {
+                return Default::default();
+            };
+            let mut contents = vec![];
+            if file.read_to_end(&mut contents).await.is_err() {
+                return Default::default();
             }
+            decode_status(contents).unwrap_or_default()
         })
     }))
 }
+
+struct StatusCache {
+    latest: Arc<std::sync::Mutex<Option<(Status, Instant)>>>,
+    ttl: Duration,
+    poller: std::sync::Mutex<StatusPoller>,
+}
+
+type StatusPollerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+enum StatusPoller {
+    /// Not yet spawned as there was no runtime when the cache was created.
+    Pending(Option<StatusPollerFuture>),
+    Running {
+        _poller: AbortOnDrop,
+    },
+}
+
+impl StatusCache {
+    /// The executable gets the state of given relay, if any, in environment
+    /// variables. The executable is polled in given runtime, or the current
+    /// one. Without a runtime, polling starts on the first status request.
+    fn start(
+        status_executable: StatusExecutable,
+        relay: Option<Weak<Mutex<RelayInner>>>,
+        runtime: Option<Handle>,
+    ) -> Self {
+        let latest = Arc::new(std::sync::Mutex::new(None));
+        let ttl = status_executable.ttl;
+        let poller = {
+            let latest = latest.clone();
+            async move {
+                loop {
+                    let environment = match &relay {
+                        Some(relay) => {
+                            let Some(relay) = relay.upgrade() else {
+                                break;
+                            };
+                            relay.lock().await.get_status_environment()
+                        }
+                        None => Vec::new(),
+                    };
+                    if let Some(status) =
+                        run_status_executable(&status_executable, environment).await
+                    {
+                        *latest.lock().unwrap() = Some((status, Instant::now()));
+                    }
+                    sleep(status_executable.interval.max(MINIMUM_STATUS_INTERVAL)).await;
+                }
+            }
+        }
+        .in_current_span();
+        let poller = match runtime.or_else(|| Handle::try_current().ok()) {
+            Some(runtime) => StatusPoller::Running {
+                _poller: AbortOnDrop(runtime.spawn(poller)),
+            },
+            None => StatusPoller::Pending(Some(Box::pin(poller))),
+        };
+        Self {
+            latest,
+            ttl,
+            poller: std::sync::Mutex::new(poller),
+        }
+    }
+
+    /// Unknown status if the latest status is too old.
+    fn get(&self) -> Status {
+        self.start_pending_poller();
+        match &*self.latest.lock().unwrap() {
+            Some((status, updated_at)) if updated_at.elapsed() < self.ttl => status.clone(),
+            _ => Default::default(),
+        }
+    }
+
+    fn start_pending_poller(&self) {
+        let mut poller = self.poller.lock().unwrap();
+        if let StatusPoller::Pending(pending) = &mut *poller
+            && let Ok(runtime) = Handle::try_current()
+            && let Some(pending) = pending.take()
+        {
+            *poller = StatusPoller::Running {
+                _poller: AbortOnDrop(runtime.spawn(pending)),
+            };
+        }
+    }
+}
+
+async fn run_status_executable(
+    status_executable: &StatusExecutable,
+    environment: Vec<(&'static str, String)>,
+) -> Option<Status> {
+    let output = Command::new(&status_executable.path)
+        .args(&status_executable.arguments)
+        .envs(environment)
+        .kill_on_drop(true)
+        .output();
+    match timeout(status_executable.timeout, output).await {
+        Ok(Ok(output)) => decode_status(output.stdout),
+        Ok(Err(error)) => {
+            error!("Failed to run status executable with error: {error}");
+            None
+        }
+        Err(_) => {
+            error!(
+                "Status executable did not finish within {:?}",
+                status_executable.timeout
+            );
+            None
+        }
+    }
+}
+
+fn decode_status(output: Vec<u8>) -> Option<Status> {
+    let output = String::from_utf8(output).unwrap_or_default();
+    match serde_json::from_str(&output) {
+        Ok(status) => Some(status),
+        Err(e) => {
+            error!("Failed to decode status with error: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_udp_static_tunnel() {
+        let static_tunnel: StaticTunnel = " 0.0.0.0:5000 -> example.com:6000 ".parse().unwrap();
+        assert_eq!(static_tunnel.protocol, TunnelProtocol::Udp);
+        assert_eq!(
+            static_tunnel.listen_address,
+            "0.0.0.0:5000".parse().unwrap()
+        );
+        assert_eq!(static_tunnel.destination_host, "example.com");
+        assert_eq!(static_tunnel.destination_port, 6000);
+    }
+
+    #[test]
+    fn parses_tcp_static_tunnel_to_ipv6() {
+        let static_tunnel: StaticTunnel = "tcp:[::]:1935->[2001:db8::1]:1935".parse().unwrap();
+        assert_eq!(static_tunnel.protocol, TunnelProtocol::Tcp);
+        assert_eq!(static_tunnel.listen_address, "[::]:1935".parse().unwrap());
+        assert_eq!(static_tunnel.destination_host, "2001:db8::1");
+        assert_eq!(static_tunnel.destination_port, 1935);
+    }
+
+    #[test]
+    fn rejects_invalid_static_tunnels() {
+        for value in [
+            "0.0.0.0:5000",
+            "example.com:5000->example.com:5000",
+            "0.0.0.0:5000->example.com",
+            "0.0.0.0:5000->example.com:70000",
+            "0.0.0.0:5000->:5000",
+            "udp:0.0.0.0:5000->example.com:5000",
+        ] {
+            assert!(value.parse::<StaticTunnel>().is_err(), "{}", value);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_cache_created_outside_runtime() {
+        let mut status_executable = StatusExecutable::new("echo".into());
+        status_executable.arguments = vec![r#"{"batteryPercentage": 93}"#.into()];
+        let get_status = create_get_status_closure(&Some(status_executable), &None).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert_eq!(get_status().await.battery_percentage, None);
+            for _ in 0..50 {
+                if get_status().await.battery_percentage == Some(93) {
+                    return;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            panic!("Status executable never polled");
+        });
+    }
+}
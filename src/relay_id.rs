@@ -0,0 +1,53 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::utils::AnyError;
+
+/// `$XDG_STATE_HOME/moblink-relay/relay-id`, with `XDG_STATE_HOME` defaulting
+/// to `~/.local/state`. `None` if neither `XDG_STATE_HOME` nor `HOME` is set.
+pub fn default_relay_id_path() -> Option<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(state_home) if !state_home.is_empty() => PathBuf::from(state_home),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("moblink-relay").join("relay-id"))
+}
+
+/// The relay id file of given `--instance`, next to given relay id file, for
+/// example `relay-id-Phone1`.
+pub fn instance_relay_id_path(path: &Path, name: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() || char == '-' || char == '_' {
+                char
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", name));
+    path.with_file_name(file_name)
+}
+
+/// Reads the relay id from given file, or generates a new one and writes it to
+/// the file if it does not exist. The streamer then recognizes the relay after
+/// restarts.
+pub async fn load_or_create_relay_id(path: &Path) -> Result<Uuid, AnyError> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(Uuid::parse_str(content.trim())
+            .map_err(|error| format!("Invalid relay id in '{}': {}", path.display(), error))?),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            let relay_id = Uuid::new_v4();
+            if let Some(directory) = path.parent() {
+                fs::create_dir_all(directory).await?;
+            }
+            fs::write(path, format!("{}\n", relay_id)).await?;
+            Ok(relay_id)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
@@ -0,0 +1,221 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::relay::{GetStatusClosure, Relay, RelayError};
+use crate::secret::SecretString;
+use crate::stats::RelayStats;
+use crate::streamer_url::normalize_streamer_url;
+
+/// Configuration of one relay in a `RelayManager`. Parsed from
+/// `name=Phone1,url=ws://192.168.1.2:7777,password=1234,id=UUID`, where
+/// password and id are optional. Values with commas can be quoted, as in
+/// `password="a,b"`, with `\"` and `\\` for quotes and backslashes in them.
+#[derive(Clone, Debug)]
+pub struct RelayInstanceConfig {
+    pub name: String,
+    pub streamer_url: String,
+    pub password: SecretString,
+    /// Random if `None`.
+    pub relay_id: Option<Uuid>,
+}
+
+/// Splits at commas outside of quotes, and removes quotes and escapes.
+fn split_parts(value: &str) -> Result<Vec<String>, String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quoted = false;
+    let mut chars = value.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '"' => quoted = !quoted,
+            '\\' if quoted => part.push(chars.next().ok_or("Unterminated quote")?),
+            ',' if !quoted => parts.push(std::mem::take(&mut part)),
+            char => part.push(char),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_string());
+    }
+    parts.push(part);
+    Ok(parts)
+}
+
+impl FromStr for RelayInstanceConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut streamer_url = None;
+        let mut password = SecretString::from("1234");
+        let mut relay_id = None;
+        for part in split_parts(value)? {
+            let Some((key, value)) = part.split_once('=') else {
+                return Err(format!("Expected key=value, got '{}'", part));
+            };
+            match key.trim() {
+                "name" => name = Some(value.to_string()),
+                "url" => {
+                    let url = normalize_streamer_url(value).map_err(|error| error.to_string())?;
+                    streamer_url = Some(url.url);
+                }
+                "password" => password = value.into(),
+                "id" => relay_id = Some(Uuid::parse_str(value).map_err(|error| error.to_string())?),
+                key => return Err(format!("Unknown key '{}'", key)),
+            }
+        }
+        Ok(Self {
+            name: name.ok_or("Missing name")?,
+            streamer_url: streamer_url.ok_or("Missing url")?,
+            password,
+            relay_id,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayInstanceState {
+    pub name: String,
+    pub streamer_url: String,
+    pub connected: bool,
+    pub status: String,
+    pub stats: RelayStats,
+}
+
+/// Runs several independent relays in one process, for example one per
+/// streamer (phone).
+#[derive(Default)]
+pub struct RelayManager {
+    relays: Vec<(String, Relay)>,
+}
+
+impl RelayManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates and sets up a relay. Configure it further with the returned
+    /// relay, and start it with `start_all()` or `Relay::start()`.
+    pub async fn add<F>(
+        &mut self,
+        config: RelayInstanceConfig,
+        on_status_updated: F,
+        get_status: Option<GetStatusClosure>,
+    ) -> Result<Relay, RelayError>
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        let mut builder = Relay::builder(config.streamer_url)
+            .password(config.password)
+            .name(config.name.clone())
+            .on_status_updated(on_status_updated)
+            .get_status(get_status);
+        if let Some(relay_id) = config.relay_id {
+            builder = builder.relay_id(relay_id);
+        }
+        let relay = builder.build().await?;
+        self.relays.push((config.name, relay.clone()));
+        Ok(relay)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Relay> {
+        self.relays
+            .iter()
+            .find(|(relay_name, _)| relay_name == name)
+            .map(|(_, relay)| relay)
+    }
+
+    pub fn relays(&self) -> impl Iterator<Item = &Relay> {
+        self.relays.iter().map(|(_, relay)| relay)
+    }
+
+    pub fn named_relays(&self) -> impl Iterator<Item = (&str, &Relay)> {
+        self.relays
+            .iter()
+            .map(|(name, relay)| (name.as_str(), relay))
+    }
+
+    pub async fn start_all(&self) -> Result<(), RelayError> {
+        for relay in self.relays() {
+            relay.start().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn stop_all(&self) {
+        for relay in self.relays() {
+            relay.stop().await;
+        }
+    }
+
+    pub async fn get_states(&self) -> Vec<RelayInstanceState> {
+        let mut states = Vec::new();
+        for (name, relay) in &self.relays {
+            states.push(RelayInstanceState {
+                name: name.clone(),
+                streamer_url: relay.get_streamer_url().await,
+                connected: relay.is_connected().await,
+                status: relay.get_status_text().await,
+                stats: relay.get_stats().await,
+            });
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config() {
+        let config: RelayInstanceConfig =
+            "name=Phone1,url=ws://192.168.1.2:7777,password=secret,id=5d2c6f3e-2a4b-4a8e-9c6d-1e2f3a4b5c6d"
+                .parse()
+                .unwrap();
+        assert_eq!(config.name, "Phone1");
+        assert_eq!(config.streamer_url, "ws://192.168.1.2:7777/");
+        assert_eq!(config.password.expose_secret(), "secret");
+        assert_eq!(
+            config.relay_id,
+            Some(Uuid::parse_str("5d2c6f3e-2a4b-4a8e-9c6d-1e2f3a4b5c6d").unwrap())
+        );
+    }
+
+    #[test]
+    fn defaults_password_and_id() {
+        let config: RelayInstanceConfig = "name=Phone1,url=192.168.1.2:7777".parse().unwrap();
+        assert_eq!(config.password.expose_secret(), "1234");
+        assert_eq!(config.relay_id, None);
+    }
+
+    #[test]
+    fn keeps_quoted_commas_and_equal_signs() {
+        let config: RelayInstanceConfig =
+            r#"name="Phone, 1",url=ws://192.168.1.2:7777,password="a,b=c\"d\\e""#
+                .parse()
+                .unwrap();
+        assert_eq!(config.name, "Phone, 1");
+        assert_eq!(config.password.expose_secret(), r#"a,b=c"d\e"#);
+        let config: RelayInstanceConfig = "name=Phone1,url=ws://192.168.1.2:7777,password=a=b"
+            .parse()
+            .unwrap();
+        assert_eq!(config.password.expose_secret(), "a=b");
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for value in [
+            "url=ws://192.168.1.2:7777",
+            "name=Phone1",
+            "name=Phone1,url=ws://192.168.1.2:7777,password=\"secret",
+            "name=Phone1,url=ws://192.168.1.2:7777,",
+            "name=Phone1,url=ws://192.168.1.2:7777,color=red",
+            "name=Phone1,url=ws://192.168.1.2:7777,id=42",
+        ] {
+            assert!(value.parse::<RelayInstanceConfig>().is_err(), "{}", value);
+        }
+    }
+}
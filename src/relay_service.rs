@@ -4,7 +4,6 @@ use std::path::PathBuf;
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use log::{error, info};
 use mdns_sd::{ServiceDaemon, ServiceEvent};
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use regex::Regex;
@@ -13,9 +12,11 @@ use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
+use tracing::{error, info};
 use uuid::Uuid;
 
 use crate::MDNS_SERVICE_TYPE;
+use crate::name_template::{NameVariables, expand_name};
 use crate::relay::{GetStatusClosure, Relay, Status};
 use crate::utils::{any_address_belongs_to_this_machine, get_first_ipv4_address};
 
@@ -73,7 +74,9 @@ struct ServiceRelay {
 }
 
 impl ServiceRelay {
+    #[allow(clippy::too_many_arguments)]
     async fn new(
+        name: String,
         interface_name: String,
         interface_address: Ipv4Addr,
         streamer_name: String,
@@ -84,17 +87,26 @@ impl ServiceRelay {
     ) -> Self {
         let relay = Relay::new();
         relay.set_bind_address(interface_address.to_string()).await;
-        relay
+        let result = relay
             .setup(
                 streamer_url.clone(),
                 password,
                 database.lock().await.get_relay_id(&interface_name).await,
-                interface_name.clone(),
+                name,
                 |_| {},
                 get_status,
             )
             .await;
-        relay.start().await;
+        let result = match result {
+            Ok(()) => relay.start().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            error!(
+                "Failed to start relay on interface {} with error: {}",
+                interface_name, error
+            );
+        }
         Self {
             interface_name,
             interface_address,
@@ -149,6 +161,7 @@ impl NetworkInterfaceFilter {
 struct RelayServiceInner {
     me: Weak<Mutex<Self>>,
     password: String,
+    name_template: String,
     network_interface_filter: NetworkInterfaceFilter,
     get_status: Option<GetStatusClosure>,
     status: Status,
@@ -174,6 +187,7 @@ impl RelayServiceInner {
             Mutex::new(Self {
                 me: me.clone(),
                 password,
+                name_template: "{interface}".to_string(),
                 network_interface_filter: NetworkInterfaceFilter::new(
                     network_interfaces_to_allow,
                     network_interfaces_to_ignore,
@@ -211,10 +225,7 @@ impl RelayServiceInner {
     fn start_network_interfaces_monitor(&mut self) {
         let relay_service = self.me.clone();
         self.network_interface_monitor = Some(tokio::spawn(async move {
-            loop {
-                let Ok(interfaces) = NetworkInterface::show() else {
-                    break;
-                };
+            while let Ok(interfaces) = NetworkInterface::show() {
                 let Some(relay_service) = relay_service.upgrade() else {
                     break;
                 };
@@ -324,13 +335,18 @@ impl RelayServiceInner {
                 if self.relay_already_added(interface_address, &streamer.url) {
                     continue;
                 }
+                let variables =
+                    NameVariables::lookup(&self.name_template, Some(interface_address.into()))
+                        .await;
+                let name = expand_name(&self.name_template, &variables);
                 info!(
-                    "Adding relay called {} with interface address {} for streamer name {} and \
-                     URL {}",
-                    interface.name, interface_address, streamer.name, streamer.url
+                    "Adding relay called {} on interface {} with address {} for streamer name {} \
+                     and URL {}",
+                    name, interface.name, interface_address, streamer.name, streamer.url
                 );
                 self.relays.push(
                     ServiceRelay::new(
+                        name,
                         interface.name.clone(),
                         interface_address,
                         streamer.name.clone(),
@@ -423,6 +439,12 @@ impl RelayService {
         }
     }
 
+    /// Relay names, with placeholders as in `expand_name()` expanded per
+    /// network interface. `{interface}` by default.
+    pub async fn set_name_template(&self, name_template: String) {
+        self.inner.lock().await.name_template = name_template;
+    }
+
     pub async fn start(&self) {
         self.inner.lock().await.start().await;
     }
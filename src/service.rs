@@ -0,0 +1,170 @@
+use std::ffi::OsString;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::relay::Relay;
+
+pub const SERVICE_NAME: &str = "moblink-relay";
+const SERVICE_DISPLAY_NAME: &str = "Moblink relay";
+
+enum ServiceCommand {
+    Stop,
+    Pause,
+    Continue,
+}
+
+/// Requests from the service control manager.
+pub struct ServiceControls {
+    commands: UnboundedReceiver<ServiceCommand>,
+    status_handle: service_control_handler::ServiceStatusHandle,
+}
+
+static SERVICE_MAIN: OnceLock<fn()> = OnceLock::new();
+static SERVICE_CONTROLS: Mutex<Option<ServiceControls>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Runs given function as a Windows service. Blocks until the service has
+/// stopped. Fails if the process was not started by the service control
+/// manager.
+pub fn run(main: fn()) -> Result<(), windows_service::Error> {
+    SERVICE_MAIN.set(main).ok();
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+/// The controls of the running service, if any. Only given once.
+pub fn take_controls() -> Option<ServiceControls> {
+    SERVICE_CONTROLS.lock().unwrap().take()
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (commands_sender, commands) = unbounded_channel();
+    let status_handle = match service_control_handler::register(SERVICE_NAME, move |control| {
+        let command = match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => ServiceCommand::Stop,
+            ServiceControl::Pause => ServiceCommand::Pause,
+            ServiceControl::Continue => ServiceCommand::Continue,
+            ServiceControl::Interrogate => return ServiceControlHandlerResult::NoError,
+            _ => return ServiceControlHandlerResult::NotImplemented,
+        };
+        commands_sender.send(command).ok();
+        ServiceControlHandlerResult::NoError
+    }) {
+        Ok(status_handle) => status_handle,
+        Err(error) => {
+            error!("Failed to register service control handler: {}", error);
+            return;
+        }
+    };
+    set_state(&status_handle, ServiceState::Running);
+    *SERVICE_CONTROLS.lock().unwrap() = Some(ServiceControls {
+        commands,
+        status_handle,
+    });
+    if let Some(main) = SERVICE_MAIN.get() {
+        main();
+    }
+    set_state(&status_handle, ServiceState::Stopped);
+}
+
+fn set_state(status_handle: &service_control_handler::ServiceStatusHandle, state: ServiceState) {
+    let controls_accepted = match state {
+        ServiceState::Running | ServiceState::Paused => {
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::PAUSE_CONTINUE
+        }
+        _ => ServiceControlAccept::empty(),
+    };
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::from_secs(5),
+        process_id: None,
+    };
+    if let Err(error) = status_handle.set_service_status(status) {
+        error!("Failed to set service status: {}", error);
+    }
+}
+
+impl ServiceControls {
+    /// Pauses, resumes and stops given relays as requested. The process exits
+    /// once stopped.
+    pub async fn handle(mut self, relays: Vec<Relay>) {
+        while let Some(command) = self.commands.recv().await {
+            match command {
+                ServiceCommand::Stop => break,
+                ServiceCommand::Pause => {
+                    info!("Pausing service");
+                    for relay in &relays {
+                        relay.pause().await;
+                    }
+                    set_state(&self.status_handle, ServiceState::Paused);
+                }
+                ServiceCommand::Continue => {
+                    info!("Continuing service");
+                    for relay in &relays {
+                        relay.resume().await;
+                    }
+                    set_state(&self.status_handle, ServiceState::Running);
+                }
+            }
+        }
+        info!("Stopping service");
+        set_state(&self.status_handle, ServiceState::StopPending);
+        for relay in &relays {
+            relay.stop().await;
+        }
+        set_state(&self.status_handle, ServiceState::Stopped);
+        std::process::exit(0);
+    }
+}
+
+/// Installs the service, started automatically at boot with given arguments
+/// as the local system account.
+pub fn install(arguments: Vec<OsString>) -> Result<(), windows_service::Error> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let service_info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: SERVICE_DISPLAY_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments: arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Use this device as an extra SRTLA bonding connection")
+}
+
+/// Uninstalls the service. It is removed once stopped.
+pub fn uninstall() -> Result<(), windows_service::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()
+}
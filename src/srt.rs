@@ -0,0 +1,172 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::metrics;
+use crate::stats::Counters;
+use crate::utils::now_us;
+
+const HEADER_SIZE: usize = 16;
+const CONTROL_TYPE_NAK: u16 = 3;
+const RETRANSMITTED_FLAG: u8 = 0x04;
+const LOSS_RANGE_FLAG: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    ToDestination,
+    ToStreamer,
+}
+
+impl Direction {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn opposite(self) -> Self {
+        match self {
+            Direction::ToDestination => Direction::ToStreamer,
+            Direction::ToStreamer => Direction::ToDestination,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Direction::ToDestination => "to_destination",
+            Direction::ToStreamer => "to_streamer",
+        }
+    }
+}
+
+/// SRT statistics for one direction, estimated by inspecting packets passing
+/// through the relay. With SRTLA only a part of all packets pass through a
+/// relay, so sequence number gaps are not used to estimate loss.
+#[derive(Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SrtStats {
+    pub data_packets: u64,
+    pub retransmitted_packets: u64,
+    /// Packets reported lost by the receiver in NAKs.
+    pub lost_packets: u64,
+    /// Share of data packets that are retransmissions.
+    pub retransmission_percentage: f64,
+    /// Interarrival jitter as in RFC 3550, based on the SRT timestamps.
+    pub jitter_ms: f64,
+}
+
+#[derive(Default)]
+struct DirectionState {
+    stats: SrtStats,
+    latest_transit_us: Option<u32>,
+    jitter_us: f64,
+    /// Counters in the latest publish to the metrics sink.
+    published: SrtStats,
+}
+
+/// Parses SRT headers without modifying the packets.
+#[derive(Default)]
+pub(crate) struct SrtInspector {
+    directions: Mutex<[DirectionState; 2]>,
+}
+
+impl SrtInspector {
+    pub(crate) fn handle_packet(&self, direction: Direction, packet: &[u8]) {
+        if packet.len() < HEADER_SIZE {
+            return;
+        }
+        if packet[0] & 0x80 == 0 {
+            self.handle_data_packet(direction, packet);
+        } else if u16::from_be_bytes([packet[0] & 0x7f, packet[1]]) == CONTROL_TYPE_NAK {
+            // NAKs are sent by the receiver, so they report loss in the
+            // opposite direction.
+            self.handle_nak_packet(direction.opposite(), &packet[HEADER_SIZE..]);
+        }
+    }
+
+    fn handle_data_packet(&self, direction: Direction, packet: &[u8]) {
+        let retransmitted = packet[4] & RETRANSMITTED_FLAG != 0;
+        let timestamp = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let mut directions = self.directions.lock().unwrap();
+        let state = &mut directions[direction.index()];
+        state.stats.data_packets += 1;
+        if retransmitted {
+            state.stats.retransmitted_packets += 1;
+        } else {
+            // Retransmissions are delayed by the sender and would distort the
+            // jitter.
+            let transit = (now_us() as u32).wrapping_sub(timestamp);
+            if let Some(latest_transit) = state.latest_transit_us {
+                let difference = (transit.wrapping_sub(latest_transit) as i32).unsigned_abs();
+                state.jitter_us += (difference as f64 - state.jitter_us) / 16.0;
+            }
+            state.latest_transit_us = Some(transit);
+            state.stats.jitter_ms = state.jitter_us / 1000.0;
+        }
+        state.stats.retransmission_percentage =
+            100.0 * state.stats.retransmitted_packets as f64 / state.stats.data_packets as f64;
+    }
+
+    fn handle_nak_packet(&self, direction: Direction, loss_list: &[u8]) {
+        let mut entries = loss_list
+            .chunks_exact(4)
+            .map(|entry| u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]));
+        let mut lost_packets = 0;
+        while let Some(entry) = entries.next() {
+            if entry & LOSS_RANGE_FLAG != 0 {
+                let Some(last) = entries.next() else {
+                    break;
+                };
+                let first = entry & !LOSS_RANGE_FLAG;
+                lost_packets += u64::from(last.wrapping_sub(first) & !LOSS_RANGE_FLAG) + 1;
+            } else {
+                lost_packets += 1;
+            }
+        }
+        self.directions.lock().unwrap()[direction.index()]
+            .stats
+            .lost_packets += lost_packets;
+    }
+
+    /// Publishes counters and jitter to the metrics sink, if any. Called
+    /// periodically.
+    pub(crate) fn publish_metrics(&self, counters: &Counters) {
+        let mut directions = self.directions.lock().unwrap();
+        for direction in [Direction::ToDestination, Direction::ToStreamer] {
+            let state = &mut directions[direction.index()];
+            let labels = [("direction", direction.label())];
+            for (name, value, published) in [
+                (
+                    metrics::SRT_DATA_PACKETS,
+                    state.stats.data_packets,
+                    &mut state.published.data_packets,
+                ),
+                (
+                    metrics::SRT_RETRANSMITTED_PACKETS,
+                    state.stats.retransmitted_packets,
+                    &mut state.published.retransmitted_packets,
+                ),
+                (
+                    metrics::SRT_LOST_PACKETS,
+                    state.stats.lost_packets,
+                    &mut state.published.lost_packets,
+                ),
+            ] {
+                if value > *published {
+                    counters.increment_metric(name, &labels, value - *published);
+                    *published = value;
+                }
+            }
+            if state.latest_transit_us.is_some() {
+                counters.set_metric(metrics::SRT_JITTER, &labels, state.jitter_us / 1_000_000.0);
+            }
+        }
+    }
+
+    /// Returns statistics to the destination and to the streamer.
+    pub(crate) fn snapshot(&self) -> (SrtStats, SrtStats) {
+        let directions = self.directions.lock().unwrap();
+        (
+            directions[Direction::ToDestination.index()].stats.clone(),
+            directions[Direction::ToStreamer.index()].stats.clone(),
+        )
+    }
+}
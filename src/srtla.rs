@@ -0,0 +1,119 @@
+//! SRTLA, the bonding protocol of Belabox, on the leg to the destination, so
+//! that a relay can feed SRTLA receivers, for example srtla_rec, directly.
+//!
+//! The relay registers a group with the receiver, with each destination
+//! socket as a connection in it, and then forwards SRT as is. The receiver
+//! sends SRTLA packets of its own too, for example keepalive replies and
+//! acknowledgements, which are not forwarded to the streamer.
+
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use rand::RngCore;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, interval, sleep_until, timeout_at};
+use tracing::debug;
+
+use crate::utils::AnyError;
+
+const TYPE_KEEPALIVE: u16 = 0x9000;
+const TYPE_REG1: u16 = 0x9200;
+const TYPE_REG2: u16 = 0x9201;
+const TYPE_REG3: u16 = 0x9202;
+const TYPE_REG_ERR: u16 = 0x9210;
+const TYPE_REG_NGP: u16 = 0x9211;
+const TYPE_REG_NAK: u16 = 0x9212;
+/// The first half is chosen by the sender and the second half by the
+/// receiver.
+const ID_SIZE: usize = 256;
+const REGISTRATION_ATTEMPTS: usize = 5;
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(1);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn packet_type(packet: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(packet.get(..2)?.try_into().ok()?))
+}
+
+fn create_packet(packet_type: u16, id: &[u8]) -> Vec<u8> {
+    let mut packet = packet_type.to_be_bytes().to_vec();
+    packet.extend_from_slice(id);
+    packet
+}
+
+/// SRTLA packets are SRT control packets with types not used by SRT.
+pub(crate) fn is_srtla_packet(packet: &[u8]) -> bool {
+    packet_type(packet).is_some_and(|packet_type| (0x9000..0x9300).contains(&packet_type))
+}
+
+/// Registers a group with the receiver given sockets are connected to, and
+/// each socket as a connection in it.
+pub(crate) async fn register(sockets: &[Arc<UdpSocket>]) -> Result<(), Error> {
+    let mut id = [0; ID_SIZE];
+    rand::rng().fill_bytes(&mut id);
+    let response = request(&sockets[0], &create_packet(TYPE_REG1, &id), TYPE_REG2).await?;
+    if response.len() != 2 + ID_SIZE || response[2..2 + ID_SIZE / 2] != id[..ID_SIZE / 2] {
+        return Err(Error::other("SRTLA receiver answered with another group"));
+    }
+    let register_connection = create_packet(TYPE_REG2, &response[2..]);
+    for socket in sockets {
+        request(socket, &register_connection, TYPE_REG3).await?;
+        debug!("Registered SRTLA connection {}", socket.local_addr()?);
+    }
+    Ok(())
+}
+
+/// Sends given packet until a packet of given type is received, which is
+/// returned.
+async fn request(socket: &UdpSocket, packet: &[u8], response_type: u16) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![0; 2048];
+    for _ in 0..REGISTRATION_ATTEMPTS {
+        let deadline = Instant::now() + REGISTRATION_TIMEOUT;
+        if let Err(error) = socket.send(packet).await
+            && error.kind() != ErrorKind::ConnectionRefused
+        {
+            return Err(error);
+        }
+        loop {
+            let size = match timeout_at(deadline, socket.recv(&mut buffer)).await {
+                Ok(Ok(size)) => size,
+                // Not listening yet, for example.
+                Ok(Err(error)) if error.kind() == ErrorKind::ConnectionRefused => {
+                    sleep_until(deadline).await;
+                    break;
+                }
+                Ok(Err(error)) => return Err(error),
+                Err(_) => break,
+            };
+            match packet_type(&buffer[..size]) {
+                Some(packet_type) if packet_type == response_type => {
+                    return Ok(buffer[..size].to_vec());
+                }
+                Some(packet_type @ (TYPE_REG_ERR | TYPE_REG_NGP | TYPE_REG_NAK)) => {
+                    return Err(Error::other(format!(
+                        "SRTLA receiver rejected the registration with type {:#06x}",
+                        packet_type
+                    )));
+                }
+                _ => debug!("Ignoring unexpected packet from SRTLA receiver"),
+            }
+        }
+    }
+    Err(Error::new(
+        ErrorKind::TimedOut,
+        "SRTLA receiver did not answer",
+    ))
+}
+
+/// Keeps the connections registered while the streamer sends nothing. Not
+/// counted as traffic.
+pub(crate) async fn send_keepalives(sockets: Vec<Arc<UdpSocket>>) -> Result<(), AnyError> {
+    let keepalive = TYPE_KEEPALIVE.to_be_bytes();
+    let mut interval = interval(KEEPALIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        for socket in &sockets {
+            // For example ICMP port unreachable while the receiver restarts.
+            socket.send(&keepalive).await.ok();
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::{trace, warn};
+
+use crate::metrics::{self, MetricsSink};
+use crate::pacing::PacingStats;
+use crate::srt::SrtStats;
+
+static DATAGRAM_SIZE_BUCKETS: LazyLock<Vec<f64>> =
+    LazyLock::new(|| metrics::log_linear_buckets(32.0, 2048.0));
+/// From about 15 µs to 2 s.
+static DATAGRAM_INTER_ARRIVAL_TIME_BUCKETS: LazyLock<Vec<f64>> =
+    LazyLock::new(|| metrics::log_linear_buckets(1.0 / 65536.0, 2.0));
+/// Of the trace logs of every relayed datagram, whichever way it is
+/// forwarded, so that they can be enabled without other trace logs.
+const TUNNEL_LOG_TARGET: &str = "moblink_rust::tunnel";
+/// Oversized datagrams are warned about at most this often.
+const OVERSIZED_DATAGRAM_WARNING_INTERVAL_US: u64 = 10_000_000;
+/// Of datagram arrival times.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// Of all histograms, plus one for values above all buckets.
+const MAXIMUM_HISTOGRAM_BUCKETS: usize = 40;
+/// Labels of the per-direction histograms, by index.
+const DIRECTIONS: [&str; 2] = ["to_destination", "to_streamer"];
+
+/// A histogram that is observed without locking or allocating, and taken
+/// when published. Values are integers, for example microseconds.
+struct AtomicHistogram {
+    counts: [AtomicU64; MAXIMUM_HISTOGRAM_BUCKETS],
+    sums: [AtomicU64; MAXIMUM_HISTOGRAM_BUCKETS],
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            sums: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    /// `unit` is the size of one in `value` in the unit of the bucket bounds.
+    fn observe(&self, buckets: &[f64], unit: f64, value: u64) {
+        let index = buckets
+            .iter()
+            .position(|bound| value as f64 * unit <= *bound)
+            .unwrap_or(buckets.len());
+        self.sums[index].fetch_add(value, Ordering::Relaxed);
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        for value in self.counts.iter().chain(&self.sums) {
+            value.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns counts and sums per bucket since the previous call, or `None`
+    /// if nothing was observed.
+    fn take(&self, buckets: &[f64], unit: f64) -> Option<(Vec<u64>, Vec<f64>)> {
+        let (counts, sums): (Vec<u64>, Vec<f64>) = (0..=buckets.len())
+            .map(|index| {
+                (
+                    self.counts[index].swap(0, Ordering::Relaxed),
+                    self.sums[index].swap(0, Ordering::Relaxed) as f64 * unit,
+                )
+            })
+            .unzip();
+        counts
+            .iter()
+            .any(|count| *count > 0)
+            .then_some((counts, sums))
+    }
+}
+
+struct MetricsTarget {
+    sink: Arc<dyn MetricsSink>,
+    relay_name: String,
+}
+
+/// Values of per-datagram counters in the latest publish.
+#[derive(Default)]
+struct PublishedCounters {
+    bytes_to_destination: u64,
+    packets_to_destination: u64,
+    bytes_to_streamer: u64,
+    packets_to_streamer: u64,
+    oversized_datagrams: u64,
+}
+
+/// Counters shared between a relay and its tasks. Also forwarded to the
+/// metrics sink, if any. Per-datagram counters are published periodically
+/// instead, so that the forwarding path does not lock or allocate.
+#[derive(Default)]
+pub(crate) struct Counters {
+    bytes_to_destination: AtomicU64,
+    packets_to_destination: AtomicU64,
+    bytes_to_streamer: AtomicU64,
+    packets_to_streamer: AtomicU64,
+    reconnects: AtomicU64,
+    authentication_failures: AtomicU64,
+    tunnel_restarts: AtomicU64,
+    destination_timeouts: AtomicU64,
+    /// That is not fragmented on the way to the destination, or zero if
+    /// unknown.
+    maximum_datagram_size: AtomicU64,
+    oversized_datagrams: AtomicU64,
+    /// In microseconds since `EPOCH`, or zero if never.
+    latest_oversized_datagram_warning_us: AtomicU64,
+    /// Zero if unknown.
+    round_trip_time_us: AtomicU64,
+    paced_packets: AtomicU64,
+    pacing_dropped_packets: AtomicU64,
+    pacing_delay_us: AtomicU64,
+    max_pacing_delay_us: AtomicU64,
+    /// Of the latest datagram per direction, in microseconds since `EPOCH`,
+    /// or zero if none in the current tunnel.
+    latest_to_destination_us: AtomicU64,
+    latest_to_streamer_us: AtomicU64,
+    /// To the destination and to the streamer.
+    datagram_sizes: [AtomicHistogram; 2],
+    datagram_inter_arrival_times: [AtomicHistogram; 2],
+    metrics_target: RwLock<Option<MetricsTarget>>,
+    /// There is a metrics sink, so histograms are observed.
+    metrics_enabled: AtomicBool,
+    published: Mutex<PublishedCounters>,
+    /// Added as a label to all metrics, if any.
+    tunnel_annotation: RwLock<Option<String>>,
+}
+
+impl Counters {
+    pub(crate) fn set_metrics_sink(&self, sink: Arc<dyn MetricsSink>, relay_name: String) {
+        *self.metrics_target.write().unwrap() = Some(MetricsTarget { sink, relay_name });
+        *self.published.lock().unwrap() = PublishedCounters {
+            bytes_to_destination: self.bytes_to_destination.load(Ordering::Relaxed),
+            packets_to_destination: self.packets_to_destination.load(Ordering::Relaxed),
+            bytes_to_streamer: self.bytes_to_streamer.load(Ordering::Relaxed),
+            packets_to_streamer: self.packets_to_streamer.load(Ordering::Relaxed),
+            oversized_datagrams: self.oversized_datagrams.load(Ordering::Relaxed),
+        };
+        self.metrics_enabled.store(true, Ordering::Relaxed);
+        for histogram in self
+            .datagram_sizes
+            .iter()
+            .chain(&self.datagram_inter_arrival_times)
+        {
+            histogram.clear();
+        }
+    }
+
+    /// Publishes per-datagram counters to the metrics sink, if any. Called
+    /// periodically, and before labels change.
+    pub(crate) fn publish_metrics(&self) {
+        let published = &mut *self.published.lock().unwrap();
+        for (name, counter, published) in [
+            (
+                metrics::BYTES_TO_DESTINATION,
+                &self.bytes_to_destination,
+                &mut published.bytes_to_destination,
+            ),
+            (
+                metrics::PACKETS_TO_DESTINATION,
+                &self.packets_to_destination,
+                &mut published.packets_to_destination,
+            ),
+            (
+                metrics::BYTES_TO_STREAMER,
+                &self.bytes_to_streamer,
+                &mut published.bytes_to_streamer,
+            ),
+            (
+                metrics::PACKETS_TO_STREAMER,
+                &self.packets_to_streamer,
+                &mut published.packets_to_streamer,
+            ),
+            (
+                metrics::OVERSIZED_DATAGRAMS,
+                &self.oversized_datagrams,
+                &mut published.oversized_datagrams,
+            ),
+        ] {
+            let value = counter.load(Ordering::Relaxed);
+            if value > *published {
+                self.increment_metric(name, &[], value - *published);
+                *published = value;
+            }
+        }
+        for (index, direction) in DIRECTIONS.iter().enumerate() {
+            let labels = [("direction", *direction)];
+            if let Some((counts, sums)) =
+                self.datagram_sizes[index].take(&DATAGRAM_SIZE_BUCKETS, 1.0)
+            {
+                self.add_histogram_metric(
+                    metrics::DATAGRAM_SIZE,
+                    &labels,
+                    &DATAGRAM_SIZE_BUCKETS,
+                    &counts,
+                    &sums,
+                );
+            }
+            if let Some((counts, sums)) = self.datagram_inter_arrival_times[index]
+                .take(&DATAGRAM_INTER_ARRIVAL_TIME_BUCKETS, 1e-6)
+            {
+                self.add_histogram_metric(
+                    metrics::DATAGRAM_INTER_ARRIVAL_TIME,
+                    &labels,
+                    &DATAGRAM_INTER_ARRIVAL_TIME_BUCKETS,
+                    &counts,
+                    &sums,
+                );
+            }
+        }
+    }
+
+    pub(crate) fn set_relay_name(&self, relay_name: String) {
+        if let Some(target) = self.metrics_target.write().unwrap().as_mut() {
+            target.relay_name = relay_name;
+        }
+    }
+
+    pub(crate) fn set_tunnel_annotation(&self, annotation: Option<String>) {
+        *self.tunnel_annotation.write().unwrap() = annotation;
+    }
+
+    pub(crate) fn add_to_destination(&self, size: usize) {
+        trace!(target: TUNNEL_LOG_TARGET, "Relayed {} bytes to the destination", size);
+        let maximum_size = self.maximum_datagram_size.load(Ordering::Relaxed);
+        if maximum_size != 0 && size as u64 > maximum_size {
+            self.add_oversized_datagram(size, maximum_size);
+        }
+        self.bytes_to_destination
+            .fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_to_destination.fetch_add(1, Ordering::Relaxed);
+        self.observe_datagram(0, size, &self.latest_to_destination_us);
+    }
+
+    pub(crate) fn add_to_streamer(&self, size: usize) {
+        trace!(target: TUNNEL_LOG_TARGET, "Relayed {} bytes to the streamer", size);
+        self.bytes_to_streamer
+            .fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_to_streamer.fetch_add(1, Ordering::Relaxed);
+        self.observe_datagram(1, size, &self.latest_to_streamer_us);
+    }
+
+    /// Sizes and inter-arrival times tell encoder pacing problems from
+    /// network problems. Only if there is a metrics sink. `direction` is an
+    /// index in `DIRECTIONS`.
+    fn observe_datagram(&self, direction: usize, size: usize, latest_us: &AtomicU64) {
+        if !self.metrics_enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        self.datagram_sizes[direction].observe(&DATAGRAM_SIZE_BUCKETS, 1.0, size as u64);
+        let now_us = (EPOCH.elapsed().as_micros() as u64).max(1);
+        let latest_us = latest_us.swap(now_us, Ordering::Relaxed);
+        if latest_us != 0 {
+            self.datagram_inter_arrival_times[direction].observe(
+                &DATAGRAM_INTER_ARRIVAL_TIME_BUCKETS,
+                1e-6,
+                now_us.saturating_sub(latest_us),
+            );
+        }
+    }
+
+    /// So that the time between tunnels is not observed.
+    pub(crate) fn reset_inter_arrival_times(&self) {
+        self.latest_to_destination_us.store(0, Ordering::Relaxed);
+        self.latest_to_streamer_us.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_maximum_datagram_size(&self, maximum_size: Option<u64>) {
+        self.maximum_datagram_size
+            .store(maximum_size.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Would be fragmented, which cellular networks handle poorly. Warned
+    /// about at most every 10 seconds, as it is typically every datagram.
+    fn add_oversized_datagram(&self, size: usize, maximum_size: u64) {
+        let oversized_datagrams = self.oversized_datagrams.fetch_add(1, Ordering::Relaxed) + 1;
+        let now_us = (EPOCH.elapsed().as_micros() as u64).max(1);
+        let latest_us = self
+            .latest_oversized_datagram_warning_us
+            .load(Ordering::Relaxed);
+        if latest_us != 0 && now_us - latest_us < OVERSIZED_DATAGRAM_WARNING_INTERVAL_US {
+            return;
+        }
+        if self
+            .latest_oversized_datagram_warning_us
+            .compare_exchange(latest_us, now_us, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            warn!(
+                "Datagram of {} bytes to the destination is larger than the path MTU allows ({} bytes) and will be fragmented, {} such datagrams so far",
+                size, maximum_size, oversized_datagrams
+            );
+        }
+    }
+
+    pub(crate) fn add_reconnect(&self, reason: &str) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.increment_metric(metrics::RECONNECTS, &[("reason", reason)], 1);
+    }
+
+    pub(crate) fn add_authentication_failure(&self) {
+        self.authentication_failures.fetch_add(1, Ordering::Relaxed);
+        self.increment_metric(metrics::AUTHENTICATION_FAILURES, &[], 1);
+    }
+
+    pub(crate) fn add_tunnel_restart(&self, reason: &str) {
+        self.tunnel_restarts.fetch_add(1, Ordering::Relaxed);
+        self.increment_metric(metrics::TUNNEL_RESTARTS, &[("reason", reason)], 1);
+    }
+
+    pub(crate) fn add_destination_timeout(&self) {
+        self.destination_timeouts.fetch_add(1, Ordering::Relaxed);
+        self.increment_metric(metrics::DESTINATION_TIMEOUTS, &[], 1);
+    }
+
+    pub(crate) fn add_pacing_delay(&self, delay: Duration) {
+        let delay_us = delay.as_micros() as u64;
+        self.paced_packets.fetch_add(1, Ordering::Relaxed);
+        self.pacing_delay_us.fetch_add(delay_us, Ordering::Relaxed);
+        self.max_pacing_delay_us
+            .fetch_max(delay_us, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_pacing_drop(&self) {
+        self.pacing_dropped_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_round_trip_time(&self, round_trip_time: Option<Duration>) {
+        let round_trip_time_us = round_trip_time.map_or(0, |value| value.as_micros().max(1) as u64);
+        self.round_trip_time_us
+            .store(round_trip_time_us, Ordering::Relaxed);
+        if let Some(round_trip_time) = round_trip_time {
+            self.set_metric(metrics::ROUND_TRIP_TIME, &[], round_trip_time.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn increment_metric(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let metrics_target = self.metrics_target.read().unwrap();
+        let Some(target) = metrics_target.as_ref() else {
+            return;
+        };
+        let tunnel_annotation = self.tunnel_annotation.read().unwrap();
+        let mut all_labels = vec![("relay", target.relay_name.as_str())];
+        if let Some(annotation) = tunnel_annotation.as_deref() {
+            all_labels.push(("annotation", annotation));
+        }
+        all_labels.extend_from_slice(labels);
+        target.sink.increment_counter(name, &all_labels, value);
+    }
+
+    pub(crate) fn set_metric(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let metrics_target = self.metrics_target.read().unwrap();
+        let Some(target) = metrics_target.as_ref() else {
+            return;
+        };
+        let tunnel_annotation = self.tunnel_annotation.read().unwrap();
+        let mut all_labels = vec![("relay", target.relay_name.as_str())];
+        if let Some(annotation) = tunnel_annotation.as_deref() {
+            all_labels.push(("annotation", annotation));
+        }
+        all_labels.extend_from_slice(labels);
+        target.sink.set_gauge(name, &all_labels, value);
+    }
+
+    fn add_histogram_metric(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        counts: &[u64],
+        sums: &[f64],
+    ) {
+        let metrics_target = self.metrics_target.read().unwrap();
+        let Some(target) = metrics_target.as_ref() else {
+            return;
+        };
+        let tunnel_annotation = self.tunnel_annotation.read().unwrap();
+        let mut all_labels = vec![("relay", target.relay_name.as_str())];
+        if let Some(annotation) = tunnel_annotation.as_deref() {
+            all_labels.push(("annotation", annotation));
+        }
+        all_labels.extend_from_slice(labels);
+        target
+            .sink
+            .add_histogram_observations(name, &all_labels, buckets, counts, sums);
+    }
+
+    pub(crate) fn snapshot(&self) -> RelayStats {
+        RelayStats {
+            bytes_to_destination: self.bytes_to_destination.load(Ordering::Relaxed),
+            packets_to_destination: self.packets_to_destination.load(Ordering::Relaxed),
+            bytes_to_streamer: self.bytes_to_streamer.load(Ordering::Relaxed),
+            packets_to_streamer: self.packets_to_streamer.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            authentication_failures: self.authentication_failures.load(Ordering::Relaxed),
+            tunnel_restarts: self.tunnel_restarts.load(Ordering::Relaxed),
+            destination_timeouts: self.destination_timeouts.load(Ordering::Relaxed),
+            oversized_datagrams: self.oversized_datagrams.load(Ordering::Relaxed),
+            round_trip_time_ms: match self.round_trip_time_us.load(Ordering::Relaxed) {
+                0 => None,
+                round_trip_time_us => Some(round_trip_time_us as f64 / 1000.0),
+            },
+            srt_to_destination: None,
+            srt_to_streamer: None,
+            pacing: self.pacing_snapshot(),
+        }
+    }
+
+    fn pacing_snapshot(&self) -> Option<PacingStats> {
+        let packets = self.paced_packets.load(Ordering::Relaxed);
+        let dropped_packets = self.pacing_dropped_packets.load(Ordering::Relaxed);
+        if packets == 0 && dropped_packets == 0 {
+            return None;
+        }
+        let delay_us = self.pacing_delay_us.load(Ordering::Relaxed);
+        Some(PacingStats {
+            packets,
+            dropped_packets,
+            average_delay_ms: delay_us as f64 / packets.max(1) as f64 / 1000.0,
+            max_delay_ms: self.max_pacing_delay_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        })
+    }
+}
+
+/// Relay counters since the relay was created.
+#[derive(Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayStats {
+    pub bytes_to_destination: u64,
+    pub packets_to_destination: u64,
+    pub bytes_to_streamer: u64,
+    pub packets_to_streamer: u64,
+    pub reconnects: u64,
+    /// The streamer rejected the password.
+    pub authentication_failures: u64,
+    /// Tunnels replaced by a new one from the streamer, or failed.
+    pub tunnel_restarts: u64,
+    /// Nothing was received from the destination within the destination
+    /// timeout.
+    pub destination_timeouts: u64,
+    /// Datagrams to the destination larger than the path MTU allows, which
+    /// are fragmented. Only known on Linux.
+    pub oversized_datagrams: u64,
+    /// Smoothed WebSocket round trip time to the streamer, if connected.
+    pub round_trip_time_ms: Option<f64>,
+    /// Only if SRT inspection is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srt_to_destination: Option<SrtStats>,
+    /// Only if SRT inspection is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srt_to_streamer: Option<SrtStats>,
+    /// Only if datagrams to the destination have been paced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pacing: Option<PacingStats>,
+}
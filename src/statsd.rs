@@ -0,0 +1,268 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+use crate::relay::{ConnectionState, Relay};
+use crate::stats::RelayStats;
+
+const INTERVAL: Duration = Duration::from_secs(10);
+const PREFIX: &str = "moblink.relay";
+/// Metrics are packed into datagrams of at most this size, to not be
+/// fragmented.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Sends relay metrics to a StatsD server, or a Datadog agent, over UDP
+/// every 10 seconds. Traffic, reconnects and SRT loss are counts since the
+/// previous interval, and bitrates, round trip time, battery and connection
+/// state are gauges. Metrics are tagged with the relay name, if any, as
+/// `#relay:<name>` in the DogStatsD format.
+pub struct StatsdEmitter {
+    task: JoinHandle<()>,
+}
+
+impl StatsdEmitter {
+    /// Given address is `host:port`, resolved until it succeeds.
+    pub fn start(address: String, relays: Vec<Relay>) -> Self {
+        let task = tokio::spawn(async move {
+            let mut socket = None;
+            let mut previous_stats: Vec<Option<RelayStats>> = vec![None; relays.len()];
+            loop {
+                tokio::time::sleep(INTERVAL).await;
+                if socket.is_none() {
+                    match connect(&address).await {
+                        Ok(connected_socket) => {
+                            info!("Sending metrics to StatsD at {}", address);
+                            socket = Some(connected_socket);
+                        }
+                        Err(error) => {
+                            debug!("Failed to connect to StatsD with error: {}", error);
+                            continue;
+                        }
+                    }
+                }
+                let mut lines = Vec::new();
+                for (relay, previous_stats) in relays.iter().zip(previous_stats.iter_mut()) {
+                    let stats = relay.get_stats().await;
+                    append_relay_metrics(&mut lines, relay, &stats, previous_stats.as_ref()).await;
+                    *previous_stats = Some(stats);
+                }
+                if let Some(socket) = &socket {
+                    for datagram in pack(&lines) {
+                        if let Err(error) = socket.send(datagram.as_bytes()).await {
+                            debug!("Failed to send to StatsD with error: {}", error);
+                        }
+                    }
+                }
+            }
+        });
+        Self { task }
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+async fn connect(address: &str) -> Result<UdpSocket, std::io::Error> {
+    let address = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("No address"))?;
+    let bind_address = if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+/// Counts are left out the first time, as there is nothing to compare with.
+async fn append_relay_metrics(
+    lines: &mut Vec<String>,
+    relay: &Relay,
+    stats: &RelayStats,
+    previous_stats: Option<&RelayStats>,
+) {
+    let state = relay.state().await;
+    // No name in static tunnels.
+    let relay_tag =
+        (!state.name.is_empty()).then(|| format!("relay:{}", sanitize_tag_value(&state.name)));
+    let mut gauge = |name: &str, value: f64| {
+        lines.push(format_line(name, value, "g", &[relay_tag.as_deref()]));
+    };
+    gauge(
+        "connected",
+        if state.connection == ConnectionState::Connected {
+            1.0
+        } else {
+            0.0
+        },
+    );
+    if let Some(round_trip_time_ms) = stats.round_trip_time_ms {
+        gauge("round_trip_time_ms", round_trip_time_ms);
+    }
+    if let Some(battery_percentage) = relay.get_status().await.battery_percentage {
+        gauge("battery_percent", battery_percentage.into());
+    }
+    let Some(previous_stats) = previous_stats else {
+        return;
+    };
+    let seconds = INTERVAL.as_secs_f64();
+    gauge(
+        "bitrate_to_destination",
+        8.0 * stats
+            .bytes_to_destination
+            .saturating_sub(previous_stats.bytes_to_destination) as f64
+            / seconds,
+    );
+    gauge(
+        "bitrate_to_streamer",
+        8.0 * stats
+            .bytes_to_streamer
+            .saturating_sub(previous_stats.bytes_to_streamer) as f64
+            / seconds,
+    );
+    let mut count = |name: &str, current: u64, previous: u64, direction_tag: Option<&str>| {
+        lines.push(format_line(
+            name,
+            current.saturating_sub(previous),
+            "c",
+            &[relay_tag.as_deref(), direction_tag],
+        ));
+    };
+    count(
+        "bytes_to_destination",
+        stats.bytes_to_destination,
+        previous_stats.bytes_to_destination,
+        None,
+    );
+    count(
+        "packets_to_destination",
+        stats.packets_to_destination,
+        previous_stats.packets_to_destination,
+        None,
+    );
+    count(
+        "bytes_to_streamer",
+        stats.bytes_to_streamer,
+        previous_stats.bytes_to_streamer,
+        None,
+    );
+    count(
+        "packets_to_streamer",
+        stats.packets_to_streamer,
+        previous_stats.packets_to_streamer,
+        None,
+    );
+    count(
+        "reconnects",
+        stats.reconnects,
+        previous_stats.reconnects,
+        None,
+    );
+    count(
+        "authentication_failures",
+        stats.authentication_failures,
+        previous_stats.authentication_failures,
+        None,
+    );
+    count(
+        "tunnel_restarts",
+        stats.tunnel_restarts,
+        previous_stats.tunnel_restarts,
+        None,
+    );
+    count(
+        "destination_timeouts",
+        stats.destination_timeouts,
+        previous_stats.destination_timeouts,
+        None,
+    );
+    count(
+        "oversized_datagrams",
+        stats.oversized_datagrams,
+        previous_stats.oversized_datagrams,
+        None,
+    );
+    let srt_stats = [
+        (
+            "to_destination",
+            &stats.srt_to_destination,
+            &previous_stats.srt_to_destination,
+        ),
+        (
+            "to_streamer",
+            &stats.srt_to_streamer,
+            &previous_stats.srt_to_streamer,
+        ),
+    ];
+    for (direction, srt_stats, previous_srt_stats) in srt_stats {
+        let Some(srt_stats) = srt_stats else {
+            continue;
+        };
+        let previous_srt_stats = previous_srt_stats.clone().unwrap_or_default();
+        let direction_tag = format!("direction:{}", direction);
+        count(
+            "srt.data_packets",
+            srt_stats.data_packets,
+            previous_srt_stats.data_packets,
+            Some(&direction_tag),
+        );
+        count(
+            "srt.retransmitted_packets",
+            srt_stats.retransmitted_packets,
+            previous_srt_stats.retransmitted_packets,
+            Some(&direction_tag),
+        );
+        count(
+            "srt.lost_packets",
+            srt_stats.lost_packets,
+            previous_srt_stats.lost_packets,
+            Some(&direction_tag),
+        );
+    }
+}
+
+fn format_line(name: &str, value: impl Display, kind: &str, tags: &[Option<&str>]) -> String {
+    let tags: Vec<&str> = tags.iter().flatten().copied().collect();
+    if tags.is_empty() {
+        format!("{}.{}:{}|{}", PREFIX, name, value, kind)
+    } else {
+        format!("{}.{}:{}|{}|#{}", PREFIX, name, value, kind, tags.join(","))
+    }
+}
+
+/// Characters with a meaning in the DogStatsD format are replaced.
+fn sanitize_tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ',' | '|' | '#' | '\n' | '\r' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// One metric per line.
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
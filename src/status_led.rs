@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use rppal::gpio::{Gpio, Level};
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::relay::Relay;
+use crate::utils::AnyError;
+
+const TICK: Duration = Duration::from_millis(100);
+/// Blinking toggles every this many ticks, and fast blinking every tick.
+const BLINK_TICKS: u32 = 5;
+/// The pattern is updated every second, which is also the window in which
+/// traffic makes a tunnel count as active.
+const UPDATE_TICKS: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Pattern {
+    /// Stopped.
+    Off,
+    /// Connecting to the streamer.
+    Blinking,
+    /// Connected to the streamer.
+    Solid,
+    /// Forwarding traffic on a tunnel.
+    FastBlinking,
+}
+
+impl Pattern {
+    fn level(self, tick: u32) -> Level {
+        let on = match self {
+            Pattern::Off => false,
+            Pattern::Blinking => (tick / BLINK_TICKS).is_multiple_of(2),
+            Pattern::Solid => true,
+            Pattern::FastBlinking => tick.is_multiple_of(2),
+        };
+        if on { Level::High } else { Level::Low }
+    }
+}
+
+/// Shows the state of a relay on an LED connected to given GPIO pin of a
+/// Raspberry Pi, for headless relays.
+///
+/// The LED is off when the relay is stopped, blinks while connecting to the
+/// streamer, is solid when connected and blinks fast while a tunnel is
+/// forwarding traffic.
+pub struct StatusLed {
+    task: JoinHandle<()>,
+}
+
+impl StatusLed {
+    /// `pin` is a BCM GPIO number, for example 17 for physical pin 11.
+    pub fn start(pin: u8, relay: Relay) -> Result<Self, AnyError> {
+        // The pin is reset when dropped, which turns the LED off when
+        // stopped.
+        let mut led = Gpio::new()
+            .and_then(|gpio| gpio.get(pin))
+            .map_err(|error| format!("Failed to use GPIO pin {}: {}", pin, error))?
+            .into_output_low();
+        let task = tokio::spawn(async move {
+            let mut ticker = interval(TICK);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut packets_to_destination = 0;
+            let mut pattern = Pattern::Off;
+            let mut tick: u32 = 0;
+            loop {
+                ticker.tick().await;
+                if tick.is_multiple_of(UPDATE_TICKS) {
+                    pattern = get_pattern(&relay, &mut packets_to_destination).await;
+                }
+                led.write(pattern.level(tick));
+                tick = tick.wrapping_add(1);
+            }
+        });
+        Ok(Self { task })
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+async fn get_pattern(relay: &Relay, latest_packets_to_destination: &mut u64) -> Pattern {
+    let packets_to_destination = relay.get_stats().await.packets_to_destination;
+    let forwarding = packets_to_destination != *latest_packets_to_destination;
+    *latest_packets_to_destination = packets_to_destination;
+    if !relay.is_started().await {
+        Pattern::Off
+    } else if !relay.is_connected().await {
+        Pattern::Blinking
+    } else if forwarding && !relay.get_tunnels().await.is_empty() {
+        Pattern::FastBlinking
+    } else {
+        Pattern::Solid
+    }
+}
@@ -0,0 +1,300 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{info, warn};
+
+use crate::control_api::get_relay_state;
+use crate::pacing::Pacing;
+use crate::relay::{Relay, RelayEvent};
+use crate::secret::SecretString;
+use crate::streamer_url::normalize_streamer_url;
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const RELAY_ERROR: i32 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct Error {
+    code: i32,
+    message: String,
+}
+
+impl Error {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Settings {
+    streamer_url: Option<String>,
+    password: Option<SecretString>,
+}
+
+#[derive(Deserialize)]
+struct Duplication {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct Name {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Annotation {
+    id: u64,
+    annotation: Option<String>,
+}
+
+/// JSON-RPC 2.0 control of a relay over stdin and stdout, one message per
+/// line, for supervising the relay from another process without opening any
+/// ports.
+///
+/// Methods are `state`, `stats`, `tunnels`, `reconnects`, `start`, `stop`,
+/// `pause`, `resume`, `setDuplication` (`{"enabled": true}`), `setPacing`
+/// (the same as `POST /pacing` in the control API), `setName`
+/// (`{"name": "Phone 2"}`), `setTunnelAnnotation`
+/// (`{"id": 1, "annotation": "camera 2"}`) and `updateSettings`
+/// (`{"streamerUrl": "...", "password": "..."}`).
+///
+/// Relay events are sent as `event` notifications, and the stats as `stats`
+/// notifications every second. Nothing else may be written to stdout.
+#[derive(Clone)]
+pub struct StdioControl {
+    output: mpsc::UnboundedSender<String>,
+}
+
+impl StdioControl {
+    /// `on_closed` is called after the relay is stopped when stdin is closed,
+    /// typically because the supervising process exited.
+    pub fn start<F>(relay: Relay, on_closed: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (output, output_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_output(output_receiver));
+        let stdio_control = Self { output };
+        tokio::spawn(
+            stdio_control
+                .clone()
+                .handle_requests(relay.clone(), on_closed),
+        );
+        tokio::spawn(stdio_control.clone().send_stats(relay));
+        stdio_control
+    }
+
+    pub fn notify_event(&self, event: &RelayEvent) {
+        self.notify("event", json!(event));
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        }));
+    }
+
+    fn send(&self, message: Value) {
+        self.output.send(message.to_string()).ok();
+    }
+
+    async fn handle_requests<F>(self, relay: Relay, on_closed: F)
+    where
+        F: FnOnce(),
+    {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if !line.trim().is_empty() {
+                        self.handle_line(&line, &relay).await;
+                    }
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    warn!("Failed to read stdin with error: {}", error);
+                    break;
+                }
+            }
+        }
+        info!("Stdin closed, stopping");
+        relay.stop().await;
+        on_closed();
+    }
+
+    async fn handle_line(&self, line: &str, relay: &Relay) {
+        let request = match serde_json::from_str::<Value>(line) {
+            Ok(request) => request,
+            Err(error) => {
+                self.respond(Value::Null, Err(Error::new(PARSE_ERROR, error.to_string())));
+                return;
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(_) => {
+                self.respond(id, Err(Error::new(INVALID_REQUEST, "Not JSON-RPC 2.0")));
+                return;
+            }
+            Err(error) => {
+                self.respond(id, Err(Error::new(INVALID_REQUEST, error.to_string())));
+                return;
+            }
+        };
+        let result = handle_request(&request.method, request.params, relay).await;
+        // Requests without id are notifications, and are not responded to.
+        if let Some(id) = request.id {
+            self.respond(id, result);
+        }
+    }
+
+    fn respond(&self, id: Value, result: Result<Value, Error>) {
+        match result {
+            Ok(result) => self.send(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result,
+            })),
+            Err(error) => self.send(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": error,
+            })),
+        }
+    }
+
+    async fn send_stats(self, relay: Relay) {
+        let mut ticker = interval(STATS_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if self.output.is_closed() {
+                break;
+            }
+            self.notify("stats", json!(relay.get_stats().await));
+        }
+    }
+}
+
+async fn write_output(mut output: mpsc::UnboundedReceiver<String>) {
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = output.recv().await {
+        let written = async {
+            stdout.write_all(line.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await
+        };
+        if let Err(error) = written.await {
+            warn!("Failed to write to stdout with error: {}", error);
+            break;
+        }
+    }
+}
+
+fn parse_params<T>(params: Value) -> Result<T, Error>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    serde_json::from_value(params).map_err(|error| Error::new(INVALID_PARAMS, error.to_string()))
+}
+
+fn relay_error(error: impl ToString) -> Error {
+    Error::new(RELAY_ERROR, error.to_string())
+}
+
+async fn handle_request(method: &str, params: Value, relay: &Relay) -> Result<Value, Error> {
+    match method {
+        "state" => Ok(json!(get_relay_state(relay).await)),
+        "stats" => Ok(json!(relay.get_stats().await)),
+        "tunnels" => Ok(json!(relay.get_tunnels().await)),
+        "reconnects" => Ok(json!(relay.get_reconnects().await)),
+        "start" => {
+            relay.start().await.map_err(relay_error)?;
+            Ok(Value::Null)
+        }
+        "stop" => {
+            relay.stop().await;
+            Ok(Value::Null)
+        }
+        "pause" => {
+            relay.pause().await;
+            Ok(Value::Null)
+        }
+        "resume" => {
+            relay.resume().await;
+            Ok(Value::Null)
+        }
+        "setDuplication" => {
+            let duplication: Duplication = parse_params(params)?;
+            relay.set_duplication(duplication.enabled).await;
+            Ok(Value::Null)
+        }
+        "setPacing" => {
+            let pacing: Option<Pacing> = parse_params(params)?;
+            relay.set_pacing(pacing).await.map_err(relay_error)?;
+            Ok(Value::Null)
+        }
+        "setName" => {
+            let name: Name = parse_params(params)?;
+            relay.set_name(name.name).await.map_err(relay_error)?;
+            Ok(Value::Null)
+        }
+        "setTunnelAnnotation" => {
+            let annotation: Annotation = parse_params(params)?;
+            relay
+                .set_tunnel_annotation(annotation.id, annotation.annotation)
+                .await
+                .map_err(relay_error)?;
+            Ok(Value::Null)
+        }
+        "updateSettings" => {
+            let settings: Settings = parse_params(params)?;
+            let mut corrections = Vec::new();
+            let streamer_url = match settings.streamer_url.as_deref().map(normalize_streamer_url) {
+                Some(Ok(streamer_url)) => {
+                    corrections = streamer_url.corrections;
+                    Some(streamer_url.url)
+                }
+                Some(Err(error)) => return Err(Error::new(INVALID_PARAMS, error.to_string())),
+                None => None,
+            };
+            relay
+                .update_settings(streamer_url, settings.password)
+                .await
+                .map_err(relay_error)?;
+            let corrections: Vec<String> = corrections
+                .into_iter()
+                .map(|correction| correction.to_string())
+                .collect();
+            Ok(json!({ "corrections": corrections }))
+        }
+        _ => Err(Error::new(
+            METHOD_NOT_FOUND,
+            format!("No method named '{}'", method),
+        )),
+    }
+}
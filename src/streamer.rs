@@ -8,7 +8,6 @@ use std::time::Duration;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use ipnetwork::Ipv4Network;
-use log::{debug, error, info};
 use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
 use notify::event::AccessKind;
 use notify::{self, EventKind, Watcher};
@@ -22,13 +21,19 @@ use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_util::bytes::Bytes;
 use tokio_util::codec::Framed;
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
 use tun::{self, AsyncDevice, TunPacketCodec};
 use uuid::Uuid;
 
+use crate::bonding::BondingSender;
+use crate::deflate::Deflate;
 use crate::protocol::{
-    API_VERSION, Authentication, Hello, Identified, Identify, MessageRequest, MessageRequestData,
-    MessageResponse, MessageToRelay, MessageToStreamer, MoblinkResult, Present, ResponseData,
-    StartTunnelRequest, calculate_authentication,
+    API_VERSION, Authentication, CAPABILITY_CBOR, CAPABILITY_DEFLATE, CAPABILITY_ERROR_RESULT,
+    CAPABILITY_PRIORITY, CAPABILITY_QUALITY_SCORE, CAPABILITY_RENAME, CAPABILITY_STATUS_PUSH,
+    COMPRESSION_DEFLATE, Encoding, Hello, Identified, Identify, MessageRequest, MessageRequestData,
+    MessageResponse, MessageToRelay, MessageToStreamer, MoblinkResult, Present, RelayPriority,
+    ResponseData, StartTunnelRequest, StatusResponseData, calculate_authentication, decode_cbor,
+    is_newer_api_version,
 };
 use crate::utils::{AnyError, execute_command, random_string, resolve_host};
 use crate::{MDNS_SERVICE_TYPE, belaui};
@@ -90,6 +95,19 @@ struct Relay {
     tun_receiver: Option<JoinHandle<()>>,
     unique_index: u32,
     pong_received: bool,
+    /// From 0 to 100, as reported by the relay.
+    quality_score: Option<u8>,
+    /// As announced by the relay.
+    priority: RelayPriority,
+    group: Option<String>,
+    /// Set if the tunnel is part of SRT bonding instead of a TUN device.
+    bonding: Option<Arc<BondingSender>>,
+    /// The encoding of the latest message from the relay.
+    encoding: Encoding,
+    /// Requested by the relay in the identify message.
+    deflate: Option<Deflate>,
+    span: Span,
+    tunnel_span: Span,
 }
 
 impl Relay {
@@ -117,6 +135,14 @@ impl Relay {
                 tun_receiver: None,
                 unique_index,
                 pong_received: true,
+                quality_score: None,
+                priority: RelayPriority::Main,
+                group: None,
+                bonding: None,
+                encoding: Encoding::Json,
+                deflate: None,
+                span: info_span!("connection", relay_address = %relay_address),
+                tunnel_span: Span::none(),
             })
         })
     }
@@ -129,7 +155,7 @@ impl Relay {
     fn start_websocket_receiver(&mut self, mut reader: WebSocketReader) {
         let relay = self.me.clone();
 
-        tokio::spawn(async move {
+        let receiver = async move {
             let Some(relay) = relay.upgrade() else {
                 return;
             };
@@ -171,13 +197,14 @@ impl Relay {
             if let Some(streamer) = streamer {
                 streamer.lock().await.remove_relay(&relay).await;
             }
-        });
+        };
+        tokio::spawn(receiver.instrument(self.span.clone()));
     }
 
     fn start_pinger(&mut self) {
         let relay = self.me.clone();
 
-        tokio::spawn(async move {
+        let pinger = async move {
             loop {
                 {
                     let Some(relay) = relay.upgrade() else {
@@ -192,10 +219,20 @@ impl Relay {
                         relay.pong_received = false;
                         relay.send_websocket(Message::Ping(Bytes::new())).await.ok();
                     }
+                    // For the quality score of relays that do not push their
+                    // status.
+                    if relay.bonding.is_some() {
+                        let request = MessageRequest {
+                            id: 2,
+                            data: MessageRequestData::Status(Present {}),
+                        };
+                        relay.send(MessageToRelay::Request(request)).await.ok();
+                    }
                 }
                 tokio::time::sleep(Duration::from_secs(10)).await;
             }
-        });
+        };
+        tokio::spawn(pinger.instrument(self.span.clone()));
     }
 
     async fn handle_websocket_message(&mut self, message: Message) -> Result<(), AnyError> {
@@ -207,6 +244,14 @@ impl Relay {
                     Err(format!("Failed to deserialize message with error: {}", error).into())
                 }
             },
+            Message::Binary(data) => match self.decode_binary_message(&data) {
+                Ok(message) => self.handle_message(message).await,
+                Err(error) => Err(format!(
+                    "Failed to deserialize binary message with error: {}",
+                    error
+                )
+                .into()),
+            },
             Message::Ping(data) => Ok(self.send_websocket(Message::Pong(data)).await?),
             Message::Pong(_) => {
                 self.pong_received = true;
@@ -216,14 +261,64 @@ impl Relay {
         }
     }
 
+    /// Compressed if requested by the relay, and CBOR otherwise.
+    fn decode_binary_message(&mut self, data: &[u8]) -> Result<MessageToStreamer, AnyError> {
+        match self.deflate.as_mut() {
+            Some(deflate) => self.encoding.decode(&deflate.decompress(data)?),
+            None => {
+                let message = decode_cbor(data)?;
+                self.encoding = Encoding::Cbor;
+                Ok(message)
+            }
+        }
+    }
+
     async fn handle_message(&mut self, message: MessageToStreamer) -> Result<(), AnyError> {
         match message {
             MessageToStreamer::Identify(identify) => self.handle_message_identify(identify).await,
             MessageToStreamer::Response(response) => self.handle_message_response(response).await,
+            MessageToStreamer::TestReport(report) => {
+                info!(
+                    "Test report from relay {} ({}): {:?}",
+                    self.relay_name, self.relay_id, report
+                );
+                Ok(())
+            }
+            MessageToStreamer::Status(status) => {
+                debug!(
+                    "Status from relay {} ({}): {:?}",
+                    self.relay_name, self.relay_id, status
+                );
+                self.set_quality_score(status.quality_score);
+                Ok(())
+            }
+            MessageToStreamer::Rename(rename) => {
+                info!(
+                    "Relay {} ({}) renamed to {}",
+                    self.relay_name, self.relay_id, rename.name
+                );
+                self.relay_name = rename.name;
+                Ok(())
+            }
+            MessageToStreamer::Unknown(_) => {
+                warn!(
+                    "Ignoring message of unknown type from relay {} ({})",
+                    self.relay_name, self.relay_id
+                );
+                Ok(())
+            }
         }
     }
 
     async fn handle_message_identify(&mut self, identify: Identify) -> Result<(), AnyError> {
+        // The relay compresses everything after the identify message.
+        match identify.compression.as_deref() {
+            Some(COMPRESSION_DEFLATE) => self.deflate = Some(Deflate::new()),
+            Some(compression) => {
+                return Err(format!("Unsupported compression {}", compression).into());
+            }
+            None => {}
+        }
         let Some(streamer) = self.streamer.upgrade() else {
             return Err("No streamer".into());
         };
@@ -234,8 +329,26 @@ impl Relay {
             self.identified = true;
             self.relay_id = identify.id;
             self.relay_name = identify.name;
+            self.priority = identify.priority.unwrap_or_default();
+            self.group = identify.group;
+            if let Some(api_version) = &identify.api_version
+                && is_newer_api_version(api_version)
+            {
+                info!(
+                    "Relay has API version {}, newer than {}, ignoring what is not understood",
+                    api_version, API_VERSION
+                );
+            }
+            info!(
+                "Relay identified: {} ({}), priority {:?}, group {}",
+                self.relay_name,
+                self.relay_id,
+                self.priority,
+                self.group.as_deref().unwrap_or("none")
+            );
             let identified = Identified {
                 result: MoblinkResult::Ok(Present {}),
+                authentication: None,
             };
             self.send(MessageToRelay::Identified(identified)).await?;
             self.start_tunnel(&streamer.destination_address, streamer.destination_port)
@@ -243,6 +356,7 @@ impl Relay {
         } else {
             let identified = Identified {
                 result: MoblinkResult::WrongPassword(Present {}),
+                authentication: None,
             };
             self.send(MessageToRelay::Identified(identified)).await?;
             Err("Relay sent wrong password".into())
@@ -250,11 +364,29 @@ impl Relay {
     }
 
     async fn handle_message_response(&mut self, response: MessageResponse) -> Result<(), AnyError> {
+        match response.result {
+            MoblinkResult::Busy(_) => {
+                info!("Relay is paused");
+                return Ok(());
+            }
+            MoblinkResult::Error(error) => {
+                error!("Relay failed to start tunnel: {}", error.message);
+                return Ok(());
+            }
+            MoblinkResult::Unknown(_) => {
+                warn!("Ignoring response {} with unknown result", response.id);
+                return Ok(());
+            }
+            _ => {}
+        }
         match response.data {
             ResponseData::StartTunnel(data) => {
                 self.relay_tunnel_port = Some(data.port);
                 self.tunnel_created().await?;
             }
+            ResponseData::Status(StatusResponseData { quality_score, .. }) => {
+                self.set_quality_score(quality_score);
+            }
             message => {
                 info!("Ignoring message {:?}", message);
             }
@@ -291,7 +423,40 @@ impl Relay {
         self.stop_udp_networking().await;
     }
 
+    fn set_quality_score(&mut self, quality_score: Option<u8>) {
+        if quality_score.is_none() || quality_score == self.quality_score {
+            return;
+        }
+        self.quality_score = quality_score;
+        if let Some(bonding) = &self.bonding {
+            bonding.set_quality_score(self.unique_index, quality_score);
+        }
+    }
+
     async fn start_udp_networking(&mut self, relay_tunnel_port: u16) -> Result<(), AnyError> {
+        let bonding = match self.streamer.upgrade() {
+            Some(streamer) => streamer.lock().await.bonding.clone(),
+            None => None,
+        };
+        if let Some(bonding) = bonding {
+            let relay_socket = self.create_relay_socket(relay_tunnel_port).await?;
+            let _enter = self.span.enter();
+            bonding.add_link(
+                self.unique_index,
+                relay_socket,
+                self.quality_score,
+                self.priority == RelayPriority::Backup,
+            );
+            self.bonding = Some(bonding);
+            return Ok(());
+        }
+        self.tunnel_span = info_span!(
+            parent: &self.span,
+            "tunnel",
+            relay_name = %self.relay_name,
+            relay_tunnel_port,
+            tun_ip_address = %self.tun_ip_address
+        );
         let (tun_writer, tun_reader) = self.create_tun_device()?;
         let relay_socket = self.create_relay_socket(relay_tunnel_port).await?;
         self.setup_os_networking().await;
@@ -305,6 +470,10 @@ impl Relay {
     }
 
     async fn stop_udp_networking(&mut self) {
+        if let Some(bonding) = self.bonding.take() {
+            bonding.remove_link(self.unique_index);
+            return;
+        }
         if let Some(relay_receiver) = self.relay_receiver.take() {
             relay_receiver.abort();
             relay_receiver.await.ok();
@@ -338,7 +507,11 @@ impl Relay {
 
     #[cfg(not(target_os = "macos"))]
     fn tun_device_name(&self) -> String {
+        #[cfg(unix)]
         use libc::IF_NAMESIZE;
+        /// As on Unix, although Wintun adapter names may be longer.
+        #[cfg(windows)]
+        const IF_NAMESIZE: usize = 16;
         let name = self
             .relay_name
             .replace(|c: char| !c.is_ascii() || c.is_whitespace(), "-");
@@ -441,7 +614,7 @@ impl Relay {
         let Ok(destination_address) = Ipv4Addr::from_str(&streamer.destination_address) else {
             return;
         };
-        self.tun_receiver = Some(tokio::spawn(async move {
+        let tun_receiver = async move {
             let mut tun_port = 0u16;
             while let Some(packet) = tun_reader.next().await {
                 if let Err(error) = Self::handle_tun_packet(
@@ -457,7 +630,10 @@ impl Relay {
                     break;
                 }
             }
-        }));
+        };
+        self.tun_receiver = Some(tokio::spawn(
+            tun_receiver.instrument(self.tunnel_span.clone()),
+        ));
     }
 
     async fn handle_tun_packet(
@@ -534,7 +710,7 @@ impl Relay {
         let destination_port = streamer.destination_port;
         let tun_ip_address = self.tun_ip_address.clone();
 
-        self.relay_receiver = Some(tokio::spawn(async move {
+        let relay_receiver = async move {
             let Ok(destination_address) = Ipv4Addr::from_str(&destination_address) else {
                 return;
             };
@@ -557,7 +733,10 @@ impl Relay {
                     break;
                 }
             }
-        }));
+        };
+        self.relay_receiver = Some(tokio::spawn(
+            relay_receiver.instrument(self.tunnel_span.clone()),
+        ));
         Ok(())
     }
 
@@ -616,6 +795,8 @@ impl Relay {
         let start_tunnel = StartTunnelRequest {
             address: destination_address.to_string(),
             port: destination_port,
+            obfuscated: false,
+            paired_ports: false,
         };
         let request = MessageRequest {
             id: 1,
@@ -631,13 +812,25 @@ impl Relay {
                 challenge: self.challenge.clone(),
                 salt: self.salt.clone(),
             },
+            capabilities: vec![
+                CAPABILITY_STATUS_PUSH.into(),
+                CAPABILITY_CBOR.into(),
+                CAPABILITY_DEFLATE.into(),
+                CAPABILITY_RENAME.into(),
+                CAPABILITY_ERROR_RESULT.into(),
+                CAPABILITY_QUALITY_SCORE.into(),
+                CAPABILITY_PRIORITY.into(),
+            ],
         });
         self.send(hello).await.ok();
     }
 
     async fn send(&mut self, message: MessageToRelay) -> Result<(), AnyError> {
-        let text = serde_json::to_string(&message)?;
-        self.send_websocket(Message::Text(text.into())).await
+        let mut message = self.encoding.encode(&message)?;
+        if let Some(deflate) = self.deflate.as_mut() {
+            message = Message::Binary(deflate.compress(&message.into_data())?.into());
+        }
+        self.send_websocket(message).await
     }
 
     async fn send_websocket(&mut self, message: Message) -> Result<(), AnyError> {
@@ -669,9 +862,12 @@ struct StreamerInner {
     unique_indexes: Vec<u32>,
     tun_ip_network: Ipv4Network,
     service_daemon: ServiceDaemon,
+    bonding_address: Option<SocketAddr>,
+    bonding: Option<Arc<BondingSender>>,
 }
 
 impl StreamerInner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         name: String,
@@ -701,6 +897,8 @@ impl StreamerInner {
                 unique_indexes: (1..tun_ip_network.size() - 1).rev().collect(),
                 tun_ip_network,
                 service_daemon: Self::create_service_daemon(),
+                bonding_address: None,
+                bonding: None,
             })
         }))
     }
@@ -714,6 +912,9 @@ impl StreamerInner {
         } else {
             self.destination_address = resolve_host(&self.destination_address).await?;
         }
+        if let Some(bonding_address) = self.bonding_address {
+            self.bonding = Some(Arc::new(BondingSender::start(bonding_address).await?));
+        }
         self.start_relay_listener().await?;
         self.start_mdns_daemon();
         Ok(())
@@ -909,6 +1110,7 @@ pub struct Streamer {
 }
 
 impl Streamer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         name: String,
@@ -937,6 +1139,13 @@ impl Streamer {
         })
     }
 
+    /// Bond SRT from the encoder, sent to given address, across all relays
+    /// instead of giving each relay a TUN device. The destination must be a
+    /// `BondingReceiver`. Must be called before `start()`.
+    pub async fn set_bonding_address(&self, address: SocketAddr) {
+        self.inner.lock().await.bonding_address = Some(address);
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         self.inner.lock().await.start().await
     }
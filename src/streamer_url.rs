@@ -0,0 +1,201 @@
+use std::fmt;
+
+use url::Url;
+
+/// A streamer URL that cannot be used, even after corrections.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamerUrlError {
+    Invalid {
+        url: String,
+        reason: String,
+    },
+    UnsupportedScheme {
+        url: String,
+        scheme: String,
+    },
+    MissingHost {
+        url: String,
+    },
+    /// Moblin's WebSocket server listens on port 7777 by default, so a plain
+    /// `ws://` URL without port is almost certainly wrong.
+    MissingPort {
+        url: String,
+    },
+}
+
+impl fmt::Display for StreamerUrlError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamerUrlError::Invalid { url, reason } => {
+                write!(formatter, "Invalid streamer URL '{}': {}", url, reason)
+            }
+            StreamerUrlError::UnsupportedScheme { url, scheme } => write!(
+                formatter,
+                "Unsupported scheme '{}' in streamer URL '{}', use ws:// or wss://",
+                scheme, url
+            ),
+            StreamerUrlError::MissingHost { url } => {
+                write!(formatter, "No host in streamer URL '{}'", url)
+            }
+            StreamerUrlError::MissingPort { url } => write!(
+                formatter,
+                "No port in streamer URL '{}', for example ws://192.168.1.2:7777",
+                url
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StreamerUrlError {}
+
+/// A valid streamer URL and the corrections made to get it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamerUrl {
+    pub url: String,
+    /// Human readable, for example "Changed scheme from http to ws".
+    pub corrections: Vec<String>,
+}
+
+/// Validates given streamer URL. `http(s)://` URLs, often pasted from Moblin,
+/// are converted to `ws(s)://` and URLs without scheme get `ws://`.
+pub fn normalize_streamer_url(url: &str) -> Result<StreamerUrl, StreamerUrlError> {
+    let mut corrections = Vec::new();
+    let mut value = url.trim().to_string();
+    if value != url {
+        corrections.push("Removed surrounding whitespace".to_string());
+    }
+    if !value.contains("://") {
+        value = format!("ws://{}", value);
+        corrections.push("Added scheme ws".to_string());
+    }
+    let mut parsed = Url::parse(&value).map_err(|error| StreamerUrlError::Invalid {
+        url: url.to_string(),
+        reason: error.to_string(),
+    })?;
+    let scheme = match parsed.scheme() {
+        "ws" | "wss" => None,
+        "http" => Some("ws"),
+        "https" => Some("wss"),
+        scheme => {
+            return Err(StreamerUrlError::UnsupportedScheme {
+                url: url.to_string(),
+                scheme: scheme.to_string(),
+            });
+        }
+    };
+    if let Some(scheme) = scheme {
+        corrections.push(format!(
+            "Changed scheme from {} to {}",
+            parsed.scheme(),
+            scheme
+        ));
+        // Replace the whole prefix as the url crate does not allow changing
+        // between special and non-special schemes.
+        let rest = &value[value.find("://").unwrap_or_default()..];
+        parsed = Url::parse(&format!("{}{}", scheme, rest)).map_err(|error| {
+            StreamerUrlError::Invalid {
+                url: url.to_string(),
+                reason: error.to_string(),
+            }
+        })?;
+    }
+    if parsed.host_str().unwrap_or_default().is_empty() {
+        return Err(StreamerUrlError::MissingHost {
+            url: url.to_string(),
+        });
+    }
+    if parsed.scheme() == "ws" && !has_explicit_port(&value) {
+        return Err(StreamerUrlError::MissingPort {
+            url: url.to_string(),
+        });
+    }
+    Ok(StreamerUrl {
+        url: parsed.to_string(),
+        corrections,
+    })
+}
+
+/// The url crate drops default ports, so `ws://example.com:80` has no port
+/// once parsed. Look for one in the authority instead.
+fn has_explicit_port(url: &str) -> bool {
+    let authority = url
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(url)
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let host_and_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let port = match host_and_port.rsplit_once(']') {
+        Some((_, port)) => port,
+        None => host_and_port,
+    };
+    port.rsplit_once(':')
+        .is_some_and(|(_, port)| !port.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> String {
+        normalize_streamer_url(url).unwrap().url
+    }
+
+    #[test]
+    fn keeps_valid_url() {
+        let streamer_url = normalize_streamer_url("ws://192.168.1.2:7777").unwrap();
+        assert_eq!(streamer_url.url, "ws://192.168.1.2:7777/");
+        assert!(streamer_url.corrections.is_empty());
+    }
+
+    #[test]
+    fn corrects_scheme_and_whitespace() {
+        let streamer_url = normalize_streamer_url(" http://192.168.1.2:7777 ").unwrap();
+        assert_eq!(streamer_url.url, "ws://192.168.1.2:7777/");
+        assert_eq!(streamer_url.corrections.len(), 2);
+        assert_eq!(normalized("192.168.1.2:7777"), "ws://192.168.1.2:7777/");
+        assert_eq!(normalized("https://example.com/x"), "wss://example.com/x");
+    }
+
+    #[test]
+    fn accepts_explicit_default_port() {
+        assert_eq!(normalized("ws://example.com:80"), "ws://example.com/");
+        assert_eq!(normalized("http://example.com:80/x"), "ws://example.com/x");
+        assert_eq!(
+            normalized("ws://user:pass@[::1]:80"),
+            "ws://user:pass@[::1]/"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_port() {
+        for url in [
+            "ws://example.com",
+            "ws://example.com:",
+            "ws://user:pass@[::1]/a:1",
+        ] {
+            assert_eq!(
+                normalize_streamer_url(url),
+                Err(StreamerUrlError::MissingPort {
+                    url: url.to_string()
+                })
+            );
+        }
+        assert_eq!(normalized("wss://example.com"), "wss://example.com/");
+    }
+
+    #[test]
+    fn rejects_unusable_urls() {
+        assert!(matches!(
+            normalize_streamer_url("ftp://example.com:21"),
+            Err(StreamerUrlError::UnsupportedScheme { .. })
+        ));
+        assert!(matches!(
+            normalize_streamer_url("ws://:7777"),
+            Err(StreamerUrlError::Invalid { .. })
+        ));
+    }
+}
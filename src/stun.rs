@@ -0,0 +1,382 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use serde::Serialize;
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::time::{Duration, timeout};
+use tracing::debug;
+
+use crate::utils::AnyError;
+
+const HEADER_SIZE: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+const NUMBER_OF_ATTEMPTS: usize = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// NAT mapping behavior as in RFC 4787.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum NatType {
+    /// The public address is an address of this machine.
+    None,
+    /// Same public address and port for all destinations, often called cone
+    /// NAT.
+    EndpointIndependent,
+    /// Different public port per destination, often called symmetric NAT.
+    EndpointDependent,
+    /// Only one STUN server answered, so the mapping behavior is unknown.
+    Unknown,
+    /// No STUN server answered. UDP is probably blocked.
+    Blocked,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            NatType::None => "no NAT",
+            NatType::EndpointIndependent => "cone NAT",
+            NatType::EndpointDependent => "symmetric NAT",
+            NatType::Unknown => "unknown NAT",
+            NatType::Blocked => "UDP blocked",
+        };
+        write!(formatter, "{}", text)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NatInfo {
+    pub nat_type: NatType,
+    /// As seen by the first STUN server that answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_address: Option<SocketAddr>,
+}
+
+impl NatInfo {
+    /// Tunnels are unlikely to work.
+    pub fn is_problematic(&self) -> bool {
+        matches!(self.nat_type, NatType::EndpointDependent | NatType::Blocked)
+    }
+}
+
+/// Sends binding requests to given STUN servers (`host:port`) from given
+/// socket and compares the public addresses they see. At least two servers
+/// are needed to tell endpoint independent and dependent mapping apart.
+pub(crate) async fn discover_nat(socket: &UdpSocket, stun_servers: &[String]) -> NatInfo {
+    let mut public_addresses = Vec::new();
+    for stun_server in stun_servers {
+        match binding_request(socket, stun_server).await {
+            Ok(public_address) => {
+                debug!("STUN server {} sees {}", stun_server, public_address);
+                public_addresses.push(public_address);
+            }
+            Err(error) => {
+                debug!("STUN server {} failed with error: {}", stun_server, error);
+            }
+        }
+    }
+    let nat_type = match public_addresses.as_slice() {
+        [] => NatType::Blocked,
+        [first, ..] if is_local_address(first.ip()) => NatType::None,
+        [_] => NatType::Unknown,
+        [first, rest @ ..] if rest.iter().all(|address| address == first) => {
+            NatType::EndpointIndependent
+        }
+        _ => NatType::EndpointDependent,
+    };
+    NatInfo {
+        nat_type,
+        public_address: public_addresses.first().copied(),
+    }
+}
+
+async fn binding_request(socket: &UdpSocket, stun_server: &str) -> Result<SocketAddr, AnyError> {
+    let is_ipv4 = socket.local_addr()?.is_ipv4();
+    let stun_server_address = lookup_host(stun_server)
+        .await?
+        .find(|address| address.is_ipv4() == is_ipv4)
+        .ok_or("STUN server not found")?;
+    let transaction_id: [u8; 12] = rand::random();
+    let mut request = Vec::with_capacity(HEADER_SIZE);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    let mut buffer = [0; 1500];
+    for _ in 0..NUMBER_OF_ATTEMPTS {
+        socket.send_to(&request, stun_server_address).await?;
+        let Ok(result) = timeout(ATTEMPT_TIMEOUT, async {
+            loop {
+                let (size, address) = socket.recv_from(&mut buffer).await?;
+                if address != stun_server_address {
+                    continue;
+                }
+                if let Some(public_address) =
+                    parse_binding_response(&buffer[..size], &transaction_id)
+                {
+                    return Ok::<_, std::io::Error>(public_address);
+                }
+            }
+        })
+        .await
+        else {
+            continue;
+        };
+        return Ok(result?);
+    }
+    Err("No response".into())
+}
+
+fn parse_binding_response(packet: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if packet.len() < HEADER_SIZE
+        || u16::from_be_bytes([packet[0], packet[1]]) != BINDING_SUCCESS_RESPONSE
+        || packet[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || packet[8..20] != transaction_id[..]
+    {
+        return None;
+    }
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let attributes = packet.get(HEADER_SIZE..HEADER_SIZE + length)?;
+    let mut mapped_address = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let length = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes.get(offset + 4..offset + 4 + length)?;
+        match kind {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction_id)),
+            ATTRIBUTE_MAPPED_ADDRESS => mapped_address = parse_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        offset += 4 + length.div_ceil(4) * 4;
+    }
+    mapped_address
+}
+
+/// XOR-MAPPED-ADDRESS is obfuscated with the magic cookie and transaction id.
+fn parse_address(value: &[u8], transaction_id: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let mut key = [0; 16];
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    if let Some(transaction_id) = transaction_id {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match value[1] {
+        FAMILY_IPV4 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets
+                .iter_mut()
+                .zip(key)
+                .for_each(|(octet, key)| *octet ^= key);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets
+                .iter_mut()
+                .zip(key)
+                .for_each(|(octet, key)| *octet ^= key);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn is_local_address(ip: IpAddr) -> bool {
+    let Ok(interfaces) = NetworkInterface::show() else {
+        return false;
+    };
+    interfaces
+        .iter()
+        .flat_map(|interface| &interface.addr)
+        .any(|addr| match addr {
+            Addr::V4(addr) => IpAddr::V4(addr.ip) == ip,
+            Addr::V6(addr) => IpAddr::V6(addr.ip) == ip,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    fn binding_response(attributes: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        response.extend_from_slice(&BINDING_SUCCESS_RESPONSE.to_be_bytes());
+        response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(&TRANSACTION_ID);
+        response.extend_from_slice(attributes);
+        response
+    }
+
+    #[test]
+    fn parses_xor_mapped_address() {
+        // 203.0.113.5:40000 XORed with the magic cookie.
+        let response = binding_response(&[
+            0x00,
+            0x20,
+            0x00,
+            0x08,
+            0x00,
+            FAMILY_IPV4,
+            0xbd,
+            0x52,
+            0xea,
+            0x12,
+            0xd5,
+            0x47,
+        ]);
+        assert_eq!(
+            parse_binding_response(&response, &TRANSACTION_ID),
+            Some("203.0.113.5:40000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn prefers_xor_mapped_address_after_mapped_address() {
+        let response = binding_response(&[
+            0x00,
+            0x01,
+            0x00,
+            0x08,
+            0x00,
+            FAMILY_IPV4,
+            0x9c,
+            0x40,
+            198,
+            51,
+            100,
+            1,
+            0x00,
+            0x20,
+            0x00,
+            0x08,
+            0x00,
+            FAMILY_IPV4,
+            0xbd,
+            0x52,
+            0xea,
+            0x12,
+            0xd5,
+            0x47,
+        ]);
+        assert_eq!(
+            parse_binding_response(&response, &TRANSACTION_ID),
+            Some("203.0.113.5:40000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parses_mapped_address() {
+        let response = binding_response(&[
+            0x00,
+            0x01,
+            0x00,
+            0x08,
+            0x00,
+            FAMILY_IPV4,
+            0x9c,
+            0x40,
+            198,
+            51,
+            100,
+            1,
+        ]);
+        assert_eq!(
+            parse_binding_response(&response, &TRANSACTION_ID),
+            Some("198.51.100.1:40000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_other_transaction() {
+        let response = binding_response(&[
+            0x00,
+            0x01,
+            0x00,
+            0x08,
+            0x00,
+            FAMILY_IPV4,
+            0x9c,
+            0x40,
+            198,
+            51,
+            100,
+            1,
+        ]);
+        assert_eq!(parse_binding_response(&response, &[0; 12]), None);
+    }
+
+    #[test]
+    fn rejects_truncated_attribute() {
+        let response = binding_response(&[0x00, 0x20, 0x00, 0x08, 0x00, FAMILY_IPV4, 0xbd, 0x52]);
+        assert_eq!(parse_binding_response(&response, &TRANSACTION_ID), None);
+        assert_eq!(
+            parse_binding_response(&response[..10], &TRANSACTION_ID),
+            None
+        );
+    }
+
+    /// Answers binding requests with the sender's address.
+    async fn start_stun_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            while let Ok((size, sender)) = socket.recv_from(&mut buffer).await {
+                let SocketAddr::V4(sender) = sender else {
+                    continue;
+                };
+                let port = sender.port() ^ (MAGIC_COOKIE >> 16) as u16;
+                let ip = u32::from(*sender.ip()) ^ MAGIC_COOKIE;
+                let mut response = Vec::new();
+                response.extend_from_slice(&BINDING_SUCCESS_RESPONSE.to_be_bytes());
+                response.extend_from_slice(&12u16.to_be_bytes());
+                response.extend_from_slice(&buffer[4..size.min(HEADER_SIZE)]);
+                response.extend_from_slice(&ATTRIBUTE_XOR_MAPPED_ADDRESS.to_be_bytes());
+                response.extend_from_slice(&8u16.to_be_bytes());
+                response.extend_from_slice(&[0, FAMILY_IPV4]);
+                response.extend_from_slice(&port.to_be_bytes());
+                response.extend_from_slice(&ip.to_be_bytes());
+                socket.send_to(&response, sender).await.ok();
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn discovers_local_address() {
+        let stun_servers = vec![
+            start_stun_server().await.to_string(),
+            start_stun_server().await.to_string(),
+        ];
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nat = discover_nat(&socket, &stun_servers).await;
+        assert_eq!(nat.nat_type, NatType::None);
+        assert_eq!(nat.public_address, Some(socket.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn no_answer_is_blocked() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nat = discover_nat(&socket, &[silent.local_addr().unwrap().to_string()]).await;
+        assert_eq!(nat.nat_type, NatType::Blocked);
+        assert_eq!(nat.public_address, None);
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tracing::{Instrument, debug, info};
+
+use crate::happy_eyeballs::connect_first;
+use crate::stats::Counters;
+use crate::utils::AnyError;
+
+/// Accepts connections from the streamer and forwards each of them over its
+/// own connection to the destination, until aborted. Every read is counted as
+/// a packet. The destination addresses are tried as in Happy Eyeballs.
+pub(crate) async fn relay_tcp_connections(
+    listener: TcpListener,
+    bind_address: SocketAddr,
+    destination_addresses: Vec<SocketAddr>,
+    counters: Arc<Counters>,
+) -> Result<(), AnyError> {
+    // Aborted with this task.
+    let mut connections = JoinSet::new();
+    loop {
+        let (streamer_stream, streamer_address) = listener.accept().await?;
+        debug!("Accepted TCP connection from {}", streamer_address);
+        let counters = counters.clone();
+        let destination_addresses = destination_addresses.clone();
+        let connection = async move {
+            match relay_tcp_connection(
+                streamer_stream,
+                bind_address,
+                &destination_addresses,
+                counters,
+            )
+            .await
+            {
+                Ok(()) => debug!("TCP connection from {} closed", streamer_address),
+                Err(error) => info!(
+                    "TCP connection from {} failed with error: {}",
+                    streamer_address, error
+                ),
+            }
+        };
+        connections.spawn(connection.in_current_span());
+        while connections.try_join_next().is_some() {}
+    }
+}
+
+async fn relay_tcp_connection(
+    streamer_stream: TcpStream,
+    bind_address: SocketAddr,
+    destination_addresses: &[SocketAddr],
+    counters: Arc<Counters>,
+) -> Result<(), AnyError> {
+    let destination_stream = connect_first(destination_addresses, |destination_address| {
+        connect_to_destination(bind_address, destination_address)
+    })
+    .await?;
+    debug!(
+        "Connected to destination {}",
+        destination_stream.peer_addr()?
+    );
+    streamer_stream.set_nodelay(true)?;
+    destination_stream.set_nodelay(true)?;
+    let (mut streamer_reader, mut streamer_writer) = streamer_stream.into_split();
+    let (mut destination_reader, mut destination_writer) = destination_stream.into_split();
+    tokio::try_join!(
+        copy(&mut streamer_reader, &mut destination_writer, |size| {
+            counters.add_to_destination(size)
+        }),
+        copy(&mut destination_reader, &mut streamer_writer, |size| {
+            counters.add_to_streamer(size)
+        }),
+    )?;
+    Ok(())
+}
+
+async fn connect_to_destination(
+    bind_address: SocketAddr,
+    destination_address: SocketAddr,
+) -> Result<TcpStream, std::io::Error> {
+    let socket = match destination_address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if bind_address.is_ipv4() == destination_address.is_ipv4() {
+        socket.bind(bind_address)?;
+    }
+    socket.connect(destination_address).await
+}
+
+/// Until end of stream, which is forwarded as a shutdown of the writer.
+async fn copy<R, W, F>(reader: &mut R, writer: &mut W, count: F) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize),
+{
+    let mut buffer = vec![0; 16384];
+    loop {
+        let size = reader.read(&mut buffer).await?;
+        if size == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buffer[..size]).await?;
+        count(size);
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::now_us;
+
+/// Probe packets start with this magic. A cooperating destination echoes
+/// probes back with its receive timestamp appended, see `create_probe_echo()`.
+const PROBE_MAGIC: &[u8; 8] = b"MOBLPRB1";
+const PROBE_SIZE: usize = PROBE_MAGIC.len() + 4 + 8;
+const PROBE_ECHO_SIZE: usize = PROBE_SIZE + 8;
+
+/// Latency and loss measured with probes during a tunnel's lifetime.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TestReport {
+    pub probes_sent: u64,
+    pub probes_received: u64,
+    pub loss_percentage: f64,
+    pub round_trip_time_min_ms: f64,
+    pub round_trip_time_avg_ms: f64,
+    pub round_trip_time_max_ms: f64,
+    /// Only meaningful if the relay and destination clocks are synchronized.
+    pub one_way_delay_avg_ms: f64,
+    pub jitter_ms: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct ProbeStats {
+    next_sequence: u32,
+    sent: u64,
+    received: u64,
+    round_trip_time_min_us: Option<u64>,
+    round_trip_time_max_us: u64,
+    round_trip_time_sum_us: u64,
+    one_way_delay_sum_us: i64,
+    latest_one_way_delay_us: Option<i64>,
+    jitter_us: f64,
+}
+
+impl ProbeStats {
+    pub(crate) fn create_probe(&mut self) -> Vec<u8> {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.sent += 1;
+        let mut probe = Vec::with_capacity(PROBE_SIZE);
+        probe.extend_from_slice(PROBE_MAGIC);
+        probe.extend_from_slice(&sequence.to_be_bytes());
+        probe.extend_from_slice(&now_us().to_be_bytes());
+        probe
+    }
+
+    /// Returns true if given packet is a probe echo, which must not be
+    /// forwarded to the streamer.
+    pub(crate) fn handle_packet(&mut self, packet: &[u8]) -> bool {
+        if packet.len() != PROBE_ECHO_SIZE || !packet.starts_with(PROBE_MAGIC) {
+            return false;
+        }
+        let now = now_us();
+        let sent = read_u64(&packet[PROBE_MAGIC.len() + 4..]);
+        let destination = read_u64(&packet[PROBE_SIZE..]);
+        let round_trip_time = now.saturating_sub(sent);
+        let one_way_delay = destination as i64 - sent as i64;
+        self.received += 1;
+        self.round_trip_time_min_us = Some(
+            self.round_trip_time_min_us
+                .unwrap_or(round_trip_time)
+                .min(round_trip_time),
+        );
+        self.round_trip_time_max_us = self.round_trip_time_max_us.max(round_trip_time);
+        self.round_trip_time_sum_us += round_trip_time;
+        self.one_way_delay_sum_us += one_way_delay;
+        // Interarrival jitter as in RFC 3550.
+        if let Some(latest_one_way_delay) = self.latest_one_way_delay_us {
+            let difference = (one_way_delay - latest_one_way_delay).abs() as f64;
+            self.jitter_us += (difference - self.jitter_us) / 16.0;
+        }
+        self.latest_one_way_delay_us = Some(one_way_delay);
+        true
+    }
+
+    pub(crate) fn report(&self) -> TestReport {
+        let received = self.received.max(1) as f64;
+        TestReport {
+            probes_sent: self.sent,
+            probes_received: self.received,
+            loss_percentage: if self.sent > 0 {
+                100.0 * self.sent.saturating_sub(self.received) as f64 / self.sent as f64
+            } else {
+                0.0
+            },
+            round_trip_time_min_ms: self.round_trip_time_min_us.unwrap_or_default() as f64 / 1000.0,
+            round_trip_time_avg_ms: self.round_trip_time_sum_us as f64 / received / 1000.0,
+            round_trip_time_max_ms: self.round_trip_time_max_us as f64 / 1000.0,
+            one_way_delay_avg_ms: self.one_way_delay_sum_us as f64 / received / 1000.0,
+            jitter_ms: self.jitter_us / 1000.0,
+        }
+    }
+}
+
+/// Used by cooperating destinations to answer a probe. Returns `None` if given
+/// packet is not a probe.
+pub fn create_probe_echo(packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() != PROBE_SIZE || !packet.starts_with(PROBE_MAGIC) {
+        return None;
+    }
+    let mut echo = packet.to_vec();
+    echo.extend_from_slice(&now_us().to_be_bytes());
+    Some(echo)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,211 @@
+//! An in-process mock streamer and UDP echo destination, to exercise the
+//! handshake and tunnel of a relay without a real streamer or network setup.
+//!
+//! Start an `EchoDestination` and a `MockStreamer` pointing at it, start a
+//! relay with the mock streamer's URL and password, wait for the tunnel with
+//! `MockStreamer::wait_for_tunnel()`, and send datagrams to the returned
+//! address. They come back through the relay.
+//!
+//! The `moblink-mock-streamer` binary does the same with relays on other
+//! machines, to validate a relay box before an event.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use futures_util::StreamExt;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{MaybeTlsStream, accept_async};
+use tracing::info;
+
+use crate::chain::{WebSocket, receive, send};
+use crate::protocol::*;
+use crate::utils::{AnyError, random_string};
+
+/// Echoes every datagram back to its sender. Stopped when dropped.
+pub struct EchoDestination {
+    address: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl EchoDestination {
+    /// Listens on an ephemeral port on localhost.
+    pub async fn start() -> Result<Self, AnyError> {
+        Self::bind((Ipv4Addr::LOCALHOST, 0).into()).await
+    }
+
+    /// Listens on given address.
+    pub async fn bind(address: SocketAddr) -> Result<Self, AnyError> {
+        let socket = UdpSocket::bind(address).await?;
+        let address = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+            while let Ok((size, sender)) = socket.recv_from(&mut buffer).await {
+                socket.send_to(&buffer[..size], sender).await.ok();
+            }
+        });
+        Ok(Self { address, task })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for EchoDestination {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Accepts relays over WebSocket, identifies them with given password and asks
+/// each of them to start a tunnel to given destination. An unspecified
+/// destination IP, for example of an `EchoDestination` listening on
+/// `0.0.0.0`, is replaced by the IP the relay connected to. Stopped when
+/// dropped.
+pub struct MockStreamer {
+    address: SocketAddr,
+    tunnel: watch::Receiver<Option<SocketAddr>>,
+    task: JoinHandle<()>,
+}
+
+impl MockStreamer {
+    /// Listens on an ephemeral port on localhost.
+    pub async fn start(password: &str, destination: SocketAddr) -> Result<Self, AnyError> {
+        Self::bind((Ipv4Addr::LOCALHOST, 0).into(), password, destination).await
+    }
+
+    /// Listens on given address.
+    pub async fn bind(
+        address: SocketAddr,
+        password: &str,
+        destination: SocketAddr,
+    ) -> Result<Self, AnyError> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let (tunnel_sender, tunnel) = watch::channel(None);
+        let password = password.to_string();
+        let task = tokio::spawn(async move {
+            while let Ok((tcp_stream, relay_address)) = listener.accept().await {
+                let password = password.clone();
+                let tunnel_sender = tunnel_sender.clone();
+                tokio::spawn(async move {
+                    let websocket = match accept_async(MaybeTlsStream::Plain(tcp_stream)).await {
+                        Ok(websocket) => websocket,
+                        Err(error) => {
+                            info!("Mock streamer handshake failed with error: {}", error);
+                            return;
+                        }
+                    };
+                    if let Err(error) =
+                        serve_relay(websocket, &password, destination, &tunnel_sender).await
+                    {
+                        info!(
+                            "Mock streamer connection from {} failed with error: {}",
+                            relay_address, error
+                        );
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            address,
+            tunnel,
+            task,
+        })
+    }
+
+    /// The streamer URL to give the relay.
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.address)
+    }
+
+    /// The address to send datagrams to for them to go through the tunnel of
+    /// the latest relay. Waits until a relay has started one.
+    pub async fn wait_for_tunnel(&self) -> Result<SocketAddr, AnyError> {
+        let mut tunnel = self.tunnel.clone();
+        let address = tunnel.wait_for(Option::is_some).await?;
+        Ok(address.ok_or("No tunnel")?)
+    }
+}
+
+impl Drop for MockStreamer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_relay(
+    mut websocket: WebSocket,
+    password: &str,
+    destination: SocketAddr,
+    tunnel: &watch::Sender<Option<SocketAddr>>,
+) -> Result<(), AnyError> {
+    let challenge = random_string();
+    let salt = random_string();
+    let hello = Hello {
+        api_version: API_VERSION.to_string(),
+        authentication: Authentication {
+            challenge: challenge.clone(),
+            salt: salt.clone(),
+        },
+        capabilities: Vec::new(),
+    };
+    send(&mut websocket, &MessageToRelay::Hello(hello)).await?;
+    let MessageToStreamer::Identify(identify) = receive(&mut websocket).await? else {
+        return Err("Expected identify".into());
+    };
+    let result = if identify.authentication == calculate_authentication(password, &salt, &challenge)
+    {
+        MoblinkResult::Ok(Present {})
+    } else {
+        MoblinkResult::WrongPassword(Present {})
+    };
+    let wrong_password = matches!(result, MoblinkResult::WrongPassword(_));
+    send(
+        &mut websocket,
+        &MessageToRelay::Identified(Identified {
+            result,
+            authentication: None,
+        }),
+    )
+    .await?;
+    if wrong_password {
+        return Err("Relay sent wrong password".into());
+    }
+    info!("Mock streamer identified relay {}", identify.name);
+    let destination_ip = match websocket.get_ref() {
+        MaybeTlsStream::Plain(tcp_stream) if destination.ip().is_unspecified() => {
+            tcp_stream.local_addr()?.ip()
+        }
+        _ => destination.ip(),
+    };
+    let request = MessageRequest {
+        id: 1,
+        data: MessageRequestData::StartTunnel(StartTunnelRequest {
+            address: destination_ip.to_string(),
+            port: destination.port(),
+            obfuscated: false,
+            paired_ports: false,
+        }),
+    };
+    send(&mut websocket, &MessageToRelay::Request(request)).await?;
+    let MessageToStreamer::Response(MessageResponse {
+        result: MoblinkResult::Ok(_),
+        data: ResponseData::StartTunnel(data),
+        ..
+    }) = receive(&mut websocket).await?
+    else {
+        return Err("Relay failed to start tunnel".into());
+    };
+    let relay_ip = match websocket.get_ref() {
+        MaybeTlsStream::Plain(tcp_stream) => tcp_stream.peer_addr()?.ip(),
+        _ => Ipv4Addr::LOCALHOST.into(),
+    };
+    tunnel.send_replace(Some(SocketAddr::new(relay_ip, data.port)));
+    // Keep the connection open, answering pings, until the relay closes it.
+    while let Some(message) = websocket.next().await {
+        message?;
+    }
+    Ok(())
+}
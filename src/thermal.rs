@@ -0,0 +1,68 @@
+use serde::Serialize;
+
+use crate::utils::AnyError;
+
+/// Temperature of one thermal zone, for example the SoC.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThermalZone {
+    /// The type of the zone, for example `cpu-thermal`, or its directory name
+    /// if not known.
+    pub name: String,
+    pub temperature_celsius: f64,
+}
+
+/// Thermal monitoring settings, see `Relay::set_thermal_monitoring()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalConfig {
+    /// The relay is overheating when the hottest zone reaches this
+    /// temperature.
+    pub warning_celsius: f64,
+    /// Pace datagrams to the destination at no more than this many bits per
+    /// second while overheating.
+    pub throttle_bitrate: Option<u64>,
+}
+
+/// The hottest of given zones, if any.
+pub(crate) fn hottest(zones: &[ThermalZone]) -> Option<&ThermalZone> {
+    zones
+        .iter()
+        .max_by(|a, b| a.temperature_celsius.total_cmp(&b.temperature_celsius))
+}
+
+/// Reads the thermal zones in `/sys/class/thermal`. Zones without a readable
+/// temperature are left out.
+#[cfg(target_os = "linux")]
+pub(crate) async fn get_thermal_zones() -> Result<Vec<ThermalZone>, AnyError> {
+    let mut entries = tokio::fs::read_dir("/sys/class/thermal").await?;
+    let mut zones = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let directory_name = entry.file_name().to_string_lossy().to_string();
+        if !directory_name.starts_with("thermal_zone") {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(temperature) = tokio::fs::read_to_string(path.join("temp")).await else {
+            continue;
+        };
+        // In millidegrees Celsius.
+        let Ok(temperature) = temperature.trim().parse::<i64>() else {
+            continue;
+        };
+        let name = match tokio::fs::read_to_string(path.join("type")).await {
+            Ok(name) => name.trim().to_string(),
+            Err(_) => directory_name,
+        };
+        zones.push(ThermalZone {
+            name,
+            temperature_celsius: temperature as f64 / 1000.0,
+        });
+    }
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(zones)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn get_thermal_zones() -> Result<Vec<ThermalZone>, AnyError> {
+    Err("Thermal zones are only available on Linux".into())
+}
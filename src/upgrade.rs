@@ -0,0 +1,250 @@
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{UdpSocket, UnixListener};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::relay::{Handover, Relay, TunnelInfo, TunnelSockets};
+use crate::utils::AnyError;
+
+const MAXIMUM_MESSAGE_SIZE: usize = 65536;
+/// Streamer and destination sockets.
+const MAXIMUM_NUMBER_OF_FDS: usize = 2;
+
+/// Sent from the old to the new process, together with the tunnel socket file
+/// descriptors.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HandoverMessage {
+    relay_id: Uuid,
+    name: String,
+    tunnel: Option<TunnelInfo>,
+}
+
+pub type OnHandedOverClosure = Box<dyn Fn() + Send + Sync>;
+
+/// Listens on a unix socket for a new relay process that wants to take over.
+/// When one connects, the relay is stopped and its identity and tunnel sockets
+/// are passed to the new process, so that relaying continues while the new
+/// process connects to the streamer.
+pub struct UpgradeServer {
+    server: JoinHandle<()>,
+    path: PathBuf,
+}
+
+impl UpgradeServer {
+    pub async fn start<F>(path: &Path, relay: Relay, on_handed_over: F) -> Result<Self, AnyError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        // Left behind by the process we took over from, if any.
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!("Upgrade server listening on '{}'", path.display());
+        let on_handed_over: OnHandedOverClosure = Box::new(on_handed_over);
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                info!("New relay process is taking over");
+                match hand_over(stream, &relay).await {
+                    Ok(stream) => {
+                        info!("Handed over to new relay process");
+                        on_handed_over();
+                        // The new process waits for the connection to be closed
+                        // before binding to addresses used by this process.
+                        drop(stream);
+                        break;
+                    }
+                    Err(error) => {
+                        error!("Hand over failed with error: {}", error);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            server,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn stop(&self) {
+        self.server.abort();
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+async fn hand_over(stream: tokio::net::UnixStream, relay: &Relay) -> Result<UnixStream, AnyError> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let Handover {
+        relay_id,
+        name,
+        tunnel,
+    } = relay.hand_over().await;
+    let message = HandoverMessage {
+        relay_id,
+        name,
+        tunnel: tunnel.as_ref().map(|tunnel| tunnel.info.clone()),
+    };
+    let data = serde_json::to_vec(&message)?;
+    let fds = match &tunnel {
+        Some(tunnel) => vec![
+            tunnel.streamer_socket.as_raw_fd(),
+            tunnel.destination_socket.as_raw_fd(),
+        ],
+        None => vec![],
+    };
+    let result =
+        tokio::task::spawn_blocking(move || send_with_fds(&stream, &data, &fds).map(|()| stream))
+            .await?;
+    match result {
+        Ok(stream) => Ok(stream),
+        Err(error) => {
+            // Continue as before.
+            if let Some(tunnel) = tunnel {
+                relay.adopt_tunnel(tunnel).await;
+            }
+            if let Err(start_error) = relay.start().await {
+                error!("Failed to restart relay with error: {}", start_error);
+            }
+            Err(error.into())
+        }
+    }
+}
+
+/// Takes over from the relay process listening on given unix socket. Use the
+/// returned relay id and name, and adopt the tunnel, if any.
+pub async fn take_over(path: &Path) -> Result<Handover, AnyError> {
+    let path = path.to_path_buf();
+    let (data, fds) = tokio::task::spawn_blocking(move || {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let result = receive_with_fds(&stream)?;
+        // Wait for the old process to close the connection, which it does when
+        // it is done (typically exits).
+        let _ = (&stream).read(&mut [0]);
+        Ok::<_, io::Error>(result)
+    })
+    .await??;
+    let message: HandoverMessage = serde_json::from_slice(&data)?;
+    let tunnel = match message.tunnel {
+        Some(info) => {
+            let mut fds = fds.into_iter();
+            let (Some(streamer_fd), Some(destination_fd)) = (fds.next(), fds.next()) else {
+                return Err("Tunnel sockets missing in hand over".into());
+            };
+            Some(TunnelSockets {
+                info,
+                streamer_socket: Arc::new(create_udp_socket(streamer_fd)?),
+                streamer_worker_sockets: Vec::new(),
+                destination_socket: Arc::new(create_udp_socket(destination_fd)?),
+                multipath_sockets: Vec::new(),
+                other_destination_addresses: Vec::new(),
+            })
+        }
+        None => None,
+    };
+    Ok(Handover {
+        relay_id: message.relay_id,
+        name: message.name,
+        tunnel,
+    })
+}
+
+fn create_udp_socket(fd: OwnedFd) -> Result<UdpSocket, io::Error> {
+    let socket = std::net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+fn send_with_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<(), io::Error> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fds_size = std::mem::size_of_val(fds) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    if !fds.is_empty() {
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+        // SAFETY: The control buffer is big enough for one header with all file
+        // descriptors.
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(header) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+    let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &message, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent as usize != data.len() {
+        return Err(io::Error::other("Hand over message truncated"));
+    }
+    Ok(())
+}
+
+fn receive_with_fds(stream: &UnixStream) -> Result<(Vec<u8>, Vec<OwnedFd>), io::Error> {
+    let mut data = vec![0u8; MAXIMUM_MESSAGE_SIZE];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fds_size = (MAXIMUM_NUMBER_OF_FDS * size_of::<RawFd>()) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control.len() as _;
+    let received =
+        unsafe { libc::recvmsg(stream.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::Error::other("Old relay process closed the connection"));
+    }
+    let mut fds = Vec::new();
+    // SAFETY: The headers are parsed with the CMSG macros within the control
+    // buffer given to recvmsg().
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let count =
+                    ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                let data = libc::CMSG_DATA(header) as *const RawFd;
+                for index in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(
+                        data.add(index),
+                    )));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    if message.msg_flags & libc::MSG_TRUNC != 0 {
+        return Err(io::Error::other("Hand over message truncated"));
+    }
+    data.truncate(received as usize);
+    Ok((data, fds))
+}
@@ -1,17 +1,36 @@
 use std::collections::HashSet;
-use std::net::Ipv4Addr;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::{error, info, warn};
 use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
 use rand::distr::{Alphanumeric, SampleString};
-use tokio::net::lookup_host;
 use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::dns::DnsResolver;
 
 pub const MDNS_SERVICE_TYPE: &str = "_moblink._tcp.local.";
 
 pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Microseconds since the Unix epoch.
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Aborts the task when dropped.
+pub(crate) struct AbortOnDrop(pub JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 pub fn random_string() -> String {
     Alphanumeric.sample_string(&mut rand::rng(), 64)
 }
@@ -37,11 +56,23 @@ pub fn format_command(executable: &str, args: &[&str]) -> String {
 }
 
 pub async fn resolve_host(address: &str) -> Result<String, AnyError> {
+    resolve_host_with(&DnsResolver::system(), address).await
+}
+
+pub async fn resolve_host_with(resolver: &DnsResolver, address: &str) -> Result<String, AnyError> {
+    Ok(resolve_host_addresses(resolver, address).await?[0].to_string())
+}
+
+/// All addresses of given host, in preference order. Never empty.
+pub async fn resolve_host_addresses(
+    resolver: &DnsResolver,
+    address: &str,
+) -> Result<Vec<IpAddr>, AnyError> {
     for _ in 0..50 {
-        match lookup_host(format!("{}:9999", address)).await {
-            Ok(mut addresses) => {
-                if let Some(address) = addresses.next() {
-                    return Ok(address.ip().to_string());
+        match resolver.lookup(address).await {
+            Ok(addresses) => {
+                if !addresses.is_empty() {
+                    return Ok(addresses);
                 } else {
                     warn!("No address found for {}", address);
                 }
@@ -70,10 +101,10 @@ pub fn any_address_belongs_to_this_machine(addresses: &HashSet<&Ipv4Addr>) -> bo
     };
     for interface in interfaces {
         for addr in interface.addr {
-            if let Addr::V4(addr) = addr {
-                if addresses.contains(&addr.ip) {
-                    return true;
-                }
+            if let Addr::V4(addr) = addr
+                && addresses.contains(&addr.ip)
+            {
+                return true;
             }
         }
     }
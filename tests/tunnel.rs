@@ -0,0 +1,71 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use moblink_rust::relay::{RelayBuilder, RelayError};
+use moblink_rust::testing::{EchoDestination, MockStreamer};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn datagrams_are_echoed_through_tunnel() {
+    let destination = EchoDestination::start().await.unwrap();
+    let streamer = MockStreamer::start("1234", destination.address())
+        .await
+        .unwrap();
+    let relay = RelayBuilder::new(streamer.url())
+        .password("1234")
+        .name("Test".to_string())
+        .build()
+        .await
+        .unwrap();
+    relay
+        .set_bind_address(Ipv4Addr::LOCALHOST.to_string())
+        .await;
+    relay.start().await.unwrap();
+    timeout(TIMEOUT, relay.await_connected())
+        .await
+        .unwrap()
+        .unwrap();
+    let tunnel = timeout(TIMEOUT, streamer.wait_for_tunnel())
+        .await
+        .unwrap()
+        .unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut buffer = [0; 2048];
+    for sequence_number in 0..10u32 {
+        let payload = sequence_number.to_be_bytes();
+        socket.send_to(&payload, tunnel).await.unwrap();
+        let (size, sender) = timeout(TIMEOUT, socket.recv_from(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sender, tunnel);
+        assert_eq!(&buffer[..size], payload);
+    }
+    relay.stop().await;
+    timeout(TIMEOUT, relay.await_stopped()).await.unwrap();
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let destination = EchoDestination::start().await.unwrap();
+    let streamer = MockStreamer::start("1234", destination.address())
+        .await
+        .unwrap();
+    let relay = RelayBuilder::new(streamer.url())
+        .password("4321")
+        .name("Test".to_string())
+        .build()
+        .await
+        .unwrap();
+    relay
+        .set_bind_address(Ipv4Addr::LOCALHOST.to_string())
+        .await;
+    relay.start().await.unwrap();
+    assert_eq!(
+        timeout(TIMEOUT, relay.await_connected()).await.unwrap(),
+        Err(RelayError::WrongPassword)
+    );
+}